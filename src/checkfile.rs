@@ -0,0 +1,1729 @@
+// Copyright 2021 Daniel Zwell.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Reading and writing the lines of checkfiles, without reading the files they list.
+//!
+//! These lines are understood:
+//!
+//! - `<hex>  <filename>`, as written by b3sum, md5sum, sha1sum and sha256sum. `<hex> *<filename>`
+//!   is also accepted.
+//! - `<ALGORITHM> (<filename>) = <hex>`, the BSD style of `--tag`.
+//! - `<filename> <CRC32 hex>` in SFV files, where lines starting with `;` are comments.
+//! - The rows of hashdeep files, after their `%%%% size,md5,sha256,filename` header.
+//!
+//! In the first two, a line starting with a backslash has a filename in which backslashes and
+//! newlines are escaped as `\\` and `\n`.
+//!
+//! With `--timestamps`, each entry follows a comment like `# computed 2026-10-14T09:30:12.123Z`,
+//! the time its checksum was computed, which is kept in [`Entry::timestamp`]. With `--metadata`,
+//! it follows a comment with the [`FileMetadata`] of the file, and with `--known-hashes`, a file
+//! that is in the [`KnownHashes`] set may follow `# known`. With `--entropy`, it follows a comment
+//! like `# entropy 7.9993`, and with `--ssdeep`, a comment like `# ssdeep 3:AXGBicFlgVNhBGcL:AXGH`.
+//! These comments are skipped when checkfiles are read. With
+//! `--piecewise`, it follows comments with the checksums of the pieces of the file, which are kept
+//! in [`Entry::pieces`].
+//!
+//! A checkfile may end with a [`Seal`], which is the BLAKE3 of the lines before it, so that
+//! accidental changes to the checkfile can be detected, or with a key, any changes. A manifest that is appended to over time can instead
+//! be chained with `--chain`, so that each entry starts with a `# chain` comment that links it to
+//! the one before it. See [`check_chain`].
+//!
+//! ```
+//! use b3sum_ng::checkfile::{format_line, Dialect, Parser};
+//! use b3sum_ng::output::Format;
+//!
+//! let mut parser = Parser::new(Dialect::default());
+//! let line = "BLAKE3 (new\\nline) = d74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24";
+//! let entry = parser.parse_line(&format!("\\{}", line)).unwrap().unwrap();
+//! assert_eq!("new\nline", entry.name);
+//! assert!(format_line(&entry, Format::B3sum).unwrap().starts_with("\\d74981ef"));
+//!
+//! let err = parser.parse_line("d74981ef  hello.txt").unwrap_err();
+//! assert_eq!((2, 1), (err.line, err.column));
+//! ```
+
+use std::{
+    error::Error,
+    fmt,
+    fs::Metadata,
+    io::BufRead,
+    path::Path,
+    time::{Duration, SystemTime},
+};
+
+#[cfg(test)]
+use crate::make_temp_file;
+use crate::{
+    engine::{Algorithm, Blake3Mode, Checksum, Chunk},
+    output::Format,
+    Result,
+};
+
+/// One checksum listed in a checkfile.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    /// The filename, after unescaping. It is relative to the directory of an SFV file, and to the
+    /// current directory otherwise.
+    pub name: String,
+    pub checksum: Checksum,
+    pub algorithm: Algorithm,
+    /// The size of the file, if the checkfile lists it, as hashdeep files do.
+    pub size: Option<u64>,
+    /// When the checksum was computed, if the checkfile has a timestamp on the line before it.
+    pub timestamp: Option<SystemTime>,
+    /// The owner, mode, modification time and size of the file, if they were recorded.
+    pub metadata: Option<FileMetadata>,
+    /// The checksums of the consecutive pieces of the file, with `--piecewise`.
+    pub pieces: Vec<Chunk>,
+}
+
+/// The attributes of a file that `--metadata` records with its checksum, on a comment line like
+/// `# metadata size=11 mode=100644 uid=1000 gid=1000 mtime=2026-10-14T09:30:12.123Z`. The mode
+/// and owner are only recorded on Unix, and the modification time is kept to the millisecond.
+/// `--xattrs` adds the digest of the extended attributes and ACL, as `xattrs=<checksum>`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FileMetadata {
+    pub size: Option<u64>,
+    /// The permissions and file type, as in `st_mode`.
+    pub mode: Option<u32>,
+    pub uid: Option<u32>,
+    pub gid: Option<u32>,
+    pub mtime: Option<SystemTime>,
+    /// The [`xattrs_digest`](crate::engine::xattrs_digest) of the file.
+    pub xattrs: Option<Checksum>,
+}
+
+impl FileMetadata {
+    /// The attributes of a file that can be recorded on this platform.
+    pub fn of(metadata: &Metadata) -> FileMetadata {
+        #[cfg(unix)]
+        use std::os::unix::fs::MetadataExt;
+
+        let mtime = metadata.modified().ok().map(|mtime| {
+            let since_epoch = mtime
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default();
+            SystemTime::UNIX_EPOCH + Duration::from_millis(since_epoch.as_millis() as u64)
+        });
+        FileMetadata {
+            size: Some(metadata.len()),
+            #[cfg(unix)]
+            mode: Some(metadata.mode()),
+            #[cfg(unix)]
+            uid: Some(metadata.uid()),
+            #[cfg(unix)]
+            gid: Some(metadata.gid()),
+            #[cfg(not(unix))]
+            mode: None,
+            #[cfg(not(unix))]
+            uid: None,
+            #[cfg(not(unix))]
+            gid: None,
+            mtime,
+            xattrs: None,
+        }
+    }
+
+    /// The names of the attributes that were recorded in `self` but are different in `actual`.
+    pub fn differences(&self, actual: &FileMetadata) -> Vec<&'static str> {
+        fn differs<T: PartialEq>(recorded: &Option<T>, actual: &Option<T>) -> bool {
+            recorded.is_some() && recorded != actual
+        }
+        let mut differences = Vec::new();
+        if differs(&self.size, &actual.size) {
+            differences.push("size");
+        }
+        if differs(&self.mode, &actual.mode) {
+            differences.push("mode");
+        }
+        if differs(&self.uid, &actual.uid) || differs(&self.gid, &actual.gid) {
+            differences.push("owner");
+        }
+        if differs(&self.mtime, &actual.mtime) {
+            differences.push("mtime");
+        }
+        if differs(&self.xattrs, &actual.xattrs) {
+            differences.push("xattrs");
+        }
+        differences
+    }
+
+    /// The comment before an entry with these attributes.
+    pub(crate) fn to_line(&self, format: Format) -> String {
+        let mut line = if format == Format::Sfv { ";" } else { "#" }.to_owned();
+        line.push_str(" metadata");
+        if let Some(size) = self.size {
+            line.push_str(&format!(" size={}", size));
+        }
+        if let Some(mode) = self.mode {
+            line.push_str(&format!(" mode={:o}", mode));
+        }
+        if let Some(uid) = self.uid {
+            line.push_str(&format!(" uid={}", uid));
+        }
+        if let Some(gid) = self.gid {
+            line.push_str(&format!(" gid={}", gid));
+        }
+        if let Some(mtime) = self.mtime {
+            line.push_str(&format!(" mtime={}", format_timestamp(mtime)));
+        }
+        if let Some(xattrs) = &self.xattrs {
+            line.push_str(&format!(" xattrs={}", xattrs));
+        }
+        line
+    }
+
+    /// Parse a metadata comment. Unknown attributes are skipped, so that newer versions can
+    /// record more of them.
+    fn parse_line(line: &str) -> Option<FileMetadata> {
+        let line = line
+            .strip_prefix("# metadata")
+            .or_else(|| line.strip_prefix("; metadata"))?;
+        if !line.is_empty() && !line.starts_with(' ') {
+            return None;
+        }
+        let mut metadata = FileMetadata::default();
+        for attribute in line.split(' ').filter(|attribute| !attribute.is_empty()) {
+            let (key, value) = attribute.split_at(attribute.find('=')?);
+            let value = &value[1..];
+            match key {
+                "size" => metadata.size = Some(value.parse().ok()?),
+                "mode" => metadata.mode = Some(u32::from_str_radix(value, 8).ok()?),
+                "uid" => metadata.uid = Some(value.parse().ok()?),
+                "gid" => metadata.gid = Some(value.parse().ok()?),
+                "mtime" => metadata.mtime = Some(parse_timestamp(value)?),
+                "xattrs" => metadata.xattrs = Some(value.parse().ok()?),
+                _ => {}
+            }
+        }
+        Some(metadata)
+    }
+}
+
+/// The kind of checkfile that lines are read from, which can't always be told from the lines.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Dialect {
+    /// Whether the lines are `<filename> <CRC32 hex>`.
+    pub sfv: bool,
+    /// Whether 32-byte checksums are SHA-256 rather than BLAKE3, as in a `SHA256SUMS` file.
+    pub sha256: bool,
+}
+
+impl Dialect {
+    /// The dialect of a checkfile. Files ending in `.sfv` are SFV files whatever `format` is,
+    /// and files named like `SHA256SUMS` or `files.sha256` list SHA-256 checksums.
+    pub fn of_path(path: &Path, format: Format) -> Dialect {
+        Dialect {
+            sfv: format == Format::Sfv
+                || path
+                    .extension()
+                    .map_or(false, |ext| ext.eq_ignore_ascii_case("sfv")),
+            sha256: path.file_name().map_or(false, |name| {
+                name.to_string_lossy()
+                    .to_ascii_lowercase()
+                    .contains("sha256")
+            }),
+        }
+    }
+}
+
+/// Why a line couldn't be read, and where. Lines and columns are counted from 1, and columns
+/// are in bytes.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ParseError {
+    pub line: usize,
+    pub column: usize,
+    pub kind: ParseErrorKind,
+    pub message: String,
+}
+
+/// Whether the rest of a checkfile can be read after a [`ParseError`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    /// The line isn't a checksum line, but the other lines can still be read.
+    BadLine,
+    /// The header of a hashdeep file is missing or has unsupported columns, so none of its rows
+    /// can be read.
+    BadHeader,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "line {}, column {}: {}",
+            self.line, self.column, self.message
+        )
+    }
+}
+
+impl Error for ParseError {}
+
+/// A position in a line and what is wrong there, before the line number is known.
+type LineError = (usize, String);
+
+/// Reads the lines of one checkfile in order. A parser is needed for each checkfile, since
+/// hashdeep files have a header that says how their rows are read.
+#[derive(Debug)]
+pub struct Parser {
+    dialect: Dialect,
+    line_number: usize,
+    is_hashdeep: bool,
+    hashdeep_columns: Option<HashdeepColumns>,
+    /// The directory of the last `# dir` line, which the names of the entries after it are in.
+    dir: Option<String>,
+    /// The timestamp and metadata on the previous lines, for the next entry.
+    timestamp: Option<SystemTime>,
+    metadata: Option<FileMetadata>,
+    pieces: Vec<Chunk>,
+}
+
+impl Parser {
+    pub fn new(dialect: Dialect) -> Parser {
+        Parser {
+            dialect,
+            line_number: 0,
+            is_hashdeep: false,
+            hashdeep_columns: None,
+            dir: None,
+            timestamp: None,
+            metadata: None,
+            pieces: Vec::new(),
+        }
+    }
+
+    /// Read the next line, without its line ending. Blank lines, comments and headers give
+    /// `Ok(None)`.
+    pub fn parse_line(&mut self, line: &str) -> Result<Option<Entry>, ParseError> {
+        self.line_number += 1;
+        let line = line.trim_end_matches('\r');
+        let (kind, parsed) = self.parse_numbered_line(line);
+        parsed.map_err(|(column, message)| ParseError {
+            line: self.line_number,
+            column,
+            kind,
+            message,
+        })
+    }
+
+    fn parse_numbered_line(
+        &mut self,
+        line: &str,
+    ) -> (ParseErrorKind, Result<Option<Entry>, LineError>) {
+        if let Some(timestamp) = parse_timestamp_line(line) {
+            self.timestamp = Some(timestamp);
+            return (ParseErrorKind::BadLine, Ok(None));
+        }
+        if let Some(dir) = parse_dir_line(line) {
+            self.dir = Some(dir.to_owned()).filter(|dir| dir != ".");
+            return (ParseErrorKind::BadLine, Ok(None));
+        }
+        if let Some(metadata) = FileMetadata::parse_line(line) {
+            self.metadata = Some(metadata);
+            return (ParseErrorKind::BadLine, Ok(None));
+        }
+        if let Some(piece) = parse_piece_line(line) {
+            self.pieces.push(piece);
+            return (ParseErrorKind::BadLine, Ok(None));
+        }
+        if line == known_line(Format::B3sum)
+            || line == known_line(Format::Sfv)
+            || is_entropy_line(line)
+            || is_ssdeep_line(line)
+            || parse_chain_line(line).is_some()
+        {
+            return (ParseErrorKind::BadLine, Ok(None));
+        }
+        let timestamp = self.timestamp.take();
+        let metadata = self.metadata.take();
+        let pieces = std::mem::take(&mut self.pieces);
+        if self.line_number == 1 && line.starts_with("%%%% HASHDEEP-") {
+            self.is_hashdeep = true;
+            return (ParseErrorKind::BadLine, Ok(None));
+        }
+        if self.is_hashdeep {
+            if let Some(header) = line.strip_prefix("%%%% ") {
+                return match HashdeepColumns::new(header) {
+                    Ok(columns) => {
+                        self.hashdeep_columns = Some(columns);
+                        (ParseErrorKind::BadLine, Ok(None))
+                    }
+                    Err(message) => (ParseErrorKind::BadHeader, Err((6, message))),
+                };
+            }
+            if line.starts_with('#') {
+                return (ParseErrorKind::BadLine, Ok(None));
+            }
+        }
+        if line.is_empty()
+            || (self.dialect.sfv && line.starts_with(';'))
+            || Seal::parse(line).is_some()
+        {
+            return (ParseErrorKind::BadLine, Ok(None));
+        }
+        let parsed = if self.is_hashdeep {
+            match &self.hashdeep_columns {
+                Some(columns) => columns.parse_line(line),
+                None => {
+                    let message = "hashdeep file is missing its column header".to_owned();
+                    return (ParseErrorKind::BadHeader, Err((1, message)));
+                }
+            }
+        } else if self.dialect.sfv {
+            parse_sfv_line(line)
+        } else if is_tag_line(line) {
+            parse_tag_line(line)
+        } else {
+            parse_b3sum_line(line, self.dialect.sha256)
+        };
+        let entry = parsed.map(|entry| Entry {
+            name: match &self.dir {
+                Some(dir) => format!("{}/{}", dir.trim_end_matches('/'), entry.name),
+                None => entry.name,
+            },
+            timestamp,
+            metadata,
+            pieces,
+            ..entry
+        });
+        (ParseErrorKind::BadLine, entry.map(Some))
+    }
+}
+
+/// Read all of the lines of a checkfile. Lines that can't be read are returned with the entries,
+/// except for a [`ParseErrorKind::BadHeader`], which is returned as the error.
+pub fn parse(read: &mut dyn BufRead, dialect: Dialect) -> Result<(Vec<Entry>, Vec<ParseError>)> {
+    let mut parser = Parser::new(dialect);
+    let mut entries = Vec::new();
+    let mut bad_lines = Vec::new();
+    for line in read.lines() {
+        match parser.parse_line(&line?) {
+            Ok(Some(entry)) => entries.push(entry),
+            Ok(None) => {}
+            Err(err) if err.kind == ParseErrorKind::BadHeader => return Err(err.into()),
+            Err(err) => bad_lines.push(err),
+        }
+    }
+    Ok((entries, bad_lines))
+}
+
+/// The last line of a checkfile written with `--seal`, like `# b3sum-ng 0.1.2 seal: <hex>`. It has
+/// the BLAKE3 of all of the lines before it, including their line endings, and the version of
+/// b3sum-ng that wrote it. In SFV files, it starts with `;` instead of `#`, so that it's a comment.
+///
+/// Anyone who edits the checkfile can compute a new plain seal, so it only shows that the
+/// checkfile wasn't damaged or cut short by accident. With `--keyed` or `--derive-key`, the seal
+/// is hashed in that mode and written as `keyed seal:`, so that it can't be forged without the
+/// key.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Seal {
+    pub version: String,
+    pub checksum: Checksum,
+    pub keyed: bool,
+}
+
+impl Seal {
+    /// The seal of the lines of a checkfile, written by this version of b3sum-ng.
+    pub fn of(body: &[u8], mode: &Blake3Mode) -> Seal {
+        let mut hasher = mode.hasher();
+        hasher.update(body);
+        Seal::from_hash(hasher.finalize(), mode)
+    }
+
+    /// The seal of lines that were hashed as they were written, by a hasher in this mode.
+    pub fn from_hash(hash: blake3::Hash, mode: &Blake3Mode) -> Seal {
+        Seal {
+            version: env!("CARGO_PKG_VERSION").to_owned(),
+            checksum: Checksum(hash.as_bytes().to_vec()),
+            keyed: *mode != Blake3Mode::Hash,
+        }
+    }
+
+    /// Write the seal as a line, without a line ending.
+    pub fn to_line(&self, format: Format) -> String {
+        let comment = if format == Format::Sfv { ';' } else { '#' };
+        let kind = if self.keyed { "keyed seal" } else { "seal" };
+        format!(
+            "{} b3sum-ng {} {}: {}",
+            comment, self.version, kind, self.checksum
+        )
+    }
+
+    fn parse(line: &str) -> Option<Seal> {
+        let line = line
+            .strip_prefix("# b3sum-ng ")
+            .or_else(|| line.strip_prefix("; b3sum-ng "))?;
+        let separator = line.find(" seal: ")?;
+        let (version, keyed) = match line[..separator].strip_suffix(" keyed") {
+            Some(version) => (version, true),
+            None => (&line[..separator], false),
+        };
+        Some(Seal {
+            version: version.to_owned(),
+            checksum: line[separator + 7..].parse().ok()?,
+            keyed,
+        })
+    }
+}
+
+/// The first line of each record of a chained manifest, written with `--chain`, like
+/// `# chain <hex>`. It has the [`chain_link`] of the record before it, or for the first record, 32
+/// zero bytes.
+pub(crate) fn chain_line(previous: &Checksum, format: Format) -> String {
+    let comment = if format == Format::Sfv { ';' } else { '#' };
+    format!("{} chain {}", comment, previous)
+}
+
+fn parse_chain_line(line: &str) -> Option<Checksum> {
+    line.strip_prefix("# chain ")
+        .or_else(|| line.strip_prefix("; chain "))?
+        .parse()
+        .ok()
+}
+
+/// The link that the first record of a chained manifest has, before there are any records.
+pub fn chain_start() -> Checksum {
+    Checksum(vec![0; blake3::OUT_LEN])
+}
+
+/// The link to a record of a chained manifest, for the `# chain` line of the next one: the BLAKE3
+/// of its lines, from its own `# chain` line to the next one, including their line endings.
+pub fn chain_link(record: &[u8]) -> Checksum {
+    Checksum(blake3::hash(record).as_bytes().to_vec())
+}
+
+/// Check the links of a chained manifest. Returns the link to its last record, its head, which
+/// is where records appended to it link to, or `None` if it isn't chained. An error is returned if
+/// any record doesn't link to the one before it, so that records that were inserted, removed,
+/// reordered or changed are found, or if there are lines before the first record, which the
+/// chain doesn't cover.
+///
+/// Records that were removed from the end of a chain can only be found by comparing its head to
+/// one that was kept from before.
+pub fn check_chain(contents: &[u8]) -> Result<Option<Checksum>> {
+    let mut previous = chain_start();
+    // Where the record that isn't finished yet starts.
+    let mut record_start = None;
+    let mut offset = 0;
+    for (index, line) in contents.split_inclusive(|&b| b == b'\n').enumerate() {
+        let text = String::from_utf8_lossy(line);
+        if let Some(link) = parse_chain_line(text.trim_end_matches(&['\r', '\n'][..])) {
+            match record_start {
+                Some(start) => previous = chain_link(&contents[start..offset]),
+                None if offset > 0 => {
+                    return Err(format!(
+                        "line {}: the lines before the first record of the chain aren't covered \
+                        by it",
+                        index + 1
+                    )
+                    .into())
+                }
+                None => {}
+            }
+            if link != previous {
+                return Err(format!(
+                    "line {}: the record doesn't link to the one before it, so records were \
+                    inserted, removed, reordered or changed",
+                    index + 1
+                )
+                .into());
+            }
+            record_start = Some(offset);
+        }
+        offset += line.len();
+    }
+    Ok(record_start.map(|start| chain_link(&contents[start..])))
+}
+
+/// The head of the chained manifest at `path`, for appending records to it, or the start of a
+/// chain if the file is empty or doesn't exist yet. An error is returned if the chain is broken, or
+/// if the file isn't chained.
+pub fn read_chain_head(path: &Path) -> Result<Checksum> {
+    let contents = match std::fs::read(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Vec::new(),
+        Err(err) => return Err(err.into()),
+    };
+    match check_chain(&contents)? {
+        Some(head) => Ok(head),
+        None if contents.is_empty() => Ok(chain_start()),
+        None => Err("the file isn't a chained manifest, so it can't be extended".into()),
+    }
+}
+
+/// Check the seal at the end of the contents of a checkfile, if there is one. An error is returned
+/// if the seal doesn't match the lines before it, or if there are lines after a seal, so that
+/// a checkfile that was changed, extended or cut short after it was sealed isn't trusted. A keyed
+/// seal is checked in `mode`, which must then be keyed, and a plain seal is checked without a key.
+pub fn check_seal(contents: &[u8], mode: &Blake3Mode) -> Result<Option<Seal>> {
+    let body = contents.strip_suffix(b"\n").unwrap_or(contents);
+    let start = body
+        .iter()
+        .rposition(|&b| b == b'\n')
+        .map_or(0, |end| end + 1);
+    let last_line = String::from_utf8_lossy(&body[start..]);
+    match Seal::parse(last_line.trim_end_matches('\r')) {
+        Some(seal) if seal.keyed && *mode == Blake3Mode::Hash => Err(
+            "the checkfile's seal is keyed, so it can only be checked with --keyed or \
+            --derive-key"
+                .into(),
+        ),
+        Some(seal)
+            if seal.keyed && seal.checksum == Seal::of(&body[..start], mode).checksum
+                || !seal.keyed
+                    && seal.checksum == Seal::of(&body[..start], &Blake3Mode::Hash).checksum =>
+        {
+            Ok(Some(seal))
+        }
+        Some(_) => Err(
+            "the checkfile doesn't match its seal, so it was changed after it was \
+            written"
+                .into(),
+        ),
+        None if String::from_utf8_lossy(contents)
+            .lines()
+            .any(|line| Seal::parse(line.trim_end_matches('\r')).is_some()) =>
+        {
+            Err("the checkfile has lines after its seal".into())
+        }
+        None => Ok(None),
+    }
+}
+
+/// Write an entry as a line of a checkfile, without a line ending, in a way that [`Parser`] reads
+/// back. Filenames with backslashes or newlines are escaped, and the timestamp and metadata are
+/// written on lines before the entry. `None` is returned if the format isn't one of a checkfile
+/// or doesn't list filenames, if the filename can't be written in an SFV file, or if an NSRL row
+/// would need a SHA-1 that the entry doesn't have. NSRL rows have no timestamp or metadata.
+pub fn format_line(entry: &Entry, format: Format) -> Option<String> {
+    if format == Format::Nsrl {
+        return format_entry(entry, format);
+    }
+    let mut lines = Vec::new();
+    if let Some(timestamp) = entry.timestamp {
+        lines.push(timestamp_line(timestamp, format));
+    }
+    if let Some(metadata) = &entry.metadata {
+        lines.push(metadata.to_line(format));
+    }
+    lines.extend(entry.pieces.iter().map(|piece| piece_line(piece, format)));
+    lines.push(format_entry(entry, format)?);
+    Some(lines.join("\n"))
+}
+
+/// The comment before an entry that says when its checksum was computed.
+pub(crate) fn timestamp_line(timestamp: SystemTime, format: Format) -> String {
+    let comment = if format == Format::Sfv { ';' } else { '#' };
+    format!("{} computed {}", comment, format_timestamp(timestamp))
+}
+
+/// A comment like `# dir photos/2021` before the entries of the files in a directory, which are
+/// named without it. See [`start_grouping`](crate::output::start_grouping).
+pub(crate) fn dir_line(dir: &str, format: Format) -> String {
+    let comment = if format == Format::Sfv { ';' } else { '#' };
+    format!("{} dir {}", comment, dir)
+}
+
+fn parse_dir_line(line: &str) -> Option<&str> {
+    line.strip_prefix("# dir ")
+        .or_else(|| line.strip_prefix("; dir "))
+        .filter(|dir| !dir.is_empty())
+}
+
+/// A comment before an entry with the location and checksum of a piece of its file, like
+/// `# piece 1048576 1048576 <hex>`.
+pub(crate) fn piece_line(piece: &Chunk, format: Format) -> String {
+    let comment = if format == Format::Sfv { ';' } else { '#' };
+    format!(
+        "{} piece {} {} {}",
+        comment, piece.offset, piece.length, piece.checksum
+    )
+}
+
+fn parse_piece_line(line: &str) -> Option<Chunk> {
+    let line = line
+        .strip_prefix("# piece ")
+        .or_else(|| line.strip_prefix("; piece "))?;
+    let mut fields = line.split(' ');
+    let piece = Chunk {
+        offset: fields.next()?.parse().ok()?,
+        length: fields.next()?.parse().ok()?,
+        checksum: fields.next()?.parse().ok()?,
+    };
+    match fields.next() {
+        Some(_) => None,
+        None => Some(piece),
+    }
+}
+
+/// The comment before an entry with the entropy of its file, in bits per byte.
+pub(crate) fn entropy_line(entropy: f64, format: Format) -> String {
+    let comment = if format == Format::Sfv { ';' } else { '#' };
+    format!("{} entropy {:.4}", comment, entropy)
+}
+
+/// The comment before an entry with the ssdeep hash of its file.
+pub(crate) fn ssdeep_line(hash: &str, format: Format) -> String {
+    let comment = if format == Format::Sfv { ';' } else { '#' };
+    format!("{} ssdeep {}", comment, hash)
+}
+
+fn is_ssdeep_line(line: &str) -> bool {
+    let hash = match line
+        .strip_prefix("# ssdeep ")
+        .or_else(|| line.strip_prefix("; ssdeep "))
+    {
+        Some(hash) => hash,
+        None => return false,
+    };
+    let mut fields = hash.split(':');
+    fields
+        .next()
+        .map_or(false, |size| size.parse::<u64>().is_ok())
+        && fields.count() == 2
+}
+
+fn is_entropy_line(line: &str) -> bool {
+    line.strip_prefix("# entropy ")
+        .or_else(|| line.strip_prefix("; entropy "))
+        .map_or(false, |entropy| entropy.parse::<f64>().is_ok())
+}
+
+/// The comment before an entry whose checksum is in the `--known-hashes` set.
+pub(crate) fn known_line(format: Format) -> &'static str {
+    if format == Format::Sfv {
+        "; known"
+    } else {
+        "# known"
+    }
+}
+
+fn parse_timestamp_line(line: &str) -> Option<SystemTime> {
+    let line = line
+        .strip_prefix("# computed ")
+        .or_else(|| line.strip_prefix("; computed "))?;
+    parse_timestamp(line)
+}
+
+/// Write a time in RFC 3339 format, in UTC with milliseconds, like `2026-10-14T09:30:12.123Z`.
+/// Times before 1970 are written as 1970.
+pub fn format_timestamp(time: SystemTime) -> String {
+    let since_epoch = time
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let seconds = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((seconds / 86400) as i64);
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+        year,
+        month,
+        day,
+        seconds % 86400 / 3600,
+        seconds % 3600 / 60,
+        seconds % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Parse a time in RFC 3339 format, like `2026-10-14T09:30:12Z` or
+/// `2026-10-14T11:30:12.123456+02:00`.
+pub fn parse_timestamp(s: &str) -> Option<SystemTime> {
+    let bytes = s.as_bytes();
+    let number = |start: usize, len: usize| -> Option<i64> {
+        let digits = s.get(start..start + len)?;
+        if !digits.bytes().all(|b| b.is_ascii_digit()) {
+            return None;
+        }
+        digits.parse().ok()
+    };
+    let separators = [(4, b'-'), (7, b'-'), (13, b':'), (16, b':')];
+    if bytes.len() < 20
+        || separators.iter().any(|&(i, c)| bytes[i] != c)
+        || !matches!(bytes[10], b'T' | b't' | b' ')
+    {
+        return None;
+    }
+    let (year, month, day) = (number(0, 4)?, number(5, 2)?, number(8, 2)?);
+    let (hour, minute, second) = (number(11, 2)?, number(14, 2)?, number(17, 2)?);
+    if !(1..=12).contains(&month) || hour > 23 || minute > 59 || second > 60 {
+        return None;
+    }
+    let days = days_from_civil(year, month as u32, day as u32);
+    if day < 1 || civil_from_days(days) != (year, month as u32, day as u32) {
+        return None;
+    }
+
+    let mut rest = &s[19..];
+    let mut nanos = 0;
+    if let Some(fraction) = rest.strip_prefix('.') {
+        let len = fraction.bytes().take_while(u8::is_ascii_digit).count();
+        if len == 0 {
+            return None;
+        }
+        let digits = &fraction[..len.min(9)];
+        nanos = digits.parse::<u32>().ok()? * 10u32.pow(9 - digits.len() as u32);
+        rest = &fraction[len..];
+    }
+    let offset = match rest.as_bytes() {
+        [b'Z'] | [b'z'] => 0,
+        [sign @ (b'+' | b'-'), _, _, b':', _, _] => {
+            let offset = number(s.len() - 5, 2)? * 3600 + number(s.len() - 2, 2)? * 60;
+            if *sign == b'+' {
+                offset
+            } else {
+                -offset
+            }
+        }
+        _ => return None,
+    };
+    let seconds = days * 86400 + hour * 3600 + minute * 60 + second - offset;
+    let since_epoch = Duration::from_secs(seconds.unsigned_abs());
+    let time = if seconds >= 0 {
+        SystemTime::UNIX_EPOCH.checked_add(since_epoch)
+    } else {
+        SystemTime::UNIX_EPOCH.checked_sub(since_epoch)
+    }?;
+    time.checked_add(Duration::new(0, nanos))
+}
+
+/// The year, month and day of a number of days since 1970-01-01, in the Gregorian calendar.
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    // These are Howard Hinnant's algorithms, which count in eras of 400 years.
+    let days = days + 719_468;
+    let era = if days >= 0 { days } else { days - 146_096 } / 146_097;
+    let day_of_era = days - era * 146_097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = (day_of_year - (153 * shifted_month + 2) / 5 + 1) as u32;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    } as u32;
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+/// The number of days since 1970-01-01 of a date in the Gregorian calendar.
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = if year >= 0 { year } else { year - 399 } / 400;
+    let year_of_era = year - era * 400;
+    let shifted_month = if month > 2 { month - 3 } else { month + 9 } as i64;
+    let day_of_year = (153 * shifted_month + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// Write an entry as a line, without its timestamp.
+fn format_entry(entry: &Entry, format: Format) -> Option<String> {
+    let escaped = entry.name.contains(&['\\', '\n'][..]);
+    let name = if escaped {
+        entry.name.replace('\\', "\\\\").replace('\n', "\\n")
+    } else {
+        entry.name.clone()
+    };
+    let prefix = if escaped { "\\" } else { "" };
+    match format {
+        Format::B3sum => Some(format!("{}{}  {}", prefix, entry.checksum, name)),
+        Format::Tag => Some(format!(
+            "{}{} ({}) = {}",
+            prefix,
+            tag_name(entry.algorithm),
+            name,
+            entry.checksum
+        )),
+        // SFV files can't escape filenames, and the space before the checksum is trimmed.
+        Format::Sfv if escaped || entry.name.trim() != entry.name || entry.name.is_empty() => None,
+        Format::Sfv => Some(format!("{} {:X}", entry.name, entry.checksum)),
+        Format::Nsrl if entry.algorithm == Algorithm::Sha1 => Some(format!(
+            "\"{:X}\"{}",
+            entry.checksum,
+            nsrl_columns(&entry.name, entry.size)
+        )),
+        Format::NoNames | Format::Raw | Format::Nsrl | Format::Json | Format::Csv => None,
+    }
+}
+
+/// A set of checksums that are already known, such as those of the files of an OS, for
+/// `--known-hashes`. Only the checksums of one length are kept, since others can't match.
+///
+/// It can be read from a text file with a checksum in hex at the start of each line, which may be
+/// followed by other columns, as in b3sum output or an NSRL RDS `NSRLFile.txt`. Lines that don't
+/// start with a checksum, such as headers, are skipped. It can also be read from a binary file of
+/// raw checksums one after another, which loads faster if they are already sorted.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct KnownHashes {
+    length: usize,
+    /// The checksums one after another, sorted and without duplicates.
+    digests: Vec<u8>,
+}
+
+impl KnownHashes {
+    /// Read the checksums of `length` bytes from a file.
+    pub fn load(path: &Path, length: usize) -> Result<KnownHashes> {
+        KnownHashes::from_bytes(&std::fs::read(path)?, length)
+    }
+
+    /// Read the checksums of `length` bytes from the contents of a text or binary file. A file is
+    /// binary if it has any bytes that aren't printable ASCII or whitespace.
+    pub fn from_bytes(contents: &[u8], length: usize) -> Result<KnownHashes> {
+        assert!(length > 0, "checksums can't be empty");
+        let is_text = contents
+            .iter()
+            .all(|byte| byte.is_ascii_graphic() || byte.is_ascii_whitespace());
+        let parsed: Vec<Checksum>;
+        let mut digests: Vec<&[u8]> = if is_text {
+            // The text is ASCII, so this can't fail.
+            let text = std::str::from_utf8(contents)?;
+            parsed = text
+                .lines()
+                .filter_map(|line| {
+                    let hex = line
+                        .trim_start()
+                        .split(|c: char| c.is_ascii_whitespace() || c == ',')
+                        .next()?
+                        .trim_matches('"');
+                    hex.parse::<Checksum>().ok()
+                })
+                .filter(|checksum| checksum.0.len() == length)
+                .collect();
+            if parsed.is_empty() && !text.trim().is_empty() {
+                return Err(format!("no checksums of {} bytes were found", length).into());
+            }
+            parsed.iter().map(|checksum| &checksum.0[..]).collect()
+        } else {
+            if contents.len() % length != 0 {
+                let message = format!(
+                    "the size of a binary set must be a multiple of the checksum length, {} bytes",
+                    length
+                );
+                return Err(message.into());
+            }
+            contents.chunks(length).collect()
+        };
+        if digests.windows(2).any(|pair| pair[0] > pair[1]) {
+            digests.sort_unstable();
+        }
+        digests.dedup();
+        Ok(KnownHashes {
+            length,
+            digests: digests.concat(),
+        })
+    }
+
+    /// Whether a checksum is in the set.
+    pub fn contains(&self, checksum: &Checksum) -> bool {
+        if checksum.0.len() != self.length {
+            return false;
+        }
+        let (mut low, mut high) = (0, self.len());
+        while low < high {
+            let middle = low + (high - low) / 2;
+            let digest = &self.digests[middle * self.length..(middle + 1) * self.length];
+            match digest.cmp(&checksum.0[..]) {
+                std::cmp::Ordering::Less => low = middle + 1,
+                std::cmp::Ordering::Greater => high = middle,
+                std::cmp::Ordering::Equal => return true,
+            }
+        }
+        false
+    }
+
+    /// The number of checksums in the set.
+    pub fn len(&self) -> usize {
+        // The length is only 0 in the default, empty set.
+        self.digests.len().checked_div(self.length).unwrap_or(0)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.digests.is_empty()
+    }
+}
+
+/// The header row of an NSRL RDS `NSRLFile.txt`.
+pub(crate) const NSRL_HEADER: &str =
+    "\"SHA-1\",\"MD5\",\"CRC32\",\"FileName\",\"FileSize\",\"ProductCode\",\"OpSystemCode\",\"SpecialCode\"";
+
+/// The columns of an NSRL row after the SHA-1. The set only has the names of files, so the
+/// directory is left out of `name`.
+pub(crate) fn nsrl_columns(name: &str, size: Option<u64>) -> String {
+    let name = name.rsplit(&['/', '\\'][..]).next().unwrap_or(name);
+    let size = size.map_or_else(String::new, |size| size.to_string());
+    // Quotes are doubled in CSV.
+    format!(
+        ",\"\",\"\",\"{}\",\"{}\",\"0\",\"\",\"\"",
+        name.replace('"', "\"\""),
+        size
+    )
+}
+
+/// The algorithm of a checksum, from its length in bytes.
+fn algorithm_of(checksum: &Checksum, is_sha256: bool) -> Option<Algorithm> {
+    match checksum.0.len() {
+        16 => Some(Algorithm::Md5),
+        20 => Some(Algorithm::Sha1),
+        32 if is_sha256 => Some(Algorithm::Sha256),
+        blake3::OUT_LEN => Some(Algorithm::Blake3),
+        _ => None,
+    }
+}
+
+/// Parse hex digits that start at byte `offset` of a line.
+fn parse_hex(hex: &str, offset: usize) -> Result<Checksum, LineError> {
+    if let Some(bad) = hex.find(|c: char| !c.is_ascii_hexdigit()) {
+        return Err((offset + bad + 1, "expected a hex digit".to_owned()));
+    }
+    if hex.is_empty() {
+        return Err((offset + 1, "expected a checksum".to_owned()));
+    }
+    if hex.len() % 2 != 0 {
+        return Err((offset + hex.len(), "odd number of hex digits".to_owned()));
+    }
+    hex.parse().map_err(|err: String| (offset + 1, err))
+}
+
+/// Parse a `<hex>  <filename>` line as written by b3sum, md5sum, etc. A leading backslash means
+/// that the filename contains escaped backslashes or newlines. The `*` that marks binary mode in
+/// md5sum output is also accepted in place of the second space.
+fn parse_b3sum_line(line: &str, is_sha256: bool) -> Result<Entry, LineError> {
+    let (escaped, offset) = if line.starts_with('\\') {
+        (true, 1)
+    } else {
+        (false, 0)
+    };
+    let rest = &line[offset..];
+    let separator = rest.find(' ').ok_or_else(|| {
+        (
+            line.len() + 1,
+            "expected a space after the checksum".to_owned(),
+        )
+    })?;
+    let checksum = parse_hex(&rest[..separator], offset)?;
+    let algorithm = algorithm_of(&checksum, is_sha256).ok_or_else(|| {
+        let message = format!(
+            "a checksum of {} bytes isn't BLAKE3, SHA-256, SHA-1 or MD5",
+            checksum.0.len()
+        );
+        (offset + 1, message)
+    })?;
+    let separator = offset + separator;
+    if !line[separator + 1..].starts_with(&[' ', '*'][..]) {
+        let message = "expected two spaces, or a space and `*`, after the checksum".to_owned();
+        return Err((separator + 2, message));
+    }
+    let name = parse_name(&line[separator + 2..], escaped, separator + 2)?;
+    Ok(Entry {
+        name,
+        checksum,
+        algorithm,
+        size: None,
+        timestamp: None,
+        metadata: None,
+        pieces: Vec::new(),
+    })
+}
+
+/// Whether a line looks like `<ALGORITHM> (<filename>) = <hex>`. The lines of b3sum have two
+/// spaces after the hex, so they aren't mistaken for these.
+fn is_tag_line(line: &str) -> bool {
+    let line = line.strip_prefix('\\').unwrap_or(line);
+    match line.find(' ') {
+        Some(space) => {
+            line[space + 1..].starts_with('(')
+                && line[..space]
+                    .chars()
+                    .all(|c| c.is_ascii_alphanumeric() || c == '-')
+                && !line[..space].chars().all(|c| c.is_ascii_hexdigit())
+        }
+        None => false,
+    }
+}
+
+/// Parse a `<ALGORITHM> (<filename>) = <hex>` line, as written by `--tag`. The filename may
+/// contain `) = `, so the last one ends it.
+fn parse_tag_line(line: &str) -> Result<Entry, LineError> {
+    let (escaped, offset) = if line.starts_with('\\') {
+        (true, 1)
+    } else {
+        (false, 0)
+    };
+    let space = line[offset..].find(' ').unwrap_or(0) + offset;
+    let algorithm = tag_algorithm(&line[offset..space]).ok_or_else(|| {
+        (
+            offset + 1,
+            format!("unknown algorithm: {}", &line[offset..space]),
+        )
+    })?;
+    let end = line
+        .rfind(") = ")
+        .filter(|&end| end > space)
+        .ok_or_else(|| {
+            (
+                line.len() + 1,
+                "expected `) = ` after the filename".to_owned(),
+            )
+        })?;
+    let checksum = parse_hex(&line[end + 4..], end + 4)?;
+    // BLAKE3 checksums can have any length, with `--length`.
+    let length = match algorithm {
+        Algorithm::Crc32 => Some(4),
+        Algorithm::Md5 => Some(16),
+        Algorithm::Sha1 => Some(20),
+        Algorithm::Sha256 => Some(32),
+        Algorithm::Blake3 | Algorithm::Digest(_) => None,
+    };
+    if length.map_or(false, |length| length != checksum.0.len()) {
+        let message = format!(
+            "a checksum of {} bytes isn't {}",
+            checksum.0.len(),
+            algorithm
+        );
+        return Err((end + 5, message));
+    }
+    let name = parse_name(&line[space + 2..end], escaped, space + 2)?;
+    Ok(Entry {
+        name,
+        checksum,
+        algorithm,
+        size: None,
+        timestamp: None,
+        metadata: None,
+        pieces: Vec::new(),
+    })
+}
+
+/// The algorithm named at the start of a tag line. Both `SHA256` and `SHA-256` are written by
+/// other tools.
+fn tag_algorithm(name: &str) -> Option<Algorithm> {
+    match name.to_ascii_uppercase().as_str() {
+        "BLAKE3" => Some(Algorithm::Blake3),
+        "CRC32" => Some(Algorithm::Crc32),
+        "MD5" => Some(Algorithm::Md5),
+        "SHA1" | "SHA-1" => Some(Algorithm::Sha1),
+        "SHA256" | "SHA-256" => Some(Algorithm::Sha256),
+        _ => None,
+    }
+}
+
+/// The name of an algorithm in tag lines, in the style of the BSD tools.
+fn tag_name(algorithm: Algorithm) -> String {
+    match algorithm {
+        Algorithm::Sha1 => "SHA1".to_owned(),
+        Algorithm::Sha256 => "SHA256".to_owned(),
+        algorithm => algorithm.to_string(),
+    }
+}
+
+/// A filename that starts at byte `offset` of a line, unescaped if the line started with a
+/// backslash.
+fn parse_name(name: &str, escaped: bool, offset: usize) -> Result<String, LineError> {
+    if name.is_empty() {
+        return Err((offset + 1, "expected a filename".to_owned()));
+    }
+    if escaped {
+        unescape_filename(name).map_err(|bad| (offset + bad + 1, invalid_escape()))
+    } else {
+        Ok(name.to_owned())
+    }
+}
+
+fn invalid_escape() -> String {
+    "invalid escape in a filename; only `\\\\` and `\\n` are allowed".to_owned()
+}
+
+/// The layout of the rows in a hashdeep file, as given by its `%%%% size,md5,sha256,filename`
+/// header.
+#[derive(Debug)]
+struct HashdeepColumns {
+    count: usize,
+    hash: usize,
+    algorithm: Algorithm,
+    size: Option<usize>,
+}
+
+impl HashdeepColumns {
+    /// Find the strongest hash column that can be verified. If a file has no hash that this build
+    /// can compute, an unavailable algorithm is chosen so the caller can report it.
+    fn new(header: &str) -> Result<Self, String> {
+        let columns: Vec<&str> = header.split(',').collect();
+        if columns.last() != Some(&"filename") {
+            return Err(format!("unsupported hashdeep columns: {}", header));
+        }
+        let found: Vec<(usize, Algorithm)> = [
+            ("sha256", Algorithm::Sha256),
+            ("sha1", Algorithm::Sha1),
+            ("md5", Algorithm::Md5),
+        ]
+        .iter()
+        .filter_map(|(name, algorithm)| {
+            let hash = columns.iter().position(|column| column == name)?;
+            Some((hash, *algorithm))
+        })
+        .collect();
+        let (hash, algorithm) = found
+            .iter()
+            .find(|(_, algorithm)| algorithm.is_available())
+            .or_else(|| found.first())
+            .copied()
+            .ok_or("hashdeep file has no md5, sha1 or sha256 column")?;
+        Ok(HashdeepColumns {
+            count: columns.len(),
+            hash,
+            algorithm,
+            size: columns.iter().position(|column| *column == "size"),
+        })
+    }
+
+    /// Parse a row like `11,5eb63bbbe01eeed093cb22bb8f5acdc3,/home/me/hello.txt`. The filename
+    /// is last, so it may contain commas.
+    fn parse_line(&self, line: &str) -> Result<Entry, LineError> {
+        let fields: Vec<&str> = line.splitn(self.count, ',').collect();
+        if fields.len() != self.count {
+            let message = format!("expected {} columns", self.count);
+            return Err((line.len() + 1, message));
+        }
+        // The byte offset of each field.
+        let offsets: Vec<usize> = fields
+            .iter()
+            .scan(0, |offset, field| {
+                let start = *offset;
+                *offset += field.len() + 1;
+                Some(start)
+            })
+            .collect();
+        let checksum = parse_hex(fields[self.hash], offsets[self.hash])?;
+        let size = match self.size {
+            Some(column) => Some(
+                fields[column]
+                    .parse()
+                    .map_err(|_| (offsets[column] + 1, "expected a size".to_owned()))?,
+            ),
+            None => None,
+        };
+        let name = self.count - 1;
+        Ok(Entry {
+            name: parse_name(fields[name], false, offsets[name])?,
+            checksum,
+            algorithm: self.algorithm,
+            size,
+            timestamp: None,
+            metadata: None,
+            pieces: Vec::new(),
+        })
+    }
+}
+
+/// Parse a `<filename> <CRC32>` line from an SFV file.
+fn parse_sfv_line(line: &str) -> Result<Entry, LineError> {
+    let separator = line.rfind(&[' ', '\t'][..]).ok_or_else(|| {
+        let message = "expected a space and a CRC32 after the filename".to_owned();
+        (line.len() + 1, message)
+    })?;
+    let checksum = parse_hex(&line[separator + 1..], separator + 1)?;
+    if checksum.0.len() != 4 {
+        let message = "expected a CRC32 of 8 hex digits".to_owned();
+        return Err((separator + 2, message));
+    }
+    Ok(Entry {
+        name: parse_name(line[..separator].trim_end(), false, 0)?,
+        checksum,
+        algorithm: Algorithm::Crc32,
+        size: None,
+        timestamp: None,
+        metadata: None,
+        pieces: Vec::new(),
+    })
+}
+
+/// Undo the `\\` and `\n` escaping used for unusual filenames in checkfiles. The error is the
+/// byte offset of an invalid escape.
+fn unescape_filename(name: &str) -> Result<String, usize> {
+    let mut unescaped = String::with_capacity(name.len());
+    let mut chars = name.char_indices();
+    while let Some((i, c)) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some((_, '\\')) => unescaped.push('\\'),
+                Some((_, 'n')) => unescaped.push('\n'),
+                _ => return Err(i),
+            }
+        } else {
+            unescaped.push(c);
+        }
+    }
+    Ok(unescaped)
+}
+
+#[cfg(test)]
+fn parse_str(text: &str, dialect: Dialect) -> Result<(Vec<Entry>, Vec<ParseError>)> {
+    parse(&mut text.as_bytes(), dialect)
+}
+
+#[test]
+fn b3_test_parse_checkfile_lines() -> Result<()> {
+    let sfv = Dialect {
+        sfv: true,
+        ..Dialect::default()
+    };
+    let (entries, bad_lines) = parse_str(
+        "; comment\r\ndir/hello.txt 0d4a1185\r\na b.txt\t0D4A1185\r\n\r\nno-checksum\r\n",
+        sfv,
+    )?;
+    let crc = Checksum(vec![0x0d, 0x4a, 0x11, 0x85]);
+    assert_eq!(
+        vec!["dir/hello.txt", "a b.txt"],
+        entries.iter().map(|entry| &entry.name).collect::<Vec<_>>()
+    );
+    assert!(entries
+        .iter()
+        .all(|entry| entry.checksum == crc && entry.algorithm == Algorithm::Crc32));
+    assert_eq!(1, bad_lines.len());
+    assert_eq!((5, 12), (bad_lines[0].line, bad_lines[0].column));
+
+    let mut parser = Parser::new(Dialect::default());
+    let line = "d74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24  hello world.txt";
+    let entry = parser.parse_line(line)?.unwrap();
+    assert_eq!("hello world.txt", entry.name);
+    assert_eq!(line[..64], entry.checksum.to_string());
+    assert_eq!(Algorithm::Blake3, entry.algorithm);
+
+    let line = "\\d74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24  new\\nline";
+    assert_eq!("new\nline", parser.parse_line(line)?.unwrap().name);
+    assert!(parser.parse_line("d74981ef  too-short").is_err());
+
+    let line = "5eb63bbbe01eeed093cb22bb8f5acdc3 *hello.txt";
+    let entry = parser.parse_line(line)?.unwrap();
+    assert_eq!(
+        ("hello.txt", Algorithm::Md5),
+        (&entry.name[..], entry.algorithm)
+    );
+    assert_eq!(line[..32], entry.checksum.to_string());
+
+    let line = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9  hello.txt";
+    let mut sha256 = Parser::new(Dialect {
+        sha256: true,
+        ..Dialect::default()
+    });
+    assert_eq!(
+        Algorithm::Sha256,
+        sha256.parse_line(line)?.unwrap().algorithm
+    );
+    assert_eq!(
+        Algorithm::Blake3,
+        parser.parse_line(line)?.unwrap().algorithm
+    );
+    Ok(())
+}
+
+#[test]
+fn b3_test_parse_tag_lines() -> Result<()> {
+    let mut parser = Parser::new(Dialect::default());
+    let line =
+        "BLAKE3 (a) = b.txt) = d74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24";
+    let entry = parser.parse_line(line)?.unwrap();
+    assert_eq!(
+        ("a) = b.txt", Algorithm::Blake3),
+        (&entry.name[..], entry.algorithm)
+    );
+
+    let entry = parser
+        .parse_line(
+            "SHA256 (hello.txt) = b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9",
+        )?
+        .unwrap();
+    assert_eq!(Algorithm::Sha256, entry.algorithm);
+    let entry = parser
+        .parse_line("\\MD5 (back\\\\slash) = 5eb63bbbe01eeed093cb22bb8f5acdc3")?
+        .unwrap();
+    assert_eq!(
+        ("back\\slash", Algorithm::Md5),
+        (&entry.name[..], entry.algorithm)
+    );
+
+    let err = parser
+        .parse_line("TIGER (hello.txt) = 5eb63bbb")
+        .unwrap_err();
+    assert_eq!((4, 1), (err.line, err.column));
+    let err = parser.parse_line("MD5 (hello.txt) = 5eb63bbb").unwrap_err();
+    assert_eq!(19, err.column);
+    Ok(())
+}
+
+#[test]
+fn b3_test_parse_dir_lines() -> Result<()> {
+    let digest = "d74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24";
+    let text = format!(
+        "{0}  top.txt\n# dir photos/2021\n{0}  a.jpg\n{0}  b.jpg\n# dir /\n{0}  root.txt\n\
+        # dir .\n{0}  photos/c.jpg\n",
+        digest
+    );
+    let (entries, errors) = parse_str(&text, Dialect::default())?;
+    assert!(errors.is_empty());
+    let names: Vec<&str> = entries.iter().map(|entry| &entry.name[..]).collect();
+    assert_eq!(
+        vec![
+            "top.txt",
+            "photos/2021/a.jpg",
+            "photos/2021/b.jpg",
+            "/root.txt",
+            "photos/c.jpg"
+        ],
+        names
+    );
+
+    let sfv = Dialect {
+        sfv: true,
+        ..Dialect::default()
+    };
+    let (entries, _) = parse_str("; dir docs\r\nhello.txt 0D4A1185\r\n", sfv)?;
+    assert_eq!("docs/hello.txt", entries[0].name);
+    Ok(())
+}
+
+#[test]
+fn b3_test_parse_errors() {
+    let mut parser = Parser::new(Dialect::default());
+    let checksum = "d74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24";
+    let errors = [
+        (checksum.to_string(), 65),
+        (format!("{} hello.txt", checksum), 66),
+        (format!("{}  ", checksum), 67),
+        (format!("{}x  hello.txt", &checksum[1..]), 64),
+        (format!("\\{}  bad\\escape", checksum), 71),
+        (format!("{}  hello.txt\r", &checksum[..63]), 63),
+    ];
+    for (line, column) in errors.iter() {
+        let err = parser.parse_line(line).unwrap_err();
+        assert_eq!(
+            (*column, ParseErrorKind::BadLine),
+            (err.column, err.kind),
+            "{}",
+            line
+        );
+    }
+    let err = parser.parse_line("").map(|_| ());
+    assert_eq!(Ok(()), err);
+}
+
+#[test]
+fn b3_test_parse_hashdeep() {
+    let hashdeep = "%%%% HASHDEEP-1.0\n%%%% size,md5,sha256,filename\n## comment\n\
+        11,5eb63bbbe01eeed093cb22bb8f5acdc3,\
+        b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9,/tmp/a,b.txt\n\
+        11,5eb63bbbe01eeed093cb22bb8f5acdc3\n";
+    let (entries, bad_lines) = parse_str(hashdeep, Dialect::default()).unwrap();
+    assert_eq!(1, entries.len());
+    assert_eq!("/tmp/a,b.txt", entries[0].name);
+    assert_eq!(Some(11), entries[0].size);
+    assert_eq!(Algorithm::Sha256, entries[0].algorithm);
+    assert_eq!(5, bad_lines[0].line);
+
+    let err = parse_str(
+        "%%%% HASHDEEP-1.0\n%%%% size,tiger,filename\n",
+        Dialect::default(),
+    )
+    .unwrap_err();
+    assert_eq!(
+        "line 2, column 6: hashdeep file has no md5, sha1 or sha256 column",
+        err.to_string()
+    );
+    assert!(parse_str("%%%% HASHDEEP-1.0\n11,abcd,a\n", Dialect::default()).is_err());
+}
+
+#[test]
+fn b3_test_format_line() -> Result<()> {
+    let entry = Entry {
+        name: "new\nline\\".to_owned(),
+        checksum: "d74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24".parse()?,
+        algorithm: Algorithm::Blake3,
+        size: None,
+        timestamp: None,
+        metadata: None,
+        pieces: Vec::new(),
+    };
+    for format in [Format::B3sum, Format::Tag].iter() {
+        let line = format_line(&entry, *format).unwrap();
+        assert!(line.starts_with('\\'));
+        let parsed = Parser::new(Dialect::default()).parse_line(&line)?;
+        assert_eq!(Some(&entry), parsed.as_ref());
+    }
+    assert_eq!(None, format_line(&entry, Format::Sfv));
+    assert_eq!(None, format_line(&entry, Format::NoNames));
+
+    let entry = Entry {
+        name: "a b.txt".to_owned(),
+        checksum: "0d4a1185".parse()?,
+        algorithm: Algorithm::Crc32,
+        size: None,
+        timestamp: None,
+        metadata: None,
+        pieces: Vec::new(),
+    };
+    assert_eq!(
+        Some("a b.txt 0D4A1185".to_owned()),
+        format_line(&entry, Format::Sfv)
+    );
+    Ok(())
+}
+
+#[test]
+fn b3_test_nsrl_row() -> Result<()> {
+    let mut entry = Entry {
+        name: "docs/say \"hi\".txt".to_owned(),
+        checksum: "2aae6c35c94fcfb415dbe95f408b9ce91ee846ed".parse()?,
+        algorithm: Algorithm::Sha1,
+        size: Some(11),
+        timestamp: None,
+        metadata: None,
+        pieces: Vec::new(),
+    };
+    assert_eq!(
+        Some(
+            "\"2AAE6C35C94FCFB415DBE95F408B9CE91EE846ED\",\"\",\"\",\"say \"\"hi\"\".txt\",\"11\",\
+            \"0\",\"\",\"\""
+                .to_owned()
+        ),
+        format_line(&entry, Format::Nsrl)
+    );
+    assert_eq!(8, NSRL_HEADER.split(',').count());
+    entry.size = None;
+    assert!(format_line(&entry, Format::Nsrl)
+        .unwrap()
+        .ends_with(",\"\",\"0\",\"\",\"\""));
+    // The set is keyed by SHA-1, so other checksums can't be written.
+    entry.algorithm = Algorithm::Blake3;
+    assert_eq!(None, format_line(&entry, Format::Nsrl));
+    Ok(())
+}
+
+#[test]
+fn b3_test_known_hashes() -> Result<()> {
+    let blake3 = "d74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24";
+    let sha1 = "2AAE6C35C94FCFB415DBE95F408B9CE91EE846ED";
+    let text = format!(
+        "{}\n\"{}\",\"\",\"\",\"hello.txt\",\"11\",\"0\",\"\",\"\"\n{}  hello.txt\n",
+        NSRL_HEADER, sha1, blake3
+    );
+    let known = KnownHashes::from_bytes(text.as_bytes(), 20)?;
+    assert_eq!(1, known.len());
+    assert!(known.contains(&sha1.parse()?));
+    assert!(!known.contains(&blake3.parse()?));
+    let known = KnownHashes::from_bytes(text.as_bytes(), 32)?;
+    assert!(known.contains(&blake3.parse()?));
+    assert!(KnownHashes::from_bytes(text.as_bytes(), 16).is_err());
+    assert!(KnownHashes::from_bytes(b"", 16)?.is_empty());
+
+    // Binary sets don't have to be sorted.
+    let digests: Vec<[u8; 4]> = vec![[9, 0, 0, 1], [0, 0, 0, 255], [9, 0, 0, 1], [3, 1, 4, 1]];
+    let known = KnownHashes::from_bytes(&digests.concat(), 4)?;
+    assert_eq!(3, known.len());
+    for digest in &digests {
+        assert!(known.contains(&Checksum(digest.to_vec())));
+    }
+    assert!(!known.contains(&Checksum(vec![3, 1, 4, 2])));
+    assert!(!known.contains(&Checksum(vec![9, 0, 0])));
+    assert!(KnownHashes::from_bytes(&digests.concat()[1..], 4).is_err());
+
+    // The comments that flag known files are skipped.
+    let text = format!("# known\n{}  hello.txt\n", blake3);
+    let (entries, bad_lines) = parse_str(&text, Dialect::default())?;
+    assert!(bad_lines.is_empty());
+    assert_eq!(1, entries.len());
+    Ok(())
+}
+
+#[test]
+fn b3_test_seal() -> Result<()> {
+    let body = "d74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24  hello.txt\n";
+    let seal = Seal::of(body.as_bytes(), &Blake3Mode::Hash);
+    let line = seal.to_line(Format::B3sum);
+    assert!(line.starts_with(&format!("# b3sum-ng {} seal: ", env!("CARGO_PKG_VERSION"))));
+    let sealed = format!("{}{}\n", body, line);
+    assert_eq!(
+        Some(seal),
+        check_seal(sealed.as_bytes(), &Blake3Mode::Hash)?
+    );
+    assert_eq!(None, check_seal(body.as_bytes(), &Blake3Mode::Hash)?);
+
+    // The seal isn't an entry or a bad line.
+    let (entries, bad_lines) = parse_str(&sealed, Dialect::default())?;
+    assert_eq!((1, 0), (entries.len(), bad_lines.len()));
+
+    let hash = &Blake3Mode::Hash;
+    assert!(check_seal(sealed.replace("hello", "jello").as_bytes(), hash).is_err());
+    assert!(check_seal(format!("{}{}", sealed, body).as_bytes(), hash).is_err());
+    let sfv = format!(
+        "hello.txt 0D4A1185\r\n{}\r\n",
+        Seal::of(b"hello.txt 0D4A1185\r\n", hash).to_line(Format::Sfv)
+    );
+    assert!(sfv.contains("\n; b3sum-ng "));
+    assert!(check_seal(sfv.as_bytes(), hash)?.is_some());
+
+    // A keyed seal can't be recomputed without the key.
+    let key = &Blake3Mode::DeriveKey("b3sum-ng test context".into());
+    let seal = Seal::of(body.as_bytes(), key);
+    let line = seal.to_line(Format::B3sum);
+    assert!(line.contains(" keyed seal: "));
+    let keyed = format!("{}{}\n", body, line);
+    assert_eq!(Some(seal), check_seal(keyed.as_bytes(), key)?);
+    assert!(check_seal(keyed.as_bytes(), hash).is_err());
+    let forged = format!(
+        "{}{}\n",
+        body,
+        Seal::of(body.as_bytes(), hash).to_line(Format::B3sum)
+    )
+    .replace(" seal: ", " keyed seal: ");
+    assert!(check_seal(forged.as_bytes(), key).is_err());
+    Ok(())
+}
+
+#[test]
+fn b3_test_chain() -> Result<()> {
+    let entry = |name: &str| {
+        format!(
+            "d74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24  {}\n",
+            name
+        )
+    };
+    // Records are appended as `--chain` prints them.
+    let mut manifest = String::new();
+    let mut records = Vec::new();
+    for name in &["a", "b", "c"] {
+        let previous = check_chain(manifest.as_bytes())?.unwrap_or_else(chain_start);
+        let record = format!("{}\n{}", chain_line(&previous, Format::B3sum), entry(name));
+        manifest.push_str(&record);
+        records.push(record);
+    }
+    let head = chain_link(records[2].as_bytes());
+    assert_eq!(Some(head.clone()), check_chain(manifest.as_bytes())?);
+    assert!(manifest.starts_with(&format!("# chain {}\n", "0".repeat(64))));
+    assert_eq!(None, check_chain(entry("a").as_bytes())?);
+    let (entries, bad_lines) = parse_str(&manifest, Dialect::default())?;
+    assert_eq!((3, 0), (entries.len(), bad_lines.len()));
+
+    let broken = |records: &[&String]| {
+        let manifest: String = records.iter().map(|record| record.as_str()).collect();
+        check_chain(manifest.as_bytes()).is_err()
+    };
+    let [a, b, c] = [&records[0], &records[1], &records[2]];
+    assert!(broken(&[a, c]));
+    assert!(broken(&[b, c]));
+    assert!(broken(&[a, c, b]));
+    assert!(broken(&[a, &records[1].replace("  b", "  x"), c]));
+    assert!(broken(&[a, b, b, c]));
+    assert!(check_chain(format!("{}{}", entry("x"), manifest).as_bytes()).is_err());
+    // A record that was removed from the end is only found by its head.
+    assert_ne!(Some(head), check_chain(format!("{}{}", a, b).as_bytes())?);
+    Ok(())
+}
+
+#[test]
+fn b3_test_timestamps() -> Result<()> {
+    let time = SystemTime::UNIX_EPOCH + Duration::from_millis(1_700_000_000_250);
+    assert_eq!("2023-11-14T22:13:20.250Z", format_timestamp(time));
+    assert_eq!(Some(time), parse_timestamp("2023-11-14T22:13:20.250Z"));
+    assert_eq!(Some(time), parse_timestamp("2023-11-15T00:13:20.25+02:00"));
+    assert_eq!(
+        Some(time),
+        parse_timestamp("2023-11-14 17:13:20.250000-05:00")
+    );
+    let leap_day = parse_timestamp("2024-02-29T00:00:00Z").unwrap();
+    assert_eq!("2024-02-29T00:00:00.000Z", format_timestamp(leap_day));
+    for invalid in &[
+        "2023-02-29T00:00:00Z",
+        "2023-13-01T00:00:00Z",
+        "2023-11-14T22:13:20",
+        "2023-11-14T22:13:20.Z",
+        "2023-11-14T22:13:20+0200",
+        "2023-11-14T24:00:00Z",
+    ] {
+        assert_eq!(None, parse_timestamp(invalid), "{}", invalid);
+    }
+
+    let checksum = "d74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24";
+    let text = format!(
+        "# computed 2023-11-14T22:13:20.250Z\n{0}  a.txt\n{0}  b.txt\n",
+        checksum
+    );
+    let (entries, bad_lines) = parse_str(&text, Dialect::default())?;
+    assert!(bad_lines.is_empty());
+    assert_eq!(Some(time), entries[0].timestamp);
+    assert_eq!(None, entries[1].timestamp);
+
+    // Timestamps are kept when entries are written again.
+    let line = format_line(&entries[0], Format::Tag).unwrap();
+    assert!(line.starts_with("# computed 2023-11-14T22:13:20.250Z\nBLAKE3 (a.txt) = "));
+    let (reparsed, _) = parse_str(&line, Dialect::default())?;
+    assert_eq!(entries[..1], reparsed[..]);
+    Ok(())
+}
+
+#[test]
+fn b3_test_metadata() -> Result<()> {
+    let recorded = FileMetadata {
+        size: Some(11),
+        mode: Some(0o100644),
+        uid: Some(1000),
+        gid: Some(100),
+        mtime: parse_timestamp("2023-11-14T22:13:20.250Z"),
+        xattrs: None,
+    };
+    let line = recorded.to_line(Format::B3sum);
+    assert_eq!(
+        "# metadata size=11 mode=100644 uid=1000 gid=100 mtime=2023-11-14T22:13:20.250Z",
+        line
+    );
+    let labeled = FileMetadata {
+        xattrs: Some(Checksum(vec![0xab; 32])),
+        ..FileMetadata::default()
+    };
+    let xattrs_line = format!("# metadata xattrs={}", "ab".repeat(32));
+    assert_eq!(xattrs_line, labeled.to_line(Format::B3sum));
+    assert_eq!(
+        Some(&labeled),
+        FileMetadata::parse_line(&xattrs_line).as_ref()
+    );
+    let relabeled = FileMetadata {
+        xattrs: Some(Checksum(vec![0xcd; 32])),
+        ..recorded.clone()
+    };
+    assert_eq!(vec!["xattrs"], labeled.differences(&relabeled));
+    assert_eq!(Some(&recorded), FileMetadata::parse_line(&line).as_ref());
+    // Attributes from newer versions are skipped, and missing ones aren't compared.
+    let partial = FileMetadata::parse_line("; metadata size=11 acl=none").unwrap();
+    assert_eq!(Some(11), partial.size);
+    assert_eq!(None, partial.mode);
+    assert!(partial.differences(&recorded).is_empty());
+    assert_eq!(None, FileMetadata::parse_line("# metadata mode=999"));
+    assert_eq!(None, FileMetadata::parse_line("# metadatas"));
+
+    let changed = FileMetadata {
+        mode: Some(0o100600),
+        gid: Some(0),
+        ..recorded.clone()
+    };
+    assert_eq!(vec!["mode", "owner"], recorded.differences(&changed));
+
+    let (_file, path, _guard) = make_temp_file(b"hello world");
+    let actual = FileMetadata::of(&std::fs::metadata(&path)?);
+    assert_eq!(Some(11), actual.size);
+    assert!(actual.differences(&actual).is_empty());
+
+    let checksum = "d74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24";
+    let text = format!("{}\n{}  a.txt\n{}  b.txt\n", line, checksum, checksum);
+    let (entries, bad_lines) = parse_str(&text, Dialect::default())?;
+    assert!(bad_lines.is_empty());
+    assert_eq!(Some(&recorded), entries[0].metadata.as_ref());
+    assert_eq!(None, entries[1].metadata);
+    let line = format_line(&entries[0], Format::Sfv).unwrap();
+    assert!(line.starts_with("; metadata size=11 "));
+    Ok(())
+}
@@ -0,0 +1,1328 @@
+// Copyright 2021 Daniel Zwell.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The command line options of b3sum-ng, and the process-wide settings that the command line
+//! program makes from them. This is only built with the `cli` feature.
+
+use std::{path::PathBuf, time::Instant};
+
+use structopt::*;
+
+use crate::{
+    checkfile::KnownHashes,
+    engine::{ArchiveFormat, Blake3Mode, Checksum},
+    output::{
+        self, ColorChoice, ErrorFormat, Format, KnownAction, LogTarget, ProgressFormat, SummaryBy,
+        SyncInterval, UnicodeForm,
+    },
+    scheduler::{self, ExclusiveLarge, IoStrategy, TimeLimit},
+    verify::{MismatchAction, PathMap, ReportTarget, SpotCheck, VerifyOptions},
+    Result,
+};
+
+#[derive(StructOpt)]
+#[structopt(
+    setting = clap::AppSettings::ArgsNegateSubcommands,
+    after_help = "EXIT STATUS:
+    0    Success.
+    1    A checksum did not match, or something else failed.
+    2    The command line was invalid.
+    3    A file could not be read.
+    4    A file could not be found.
+    5    The run stopped at its --timeout before every file was read.
+If several of these happened, the highest status is used."
+)]
+pub struct Options {
+    #[structopt(flatten)]
+    pub global: GlobalOptions,
+
+    #[structopt(
+        short,
+        long,
+        conflicts_with_all = &["length", "no-names", "raw", "tag", "expect"],
+        help = "Read checksums from the given files and verify them, the same as the 'check' \
+            subcommand. SFV files are recognized by their .sfv extension. With --keyed or \
+            --derive-key, BLAKE3 checksums and seals are verified in that mode."
+    )]
+    pub check: bool,
+
+    #[structopt(flatten)]
+    pub verify: VerifyArgs,
+
+    #[structopt(flatten)]
+    pub hash: HashOptions,
+
+    #[structopt(subcommand)]
+    pub command: Option<Command>,
+}
+
+/// Options that apply to every subcommand. Most options can be given by an environment variable
+/// instead, such as `B3SUM_JOBS` for `--job-count`. See [`GlobalOptions::apply_env`] for flags.
+#[derive(StructOpt)]
+pub struct GlobalOptions {
+    #[structopt(
+        long,
+        global = true,
+        // The author of rigrep says mmap causes random SIGSEGV or SIGBUS
+        // when files are changed during reading. Unlikely.
+        help = "Use mmap. This gives better performance on SSDs. It is possible that the program will crash \
+            if a file is modified while being read.",
+    )]
+    pub mmap: bool,
+
+    #[structopt(
+        long,
+        global = true,
+        conflicts_with = "mmap",
+        help = "Choose between mmap and normal reads for the large files of each disk. The first \
+            time a disk is seen, both are timed on the start of a large file on it, and the faster \
+            one is remembered for that disk in --mmap-cache. Disks are only told apart on Unix."
+    )]
+    pub auto_mmap: bool,
+
+    #[structopt(
+        long,
+        global = true,
+        value_name = "file",
+        requires = "auto-mmap",
+        help = "Where --auto-mmap remembers which way is faster on each disk [default: \
+            b3sum-ng/mmap in $XDG_CACHE_HOME or ~/.cache]"
+    )]
+    pub mmap_cache: Option<PathBuf>,
+
+    // Note: this number that was found to have good performance in testing
+    // on hard drives and SSDs.
+    #[structopt(
+        short,
+        long,
+        global = true,
+        env = "B3SUM_JOBS",
+        default_value = "16",
+        help = "The number of concurrent reads to allow from each disk. Regardless of this value, \
+            checksums of large files will still be computed one at a time with multithreading."
+    )]
+    pub job_count: usize,
+
+    #[structopt(
+        long,
+        global = true,
+        value_name = "when",
+        default_value = "auto",
+        possible_values = &["auto", "always", "never"],
+        help = "Whether to wait for other reads to finish before reading a large file, and read \
+            nothing else until it's done. This is faster on hard drives, but not on SSDs. With \
+            'auto', it is done unless the file is on an SSD. SSDs are only detected on Linux."
+    )]
+    pub io_exclusive_large: ExclusiveLarge,
+
+    #[structopt(
+        long,
+        global = true,
+        value_name = "strategy",
+        default_value = "auto",
+        possible_values = &["auto", "hdd", "ssd"],
+        help = "How to read files, for the kind of storage they're on. 'hdd' reads large files \
+            one at a time. 'ssd' reads large files at the same time, each with several threads \
+            unless --mmap is given, and doesn't limit small files by --job-count. 'auto' detects \
+            this for each file, and reads network filesystems differently. \
+            --io-exclusive-large always or never overrides this for large files."
+    )]
+    pub io_strategy: IoStrategy,
+
+    #[structopt(
+        long,
+        global = true,
+        env = "B3SUM_THREADS",
+        help = "The number of threads used for checksumming large files. By default, this is \
+            the number of logical CPU cores that the process may use, or RAYON_NUM_THREADS if it \
+            is set. On Linux, that takes the CPU affinity and the cgroup CPU quota into account."
+    )]
+    pub num_threads: Option<usize>,
+
+    #[structopt(
+        long,
+        global = true,
+        help = "Print the checksum of the decompressed contents of .gz, .zst and .xz files. \
+            Compressed tar archives are also decompressed when used with --archive."
+    )]
+    pub decompress: bool,
+
+    #[structopt(
+        long,
+        global = true,
+        help = "Don't keep the files that are read in the OS cache, so that a scan of many files \
+            doesn't push out other cached data. Only supported on macOS."
+    )]
+    pub no_cache: bool,
+
+    #[structopt(
+        long,
+        global = true,
+        value_name = "bytes",
+        help = "Ask the OS to read this many bytes ahead of where large files are being read, so \
+            that high-latency storage like NFS and USB disks keeps the hasher busy. Only used for \
+            buffered reads, not with --mmap or the parallel reads of SSDs. Only supported on \
+            Linux and macOS."
+    )]
+    pub readahead: Option<u64>,
+
+    #[structopt(
+        long,
+        global = true,
+        help = "Take a shared lock on each file while reading it, so that programs that lock \
+            files to write them wait until it has been read. Files that another program has \
+            locked are reported as errors instead of being read."
+    )]
+    pub lock: bool,
+
+    #[structopt(
+        long,
+        global = true,
+        value_name = "path",
+        parse(from_os_str),
+        help = "Take an exclusive lock on this file while running, so that a scan that is started \
+            while another one is running, such as by a timer, exits without doing anything \
+            instead of doubling the load on the disks. By default, the manifest of --chain or \
+            the file of --last-verified or --verified-cache with '.lock' appended is used, if \
+            they are given."
+    )]
+    pub lockfile: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        global = true,
+        help = "Wait for the scan that has the --lockfile to finish, instead of exiting."
+    )]
+    pub wait_for_lock: bool,
+
+    #[structopt(
+        long,
+        global = true,
+        help = "Run at the lowest CPU and I/O priority, and on Linux, don't start new files while \
+            other processes use more CPU or disk I/O than --pause-cpu and --pause-io allow, so \
+            that a scan never slows down the work that the system is for."
+    )]
+    pub background: bool,
+
+    #[structopt(
+        long,
+        global = true,
+        value_name = "percent",
+        default_value = "50",
+        help = "With --background, pause while other processes use more than this share of all \
+            CPUs."
+    )]
+    pub pause_cpu: f64,
+
+    #[structopt(
+        long,
+        global = true,
+        value_name = "bytes",
+        default_value = "20000000",
+        help = "With --background, pause while other processes read and write more than this \
+            many bytes per second on the disks."
+    )]
+    pub pause_io: u64,
+
+    #[structopt(
+        long,
+        global = true,
+        help = "Compute the Shannon entropy of each file while it's read, in bits per byte from 0 \
+            to 8, so that encrypted and compressed files, which are close to 8, stand out. It's \
+            printed in a comment like '# entropy 7.9993' before each checksum line, or in the rows \
+            of --format json and csv, and with --check, it's in the --report."
+    )]
+    pub entropy: bool,
+
+    #[structopt(
+        long,
+        global = true,
+        help = "Compute the ssdeep similarity hash of each file while it's read, so that files \
+            that are almost the same can be found with 'ssdeep -k'. It's printed in a comment like \
+            '# ssdeep 3:AXGBicFlgVNhBGcL:AXGH' before each checksum line, or in the rows of \
+            --format json and csv, and with --check, it's in the --report. Requires b3sum-ng to be built with the fuzzy feature."
+    )]
+    pub ssdeep: bool,
+
+    #[structopt(
+        long,
+        global = true,
+        value_name = "retries",
+        help = "If a file changes while it is read, read it again, up to this many times. If it \
+            still changes, it is reported as an error. Without this option, a warning is printed \
+            for files that change."
+    )]
+    pub verify_stable: Option<usize>,
+
+    #[structopt(
+        long,
+        global = true,
+        help = "Read every file twice, the second time another way: with mmap if it was read \
+            normally, and normally if it was mapped. On Linux, the file is dropped from the cache \
+            first, so it's read from the disk again. Files whose checksums differ are reported as \
+            errors, since the disk, its cable, its controller or the RAM may be corrupting data. \
+            This takes twice as long."
+    )]
+    pub paranoid: bool,
+
+    #[structopt(
+        short,
+        long,
+        global = true,
+        help = "Only print errors and warnings, and with --check, files that failed. \
+            The exit status is nonzero if there were any errors."
+    )]
+    pub quiet: bool,
+
+    #[structopt(
+        short,
+        long,
+        global = true,
+        help = "Print to standard error how each file was read (whole into memory, buffered or \
+            with mmap), how long it took, and the throughput."
+    )]
+    pub verbose: bool,
+
+    #[structopt(
+        long,
+        global = true,
+        help = "Remove leading ./ and repeated slashes from the paths that are printed, and from \
+            the paths in checkfiles with --check, so that checkfiles list files the same way no \
+            matter how they were given."
+    )]
+    pub normalize_paths: bool,
+
+    #[structopt(
+        long,
+        global = true,
+        value_name = "form",
+        possible_values = &["nfc", "nfd"],
+        help = "Convert the paths that are printed, and the paths in checkfiles with --check, to \
+            this Unicode normalization form. macOS names files in NFD, while Linux keeps names as \
+            they were given, which is usually NFC."
+    )]
+    pub unicode_form: Option<UnicodeForm>,
+
+    #[structopt(
+        long,
+        global = true,
+        env = "B3SUM_COLOR",
+        default_value = "auto",
+        possible_values = &["auto", "always", "never"],
+        help = "When to color the output. With 'auto', output is colored if it is a terminal \
+            and the NO_COLOR environment variable is not set."
+    )]
+    pub color: ColorChoice,
+
+    #[structopt(
+        long,
+        global = true,
+        env = "B3SUM_PROGRESS",
+        possible_values = &["json"],
+        help = "Print progress to standard error every second. With 'json', each line is a JSON \
+            object with the files and bytes done, the current rate and the estimated time left."
+    )]
+    pub progress: Option<ProgressFormat>,
+
+    #[structopt(
+        long,
+        global = true,
+        env = "B3SUM_ERRORS",
+        default_value = "text",
+        possible_values = &["text", "json"],
+        help = "How errors are printed to standard error. With 'json', each error is a JSON \
+            object with the path, the phase that failed, the kind of I/O error and its errno, \
+            and the number of times the file was read again, for log shippers to index."
+    )]
+    pub errors: ErrorFormat,
+
+    #[structopt(
+        long,
+        global = true,
+        env = "B3SUM_SYSTEM_LOG",
+        possible_values = &["syslog", "journald"],
+        help = "Also send errors, warnings and a summary of the run to the system log, with their \
+            priorities, so that they aren't lost when the standard error of a scan that runs \
+            from cron is discarded. Only supported on Unix."
+    )]
+    pub log: Option<LogTarget>,
+
+    #[structopt(
+        long,
+        global = true,
+        value_name = "file",
+        help = "At the end of the run, write a summary of it to this file as JSON: the exit \
+            status, the number of files with each status, the bytes that were read, how long it \
+            took and the throughput, and every error. The file is replaced at once."
+    )]
+    pub summary: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        global = true,
+        value_name = "duration",
+        help = "Stop starting files once the run has taken this long, such as 90s, 30m or 2h, \
+            for a maintenance window of a fixed length. The files that are being read are \
+            finished, the output and the summary are written for the files that were read, and \
+            the exit status is 5 if any files were skipped."
+    )]
+    pub timeout: Option<TimeLimit>,
+}
+
+impl GlobalOptions {
+    /// Turn on flags that are set by environment variables, like `B3SUM_MMAP=1` for `--mmap`.
+    /// Clap only reads options with values from the environment.
+    pub fn apply_env(&mut self) {
+        self.mmap |= env_flag("B3SUM_MMAP");
+        self.decompress |= env_flag("B3SUM_DECOMPRESS");
+        self.no_cache |= env_flag("B3SUM_NO_CACHE");
+        self.quiet |= env_flag("B3SUM_QUIET");
+        self.verbose |= env_flag("B3SUM_VERBOSE");
+    }
+}
+
+/// Print the `tracing` events of the library to standard error if `B3SUM_LOG` is set. It takes a
+/// filter in the same format as `RUST_LOG`, such as `debug` or `b3sum_ng::scheduler=trace`.
+pub fn init_tracing() {
+    if std::env::var_os("B3SUM_LOG").is_some() {
+        tracing_subscriber::fmt()
+            .with_env_filter(tracing_subscriber::EnvFilter::from_env("B3SUM_LOG"))
+            .with_writer(std::io::stderr)
+            .init();
+    }
+}
+
+/// Whether an environment variable is set to turn on a flag. Empty values, `0`, `false` and `no`
+/// leave it off.
+fn env_flag(name: &str) -> bool {
+    match std::env::var(name) {
+        Ok(value) => !matches!(
+            value.to_ascii_lowercase().as_str(),
+            "" | "0" | "false" | "no"
+        ),
+        Err(_) => false,
+    }
+}
+
+/// Options for computing checksums, which is done when no subcommand is given.
+#[derive(StructOpt)]
+pub struct HashOptions {
+    #[structopt(
+        default_value = "-",
+        help = "Files to get the checksum of. When '-' is given, \
+            calculate the checksum of standard input."
+    )]
+    pub paths: Vec<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "Use the keyed mode of BLAKE3. The secret key is read from standard input, \
+            and it must be exactly 32 raw bytes."
+    )]
+    pub keyed: bool,
+
+    #[structopt(
+        long,
+        value_name = "context",
+        conflicts_with = "keyed",
+        help = "Use the key derivation mode of BLAKE3, with the given context string."
+    )]
+    pub derive_key: Option<String>,
+
+    #[structopt(
+        short,
+        long,
+        help = "The number of bytes of BLAKE3 output, before hex encoding. [default: 32]"
+    )]
+    pub length: Option<usize>,
+
+    #[structopt(long, help = "Omit filenames from the output.")]
+    pub no_names: bool,
+
+    #[structopt(
+        long,
+        help = "Print the raw bytes of the checksum rather than hex. Only one input is allowed, \
+            and no filename is printed."
+    )]
+    pub raw: bool,
+
+    #[structopt(
+        long,
+        help = "Print checksums in the BSD style: 'BLAKE3 (filename) = checksum'."
+    )]
+    pub tag: bool,
+
+    #[structopt(
+        long,
+        conflicts_with_all = &["archive", "expect"],
+        help = "Print one checksum for each directory instead of the checksum of each file, \
+            computed from the checksums and paths of all the files under it, so that two trees \
+            can be compared by one checksum. Empty directories are left out."
+    )]
+    pub tree_digest: bool,
+
+    #[structopt(
+        long,
+        conflicts_with_all = &["archive", "ads", "decompress", "dry-run", "tree-digest"],
+        help = "Print one checksum for all the inputs, as if they were one file made of their \
+            contents one after another, for a file that was split into parts such as 'image.001 \
+            image.002'. Inputs may be glob patterns such as 'image.*', whose matches are sorted. \
+            The checksum is printed with the name of the first part. --check only reads the file \
+            it's named for, so use --join with --expect to verify the checksum again."
+    )]
+    pub join: bool,
+
+    #[structopt(
+        long,
+        value_name = "checksum",
+        conflicts_with_all = &["archive", "no-names", "raw", "tag"],
+        help = "Compare the checksum of a single input with this one, and print nothing unless \
+            they differ. The exit status is 0 if they match, 1 if they don't, and 3 if the input \
+            could not be read."
+    )]
+    pub expect: Option<Checksum>,
+
+    #[structopt(
+        long,
+        env = "B3SUM_FORMAT",
+        default_value = "b3sum",
+        possible_values = &["b3sum", "sfv", "nsrl", "json", "csv"],
+        help = "The output format. 'sfv' writes CRC32 checksums that other SFV tools can verify. \
+            With --check, 'sfv' means that all checkfiles are SFV files. 'nsrl' writes the SHA-1, \
+            name and size of each file as the CSV rows of an NSRL RDS NSRLFile.txt, after its \
+            header, and requires the extra-algorithms feature. 'json' writes a JSON object for \
+            each file, and 'csv' writes a CSV row after a header, with the entropy, ssdeep hash \
+            and attribute digest when --entropy, --ssdeep and --xattrs are given. They can't be \
+            checked."
+    )]
+    pub format: Format,
+
+    #[structopt(
+        long,
+        possible_values = &["auto", "tar", "zip"],
+        help = "Print a checksum for each file inside archives instead of one for the archive. \
+            With 'auto', only files with a .tar or .zip extension are treated as archives."
+    )]
+    pub archive: Option<ArchiveFormat>,
+
+    #[structopt(
+        long,
+        conflicts_with = "expect",
+        help = "Print the size of each input and whether it would be read as a small file, a large \
+            file or an archive, and the total size, without reading any of them."
+    )]
+    pub dry_run: bool,
+
+    #[structopt(
+        long,
+        conflicts_with_all = &["expect", "dry-run", "join", "tree-digest", "archive", "keyed"],
+        help = "Run as a co-process for another program: read paths from standard input, one per \
+            line, and answer each one with a line with 'ok <checksum>' or 'error <message>', \
+            until standard input ends. No paths are given on the command line."
+    )]
+    pub server: bool,
+
+    #[structopt(
+        long,
+        requires = "server",
+        help = "With --server, end each request and each response with NUL instead of a newline, \
+            so that paths may contain newlines."
+    )]
+    pub server_nul: bool,
+
+    #[structopt(
+        long = "string",
+        value_name = "text",
+        number_of_values = 1,
+        conflicts_with_all = &["expect", "dry-run", "join", "tree-digest", "archive", "server"],
+        help = "Print the checksum of this text instead of a file, labeled with the text in \
+            quotes, without a newline at its end. It may be given more than once. No paths are \
+            given with it."
+    )]
+    pub strings: Vec<String>,
+
+    #[structopt(
+        long,
+        conflicts_with_all = &["join", "dry-run", "tree-digest", "archive", "server", "string"],
+        help = "Print one checksum of all the inputs, as if they were one stream made of their \
+            contents one after another in the order they're given, such as for a cache key of \
+            several files. Unlike --join, standard input can be one of the inputs, and glob \
+            patterns aren't expanded. The checksum is labeled with the names of the inputs."
+    )]
+    pub combine: bool,
+
+    #[structopt(
+        long,
+        value_name = "file",
+        conflicts_with_all = &[
+            "join", "combine", "tree-digest", "sample", "archive", "ads", "server", "string",
+            "expect",
+        ],
+        help = "Checksum the files listed in this checkfile, in the order they're listed, \
+            instead of the paths given, such as to make the manifest again with another \
+            --length or --format. Its checksums are ignored, so it can be of any algorithm."
+    )]
+    pub from_manifest: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        conflicts_with_all = &[
+            "join", "combine", "tree-digest", "sample", "archive", "ads", "server", "string",
+            "expect", "from-manifest", "raw", "dry-run",
+        ],
+        help = "Copy standard input to standard output unchanged while checksumming it, and \
+            print the checksum line to standard error at the end, such as in \
+            'curl ... | b3sum-ng --tee | tar x'."
+    )]
+    pub tee: bool,
+
+    #[structopt(
+        long,
+        value_name = "file",
+        requires = "tee",
+        help = "With --tee, write the checksum line to this file instead of standard error."
+    )]
+    pub tee_checksum: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        value_name = "root",
+        conflicts_with_all = &["raw", "no-names", "tree-digest", "sample", "server", "tee"],
+        help = "Group the checksums by directory: when the directory changes, print a line like \
+            '# dir photos/2021', and then only the names of its files. Directories are named \
+            relative to root, so check the output from there. --check reads these lines."
+    )]
+    pub grouped: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        value_name = "dir",
+        conflicts_with_all = &[
+            "keyed", "derive-key", "length", "join", "combine", "tree-digest", "sample", "archive",
+            "ads", "server", "string", "expect", "tee", "dry-run", "decompress",
+        ],
+        help = "Also store each file in this content-addressed directory, named by its BLAKE3 \
+            like 'dir/ab/cdef...', so files with the same contents are stored once, and list their \
+            paths in 'dir/index', a checkfile. Each file is copied, and only stored if the copy \
+            has the BLAKE3 that was printed, so a file that changes in between is an error. Stored \
+            files are read-only."
+    )]
+    pub cas_export: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        value_name = "group",
+        possible_values = &["dir", "ext"],
+        help = "After the checksums, print to standard error the number of files and bytes in \
+            each directory or with each extension. Each directory also has the digest of the \
+            files directly in it, computed the way --tree-digest computes it."
+    )]
+    pub summary_by: Option<SummaryBy>,
+
+    #[structopt(
+        long,
+        value_name = "bytes",
+        conflicts_with_all = &[
+            "expect", "dry-run", "join", "combine", "tree-digest", "archive", "server", "string",
+            "seal", "chain", "tag", "raw", "no-names", "summary-by", "known-hashes"
+        ],
+        help = "Instead of the checksum of each file, print a quick sample digest of its size, \
+            its first bytes and its last bytes, this many of each, labeled like \
+            'sample 1048576 <digest>  <file>'. Files that differ only in the middle have the same \
+            sample, so it's for finding candidates to hash fully, and --check can't verify it."
+    )]
+    pub sample: Option<u64>,
+
+    #[structopt(
+        long,
+        help = "Also print a checksum for each NTFS alternate data stream of the files, with the \
+            name 'file:stream'. Only supported on Windows."
+    )]
+    pub ads: bool,
+
+    #[structopt(
+        long,
+        value_name = "subvolume",
+        conflicts_with_all = &["dry-run", "expect"],
+        help = "Make a read-only snapshot of this Btrfs subvolume or ZFS dataset, and read the \
+            files under it from the snapshot, so their checksums are all from one point in time. \
+            The snapshot is deleted afterwards. This usually needs root privileges."
+    )]
+    pub snapshot: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        help = "Checksum all small files before any large files, instead of in the order they \
+            are given, so that small files don't wait for large files to be read."
+    )]
+    pub small_first: bool,
+
+    #[structopt(
+        long,
+        conflicts_with_all = &["dry-run", "expect", "no-names", "raw"],
+        help = "After the checksums, print a line with the BLAKE3 of the lines before it and the \
+            version of b3sum-ng, so that --check can tell if the checkfile was damaged or cut \
+            short by accident. Anyone can recompute the seal after editing the checkfile, unless \
+            it's keyed with --keyed or --derive-key."
+    )]
+    pub seal: bool,
+
+    #[structopt(
+        long,
+        conflicts_with_all = &["dry-run", "expect", "no-names", "raw"],
+        help = "Before each checksum line, print a comment with the time the checksum was \
+            computed, in RFC 3339 format in UTC, such as '# computed 2026-10-14T09:30:12.123Z'. \
+            --check reads the timestamps, and ignores them."
+    )]
+    pub timestamps: bool,
+
+    #[structopt(
+        long,
+        conflicts_with_all = &["dry-run", "expect", "no-names", "raw"],
+        help = "Before each checksum line, print a comment with the size, mode, owner and \
+            modification time of the file, such as '# metadata size=11 mode=100644 uid=1000 \
+            gid=1000 mtime=2026-10-14T09:30:12.123Z'. --check reports the files whose metadata \
+            changed separately from the files whose contents changed."
+    )]
+    pub metadata: bool,
+
+    #[structopt(
+        long,
+        conflicts_with_all = &["dry-run", "expect", "no-names", "raw"],
+        help = "Also record the BLAKE3 of each file's extended attributes, which include SELinux \
+            labels, capabilities and ACLs, in the metadata comment before its checksum line, like \
+            '# metadata xattrs=<checksum>', or in the xattrs field of --format json and csv. \
+            --check reports the files whose attributes changed separately from the files whose \
+            contents changed. Only supported on Linux and macOS."
+    )]
+    pub xattrs: bool,
+
+    #[structopt(
+        long,
+        value_name = "manifest",
+        parse(from_os_str),
+        conflicts_with_all = &["dry-run", "expect", "no-names", "raw", "seal"],
+        help = "Start each checksum line with a comment like '# chain <checksum>', which has the \
+            BLAKE3 of the lines printed for the file before it, so that the output can be appended \
+            to this manifest with '>>'. The first line links to the last record of the manifest, \
+            which is checked first, or to zeros if it's empty or doesn't exist. --check finds \
+            records that were inserted, removed, reordered or changed. The head of the chain is \
+            printed to standard error, for --chain-head."
+    )]
+    pub chain: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        value_name = "interval",
+        help = "If the output is a file, sync it to disk every this many checksums, like 1000, or \
+            after a checksum once this many seconds have passed, like 30s, and at the end, so that \
+            the checksums that were printed before a crash or a power loss are kept as whole \
+            lines."
+    )]
+    pub fsync_every: Option<SyncInterval>,
+
+    #[structopt(
+        long,
+        value_name = "bytes",
+        conflicts_with_all = &["no-names", "raw"],
+        help = "Also print the checksums of the consecutive pieces of this many bytes of each \
+            file, such as 1048576 for 1 MiB, in comments like '# piece 0 1048576 <checksum>' \
+            before its checksum line. When a file doesn't match, --check reads its pieces too, and \
+            prints which ranges of bytes changed."
+    )]
+    pub piecewise: Option<usize>,
+
+    #[structopt(
+        long,
+        value_name = "file",
+        parse(from_os_str),
+        help = "A set of checksums that are already known, such as those of the files of an OS, so \
+            that only unknown files stand out. It's a text file with a checksum in hex at the \
+            start of each line, such as b3sum output or an NSRL NSRLFile.txt, or a binary file of \
+            raw checksums one after another. The files whose checksums are in the set are treated \
+            as --known says."
+    )]
+    pub known_hashes: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        value_name = "action",
+        default_value = "flag",
+        possible_values = &["flag", "exclude"],
+        help = "What to do with the files that are in the --known-hashes set. 'flag' prints the \
+            comment '# known' before their checksum lines, which --check ignores, and 'exclude' \
+            doesn't print them."
+    )]
+    pub known: KnownAction,
+}
+
+/// Options for verifying checkfiles.
+#[derive(StructOpt)]
+pub struct CheckOptions {
+    #[structopt(
+        default_value = "-",
+        help = "Checkfiles to verify. When '-' is given, read the checkfile from standard input. \
+            Patterns with * and ? are expanded. Files listed in several checkfiles are checked \
+            once, and fail if the checkfiles list different checksums."
+    )]
+    pub checkfiles: Vec<PathBuf>,
+
+    #[structopt(flatten)]
+    pub verify: VerifyArgs,
+
+    #[structopt(
+        long,
+        env = "B3SUM_FORMAT",
+        default_value = "b3sum",
+        possible_values = &["b3sum", "sfv"],
+        help = "With 'sfv', all checkfiles are read as SFV files. Otherwise, only those with an \
+            .sfv extension are."
+    )]
+    pub format: Format,
+
+    #[structopt(
+        long,
+        help = "Verify BLAKE3 checksums and seals in the keyed mode. The secret key is read from \
+            standard input, and it must be exactly 32 raw bytes."
+    )]
+    pub keyed: bool,
+
+    #[structopt(
+        long,
+        value_name = "context",
+        conflicts_with = "keyed",
+        help = "Verify BLAKE3 checksums and seals in the key derivation mode, with the given \
+            context string."
+    )]
+    pub derive_key: Option<String>,
+}
+
+#[derive(StructOpt)]
+pub enum Command {
+    #[structopt(
+        about = "Print the checksums of files. This is what b3sum-ng does when no subcommand \
+            is given."
+    )]
+    Hash(HashOptions),
+
+    #[structopt(
+        about = "Read checksums from checkfiles and verify them. This is the same as --check."
+    )]
+    Check(CheckOptions),
+
+    #[structopt(
+        alias = "cmp",
+        about = "Compare two files, or two directories recursively, by reading and checksumming \
+            both at the same time. Differences are printed like 'diff -r' does. The exit status \
+            is 0 if they are the same, 1 if they differ, and 3 if something could not be read."
+    )]
+    Diff {
+        #[structopt(help = "The file or directory to compare.")]
+        first: PathBuf,
+        #[structopt(help = "The file or directory to compare it with.")]
+        second: PathBuf,
+    },
+
+    #[structopt(
+        about = "Find files with identical contents. Each set of duplicates is printed as \
+            checksum lines, followed by an empty line."
+    )]
+    Dup {
+        #[structopt(
+            required = true,
+            help = "Files and directories to search. Directories are searched recursively."
+        )]
+        paths: Vec<PathBuf>,
+        #[structopt(
+            long,
+            help = "Don't read files that share all of their data with another file through \
+                reflinks, since their contents must be the same. Only supported on Linux."
+        )]
+        skip_reflinks: bool,
+    },
+
+    #[structopt(
+        about = "Measure how fast files are checksummed with each way of reading them, to help \
+            decide whether to use --mmap."
+    )]
+    Bench {
+        #[structopt(required = true, help = "Files to read.")]
+        paths: Vec<PathBuf>,
+    },
+
+    #[structopt(
+        about = "Run as a co-process for another program: read paths from standard input, one \
+            per line, and answer each one with a line with 'ok <checksum>' or 'error <message>', \
+            until standard input ends. This is the same as --server."
+    )]
+    Serve {
+        #[structopt(
+            long,
+            help = "End each request and each response with NUL instead of a newline, so that \
+                paths may contain newlines."
+        )]
+        nul: bool,
+    },
+
+    #[structopt(
+        about = "Copy a file while computing its checksum, then read the copy back to verify it. \
+            The checksum of the copy is printed."
+    )]
+    Cp {
+        #[structopt(help = "The file to copy. When '-' is given, copy standard input.")]
+        src: PathBuf,
+        #[structopt(
+            help = "Where to copy the file. If this is a directory, the file is copied into it."
+        )]
+        dst: PathBuf,
+    },
+
+    #[structopt(
+        about = "Split files into content-defined chunks and print the offset, length and BLAKE3 \
+            checksum of each chunk. Comparing the chunks of two copies of a file shows which \
+            parts of it differ, even if data was inserted or removed."
+    )]
+    Chunks {
+        #[structopt(
+            default_value = "-",
+            help = "Files to split into chunks. When '-' is given, read standard input."
+        )]
+        paths: Vec<PathBuf>,
+        #[structopt(
+            long,
+            value_name = "bytes",
+            help = "Split files into blocks of this size instead, such as 1048576 for 1 MiB. Blocks \
+                that can't be read are printed as UNREADABLE, and the blocks after them are still \
+                read, so that the damaged regions of a failing disk image or block device can be \
+                found and imaged again."
+        )]
+        block_size: Option<usize>,
+    },
+
+    #[structopt(
+        about = "Decode data in the Bao format, as written by 'bao encode', and write its contents \
+            to standard output as they're verified against their BLAKE3 checksum. If a chunk is \
+            corrupt, the output stops before it, and the exit status is 1."
+    )]
+    Decode {
+        #[structopt(
+            long,
+            value_name = "checksum",
+            help = "The BLAKE3 checksum of the contents, which is the root of the Bao tree."
+        )]
+        hash: Checksum,
+        #[structopt(
+            default_value = "-",
+            help = "The encoded data, or with --outboard, the contents. When '-' is given, read \
+                standard input."
+        )]
+        input: PathBuf,
+        #[structopt(
+            long,
+            value_name = "file",
+            help = "Read the tree from this file, as written by 'bao encode --outboard', and the \
+                contents from the input."
+        )]
+        outboard: Option<PathBuf>,
+    },
+
+    #[structopt(
+        about = "Verify the files listed in checkfiles against the copies on another machine \
+            without sending their data. Each listed checksum is sent to 'remote-verify --serve' \
+            or 'remote-verify --listen' on the other machine, which reads its copy and answers \
+            whether it matches. OK or FAILED is printed for each file, as by --check."
+    )]
+    RemoteVerify {
+        #[structopt(
+            required_unless_one = &["serve", "listen"],
+            help = "Checkfiles that list the files to verify. When '-' is given, read standard \
+                input."
+        )]
+        checkfiles: Vec<PathBuf>,
+        #[structopt(
+            long,
+            value_name = "command",
+            required_unless_one = &["connect", "serve", "listen"],
+            help = "Run this shell command to reach the other machine, and talk to it through \
+                its standard input and output, such as 'ssh backup \"cd /srv && b3sum-ng \
+                remote-verify --serve\"'."
+        )]
+        command: Option<String>,
+        #[structopt(
+            long,
+            value_name = "host:port",
+            conflicts_with = "command",
+            help = "Connect to 'remote-verify --listen' on the other machine over TCP."
+        )]
+        connect: Option<String>,
+        #[structopt(
+            long,
+            conflicts_with_all = &["command", "connect", "listen"],
+            help = "Answer the requests of another remote-verify on standard input and output, \
+                such as over SSH. Only the files under the current directory can be asked about."
+        )]
+        serve: bool,
+        #[structopt(
+            long,
+            value_name = "address",
+            conflicts_with_all = &["command", "connect"],
+            help = "Answer the requests of other remote-verify commands that connect to this \
+                address over TCP, such as 0.0.0.0:7373, until interrupted. Up to 16 connections \
+                are answered at once. Connections aren't authenticated or encrypted, and can ask \
+                about any file under the current directory, so only listen on a trusted \
+                network."
+        )]
+        listen: Option<String>,
+    },
+
+    #[structopt(about = "Print a shell completion script for b3sum-ng.")]
+    Completions {
+        #[structopt(
+            possible_values = &clap::Shell::variants(),
+            case_insensitive = true,
+            help = "The shell to print a completion script for."
+        )]
+        shell: clap::Shell,
+    },
+}
+
+/// The command line options for finding the files listed in checkfiles somewhere else, and for
+/// checking only some of them. See [`VerifyOptions`].
+#[derive(StructOpt, Default)]
+pub struct VerifyArgs {
+    #[structopt(
+        long,
+        value_name = "n",
+        default_value = "0",
+        help = "With --check, remove this many leading directories from each path in the \
+            checkfiles before reading it. A leading / is removed too."
+    )]
+    pub strip_prefix: usize,
+
+    #[structopt(
+        long,
+        value_name = "old=new",
+        number_of_values = 1,
+        help = "With --check, read files whose paths in the checkfiles start with the directory \
+            'old' from 'new' instead. This is done after --strip-prefix. Can be given more than \
+            once, and the first one that matches is used."
+    )]
+    pub path_map: Vec<PathMap>,
+
+    #[structopt(
+        long,
+        help = "With --check, find listed files whose names differ in case, such as when a \
+            checkfile made on a case-insensitive filesystem is checked on Linux. Backslashes are \
+            also read as directory separators, for checkfiles made on Windows."
+    )]
+    pub ignore_case: bool,
+
+    #[structopt(
+        long,
+        value_name = "pattern",
+        number_of_values = 1,
+        help = "With --check, only check the files whose names in the checkfiles match this \
+            pattern, such as 'photos/**'. * and ? match within a directory name, and ** matches \
+            any number of directories. Can be given more than once."
+    )]
+    pub only: Vec<String>,
+
+    #[structopt(
+        long,
+        help = "With --check, stop at the first file that fails or can't be read, instead of \
+            checking the rest."
+    )]
+    pub fail_fast: bool,
+
+    #[structopt(
+        long,
+        value_name = "action",
+        help = "With --check, quarantine the files whose contents don't match: 'move:<dir>' moves \
+            them into a directory, under the paths they were read from, 'rename-suffix' renames \
+            them with a '.mismatch' suffix, or another with 'rename-suffix:<suffix>', and 'delete' \
+            deletes them. Files are never moved over existing files."
+    )]
+    pub on_mismatch: Option<MismatchAction>,
+
+    #[structopt(
+        long,
+        value_name = "dir",
+        parse(from_os_str),
+        help = "With --check, also report the files in this directory that no checkfile lists. \
+            Directories are searched recursively, and the checkfiles themselves are skipped. A \
+            listed file that is missing is reported as moved if one of these files has its \
+            checksum."
+    )]
+    pub root: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        value_name = "file",
+        parse(from_os_str),
+        help = "With --check, don't read files again if their size and modification time haven't \
+            changed since they last matched. The files that match are recorded in this file."
+    )]
+    pub verified_cache: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        value_name = "format=file",
+        help = "With --check, also write a report to this file. With 'json=<file>', it has the \
+            result of each file and how long it took to read, and the totals. With \
+            'prometheus=<file>', it has the totals as metrics for the textfile collector of the \
+            Prometheus node exporter, with the speed of the check and the time of the last one \
+            that passed, for monitoring checks that are run on a schedule."
+    )]
+    pub report: Option<ReportTarget>,
+
+    #[structopt(
+        long,
+        value_name = "url",
+        help = "With --check, if the check fails, post the files that failed and the totals to \
+            this http:// URL as JSON, in the format of --report json=<file>, so that someone can \
+            be paged. HTTPS isn't supported, but --on-fail can run curl."
+    )]
+    pub notify_url: Option<String>,
+
+    #[structopt(
+        long,
+        value_name = "command",
+        help = "With --check, if the check fails, run this shell command with the files that \
+            failed and the totals as JSON on its standard input, as for --notify-url."
+    )]
+    pub on_fail: Option<String>,
+
+    #[structopt(
+        long,
+        help = "With --check, fail checkfiles that don't end with a seal from --seal. Seals are \
+            always checked, but without this, a checkfile that was cut short along with its seal \
+            isn't noticed."
+    )]
+    pub require_seal: bool,
+
+    #[structopt(
+        long,
+        value_name = "checksum",
+        help = "With --check, fail checkfiles that aren't chained with --chain, or whose chain \
+            doesn't end at this head, as printed by --chain, so that records that were removed \
+            from the end are noticed. Chains are always checked, but without this, only their \
+            end can be changed."
+    )]
+    pub chain_head: Option<Checksum>,
+
+    #[structopt(
+        long,
+        value_name = "file",
+        parse(from_os_str),
+        help = "With --check, record when each file last matched in this file, for --budget."
+    )]
+    pub last_verified: Option<PathBuf>,
+
+    #[structopt(
+        long,
+        value_name = "bytes",
+        requires = "last-verified",
+        help = "With --check, only check this many bytes of files, starting with the ones that \
+            --last-verified has never seen matching, and then the ones that were verified \
+            longest ago, so that a large archive is verified a part at a time. Files that fail \
+            are checked first again the next time."
+    )]
+    pub budget: Option<u64>,
+
+    #[structopt(
+        long,
+        value_name = "seconds",
+        help = "With --check, keep running, and check again this many seconds after each check \
+            finishes. With --budget, each check verifies the next part of the files, so that a \
+            large archive is verified over days without a cron job. The checkfiles can't be read \
+            from standard input, and the exit status is 1 if any of the checks failed."
+    )]
+    pub every: Option<u64>,
+
+    #[structopt(
+        long,
+        value_name = "share",
+        help = "With --check, only check a random part of the listed files: a percentage of them, \
+            like 10%, or as many as can be checked in a time, like 15min or 2h. At the end, how \
+            many were checked is printed, with a bound on the share of bad files that a check \
+            that found none could have missed."
+    )]
+    pub spot_check: Option<SpotCheck>,
+
+    #[structopt(
+        long,
+        requires = "spot-check",
+        help = "With --spot-check, choose the files with this seed, so that the same files are \
+            checked again. Without it, a new seed is used each time, and printed."
+    )]
+    pub seed: Option<u64>,
+}
+
+impl VerifyArgs {
+    /// Whether any of these options are given.
+    pub fn is_used(&self) -> bool {
+        self.strip_prefix > 0
+            || !self.path_map.is_empty()
+            || self.ignore_case
+            || !self.only.is_empty()
+            || self.fail_fast
+            || self.on_mismatch.is_some()
+            || self.root.is_some()
+            || self.verified_cache.is_some()
+            || self.report.is_some()
+            || self.notify_url.is_some()
+            || self.on_fail.is_some()
+            || self.require_seal
+            || self.chain_head.is_some()
+            || self.last_verified.is_some()
+            || self.budget.is_some()
+            || self.every.is_some()
+            || self.spot_check.is_some()
+    }
+}
+
+impl From<&VerifyArgs> for VerifyOptions {
+    fn from(args: &VerifyArgs) -> VerifyOptions {
+        VerifyOptions {
+            strip_prefix: args.strip_prefix,
+            path_map: args.path_map.clone(),
+            ignore_case: args.ignore_case,
+            only: args.only.clone(),
+            fail_fast: args.fail_fast,
+            on_mismatch: args.on_mismatch.clone(),
+            root: args.root.clone(),
+            verified_cache: args.verified_cache.clone(),
+            report: args.report.clone(),
+            notify_url: args.notify_url.clone(),
+            on_fail: args.on_fail.clone(),
+            require_seal: args.require_seal,
+            chain_head: args.chain_head.clone(),
+            last_verified: args.last_verified.clone(),
+            budget: args.budget,
+            spot_check: args.spot_check,
+            seed: args.seed,
+        }
+    }
+}
+
+// The process-wide settings of the command line program. They change what the library prints
+// and when it stops reading, for every caller in the process, so they're only reachable from here
+// and aren't part of the API of `output` and `scheduler`.
+
+/// Print a comment with the time each checksum was computed, for `--timestamps`.
+pub fn set_timestamps(timestamps: bool) {
+    output::set_timestamps(timestamps);
+}
+
+/// Print a comment with the metadata of each file before its checksum, for `--metadata`.
+pub fn set_metadata(metadata: bool) {
+    output::set_metadata(metadata);
+}
+
+/// Print the digest of each file's extended attributes, for `--xattrs`.
+pub fn set_xattrs(xattrs: bool) {
+    output::set_xattrs(xattrs);
+}
+
+/// Flag or leave out the files whose checksums are in `known`, for `--known`.
+pub fn set_known_hashes(known: KnownHashes, action: KnownAction) {
+    output::set_known_hashes(known, action);
+}
+
+/// Hash the checksum lines that are printed in `mode`, for `--seal`.
+pub fn start_seal(mode: Blake3Mode) {
+    output::start_seal(mode);
+}
+
+/// Group the checksums that are printed by directory, relative to `root`, for `--grouped`.
+pub fn start_grouping(root: PathBuf) {
+    output::start_grouping(root);
+}
+
+/// Link each record that's printed to the one before it, starting at `head`, for `--chain`.
+pub fn start_chain(head: Checksum) {
+    output::start_chain(head);
+}
+
+/// Sync standard output to disk every `interval`, for `--fsync-every`.
+pub fn start_output_sync(interval: SyncInterval) {
+    output::start_output_sync(interval);
+}
+
+/// Batch the lines written to standard output, which are then written by
+/// [`flush_output`](output::flush_output).
+pub fn start_batched_output() {
+    output::start_batched_output();
+}
+
+/// Write the lines that are held before SIGINT or SIGTERM ends the process. Only on Unix.
+pub fn flush_on_termination() -> std::io::Result<()> {
+    output::flush_on_termination()
+}
+
+/// Print errors as text or as JSON, for `--errors`.
+pub fn set_error_format(format: ErrorFormat) {
+    output::set_error_format(format);
+}
+
+/// Also send errors, warnings and the summary to the system log, for `--log`.
+pub fn start_log(target: LogTarget) -> Result<()> {
+    output::start_log(target)
+}
+
+/// Group the checksums that are printed by directory or extension, for `--summary-by`.
+pub fn start_rollup(by: SummaryBy) {
+    output::start_rollup(by);
+}
+
+/// Collect the statuses and errors of the run, to be written to `path`, for `--summary`.
+pub fn start_summary(path: PathBuf) {
+    output::start_summary(path);
+}
+
+/// Only print errors, warnings and failures, for `--quiet`.
+pub fn set_quiet(quiet: bool) {
+    output::set_quiet(quiet);
+}
+
+/// Normalize the paths that are printed and checked, for `--normalize-paths` and
+/// `--unicode-form`.
+pub fn set_path_normalization(normalize_paths: bool, unicode_form: Option<UnicodeForm>) {
+    output::set_path_normalization(normalize_paths, unicode_form);
+}
+
+/// Print how each file is read, and how long it takes, for `--verbose`.
+pub fn set_verbose(verbose: bool) {
+    output::set_verbose(verbose);
+}
+
+/// Decide whether the output is colored, for `--color`.
+pub fn set_color(choice: ColorChoice) {
+    output::set_color(choice);
+}
+
+/// Time mmap against buffered reads on each disk, and record the faster in `cache`, for
+/// `--auto-mmap`.
+pub fn start_auto_mmap(cache: PathBuf) -> Result<()> {
+    scheduler::start_auto_mmap(cache)
+}
+
+/// Stop starting files at `deadline`, for `--timeout`.
+pub fn set_deadline(deadline: Instant) {
+    scheduler::set_deadline(deadline);
+}
+
+#[test]
+fn b3_test_env_flag() {
+    std::env::set_var("B3SUM_TEST_FLAG", "1");
+    assert!(env_flag("B3SUM_TEST_FLAG"));
+    std::env::set_var("B3SUM_TEST_FLAG", "False");
+    assert!(!env_flag("B3SUM_TEST_FLAG"));
+    std::env::remove_var("B3SUM_TEST_FLAG");
+    assert!(!env_flag("B3SUM_TEST_FLAG"));
+}
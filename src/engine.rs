@@ -0,0 +1,4427 @@
+// Copyright 2021 Daniel Zwell.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Reading files and computing their checksums: the checksum algorithms, the ways that files
+//! are read, decompression and archives, and the other operations on the contents of files.
+
+use std::{
+    borrow::Cow,
+    collections::{hash_map::Entry, HashMap},
+    convert::{TryFrom, TryInto},
+    error::Error,
+    ffi::OsString,
+    fmt,
+    fs::{metadata, symlink_metadata, File, FileType, Metadata},
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+#[cfg(all(feature = "mmap", not(target_os = "wasi")))]
+use memmap::MmapOptions;
+use multi_semaphore::Semaphore;
+
+use crate::{
+    output::{
+        add_progress_total, print_error, print_error_in, print_warning, read_error,
+        record_progress, Metrics, MetricsCounters, Phase,
+    },
+    scheduler::{checksum_listed, small_file_limit, Descriptor, ExclusiveLarge, IoStrategy},
+    sys::{
+        is_symlink_error, list_dir, open_nofollow, read_unbuffered, OPEN_AT_SUPPORTED,
+        UNBUFFERED_READS_SUPPORTED,
+    },
+    Result,
+};
+
+#[cfg(test)]
+use crate::{
+    hex, make_temp_file,
+    scheduler::{checksum_with, do_checksum},
+    TempDirGuard, TempFileGuard,
+};
+#[cfg(all(test, feature = "extra-algorithms"))]
+use crate::{
+    output::Format,
+    verify::{check, VerifyOptions},
+};
+
+/// Checksum a file with `read`, and check that its size and modification time are the same
+/// afterwards. If the file changed while it was read, a warning is printed, or with
+/// `config.verify_stable`, it is read again, and an error is returned if it keeps changing.
+/// With `config.paranoid`, the file is also checksummed with `read_again`, which should read it
+/// another way, and an error is returned if the checksums differ while the file didn't change.
+pub(crate) fn read_stable(
+    path: &Path,
+    config: &Config,
+    read: &dyn Fn() -> Result<Hashed>,
+    read_again: &dyn Fn() -> Result<Hashed>,
+) -> Result<Hashed> {
+    let mut retries = 0;
+    loop {
+        let before = file_state(path);
+        let descriptor = Descriptor::reserve();
+        let hashed = read().map_err(|err| read_error(retries, err))?;
+        let again = if config.paranoid {
+            Some(read_again().map_err(|err| read_error(retries, err))?)
+        } else {
+            None
+        };
+        drop(descriptor);
+        if file_state(path) == before {
+            return match again {
+                Some(again) if again.checksum != hashed.checksum => {
+                    let (checksum, again) = (hashed.checksum, again.checksum);
+                    tracing::warn!(first = %checksum, second = %again, "the reads disagree");
+                    Err(read_error(
+                        retries,
+                        format!(
+                            "the file was read twice with different checksums, {} and then {}, so \
+                         the disk, its cable, its controller or the RAM may be corrupting data",
+                            checksum, again
+                        )
+                        .into(),
+                    ))
+                }
+                _ => Ok(hashed),
+            };
+        }
+        match config.verify_stable {
+            None => {
+                tracing::warn!(path = %path.display(), "the file changed while it was read");
+                let message = format!("{}: the file changed while it was read", path.display());
+                print_warning(&message);
+                return Ok(hashed);
+            }
+            Some(max_retries) if retries < max_retries => {
+                retries += 1;
+                tracing::debug!(
+                    retries,
+                    "the file changed while it was read, so it's read again"
+                );
+            }
+            Some(_) => {
+                tracing::warn!(retries, "the file kept changing while it was read");
+                return Err(read_error(
+                    retries,
+                    "the file kept changing while it was read".into(),
+                ));
+            }
+        }
+    }
+}
+
+/// The size and modification time of a file.
+fn file_state(path: &Path) -> Option<(u64, SystemTime)> {
+    let metadata = metadata(path).ok()?;
+    Some((metadata.len(), metadata.modified().ok()?))
+}
+
+/// The size of a file. The metadata of a block device, such as a disk, has a size of 0, so its
+/// size is found by seeking to the end instead.
+pub(crate) fn input_size(path: &Path) -> Result<u64> {
+    let metadata = metadata(path)?;
+    if is_block_device(&metadata) {
+        Ok(File::open(path)?.seek(SeekFrom::End(0))?)
+    } else {
+        Ok(metadata.len())
+    }
+}
+
+/// Take a shared advisory lock on a file, so that writers that lock it exclusively wait until it has
+/// been read. Fails without waiting if another process has an exclusive lock.
+fn lock_shared(file: &File) -> Result<()> {
+    if crate::sys::lock_file(file, false, false)? {
+        Ok(())
+    } else {
+        Err("the file is locked by another process".into())
+    }
+}
+
+/// An exclusive lock on a file, which keeps other runs of b3sum-ng that use the same lockfile
+/// from scanning at the same time, for `--lockfile`. The lock is released when this is dropped,
+/// or when the process exits, even if it's killed, so a stale lockfile doesn't block later runs.
+#[derive(Debug)]
+pub struct RunLock {
+    _file: File,
+}
+
+impl RunLock {
+    /// Take the lock, creating the lockfile if it doesn't exist. If another process has it, this
+    /// waits for it to be released with `wait`, and otherwise returns `None`. The process ID of
+    /// the owner is written to the lockfile.
+    pub fn acquire(path: &Path, wait: bool) -> Result<Option<RunLock>> {
+        let mut file = std::fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            // The process ID of the owner is only replaced once the lock is taken.
+            .truncate(false)
+            .open(path)?;
+        if !crate::sys::lock_file(&file, true, wait)? {
+            return Ok(None);
+        }
+        file.set_len(0)?;
+        writeln!(file, "{}", std::process::id())?;
+        Ok(Some(RunLock { _file: file }))
+    }
+}
+
+/// The device and inode numbers of a file, which identify it on Unix. Used to detect that a path
+/// leads to a different file than it did before.
+pub type FileId = (u64, u64);
+
+#[cfg(unix)]
+pub(crate) fn file_id(metadata: &Metadata) -> Option<FileId> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+/// Files can't be identified here, so they're assumed to be unchanged.
+#[cfg(not(unix))]
+pub(crate) fn file_id(_metadata: &Metadata) -> Option<FileId> {
+    None
+}
+
+/// Whether two open files are the same file, by their device and inode on Unix, or their volume
+/// and file index on Windows with the `sys` feature. Elsewhere, they're assumed to be different.
+fn same_file(a: &File, b: &File) -> bool {
+    #[cfg(unix)]
+    {
+        match (a.metadata(), b.metadata()) {
+            (Ok(a), Ok(b)) => file_id(&a) == file_id(&b),
+            _ => false,
+        }
+    }
+    #[cfg(windows)]
+    {
+        match (crate::sys::file_index(a), crate::sys::file_index(b)) {
+            (Ok(a), Ok(b)) => a == b,
+            _ => false,
+        }
+    }
+    #[cfg(not(any(unix, windows)))]
+    {
+        let _ = (a, b);
+        false
+    }
+}
+
+#[cfg(unix)]
+fn is_block_device(metadata: &Metadata) -> bool {
+    use std::os::unix::fs::FileTypeExt;
+    metadata.file_type().is_block_device()
+}
+
+#[cfg(not(unix))]
+fn is_block_device(_metadata: &Metadata) -> bool {
+    false
+}
+
+/// Compute a checksum of a small file or stdin by reading it all into memory.
+pub(crate) fn b3sum_small(file: &mut dyn Read, config: &Config) -> Result<Checksum> {
+    hash_small(file, config).map(|hashed| hashed.checksum)
+}
+
+/// Like [`b3sum_small`], with what else `config` asks to be computed from the data.
+pub(crate) fn hash_small(file: &mut dyn Read, config: &Config) -> Result<Hashed> {
+    let mut buf = Vec::new();
+    file.read_to_end(&mut buf)?;
+    let mut hasher = Hasher::new(config)?;
+    hasher.update(&buf);
+    Ok(hasher.finish())
+}
+
+/// Whether files can be memory mapped. This needs the `mmap` feature, and WASI has no mmap.
+/// Otherwise, `use_mmap` is ignored.
+pub const MMAP_SUPPORTED: bool = cfg!(all(feature = "mmap", not(target_os = "wasi")));
+
+/// Compute a multi-threaded checksum of a large file by buffering it or memory mapping it.
+pub(crate) fn b3sum_large(file: Input, config: &Config) -> Result<Checksum> {
+    hash_large(file, config).map(|hashed| hashed.checksum)
+}
+
+/// Like [`b3sum_large`], with what else `config` asks to be computed from the data.
+pub(crate) fn hash_large(file: Input, config: &Config) -> Result<Hashed> {
+    let mut hasher = Hasher::new(config)?;
+    // Note: if we use io::Cursor<Mmap> to treat all inputs as Read, it's slower on HDDs
+    if let Input::File(file) = &file {
+        read_hints(file, config, true);
+    }
+    match file {
+        #[cfg(all(feature = "mmap", not(target_os = "wasi")))]
+        Input::File(file) if config.use_mmap => hash_mmap(&file, &mut hasher, MMAP_WINDOW_SIZE)?,
+        #[cfg(any(unix, windows))]
+        Input::File(file) if config.parallel_reads => {
+            hash_parallel_reads(&file, &mut hasher, PARALLEL_READ_WINDOW_SIZE)?
+        }
+        Input::File(file)
+            if UNBUFFERED_READS_SUPPORTED && config.readahead.is_none() && !config.network =>
+        {
+            read_unbuffered(&file, &mut |block| hasher.update_parallel(block))?
+        }
+        _ => {
+            let mut file: Box<dyn Read> = match file {
+                Input::File(file) => match config.readahead {
+                    Some(window) => Box::new(ReadAhead::new(file, window)),
+                    None => Box::new(file),
+                },
+                Input::Stream(read, _) => read,
+            };
+            let buffer_size = if config.network {
+                NETWORK_READ_BUFFER_SIZE
+            } else {
+                READ_BUFFER_SIZE
+            };
+            hasher = hash_pipelined(&mut file, hasher, buffer_size)?;
+        }
+    }
+    Ok(hasher.finish())
+}
+
+/// How much of a file is hashed each way by [`mmap_is_faster`].
+const MMAP_TRIAL_SIZE: u64 = 64 * (1 << 20);
+
+/// Whether memory mapping the file at `path` is faster than reading it with buffers, judged by
+/// hashing its first 64 MiB each way. They're read once before either way is timed, so that both
+/// read from the OS cache, and only the cost of each way is compared. It's always false without
+/// mmap support.
+pub(crate) fn mmap_is_faster(path: &Path, config: &Config) -> Result<bool> {
+    #[cfg(all(feature = "mmap", not(target_os = "wasi")))]
+    {
+        let config = Config {
+            entropy: false,
+            piecewise: None,
+            ssdeep: false,
+            ..config.clone()
+        };
+        let file = File::open(path)?;
+        let bytes = file_len(&file)?.min(MMAP_TRIAL_SIZE);
+        let buffered = || -> Result<Duration> {
+            let start = Instant::now();
+            (&file).seek(SeekFrom::Start(0))?;
+            hash_pipelined(
+                &mut (&file).take(bytes),
+                Hasher::new(&config)?,
+                READ_BUFFER_SIZE,
+            )?;
+            Ok(start.elapsed())
+        };
+        buffered()?;
+        let buffered = buffered()?;
+        let start = Instant::now();
+        if bytes > 0 {
+            let map = unsafe { MmapOptions::new().len(bytes as usize).map(&file) }?;
+            let mut hasher = Hasher::new(&config)?;
+            for slice in map.chunks(4 * (1 << 20)) {
+                hasher.update_parallel(slice);
+            }
+        }
+        Ok(start.elapsed() < buffered)
+    }
+    #[cfg(not(all(feature = "mmap", not(target_os = "wasi"))))]
+    {
+        let _ = (path, config);
+        Ok(false)
+    }
+}
+
+/// How much of a file is mapped at once. 32-bit systems don't have the address space to map large
+/// files whole.
+#[cfg(all(feature = "mmap", not(target_os = "wasi")))]
+const MMAP_WINDOW_SIZE: u64 = if cfg!(target_pointer_width = "64") {
+    u64::MAX
+} else {
+    256 * (1 << 20)
+};
+
+/// Memory map a file, in windows of at most `window_size` bytes, and feed it to the hasher.
+#[cfg(all(feature = "mmap", not(target_os = "wasi")))]
+fn hash_mmap(file: &File, hasher: &mut Hasher, window_size: u64) -> Result<()> {
+    let len = file_len(file)?;
+    let mut offset = 0;
+    while offset < len {
+        let window = (len - offset).min(window_size);
+        let buf = unsafe {
+            MmapOptions::new()
+                .offset(offset)
+                .len(window as usize)
+                .map(file)
+        }?;
+        // Iterating over chunks is faster than computing the whole buffer,
+        // even on SSDs. On spinning discs, mmap is still slower than normal file reads.
+        // TODO: the buffer size may need to be tuned based on the number of threads.
+        for slice in buf.chunks(4 * (1 << 20)) {
+            hasher.update_parallel(slice);
+        }
+        offset += window;
+    }
+    Ok(())
+}
+
+/// How much of a file is read at once by [`hash_parallel_reads`].
+#[cfg(any(unix, windows))]
+const PARALLEL_READ_WINDOW_SIZE: usize = 64 * (1 << 20);
+
+/// Read a file in windows of `window_size` bytes, and feed them to the hasher. Each window is
+/// read in pieces by several threads at once, at different offsets, which is faster on SSDs that
+/// a single reader can't keep busy.
+#[cfg(any(unix, windows))]
+fn hash_parallel_reads(file: &File, hasher: &mut Hasher, window_size: usize) -> Result<()> {
+    let len = file_len(file)?;
+    let piece_size = (window_size / rayon::current_num_threads().max(1)).max(1 << 20);
+    let mut buf = vec![0u8; window_size];
+    let mut offset = 0;
+    while offset < len {
+        let window = &mut buf[..(len - offset).min(window_size as u64) as usize];
+        let error = Mutex::new(None);
+        rayon::scope(|s| {
+            for (i, piece) in window.chunks_mut(piece_size).enumerate() {
+                let error = &error;
+                let piece_offset = offset + (i * piece_size) as u64;
+                s.spawn(move |_| {
+                    if let Err(err) = read_exact_at(file, piece, piece_offset) {
+                        *error.lock().unwrap() = Some(err);
+                    }
+                });
+            }
+        });
+        if let Some(err) = error.into_inner().unwrap() {
+            return Err(err.into());
+        }
+        hasher.update_parallel(window);
+        offset += window.len() as u64;
+    }
+    Ok(())
+}
+
+/// Fill `buf` from a file, starting at `offset`, without using the position of the file.
+#[cfg(unix)]
+fn read_exact_at(file: &File, buf: &mut [u8], offset: u64) -> std::io::Result<()> {
+    use std::os::unix::fs::FileExt;
+    file.read_exact_at(buf, offset)
+}
+
+#[cfg(windows)]
+fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> std::io::Result<()> {
+    use std::os::windows::fs::FileExt;
+    while !buf.is_empty() {
+        match file.seek_read(buf, offset)? {
+            0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+            bytes_read => {
+                buf = &mut buf[bytes_read..];
+                offset += bytes_read as u64;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// The size of an open file, which may be a block device.
+fn file_len(file: &File) -> Result<u64> {
+    let metadata = file.metadata()?;
+    if is_block_device(&metadata) {
+        Ok((&mut &*file).seek(SeekFrom::End(0))?)
+    } else {
+        Ok(metadata.len())
+    }
+}
+
+/// The size of reads for large files. Windows has a higher cost per read call, so larger reads
+/// are used there.
+const READ_BUFFER_SIZE: usize = if cfg!(windows) { 8 } else { 2 } * (1 << 20);
+
+/// The size of reads for large files on network filesystems, where each read has a high latency.
+const NETWORK_READ_BUFFER_SIZE: usize = 16 * (1 << 20);
+
+/// Feed everything from `read` to the hasher, in buffers large enough for multithreading.
+pub(crate) fn hash_reader(read: &mut dyn Read, hasher: &mut Hasher) -> Result<()> {
+    hash_reader_sized(read, hasher, READ_BUFFER_SIZE)
+}
+
+/// Like [`hash_reader`], but with reads of `buffer_size` bytes.
+fn hash_reader_sized(read: &mut dyn Read, hasher: &mut Hasher, buffer_size: usize) -> Result<()> {
+    let mut buf = vec![0u8; buffer_size];
+    loop {
+        let bytes_read = read.read(&mut buf)?;
+        if bytes_read == 0 {
+            return Ok(());
+        }
+        hasher.update_parallel(&buf[0..bytes_read]);
+    }
+}
+
+/// How many buffers can be waiting to be hashed while the next one is read.
+const PIPELINE_DEPTH: usize = 2;
+
+/// Feed data from a reader to a hasher, reading on this thread while another thread hashes. The
+/// buffers are reused, so reading blocks when the hasher falls behind, and the hasher waits when
+/// reading does. Returns the hasher when the reader is exhausted.
+#[cfg(not(target_os = "wasi"))]
+fn hash_pipelined(read: &mut dyn Read, mut hasher: Hasher, buffer_size: usize) -> Result<Hasher> {
+    let (full_sender, full) = mpsc::sync_channel::<(Vec<u8>, usize)>(PIPELINE_DEPTH);
+    let (empty_sender, empty) = mpsc::channel();
+    for _ in 0..=PIPELINE_DEPTH {
+        empty_sender.send(vec![0u8; buffer_size]).unwrap();
+    }
+    let hashing = std::thread::spawn(move || {
+        for (buf, len) in full {
+            hasher.update_parallel(&buf[..len]);
+            // The reader may have stopped because of an error.
+            let _ = empty_sender.send(buf);
+        }
+        hasher
+    });
+
+    let result = (|| loop {
+        let mut buf = match empty.recv() {
+            Ok(buf) => buf,
+            // The hashing thread panicked. That's resumed below.
+            Err(_) => return Ok(()),
+        };
+        let bytes_read = read.read(&mut buf)?;
+        if bytes_read == 0 {
+            return Ok(());
+        }
+        if full_sender.send((buf, bytes_read)).is_err() {
+            return Ok(());
+        }
+    })();
+    drop(full_sender);
+    let hasher = hashing
+        .join()
+        .unwrap_or_else(|panic| std::panic::resume_unwind(panic));
+    result.map(|()| hasher)
+}
+
+/// WASI has no threads, so data is read and hashed in turn.
+#[cfg(target_os = "wasi")]
+fn hash_pipelined(read: &mut dyn Read, mut hasher: Hasher, buffer_size: usize) -> Result<Hasher> {
+    hash_reader_sized(read, &mut hasher, buffer_size)?;
+    Ok(hasher)
+}
+
+/// Copy `src` to `dst` while checksumming the data, then read the copy back and verify that it has
+/// the same checksum. If `dst` is a directory, the file is copied into it. `src` may be `-` for
+/// standard input. Returns the path of the copy and its checksum.
+///
+/// The copy is flushed to disk before it is read back, but it will likely be read from the OS
+/// cache. So this catches errors in copying, but not necessarily a bad disk.
+pub fn copy_and_verify(src: &Path, dst: &Path, config: &Config) -> Result<(PathBuf, Checksum)> {
+    let is_stdin = src.to_str() == Some("-");
+    let dst = if dst.is_dir() {
+        match src.file_name() {
+            Some(name) if !is_stdin => dst.join(name),
+            _ => return Err(format!("can't copy {} into a directory", src.display()).into()),
+        }
+    } else {
+        dst.to_owned()
+    };
+    let mut input: Box<dyn Read> = if is_stdin {
+        Box::new(std::io::stdin())
+    } else {
+        let input = File::open(src)?;
+        // Creating the destination would truncate the source if they are the same file, even
+        // by another name, such as a hard link.
+        let same = |dst: File| same_file(&input, &dst);
+        if dst.exists()
+            && (src.canonicalize()? == dst.canonicalize()? || File::open(&dst).map_or(false, same))
+        {
+            let err = format!("{} and {} are the same file", src.display(), dst.display());
+            return Err(err.into());
+        }
+        Box::new(input)
+    };
+    let mut output = File::create(&dst)?;
+    let mut hasher = Hasher::new(config)?;
+    hash_reader(
+        &mut TeeReader {
+            read: &mut input,
+            write: &mut output,
+        },
+        &mut hasher,
+    )?;
+    output.sync_all()?;
+    drop(output);
+    if !is_stdin {
+        std::fs::set_permissions(&dst, metadata(src)?.permissions())?;
+    }
+
+    let expected = hasher.finalize();
+    let actual = b3sum_large(Input::File(File::open(&dst)?), config)?;
+    if actual != expected {
+        return Err(format!(
+            "the copy does not match: {} was written but {} was read back",
+            expected, actual
+        )
+        .into());
+    }
+    Ok((dst, expected))
+}
+
+/// Decode data in the Bao format, as written by `bao encode`, and write its contents to `output`
+/// as they're verified against `root`, their BLAKE3 checksum. With `outboard`, `encoded` is the
+/// contents, and `outboard` is the tree of checksums that was written apart from them, as by
+/// `bao encode --outboard`. Returns the length of the contents.
+///
+/// Each chunk of 1 KiB is verified before it's written, so if the data is corrupt, the chunks
+/// before the first corrupt one are written, and that one is reported by the error. Only
+/// checksums of the default mode can be verified, not keyed or derived ones.
+pub fn bao_decode<'a>(
+    encoded: &'a mut dyn Read,
+    outboard: Option<&'a mut dyn Read>,
+    root: &Checksum,
+    output: &'a mut dyn Write,
+) -> Result<u64> {
+    let root: [u8; blake3::OUT_LEN] = match root.0[..].try_into() {
+        Ok(root) => root,
+        Err(_) => return Err("the root checksum must be 32 bytes long".into()),
+    };
+    let mut decoder = BaoDecoder {
+        contents: encoded,
+        tree: outboard,
+        output,
+    };
+    let mut header = [0; 8];
+    decoder.read_tree(&mut header)?;
+    let len = u64::from_le_bytes(header);
+    decoder.subtree(len, 0, &blake3::Hash::from(root), true)?;
+    Ok(len)
+}
+
+/// The readers and the output of [`bao_decode`].
+struct BaoDecoder<'a> {
+    contents: &'a mut dyn Read,
+    /// The tree, if it isn't mixed with the contents.
+    tree: Option<&'a mut dyn Read>,
+    output: &'a mut dyn Write,
+}
+
+impl BaoDecoder<'_> {
+    /// Read the next part of the tree, which is the header or a parent node.
+    fn read_tree(&mut self, buf: &mut [u8]) -> Result<()> {
+        let read = match &mut self.tree {
+            Some(tree) => tree.read_exact(buf),
+            None => self.contents.read_exact(buf),
+        };
+        read.map_err(bao_read_error)
+    }
+
+    /// Verify a subtree of `len` bytes that starts at chunk number `chunk` against its chaining
+    /// value `cv`, and write its contents. The parent node of a subtree comes before its
+    /// children, so each part is verified before it's used.
+    fn subtree(&mut self, len: u64, chunk: u64, cv: &blake3::Hash, is_root: bool) -> Result<()> {
+        use blake3::guts::{parent_cv, ChunkState, CHUNK_LEN};
+
+        if len <= CHUNK_LEN as u64 {
+            let mut buf = [0; CHUNK_LEN];
+            let buf = &mut buf[..len as usize];
+            self.contents.read_exact(buf).map_err(bao_read_error)?;
+            if ChunkState::new(chunk).update(buf).finalize(is_root) != *cv {
+                return Err(format!(
+                    "chunk {} is corrupt, at byte {} of the contents",
+                    chunk,
+                    chunk * CHUNK_LEN as u64
+                )
+                .into());
+            }
+            self.output.write_all(buf)?;
+            return Ok(());
+        }
+        let mut node = [0; 2 * blake3::OUT_LEN];
+        self.read_tree(&mut node)?;
+        let (left, right) = node.split_at(blake3::OUT_LEN);
+        let left = blake3::Hash::from(<[u8; blake3::OUT_LEN]>::try_from(left).unwrap());
+        let right = blake3::Hash::from(<[u8; blake3::OUT_LEN]>::try_from(right).unwrap());
+        if parent_cv(&left, &right, is_root) != *cv {
+            return Err(format!(
+                "the tree is corrupt, before byte {} of the contents",
+                chunk * CHUNK_LEN as u64
+            )
+            .into());
+        }
+        // The left subtree has the most whole chunks that are a power of two, but not all of them.
+        let chunks = (len - 1) / CHUNK_LEN as u64;
+        let left_len = (1 << (63 - chunks.leading_zeros())) * CHUNK_LEN as u64;
+        self.subtree(left_len, chunk, &left, false)?;
+        self.subtree(
+            len - left_len,
+            chunk + left_len / CHUNK_LEN as u64,
+            &right,
+            false,
+        )
+    }
+}
+
+/// Report encodings that end too early as bad data rather than as I/O errors.
+fn bao_read_error(err: std::io::Error) -> Box<dyn Error> {
+    if err.kind() == std::io::ErrorKind::UnexpectedEof {
+        "the encoding ends before its contents do".into()
+    } else {
+        err.into()
+    }
+}
+
+/// Compute the checksum of one file, or of standard input if the path is `-`, and compare it with
+/// `expected` in constant time.
+pub fn matches_expected(path: &Path, config: &Config, expected: &Checksum) -> Result<bool> {
+    let input = if path.to_str() == Some("-") {
+        Input::Stream(Box::new(std::io::stdin()), None)
+    } else {
+        let compression = if config.decompress {
+            Compression::detect(path)
+        } else {
+            None
+        };
+        Input::open(path, compression)?
+    };
+    Ok(b3sum_large(input, config)?.constant_time_eq(expected))
+}
+
+/// Open the parts of a file that was split into several, such as `image.001` and `image.002`, as
+/// one stream of their contents one after another, in the order they're given. All of the parts
+/// are opened first, so that a missing part is an error before anything is read. A part named `-`
+/// is standard input, and then the size of the stream isn't known.
+pub fn open_joined(paths: &[PathBuf]) -> Result<Input> {
+    let mut parts: Vec<Box<dyn Read + Send>> = Vec::new();
+    let mut size = Some(0);
+    for path in paths {
+        if path.to_str() == Some("-") {
+            size = None;
+            parts.push(Box::new(std::io::stdin()));
+            continue;
+        }
+        let file = File::open(path).map_err(|err| format!("{}: {}", path.display(), err))?;
+        let len = file.metadata()?.len();
+        size = size.map(|size| size + len);
+        parts.push(Box::new(file));
+    }
+    let joined = Joined {
+        parts: parts.into_iter(),
+        current: None,
+    };
+    Ok(Input::Stream(Box::new(joined), size))
+}
+
+/// Compute the checksum of the parts of a split file, as if they were one file. See
+/// [`open_joined`].
+pub fn checksum_joined(paths: &[PathBuf], config: &Config) -> Result<Checksum> {
+    b3sum_large(open_joined(paths)?, config)
+}
+
+/// Compute a quick fingerprint of a file from its size, its first `length` bytes and its last
+/// `length` bytes, without reading the rest, for `--sample`. Files of up to twice `length` bytes
+/// are hashed whole after their size, so the sample of a small file still covers all of it. It
+/// isn't the checksum of the file, and files that differ only in the middle have the same
+/// sample.
+pub fn sample_digest(path: &Path, length: u64, config: &Config) -> Result<Checksum> {
+    let config = Config {
+        entropy: false,
+        piecewise: None,
+        ssdeep: false,
+        ..config.clone()
+    };
+    let mut file = File::open(path)?;
+    let size = file.metadata()?.len();
+    let mut hasher = Hasher::new(&config)?;
+    hasher.update(&size.to_le_bytes());
+    let mut buf = Vec::new();
+    if size <= length.saturating_mul(2) {
+        file.read_to_end(&mut buf)?;
+        hasher.update(&buf);
+    } else {
+        (&mut file).take(length).read_to_end(&mut buf)?;
+        hasher.update(&buf);
+        buf.clear();
+        file.seek(SeekFrom::Start(size - length))?;
+        file.take(length).read_to_end(&mut buf)?;
+        hasher.update(&buf);
+    }
+    Ok(hasher.finalize())
+}
+
+/// Pass the [`sample_digest`] of `path`, or of each file under it if it's a directory, to
+/// `on_sample`. Errors listing directories are printed.
+pub fn sample_tree<F>(path: &Path, length: u64, config: &Config, mut on_sample: F)
+where
+    F: FnMut(&Path, Result<Checksum>),
+{
+    let mut files = Vec::new();
+    list_files(path, &mut |listed, _| files.push(listed.path()));
+    for file in files {
+        on_sample(&file, sample_digest(&file, length, config));
+    }
+}
+
+/// Readers that are read one after another, for [`open_joined`].
+struct Joined {
+    parts: std::vec::IntoIter<Box<dyn Read + Send>>,
+    current: Option<Box<dyn Read + Send>>,
+}
+
+impl Read for Joined {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        loop {
+            if let Some(current) = &mut self.current {
+                let bytes_read = current.read(buf)?;
+                if bytes_read > 0 || buf.is_empty() {
+                    return Ok(bytes_read);
+                }
+            }
+            match self.parts.next() {
+                Some(part) => self.current = Some(part),
+                None => return Ok(0),
+            }
+        }
+    }
+}
+
+/// A reader that writes everything it reads to `write`, such as to checksum data on its way
+/// through a pipeline. `write` is flushed at the end of the data.
+pub struct TeeReader<R, W> {
+    read: R,
+    write: W,
+}
+
+impl<R: Read, W: Write> TeeReader<R, W> {
+    pub fn new(read: R, write: W) -> Self {
+        TeeReader { read, write }
+    }
+}
+
+impl<R: Read, W: Write> Read for TeeReader<R, W> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let bytes_read = self.read.read(buf)?;
+        self.write.write_all(&buf[..bytes_read])?;
+        if bytes_read == 0 && !buf.is_empty() {
+            self.write.flush()?;
+        }
+        Ok(bytes_read)
+    }
+}
+
+/// Find files with identical contents among `paths`, searching directories recursively. Each set
+/// of duplicates is passed to `on_duplicates`, sorted by path. Symbolic links and empty files are
+/// skipped, and errors are printed.
+///
+/// A file is only read if another file has the same size. With `skip_reflinks`, a file that
+/// shares all of its data with another file through reflinks isn't read either, since it must
+/// have the same contents.
+pub fn find_duplicates<F>(
+    paths: &[PathBuf],
+    config: &Config,
+    io_lock: Arc<Semaphore>,
+    skip_reflinks: bool,
+    mut on_duplicates: F,
+) where
+    F: FnMut(&Checksum, &[Duplicate]),
+{
+    let mut by_size: HashMap<u64, Vec<(PathBuf, Listed)>> = HashMap::new();
+    for path in paths {
+        list_files(path, &mut |listed, metadata| {
+            if metadata.len() > 0 {
+                let file = (listed.path(), listed);
+                by_size.entry(metadata.len()).or_default().push(file);
+            }
+        });
+    }
+
+    let checksums = Mutex::new(Vec::new());
+    // The extents of files whose data is all shared, and files that weren't read because they
+    // have the same extents as another file.
+    let mut extents_of = HashMap::new();
+    let mut copies = Vec::new();
+    rayon::scope(|s| {
+        let candidates = by_size.into_iter().filter(|(_, paths)| paths.len() > 1);
+        let mut originals: HashMap<Vec<Extent>, PathBuf> = HashMap::new();
+        for (path, listed) in candidates.flat_map(|(_, paths)| paths) {
+            if let Some(extents) = shared_extents(&path) {
+                extents_of.insert(path.clone(), extents.clone());
+                match originals.entry(extents) {
+                    Entry::Occupied(original) if skip_reflinks => {
+                        copies.push((path, original.get().clone()));
+                        continue;
+                    }
+                    Entry::Occupied(_) => {}
+                    Entry::Vacant(entry) => {
+                        entry.insert(path.clone());
+                    }
+                }
+            }
+            let checksums = &checksums;
+            let result = checksum_listed(
+                path.clone(),
+                Some(listed),
+                None,
+                config,
+                Arc::clone(&io_lock),
+                s,
+                move |path, hashed| match hashed {
+                    Ok(hashed) => checksums
+                        .lock()
+                        .unwrap()
+                        .push((hashed.checksum, path.to_owned())),
+                    Err(err) => print_error(path, err),
+                },
+            );
+            if let Err(err) = result {
+                print_error(&path, err);
+            }
+        }
+    });
+
+    let mut checksums = checksums.into_inner().unwrap();
+    let checksum_of: HashMap<_, _> = checksums
+        .iter()
+        .map(|(checksum, path)| (path.clone(), checksum.clone()))
+        .collect();
+    for (copy, original) in copies {
+        if let Some(checksum) = checksum_of.get(&original) {
+            checksums.push((checksum.clone(), copy));
+        }
+    }
+
+    let mut by_checksum: HashMap<Checksum, Vec<PathBuf>> = HashMap::new();
+    for (checksum, path) in checksums {
+        by_checksum.entry(checksum).or_default().push(path);
+    }
+    let mut duplicates: Vec<_> = by_checksum
+        .into_iter()
+        .filter(|(_, paths)| paths.len() > 1)
+        .map(|(checksum, mut paths)| {
+            paths.sort();
+            let shared = |path: &PathBuf| {
+                let extents = extents_of.get(path);
+                let same = |other: &PathBuf| extents_of.get(other) == extents;
+                extents.is_some() && paths.iter().filter(|&other| same(other)).count() > 1
+            };
+            let duplicates: Vec<_> = paths
+                .iter()
+                .map(|path| Duplicate {
+                    path: path.clone(),
+                    reflink: shared(path),
+                })
+                .collect();
+            (checksum, duplicates)
+        })
+        .collect();
+    duplicates.sort_by(|(_, a), (_, b)| a[0].path.cmp(&b[0].path));
+    for (checksum, duplicates) in duplicates {
+        on_duplicates(&checksum, &duplicates);
+    }
+}
+
+/// A file in a set of duplicates found by [`find_duplicates`].
+#[derive(Debug, PartialEq, Eq)]
+pub struct Duplicate {
+    pub path: PathBuf,
+    /// Whether all of the data of the file is shared with another file in the set, through
+    /// reflinks, so it doesn't take up any more space.
+    pub reflink: bool,
+}
+
+/// The logical offset, physical offset and length of a part of a file on disk.
+type Extent = (u64, u64, u64);
+
+/// The extents of a file, if all of them are shared with other files through reflinks. Files
+/// with the same shared extents have the same contents. This is only supported on Linux, with
+/// filesystems like Btrfs and XFS.
+fn shared_extents(path: &Path) -> Option<Vec<Extent>> {
+    use crate::sys::{FIEMAP_EXTENT_LAST, FIEMAP_EXTENT_SHARED, FIEMAP_EXTENT_UNPLACED};
+
+    let file = File::open(path).ok()?;
+    let mut extents = Vec::new();
+    let mut start = 0;
+    loop {
+        let map = crate::sys::fiemap(&file, start).ok()?;
+        if map.is_empty() {
+            return None;
+        }
+        for extent in &map {
+            if extent.flags & FIEMAP_EXTENT_SHARED == 0
+                || extent.flags & FIEMAP_EXTENT_UNPLACED != 0
+            {
+                return None;
+            }
+            extents.push((extent.logical, extent.physical, extent.length));
+            if extent.flags & FIEMAP_EXTENT_LAST != 0 {
+                return Some(extents);
+            }
+            start = extent.logical + extent.length;
+        }
+    }
+}
+
+/// Pass each regular file at `path` and its metadata to `on_file`, searching directories
+/// recursively in sorted order. Symbolic links under `path` are not followed, but `path` is
+/// followed if it's one, the way the paths given on the command line are. Errors are printed, and
+/// false is returned if there were any.
+///
+/// Where the OS can, the names of each directory are read from the directory that was opened,
+/// and each entry is opened by its name relative to it, as [`open_entry`] does, and its metadata
+/// is that of what was opened. So a directory that's replaced by a symbolic link while the tree is
+/// searched isn't followed out of the tree, and [`Listed::open`] opens each file again the same
+/// way.
+pub(crate) fn list_files(path: &Path, on_file: &mut dyn FnMut(Listed, &Metadata)) -> bool {
+    let listed = Listed {
+        root: path.to_owned(),
+        names: Vec::new(),
+        id: None,
+    };
+    list_entry(None, listed, on_file)
+}
+
+/// Pass the file of `listed` to `on_file`, or each file under it if it's a directory, for
+/// [`list_files`]. `dir` is the directory it's in, if it was opened.
+fn list_entry(
+    dir: Option<&File>,
+    mut listed: Listed,
+    on_file: &mut dyn FnMut(Listed, &Metadata),
+) -> bool {
+    let path = listed.path();
+    let root = listed.names.is_empty();
+    let (opened, metadata) = match open_entry(dir, &path, root) {
+        Ok(Found::Entry(opened, metadata)) => (opened, metadata),
+        Ok(Found::Symlink) | Ok(Found::Other(_)) => return true,
+        Err(err) => {
+            print_error_in(Phase::Walk, &path, err.into());
+            return false;
+        }
+    };
+    if metadata.is_file() {
+        listed.id = file_id(&metadata);
+        on_file(listed, &metadata);
+        return true;
+    }
+    let mut complete = true;
+    let mut names = Vec::new();
+    match &opened {
+        Some(opened) => match list_dir(opened) {
+            Ok(listed) => names = listed,
+            Err(err) => {
+                print_error_in(Phase::Walk, &path, err.into());
+                return false;
+            }
+        },
+        None => {
+            let entries = match std::fs::read_dir(&path) {
+                Ok(entries) => entries,
+                Err(err) => {
+                    print_error_in(Phase::Walk, &path, err.into());
+                    return false;
+                }
+            };
+            for entry in entries {
+                match entry {
+                    Ok(entry) => names.push(entry.file_name()),
+                    Err(err) => {
+                        print_error_in(Phase::Walk, &path, err.into());
+                        complete = false;
+                    }
+                }
+            }
+        }
+    }
+    names.sort();
+    for name in names {
+        let mut child = listed.clone();
+        child.names.push(name);
+        complete &= list_entry(opened.as_ref(), child, on_file);
+    }
+    complete
+}
+
+/// A file that [`list_files`] found: the path that was searched, the names of the entries under
+/// it that lead to the file, and the [`FileId`] the file had.
+#[derive(Clone, Debug)]
+pub struct Listed {
+    root: PathBuf,
+    names: Vec<OsString>,
+    id: Option<FileId>,
+}
+
+impl Listed {
+    /// The path of the file.
+    pub fn path(&self) -> PathBuf {
+        let mut path = self.root.clone();
+        path.extend(&self.names);
+        path
+    }
+
+    /// The [`FileId`] the file had when it was found.
+    pub fn id(&self) -> Option<FileId> {
+        self.id
+    }
+
+    /// Open the file again, to read it. Where the OS can, the path that was searched is opened,
+    /// and then each name under it relative to the directory before it, without following
+    /// symbolic links under the path that was searched, so that a directory on the way that was
+    /// replaced by a link since the file was found fails to open instead of leading out of the
+    /// tree. Whether the file is still the one that was found is checked by [`Input::from_file`].
+    pub(crate) fn open(&self) -> Result<File> {
+        if !OPEN_AT_SUPPORTED || self.names.is_empty() {
+            return Ok(open_sequential(&self.path())?);
+        }
+        let replaced = |err: std::io::Error| -> Box<dyn Error> {
+            if is_symlink_error(&err) {
+                "the file was replaced after it was found".into()
+            } else {
+                err.into()
+            }
+        };
+        let (name, dirs) = self.names.split_last().unwrap();
+        let mut dir = File::open(&self.root)?;
+        for name in dirs {
+            dir = open_nofollow(Some(&dir), name, true).map_err(replaced)?;
+        }
+        open_nofollow(Some(&dir), name, false).map_err(replaced)
+    }
+}
+
+/// An entry of a directory that's being searched, opened by [`open_entry`].
+pub(crate) enum Found {
+    /// A regular file or a directory, with its metadata. It's open if the OS can open it relative
+    /// to its directory, and then the metadata is that of the file that's open.
+    Entry(Option<File>, Metadata),
+    Symlink,
+    /// Anything else, such as a FIFO or a device, which isn't opened.
+    Other(FileType),
+}
+
+/// Open the entry at `path` to read it or search it, without following it if it's a symbolic
+/// link. If it's in `dir`, it's opened by its name relative to `dir`, so that a directory on the
+/// path that was replaced by a link since `dir` was opened isn't followed. A FIFO or a device that
+/// replaced it is opened without waiting, and then found to be [`Found::Other`]. With `follow`, a
+/// symbolic link at `path` is followed, the way the paths that are given to search are.
+pub(crate) fn open_entry(dir: Option<&File>, path: &Path, follow: bool) -> std::io::Result<Found> {
+    let listed = if follow {
+        metadata(path)?
+    } else {
+        symlink_metadata(path)?
+    };
+    let file_type = listed.file_type();
+    if file_type.is_symlink() {
+        return Ok(Found::Symlink);
+    } else if !file_type.is_file() && !file_type.is_dir() {
+        return Ok(Found::Other(file_type));
+    } else if !OPEN_AT_SUPPORTED {
+        return Ok(Found::Entry(None, listed));
+    }
+    let name = path.file_name().unwrap_or_else(|| path.as_os_str());
+    let opened = match dir {
+        Some(dir) => open_nofollow(Some(dir), name, file_type.is_dir()),
+        None if follow => File::open(path),
+        None => open_nofollow(None, path.as_os_str(), file_type.is_dir()),
+    };
+    let file = match opened {
+        Err(err) if is_symlink_error(&err) => return Ok(Found::Symlink),
+        opened => opened?,
+    };
+    let metadata = file.metadata()?;
+    if metadata.is_file() || metadata.is_dir() {
+        Ok(Found::Entry(Some(file), metadata))
+    } else {
+        Ok(Found::Other(metadata.file_type()))
+    }
+}
+
+/// Compute one checksum for a directory from the checksums of all the files under it and their
+/// paths, so that two trees can be compared by their digests. The digest of a file is its
+/// checksum.
+///
+/// The digest of a directory is the BLAKE3 hash of its entries, sorted by name. Each entry is
+/// `f` for a file or `d` for a directory, the length of its name as a little-endian `u64`, its
+/// name as UTF-8, the length of its digest as a `u64`, and its digest. Only regular files are
+/// included, so directories without any files under them are left out. Errors are printed, and
+/// no digest is returned if any file couldn't be listed or read.
+pub fn tree_digest(root: &Path, config: &Config, io_lock: Arc<Semaphore>) -> Result<Checksum> {
+    let mut files = Vec::new();
+    if !list_files(root, &mut |listed, _| files.push(listed)) {
+        return Err("some files could not be listed, so there is no digest".into());
+    }
+    add_progress_total(files.len() as u64, 0);
+    let checksums = Mutex::new(Vec::new());
+    let failed = AtomicBool::new(false);
+    rayon::scope(|s| {
+        for listed in files {
+            let path = listed.path();
+            let checksums = &checksums;
+            let failed = &failed;
+            let result = checksum_listed(
+                path.clone(),
+                Some(listed),
+                None,
+                config,
+                Arc::clone(&io_lock),
+                s,
+                move |path, hashed| match hashed {
+                    Ok(hashed) => checksums
+                        .lock()
+                        .unwrap()
+                        .push((path.to_owned(), hashed.checksum)),
+                    Err(err) => {
+                        print_error(path, err);
+                        failed.store(true, Ordering::Relaxed);
+                    }
+                },
+            );
+            if let Err(err) = result {
+                print_error(&path, err);
+                failed.store(true, Ordering::Relaxed);
+            }
+        }
+    });
+    if failed.into_inner() {
+        return Err("some files could not be read, so there is no digest".into());
+    }
+
+    let mut files: Vec<(Vec<String>, Checksum)> = checksums
+        .into_inner()
+        .unwrap()
+        .into_iter()
+        .map(|(path, checksum)| {
+            let relative = path.strip_prefix(root).unwrap_or(&path);
+            let names = relative
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy().into_owned())
+                .collect();
+            (names, checksum)
+        })
+        .collect();
+    files.sort_by(|a, b| a.0.cmp(&b.0));
+    match files.as_slice() {
+        // The root is a file.
+        [(names, checksum)] if names.is_empty() => Ok(checksum.clone()),
+        files => Ok(directory_digest(files)),
+    }
+}
+
+/// The digest of a directory for [`tree_digest`], from the files under it, which are sorted by
+/// their names relative to it.
+pub(crate) fn directory_digest(files: &[(Vec<String>, Checksum)]) -> Checksum {
+    let mut hasher = blake3::Hasher::new();
+    let mut add = |kind: &[u8], name: &str, digest: &Checksum| {
+        hasher.update(kind);
+        hasher.update(&(name.len() as u64).to_le_bytes());
+        hasher.update(name.as_bytes());
+        hasher.update(&(digest.0.len() as u64).to_le_bytes());
+        hasher.update(&digest.0);
+    };
+    let mut rest = files;
+    while let Some((names, checksum)) = rest.first() {
+        let name = &names[0];
+        if names.len() == 1 {
+            add(b"f", name, checksum);
+            rest = &rest[1..];
+            continue;
+        }
+        let count = rest
+            .iter()
+            .take_while(|(names, _)| names.len() > 1 && names[0] == *name)
+            .count();
+        let children: Vec<(Vec<String>, Checksum)> = rest[..count]
+            .iter()
+            .map(|(names, checksum)| (names[1..].to_vec(), checksum.clone()))
+            .collect();
+        add(b"d", name, &directory_digest(&children));
+        rest = &rest[count..];
+    }
+    Checksum(hasher.finalize().as_bytes().to_vec())
+}
+
+/// How long it took to checksum a file with one way of reading it. See [`benchmark`].
+#[derive(Debug)]
+pub struct Measurement {
+    pub strategy: &'static str,
+    pub bytes: u64,
+    pub elapsed: Duration,
+}
+
+/// Checksum a file with each of the ways that it can be read, and measure how long each one
+/// takes. Small files are read into memory by one thread, and large files are read with buffers
+/// or with mmap, so all three are measured. Files too large to fit in memory are only read the
+/// other two ways.
+///
+/// The file is read once before anything is measured, so that every measurement reads from the
+/// OS cache. On a slow drive this measures the cost of each way of reading, not the drive.
+pub fn benchmark(path: &Path, config: &Config) -> Result<Vec<Measurement>> {
+    let bytes = metadata(path)?.len();
+    let buffered = Config {
+        use_mmap: false,
+        ..config.clone()
+    };
+    let mmap = Config {
+        use_mmap: true,
+        ..config.clone()
+    };
+    b3sum_large(Input::File(File::open(path)?), &buffered)?;
+
+    let mut measurements = Vec::new();
+    let mut measure = |strategy, run: &dyn Fn() -> Result<Checksum>| -> Result<()> {
+        let start = Instant::now();
+        run()?;
+        let elapsed = start.elapsed();
+        measurements.push(Measurement {
+            strategy,
+            bytes,
+            elapsed,
+        });
+        Ok(())
+    };
+    if bytes <= 256 * (1 << 20) {
+        measure("in memory", &|| b3sum_small(&mut File::open(path)?, config))?;
+    }
+    measure("buffered", &|| {
+        b3sum_large(Input::File(File::open(path)?), &buffered)
+    })?;
+    if MMAP_SUPPORTED {
+        measure("mmap", &|| {
+            b3sum_large(Input::File(File::open(path)?), &mmap)
+        })?;
+    }
+    Ok(measurements)
+}
+
+/// Chunks are at least this large, except for the last chunk of a file.
+const CHUNK_MIN: usize = 16 * (1 << 10);
+/// Chunks are cut at this size if no boundary is found earlier.
+const CHUNK_MAX: usize = 256 * (1 << 10);
+/// A boundary is found when these bits of the rolling hash are zero, so chunks average about
+/// 64 KiB beyond the minimum size.
+const CHUNK_MASK: u64 = (1 << 16) - 1;
+
+/// Random numbers for the gear rolling hash, generated with splitmix64. Changing these changes where
+/// chunks are cut, so chunk lists would no longer be comparable with older versions.
+const GEAR: [u64; 256] = {
+    let mut table = [0u64; 256];
+    let mut state = 0u64;
+    let mut i = 0;
+    while i < table.len() {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+/// A piece of a file, as found by [`checksum_chunks`], [`checksum_blocks`] or
+/// [`Config::piecewise`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Chunk {
+    pub offset: u64,
+    pub length: usize,
+    /// The BLAKE3 checksum of the chunk's contents.
+    pub checksum: Checksum,
+}
+
+/// Split a file (or standard input if the path is `-`) into content-defined chunks, and pass
+/// each chunk's location and checksum to `on_chunk`.
+///
+/// Chunk boundaries are chosen by a rolling hash of the data, so when bytes are inserted or
+/// removed, only the chunks near the change are different. Comparing the chunk lists of two
+/// copies of a large file shows which parts of it differ.
+pub fn checksum_chunks<F>(path: &Path, config: &Config, mut on_chunk: F) -> Result<()>
+where
+    F: FnMut(Chunk),
+{
+    let mut read: Box<dyn Read> = if path.to_str() == Some("-") {
+        Box::new(std::io::stdin())
+    } else {
+        let compression = if config.decompress {
+            Compression::detect(path)
+        } else {
+            None
+        };
+        match Input::open(path, compression)? {
+            Input::File(file) => Box::new(file),
+            Input::Stream(read, _) => read,
+        }
+    };
+
+    // Data that hasn't been assigned to a chunk is moved to the front of the buffer before more
+    // is read. That is always less than `CHUNK_MAX`, so there is room to read into.
+    let mut buf = vec![0u8; 4 * CHUNK_MAX];
+    let mut len = 0;
+    let mut offset = 0;
+    loop {
+        let bytes_read = read.read(&mut buf[len..])?;
+        len += bytes_read;
+        let eof = bytes_read == 0;
+
+        let mut start = 0;
+        while start < len {
+            let length = match find_chunk_boundary(&buf[start..len]) {
+                Some(length) => length,
+                None if eof => len - start,
+                None => break,
+            };
+            on_chunk(Chunk {
+                offset,
+                length,
+                checksum: Checksum(
+                    blake3::hash(&buf[start..start + length])
+                        .as_bytes()
+                        .to_vec(),
+                ),
+            });
+            offset += length as u64;
+            start += length;
+        }
+        buf.copy_within(start..len, 0);
+        len -= start;
+        if eof {
+            return Ok(());
+        }
+    }
+}
+
+/// Return the length of the chunk at the start of `data`, or `None` if more data is needed to
+/// find the end of it.
+fn find_chunk_boundary(data: &[u8]) -> Option<usize> {
+    // Bytes are shifted out of the hash after 64 steps, so earlier bytes don't matter.
+    let mut hash = 0u64;
+    for (i, &byte) in data.iter().enumerate().take(CHUNK_MAX).skip(CHUNK_MIN - 64) {
+        hash = (hash << 1).wrapping_add(GEAR[byte as usize]);
+        if i + 1 >= CHUNK_MIN && hash & CHUNK_MASK == 0 {
+            return Some(i + 1);
+        }
+    }
+    if data.len() >= CHUNK_MAX {
+        Some(CHUNK_MAX)
+    } else {
+        None
+    }
+}
+
+/// A block that [`checksum_blocks`] couldn't read.
+#[derive(Debug)]
+pub struct BlockError {
+    pub offset: u64,
+    pub length: usize,
+    pub error: std::io::Error,
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "the {} bytes at offset {} could not be read: {}",
+            self.length, self.offset, self.error
+        )
+    }
+}
+
+impl Error for BlockError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
+/// Split a file, such as a disk image or a block device, into blocks of `block_size` bytes, and
+/// pass each block's location and BLAKE3 checksum to `on_block`. The last block may be shorter.
+///
+/// A block that can't be read is passed as a [`BlockError`], and the blocks after it are still
+/// read, so that comparing the blocks of a failing disk with those of a good copy shows which
+/// regions were damaged. That needs seeking, so errors end the reading of standard input (if the
+/// path is `-`) and of compressed files, which are returned instead. A `block_size` of 0 is an
+/// error.
+pub fn checksum_blocks<F>(
+    path: &Path,
+    config: &Config,
+    block_size: usize,
+    mut on_block: F,
+) -> Result<()>
+where
+    F: FnMut(std::result::Result<Chunk, BlockError>),
+{
+    if block_size == 0 {
+        return Err("blocks can't be empty".into());
+    }
+    let mut buf = vec![0u8; block_size];
+    let compression = if config.decompress {
+        Compression::detect(path)
+    } else {
+        None
+    };
+    let mut read: Box<dyn Read> = if path.to_str() == Some("-") {
+        Box::new(std::io::stdin())
+    } else {
+        match Input::open(path, compression)? {
+            Input::File(file) => return checksum_file_blocks(file, &mut buf, on_block),
+            Input::Stream(read, _) => read,
+        }
+    };
+    let mut offset = 0;
+    loop {
+        let length = read_full(&mut read, &mut buf)?;
+        if length == 0 {
+            return Ok(());
+        }
+        on_block(Ok(block_of(offset, &buf[..length])));
+        offset += length as u64;
+    }
+}
+
+/// Checksum the blocks of a file that can be seeked, skipping those that can't be read.
+fn checksum_file_blocks<F>(mut file: File, buf: &mut [u8], mut on_block: F) -> Result<()>
+where
+    F: FnMut(std::result::Result<Chunk, BlockError>),
+{
+    // Block devices have no length in their metadata, but they can be seeked to their end.
+    let size = file.seek(SeekFrom::End(0))?;
+    file.seek(SeekFrom::Start(0))?;
+    let mut offset = 0;
+    while offset < size {
+        let length = (size - offset).min(buf.len() as u64) as usize;
+        match read_full(&mut file, &mut buf[..length]) {
+            Ok(read) if read == length => on_block(Ok(block_of(offset, &buf[..length]))),
+            // The file was truncated while it was read.
+            Ok(read) => {
+                if read > 0 {
+                    on_block(Ok(block_of(offset, &buf[..read])));
+                }
+                return Ok(());
+            }
+            Err(error) => {
+                on_block(Err(BlockError {
+                    offset,
+                    length,
+                    error,
+                }));
+                file.seek(SeekFrom::Start(offset + length as u64))?;
+            }
+        }
+        offset += length as u64;
+    }
+    Ok(())
+}
+
+fn block_of(offset: u64, buf: &[u8]) -> Chunk {
+    Chunk {
+        offset,
+        length: buf.len(),
+        checksum: Checksum(blake3::hash(buf).as_bytes().to_vec()),
+    }
+}
+
+/// Read until `buf` is full or the end of the input, and return the number of bytes read.
+fn read_full(read: &mut dyn Read, buf: &mut [u8]) -> std::io::Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match read.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(bytes_read) => len += bytes_read,
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(len)
+}
+
+/// Compute a checksum of every regular file inside an archive, or inside standard input if the
+/// path is `-`. Each result is passed to `on_result` with a path like `backup.tar//notes.txt`.
+/// Zip members are decompressed before being checksummed.
+///
+/// The archive is streamed rather than extracted. Since it is one file that is read from start to
+/// end, all I/O resources are held while it is read, the same as for a large file. Zip archives
+/// can't be read from standard input, since their index is at the end.
+///
+/// With [`ArchiveFormat::Auto`], the format is [resolved](ArchiveFormat::resolve) from the path,
+/// and a path that isn't named like an archive is an error.
+pub fn checksum_archive<F>(
+    path: &Path,
+    format: ArchiveFormat,
+    config: &Config,
+    io_lock: &Semaphore,
+    mut on_result: F,
+) -> Result<()>
+where
+    F: FnMut(&Path, Result<Hashed>),
+{
+    let io_lock = io_lock.access_many(config.max_job_count as isize);
+    let is_stdin = path.to_str() == Some("-");
+    match format.resolve(path, config.decompress) {
+        Some(ArchiveFormat::Tar) => {
+            let input: Box<dyn Read> = if is_stdin {
+                Box::new(std::io::stdin())
+            } else {
+                let compression = if config.decompress {
+                    Compression::detect(path)
+                } else {
+                    None
+                };
+                match Input::open(path, compression)? {
+                    Input::File(file) => Box::new(file),
+                    Input::Stream(read, _) => read,
+                }
+            };
+            let mut archive = tar::Archive::new(input);
+            for entry in archive.entries()? {
+                let mut entry = entry?;
+                if !entry.header().entry_type().is_file() {
+                    continue;
+                }
+                let member_path = archive_member_path(path, &entry.path()?);
+                let mut hasher = Hasher::new(config)?;
+                let checksum = hash_reader(&mut entry, &mut hasher).map(|()| hasher.finish());
+                on_result(&member_path, checksum);
+            }
+        }
+        Some(ArchiveFormat::Zip) => {
+            if is_stdin {
+                return Err("zip archives can't be read from standard input".into());
+            }
+            let mut archive = zip::ZipArchive::new(File::open(path)?)?;
+            for i in 0..archive.len() {
+                let mut member = archive.by_index(i)?;
+                if member.is_dir() {
+                    continue;
+                }
+                let member_path = archive_member_path(path, Path::new(member.name()));
+                let mut hasher = Hasher::new(config)?;
+                let checksum = hash_reader(&mut member, &mut hasher).map(|()| hasher.finish());
+                on_result(&member_path, checksum);
+            }
+        }
+        _ => return Err("the kind of archive can't be told from its name".into()),
+    }
+    drop(io_lock);
+    record_progress(metadata(path).map_or(0, |metadata| metadata.len()));
+    Ok(())
+}
+
+/// The name printed for a file inside an archive: `<archive>//<member>`.
+fn archive_member_path(archive: &Path, member: &Path) -> PathBuf {
+    let mut path = archive.as_os_str().to_owned();
+    path.push("//");
+    path.push(member.as_os_str());
+    PathBuf::from(path)
+}
+
+/// Settings for how inputs are read and checksummed. Outside this crate, it's made with
+/// [`Config::builder`], or changed from [`Config::default`], since more fields may be added.
+#[derive(Clone)]
+#[non_exhaustive]
+pub struct Config {
+    /// The number of small files that may be read at once. See
+    /// [`do_checksum`](crate::scheduler::do_checksum).
+    pub max_job_count: usize,
+    pub use_mmap: bool,
+    pub algorithm: Algorithm,
+    /// How BLAKE3 checksums are computed. Other algorithms ignore this.
+    pub blake3_mode: Blake3Mode,
+    /// The length of BLAKE3 checksums in bytes. Other algorithms have a fixed length.
+    pub length: usize,
+    /// Whether to checksum the decompressed contents of compressed files.
+    pub decompress: bool,
+    /// Whether to avoid filling the OS cache with the files that are read. Only used on macOS.
+    pub no_cache: bool,
+    /// How far ahead of buffered reads of large files the OS is asked to read.
+    pub readahead: Option<u64>,
+    /// Whether to take a shared lock on each file while it is read. See `--lock`.
+    pub lock: bool,
+    /// How many times to read a file again if it changes while it is read. Without this, a
+    /// warning is printed instead.
+    pub verify_stable: Option<usize>,
+    /// Whether each file is read twice, the second time another way, and an error is returned if
+    /// the checksums differ. See `--paranoid`.
+    pub paranoid: bool,
+    /// When large files are read while nothing else is.
+    pub exclusive_large: ExclusiveLarge,
+    /// Whether files are read the way that suits their storage, or as if they're on a hard drive
+    /// or an SSD.
+    pub io_strategy: IoStrategy,
+    /// Whether large files are read by several threads at once. This is done for files on SSDs,
+    /// which is detected for each file.
+    pub parallel_reads: bool,
+    /// Whether the input is on a network filesystem, so larger reads are used. This is detected
+    /// for each file.
+    pub network: bool,
+    /// When this is set, inputs that haven't started to be read yet are skipped, and their results
+    /// are never reported.
+    pub cancel: Option<Arc<AtomicBool>>,
+    /// The counters of the files of this run. Each default config has its own, and the clones of
+    /// a config share them, so a config that is used for two runs counts the files of both.
+    pub metrics: Arc<MetricsCounters>,
+    /// Called with the [`metrics`](Self::metrics) whenever the scheduler queues, starts or
+    /// finishes a file. It's called on the threads that read files, so it should be quick.
+    pub on_metrics: Option<Arc<dyn Fn(Metrics) + Send + Sync>>,
+    /// Whether the Shannon entropy of the data is computed while it's checksummed. See
+    /// [`Hashed::entropy`].
+    pub entropy: bool,
+    /// If this is set, the checksums of the consecutive pieces of this many bytes of the data are
+    /// computed along with the checksum of all of it, with the same algorithm. See
+    /// [`Hashed::pieces`].
+    pub piecewise: Option<usize>,
+    /// Whether the ssdeep similarity hash of the data is computed while it's checksummed. See
+    /// [`Hashed::ssdeep`]. This needs the `fuzzy` feature, and without it, hashing fails.
+    pub ssdeep: bool,
+    /// Whether the [`xattrs_digest`] of each file is taken from the file that's read, when it's
+    /// opened. See [`Hashed::xattrs`].
+    pub xattrs: bool,
+}
+
+impl Config {
+    /// A [`ConfigBuilder`] that starts from the default config.
+    pub fn builder() -> ConfigBuilder {
+        ConfigBuilder::default()
+    }
+
+    /// The length in bytes of the checksums computed with this configuration.
+    ///
+    /// Panics if the algorithm is not [available](Algorithm::is_available).
+    pub fn checksum_length(&self) -> usize {
+        let config = Config {
+            entropy: false,
+            piecewise: None,
+            ssdeep: false,
+            ..self.clone()
+        };
+        // The other options that can fail were turned off.
+        Hasher::new(&config)
+            .unwrap_or_else(|err| panic!("{}", err))
+            .finalize()
+            .0
+            .len()
+    }
+
+    /// Whether the inputs that haven't been read yet should be skipped. See `cancel`.
+    pub(crate) fn is_cancelled(&self) -> bool {
+        self.cancel
+            .as_ref()
+            .map_or(false, |cancel| cancel.load(Ordering::Relaxed))
+    }
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            max_job_count: 16,
+            use_mmap: false,
+            algorithm: Algorithm::Blake3,
+            blake3_mode: Blake3Mode::Hash,
+            length: blake3::OUT_LEN,
+            decompress: false,
+            no_cache: false,
+            readahead: None,
+            lock: false,
+            verify_stable: None,
+            paranoid: false,
+            exclusive_large: ExclusiveLarge::Auto,
+            io_strategy: IoStrategy::Auto,
+            parallel_reads: false,
+            network: false,
+            cancel: None,
+            metrics: Arc::new(MetricsCounters::default()),
+            on_metrics: None,
+            entropy: false,
+            piecewise: None,
+            ssdeep: false,
+            xattrs: false,
+        }
+    }
+}
+
+// The hook of `on_metrics` can't be printed, so only whether there is one is.
+impl fmt::Debug for Config {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Config")
+            .field("max_job_count", &self.max_job_count)
+            .field("use_mmap", &self.use_mmap)
+            .field("algorithm", &self.algorithm)
+            .field("blake3_mode", &self.blake3_mode)
+            .field("length", &self.length)
+            .field("decompress", &self.decompress)
+            .field("no_cache", &self.no_cache)
+            .field("readahead", &self.readahead)
+            .field("lock", &self.lock)
+            .field("verify_stable", &self.verify_stable)
+            .field("paranoid", &self.paranoid)
+            .field("exclusive_large", &self.exclusive_large)
+            .field("io_strategy", &self.io_strategy)
+            .field("parallel_reads", &self.parallel_reads)
+            .field("network", &self.network)
+            .field("cancel", &self.cancel)
+            .field("metrics", &self.metrics)
+            .field("on_metrics", &self.on_metrics.is_some())
+            .field("entropy", &self.entropy)
+            .field("piecewise", &self.piecewise)
+            .field("ssdeep", &self.ssdeep)
+            .field("xattrs", &self.xattrs)
+            .finish()
+    }
+}
+
+/// Builds a [`Config`], starting from [`Config::default`]. Since fields may be added to `Config`
+/// in any release, crates outside this one can't write it as a struct literal, and set the fields
+/// that they need with this instead.
+///
+/// ```
+/// use b3sum_ng::engine::{Algorithm, Config};
+///
+/// let config = Config::builder()
+///     .algorithm(Algorithm::Crc32)
+///     .max_job_count(4)
+///     .build();
+/// assert_eq!(4, config.max_job_count);
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct ConfigBuilder {
+    config: Config,
+}
+
+impl ConfigBuilder {
+    /// Set [`Config::max_job_count`].
+    pub fn max_job_count(mut self, max_job_count: usize) -> Self {
+        self.config.max_job_count = max_job_count;
+        self
+    }
+
+    /// Set [`Config::use_mmap`].
+    pub fn use_mmap(mut self, use_mmap: bool) -> Self {
+        self.config.use_mmap = use_mmap;
+        self
+    }
+
+    /// Set [`Config::algorithm`].
+    pub fn algorithm(mut self, algorithm: Algorithm) -> Self {
+        self.config.algorithm = algorithm;
+        self
+    }
+
+    /// Set [`Config::blake3_mode`].
+    pub fn blake3_mode(mut self, blake3_mode: Blake3Mode) -> Self {
+        self.config.blake3_mode = blake3_mode;
+        self
+    }
+
+    /// Set [`Config::length`].
+    pub fn length(mut self, length: usize) -> Self {
+        self.config.length = length;
+        self
+    }
+
+    /// Set [`Config::decompress`].
+    pub fn decompress(mut self, decompress: bool) -> Self {
+        self.config.decompress = decompress;
+        self
+    }
+
+    /// Set [`Config::no_cache`].
+    pub fn no_cache(mut self, no_cache: bool) -> Self {
+        self.config.no_cache = no_cache;
+        self
+    }
+
+    /// Set [`Config::readahead`].
+    pub fn readahead(mut self, readahead: Option<u64>) -> Self {
+        self.config.readahead = readahead;
+        self
+    }
+
+    /// Set [`Config::lock`].
+    pub fn lock(mut self, lock: bool) -> Self {
+        self.config.lock = lock;
+        self
+    }
+
+    /// Set [`Config::verify_stable`].
+    pub fn verify_stable(mut self, verify_stable: Option<usize>) -> Self {
+        self.config.verify_stable = verify_stable;
+        self
+    }
+
+    /// Set [`Config::paranoid`].
+    pub fn paranoid(mut self, paranoid: bool) -> Self {
+        self.config.paranoid = paranoid;
+        self
+    }
+
+    /// Set [`Config::exclusive_large`].
+    pub fn exclusive_large(mut self, exclusive_large: ExclusiveLarge) -> Self {
+        self.config.exclusive_large = exclusive_large;
+        self
+    }
+
+    /// Set [`Config::io_strategy`].
+    pub fn io_strategy(mut self, io_strategy: IoStrategy) -> Self {
+        self.config.io_strategy = io_strategy;
+        self
+    }
+
+    /// Set [`Config::parallel_reads`].
+    pub fn parallel_reads(mut self, parallel_reads: bool) -> Self {
+        self.config.parallel_reads = parallel_reads;
+        self
+    }
+
+    /// Set [`Config::network`].
+    pub fn network(mut self, network: bool) -> Self {
+        self.config.network = network;
+        self
+    }
+
+    /// Set [`Config::cancel`].
+    pub fn cancel(mut self, cancel: Option<Arc<AtomicBool>>) -> Self {
+        self.config.cancel = cancel;
+        self
+    }
+
+    /// Set [`Config::metrics`].
+    pub fn metrics(mut self, metrics: Arc<MetricsCounters>) -> Self {
+        self.config.metrics = metrics;
+        self
+    }
+
+    /// Set [`Config::on_metrics`].
+    pub fn on_metrics(mut self, on_metrics: Option<Arc<dyn Fn(Metrics) + Send + Sync>>) -> Self {
+        self.config.on_metrics = on_metrics;
+        self
+    }
+
+    /// Set [`Config::entropy`].
+    pub fn entropy(mut self, entropy: bool) -> Self {
+        self.config.entropy = entropy;
+        self
+    }
+
+    /// Set [`Config::piecewise`].
+    pub fn piecewise(mut self, piecewise: Option<usize>) -> Self {
+        self.config.piecewise = piecewise;
+        self
+    }
+
+    /// Set [`Config::ssdeep`].
+    pub fn ssdeep(mut self, ssdeep: bool) -> Self {
+        self.config.ssdeep = ssdeep;
+        self
+    }
+
+    /// Set [`Config::xattrs`].
+    pub fn xattrs(mut self, xattrs: bool) -> Self {
+        self.config.xattrs = xattrs;
+        self
+    }
+
+    /// The config that was built.
+    pub fn build(self) -> Config {
+        self.config
+    }
+}
+
+/// The modes of BLAKE3, as chosen by `--keyed` and `--derive-key`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Blake3Mode {
+    Hash,
+    Keyed([u8; blake3::KEY_LEN]),
+    /// Key derivation with a context string, which is borrowed if it's a constant and owned if
+    /// it's read at run time, as from the command line.
+    DeriveKey(Cow<'static, str>),
+}
+
+impl Blake3Mode {
+    /// A BLAKE3 hasher in this mode.
+    pub fn hasher(&self) -> blake3::Hasher {
+        match self {
+            Blake3Mode::Hash => blake3::Hasher::new(),
+            Blake3Mode::Keyed(key) => blake3::Hasher::new_keyed(key),
+            Blake3Mode::DeriveKey(context) => blake3::Hasher::new_derive_key(context),
+        }
+    }
+}
+
+/// Read a key for [`Blake3Mode::Keyed`], which must be exactly 32 bytes.
+pub fn read_key(read: &mut dyn Read) -> Result<[u8; blake3::KEY_LEN]> {
+    let mut key = Vec::new();
+    read.take(blake3::KEY_LEN as u64 + 1)
+        .read_to_end(&mut key)?;
+    let len = key.len();
+    let mut key_bytes = [0; blake3::KEY_LEN];
+    if len != key_bytes.len() {
+        return Err(format!("the key must be exactly 32 bytes, but {} were read", len).into());
+    }
+    key_bytes.copy_from_slice(&key);
+    Ok(key_bytes)
+}
+
+/// The compression formats that `--decompress` understands.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Compression {
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+impl Compression {
+    /// Detect the compression of a file from its extension.
+    pub fn detect(path: &Path) -> Option<Compression> {
+        let ext = path.extension()?.to_str()?.to_ascii_lowercase();
+        match ext.as_str() {
+            "gz" | "tgz" => Some(Compression::Gzip),
+            "zst" | "tzst" => Some(Compression::Zstd),
+            "xz" | "txz" => Some(Compression::Xz),
+            _ => None,
+        }
+    }
+
+    /// Wrap a reader so that it returns decompressed data.
+    #[cfg(feature = "decompress")]
+    pub fn decoder(self, read: Box<dyn Read + Send>) -> Result<Box<dyn Read + Send>> {
+        Ok(match self {
+            Compression::Gzip => Box::new(flate2::read::MultiGzDecoder::new(read)),
+            Compression::Zstd => Box::new(zstd::stream::read::Decoder::new(read)?),
+            Compression::Xz => Box::new(xz2::read::XzDecoder::new_multi_decoder(read)),
+        })
+    }
+
+    /// Wrap a reader so that it returns decompressed data.
+    #[cfg(not(feature = "decompress"))]
+    pub fn decoder(self, _read: Box<dyn Read + Send>) -> Result<Box<dyn Read + Send>> {
+        Err(format!(
+            "{} files can only be decompressed when b3sum-ng is built with the decompress feature",
+            self
+        )
+        .into())
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+            Compression::Xz => "xz",
+        })
+    }
+}
+
+/// The kinds of archive whose members can be checksummed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    /// Choose based on the file extension.
+    Auto,
+    Tar,
+    Zip,
+}
+
+impl ArchiveFormat {
+    /// Decide which kind of archive `path` is. Returns `None` if it should be checksummed as a
+    /// single file. With `decompress`, compressed tar files like `backup.tar.gz` are tar archives.
+    pub fn resolve(self, path: &Path, decompress: bool) -> Option<ArchiveFormat> {
+        match self {
+            ArchiveFormat::Auto if decompress && Compression::detect(path).is_some() => {
+                let stem = Path::new(path.file_stem()?);
+                let is_tar = match path.extension()?.to_str()?.to_ascii_lowercase().as_str() {
+                    "tgz" | "tzst" | "txz" => true,
+                    _ => stem
+                        .extension()
+                        .map_or(false, |ext| ext.eq_ignore_ascii_case("tar")),
+                };
+                if is_tar {
+                    Some(ArchiveFormat::Tar)
+                } else {
+                    None
+                }
+            }
+            ArchiveFormat::Auto => match path.extension() {
+                Some(ext) if ext.eq_ignore_ascii_case("tar") => Some(ArchiveFormat::Tar),
+                Some(ext) if ext.eq_ignore_ascii_case("zip") => Some(ArchiveFormat::Zip),
+                _ => None,
+            },
+            format => Some(format),
+        }
+    }
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ArchiveFormat::Auto),
+            "tar" => Ok(ArchiveFormat::Tar),
+            "zip" => Ok(ArchiveFormat::Zip),
+            _ => Err(format!("unknown archive format: {}", s)),
+        }
+    }
+}
+
+/// The checksum algorithms that can be computed. BLAKE3 is used unless a file format requires
+/// something else. MD5, SHA-1 and SHA-256 are only available with the `extra-algorithms` feature,
+/// for verifying checkfiles made by other tools. Other algorithms can be given as a
+/// [`DigestAlgorithm`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Algorithm {
+    Blake3,
+    Crc32,
+    Md5,
+    Sha1,
+    Sha256,
+    Digest(DigestAlgorithm),
+}
+
+impl Algorithm {
+    /// Whether this build can compute the algorithm.
+    pub fn is_available(self) -> bool {
+        match self {
+            Algorithm::Blake3 | Algorithm::Crc32 | Algorithm::Digest(_) => true,
+            Algorithm::Md5 | Algorithm::Sha1 | Algorithm::Sha256 => {
+                cfg!(feature = "extra-algorithms")
+            }
+        }
+    }
+}
+
+impl fmt::Display for Algorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Algorithm::Blake3 => "BLAKE3",
+            Algorithm::Crc32 => "CRC32",
+            Algorithm::Md5 => "MD5",
+            Algorithm::Sha1 => "SHA-1",
+            Algorithm::Sha256 => "SHA-256",
+            Algorithm::Digest(digest) => digest.name,
+        })
+    }
+}
+
+/// An algorithm that is implemented with the [`DynDigest`](digest::DynDigest) trait of the
+/// RustCrypto `digest` crate, so that inputs are read and scheduled the same way as for BLAKE3.
+/// Unlike BLAKE3, it is computed with one thread. Algorithms with the same name are considered
+/// the same.
+#[derive(Clone, Copy)]
+pub struct DigestAlgorithm {
+    /// The name of the algorithm, which is printed in place of names like "BLAKE3".
+    pub name: &'static str,
+    /// Start a computation.
+    pub new: fn() -> Box<dyn digest::DynDigest + Send>,
+}
+
+impl DigestAlgorithm {
+    /// The algorithm of `D`, such as `sha2::Sha512`.
+    pub fn of<D: digest::DynDigest + Default + Send + 'static>(
+        name: &'static str,
+    ) -> DigestAlgorithm {
+        fn new<D: digest::DynDigest + Default + Send + 'static>(
+        ) -> Box<dyn digest::DynDigest + Send> {
+            Box::new(D::default())
+        }
+        DigestAlgorithm {
+            name,
+            new: new::<D>,
+        }
+    }
+}
+
+impl fmt::Debug for DigestAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("DigestAlgorithm").field(&self.name).finish()
+    }
+}
+
+impl PartialEq for DigestAlgorithm {
+    fn eq(&self, other: &Self) -> bool {
+        self.name == other.name
+    }
+}
+
+impl Eq for DigestAlgorithm {}
+
+impl std::hash::Hash for DigestAlgorithm {
+    fn hash<H: std::hash::Hasher>(&self, state: &mut H) {
+        self.name.hash(state)
+    }
+}
+
+/// A checksum computation in progress, for any [`Algorithm`].
+pub(crate) enum Hasher {
+    /// The length of the checksum is kept with the hasher.
+    Blake3(Box<blake3::Hasher>, usize),
+    Crc32(crc32fast::Hasher),
+    #[cfg(feature = "extra-algorithms")]
+    Md5(md5::Md5),
+    #[cfg(feature = "extra-algorithms")]
+    Sha1(sha1::Sha1),
+    #[cfg(feature = "extra-algorithms")]
+    Sha256(sha2::Sha256),
+    Digest(Box<dyn digest::DynDigest + Send>),
+    /// Another hasher, and how many times each byte value was seen, for `Config::entropy`.
+    Entropy(Box<Hasher>, Box<[u64; 256]>),
+    /// Another hasher, and the checksums of the pieces of the data, for `Config::piecewise`.
+    Pieces(Box<Hasher>, Box<Pieces>),
+    /// Another hasher, and the ssdeep hash of the data, for `Config::ssdeep`.
+    #[cfg(feature = "fuzzy")]
+    Ssdeep(Box<Hasher>, Box<Ssdeep>),
+}
+
+/// The pieces of the data that a [`Hasher`] has been given so far.
+pub(crate) struct Pieces {
+    size: usize,
+    config: Config,
+    /// The checksum of the piece that isn't complete yet, and its offset and length.
+    current: Hasher,
+    offset: u64,
+    length: usize,
+    done: Vec<Chunk>,
+}
+
+impl Pieces {
+    fn update(&mut self, mut buf: &[u8], parallel: bool) {
+        while !buf.is_empty() {
+            let length = buf.len().min(self.size - self.length);
+            if parallel {
+                self.current.update_parallel(&buf[..length]);
+            } else {
+                self.current.update(&buf[..length]);
+            }
+            self.length += length;
+            buf = &buf[length..];
+            if self.length == self.size {
+                self.finish_piece();
+            }
+        }
+    }
+
+    fn finish_piece(&mut self) {
+        // The config was checked when the first piece was started.
+        let next = Hasher::new(&self.config).unwrap();
+        let current = std::mem::replace(&mut self.current, next);
+        self.done.push(Chunk {
+            offset: self.offset,
+            length: self.length,
+            checksum: current.finalize(),
+        });
+        self.offset += self.length as u64;
+        self.length = 0;
+    }
+}
+
+/// The length of the window of the rolling hash of ssdeep, in bytes.
+#[cfg(feature = "fuzzy")]
+const SSDEEP_WINDOW: usize = 7;
+/// The number of characters in the first part of an ssdeep hash. The second part has half as many.
+#[cfg(feature = "fuzzy")]
+const SSDEEP_LENGTH: usize = 64;
+/// The number of block sizes, from 3 bytes to 3 GiB, that ssdeep hashes can have.
+#[cfg(feature = "fuzzy")]
+const SSDEEP_BLOCK_SIZES: usize = 31;
+#[cfg(feature = "fuzzy")]
+const SSDEEP_HASH_INIT: u32 = 0x2802_1967;
+#[cfg(feature = "fuzzy")]
+const SSDEEP_BASE64: &[u8; 64] =
+    b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+
+/// An ssdeep hash that is computed as data is given, with the same results as libfuzzy. A
+/// piece of the hash ends wherever the rolling hash of the last 7 bytes is one less than a
+/// multiple of the block size, so a change to the data only changes the pieces around it. Since
+/// the block size depends on the size of the data, which may not be known, the hash is computed
+/// for every block size that may still be used, from the smallest.
+#[cfg(feature = "fuzzy")]
+pub(crate) struct Ssdeep {
+    window: [u8; SSDEEP_WINDOW],
+    window_index: usize,
+    /// The sum, the weighted sum and the shifted hash of the bytes in the window.
+    rolling: (u32, u32, u32),
+    /// The hashes for each block size, of which the ones before `start` are no longer used.
+    blocks: Vec<SsdeepBlock>,
+    start: usize,
+    /// The hash of the data since the last piece of the largest block size ended, once it exists.
+    last: Option<u32>,
+    size: u64,
+}
+
+/// The ssdeep hash of the data for one block size.
+#[cfg(feature = "fuzzy")]
+struct SsdeepBlock {
+    /// The hash of the data since the last piece ended, and the hash for the second part of the
+    /// ssdeep hash, which only ends pieces until it's half as long as the first.
+    hash: u32,
+    half_hash: u32,
+    digest: Vec<u8>,
+    /// The last character of the second part, once it's full.
+    half_last: Option<u8>,
+    /// The last character of the first part, once it's full.
+    last: Option<u8>,
+}
+
+#[cfg(feature = "fuzzy")]
+fn ssdeep_block_size(index: usize) -> u64 {
+    3 << index
+}
+
+#[cfg(feature = "fuzzy")]
+fn ssdeep_sum(hash: u32, byte: u8) -> u32 {
+    hash.wrapping_mul(0x0100_0193) ^ u32::from(byte)
+}
+
+#[cfg(feature = "fuzzy")]
+impl Ssdeep {
+    fn new() -> Self {
+        let block = SsdeepBlock {
+            hash: SSDEEP_HASH_INIT,
+            half_hash: SSDEEP_HASH_INIT,
+            digest: Vec::with_capacity(SSDEEP_LENGTH),
+            half_last: None,
+            last: None,
+        };
+        Ssdeep {
+            window: [0; SSDEEP_WINDOW],
+            window_index: 0,
+            rolling: (0, 0, 0),
+            blocks: vec![block],
+            start: 0,
+            last: None,
+            size: 0,
+        }
+    }
+
+    fn rolling_hash(&self) -> u32 {
+        let (sum, weighted, shifted) = self.rolling;
+        sum.wrapping_add(weighted).wrapping_add(shifted)
+    }
+
+    fn update(&mut self, buf: &[u8]) {
+        self.size += buf.len() as u64;
+        for &byte in buf {
+            self.step(byte);
+        }
+    }
+
+    fn step(&mut self, byte: u8) {
+        let (sum, weighted, shifted) = &mut self.rolling;
+        *weighted = weighted
+            .wrapping_sub(*sum)
+            .wrapping_add(SSDEEP_WINDOW as u32 * u32::from(byte));
+        *sum = sum
+            .wrapping_add(u32::from(byte))
+            .wrapping_sub(u32::from(self.window[self.window_index]));
+        *shifted = (*shifted << 5) ^ u32::from(byte);
+        self.window[self.window_index] = byte;
+        self.window_index = (self.window_index + 1) % SSDEEP_WINDOW;
+
+        for block in &mut self.blocks[self.start..] {
+            block.hash = ssdeep_sum(block.hash, byte);
+            block.half_hash = ssdeep_sum(block.half_hash, byte);
+        }
+        if let Some(last) = &mut self.last {
+            *last = ssdeep_sum(*last, byte);
+        }
+
+        let rolling = u64::from(self.rolling_hash());
+        let mut index = self.start;
+        while index < self.blocks.len() {
+            let block_size = ssdeep_block_size(index);
+            if rolling % block_size != block_size - 1 {
+                break;
+            }
+            // The next block size is started from the first piece of this one, since it can't end
+            // a piece before this one does.
+            if self.blocks[index].digest.is_empty() {
+                if self.blocks.len() < SSDEEP_BLOCK_SIZES {
+                    let previous = &self.blocks[self.blocks.len() - 1];
+                    let next = SsdeepBlock {
+                        hash: previous.hash,
+                        half_hash: previous.half_hash,
+                        digest: Vec::with_capacity(SSDEEP_LENGTH),
+                        half_last: None,
+                        last: None,
+                    };
+                    self.blocks.push(next);
+                } else if self.last.is_none() {
+                    self.last = Some(self.blocks[self.blocks.len() - 1].hash);
+                }
+            }
+            let block = &mut self.blocks[index];
+            let character = SSDEEP_BASE64[block.hash as usize % 64];
+            block.half_last = Some(SSDEEP_BASE64[block.half_hash as usize % 64]);
+            if block.digest.len() < SSDEEP_LENGTH - 1 {
+                block.digest.push(character);
+                block.hash = SSDEEP_HASH_INIT;
+                if block.digest.len() < SSDEEP_LENGTH / 2 {
+                    block.half_hash = SSDEEP_HASH_INIT;
+                    block.half_last = None;
+                }
+            } else {
+                block.last = Some(character);
+                // The smallest block size is dropped once it's too small for the data, and the
+                // next one is long enough to be used instead.
+                if self.blocks.len() - self.start >= 2
+                    && ssdeep_block_size(self.start) * (SSDEEP_LENGTH as u64) < self.size
+                    && self.blocks[self.start + 1].digest.len() >= SSDEEP_LENGTH / 2
+                {
+                    self.start += 1;
+                }
+            }
+            index += 1;
+        }
+    }
+
+    /// The hash, like `3:AXGBicFlgVNhBGcL6wCrFQEv:AXGHsNhxLsr2C`: the block size, and the pieces
+    /// for that block size and twice that. Data over 192 GiB has no hash.
+    fn finish(&self) -> Option<String> {
+        let rolling = self.rolling_hash();
+        let mut index = self.start;
+        while ssdeep_block_size(index) * (SSDEEP_LENGTH as u64) < self.size {
+            index += 1;
+            if index >= SSDEEP_BLOCK_SIZES {
+                return None;
+            }
+        }
+        index = index.min(self.blocks.len() - 1);
+        while index > self.start && self.blocks[index].digest.len() < SSDEEP_LENGTH / 2 {
+            index -= 1;
+        }
+
+        let block = &self.blocks[index];
+        let mut hash = format!("{}:", ssdeep_block_size(index)).into_bytes();
+        hash.extend_from_slice(&block.digest);
+        if rolling != 0 {
+            hash.push(SSDEEP_BASE64[block.hash as usize % 64]);
+        } else if let Some(last) = block.last {
+            hash.push(last);
+        }
+        hash.push(b':');
+        if let Some(next) = self.blocks.get(index + 1) {
+            let length = next.digest.len().min(SSDEEP_LENGTH / 2 - 1);
+            hash.extend_from_slice(&next.digest[..length]);
+            if rolling != 0 {
+                hash.push(SSDEEP_BASE64[next.half_hash as usize % 64]);
+            } else if let Some(half_last) = next.half_last {
+                hash.push(half_last);
+            }
+        } else if rolling != 0 {
+            let last = if index == 0 {
+                block.hash
+            } else {
+                self.last.unwrap_or(block.hash)
+            };
+            hash.push(SSDEEP_BASE64[last as usize % 64]);
+        }
+        Some(String::from_utf8(hash).unwrap())
+    }
+}
+
+/// The checksum of a file, with what else was computed from the same data, as the [`Config`]
+/// asked. This is what [`checksum_paths`](crate::scheduler::checksum_paths) passes to
+/// `on_result`.
+#[derive(Clone, Debug)]
+#[non_exhaustive]
+pub struct Hashed {
+    pub checksum: Checksum,
+    /// When the checksum was finalized, after the last of the data was hashed. It's recorded by
+    /// `--timestamps`, which is why it isn't taken when the checksum is printed, perhaps much
+    /// later.
+    pub computed: SystemTime,
+    /// The Shannon entropy, in bits per byte from 0 to 8, with [`Config::entropy`]. Encrypted and
+    /// compressed data is close to 8.
+    pub entropy: Option<f64>,
+    /// The checksums of the consecutive pieces, with [`Config::piecewise`]. The last piece may be
+    /// shorter, and empty data has no pieces.
+    pub pieces: Vec<Chunk>,
+    /// The ssdeep similarity hash, with [`Config::ssdeep`]. Files that were slightly changed have
+    /// similar ssdeep hashes, which `ssdeep -k` can match. This is always `None` without the
+    /// `fuzzy` feature.
+    pub ssdeep: Option<String>,
+    /// The metadata of the file, which is taken from it when it's opened, so that it's that of the
+    /// file that was read even if the path is changed before the checksum is printed. It's `None`
+    /// for standard input and other streams.
+    pub metadata: Option<Metadata>,
+    /// The [`xattrs_digest`] of the file, with [`Config::xattrs`], which is also taken when it's
+    /// opened.
+    pub xattrs: Option<Checksum>,
+}
+
+impl From<Checksum> for Hashed {
+    /// A checksum without anything else, which was computed now.
+    fn from(checksum: Checksum) -> Self {
+        Hashed {
+            checksum,
+            computed: SystemTime::now(),
+            entropy: None,
+            pieces: Vec::new(),
+            ssdeep: None,
+            metadata: None,
+            xattrs: None,
+        }
+    }
+}
+
+/// What [`Hashed`] records of a file besides its contents, from the open file.
+pub(crate) struct FileDetails {
+    metadata: Option<Metadata>,
+    xattrs: Option<Checksum>,
+}
+
+impl FileDetails {
+    /// The details of a file that was opened, before it's read. With `xattrs`, the digest of its
+    /// extended attributes is taken too, and an error is printed if they can't be read.
+    pub(crate) fn of(path: &Path, file: &File, xattrs: bool) -> FileDetails {
+        let xattrs = if xattrs {
+            xattrs_digest_of(file)
+                .map_err(|err| print_error(path, err))
+                .ok()
+        } else {
+            None
+        };
+        FileDetails {
+            metadata: file.metadata().ok(),
+            xattrs,
+        }
+    }
+
+    pub(crate) fn add_to(self, hashed: Hashed) -> Hashed {
+        Hashed {
+            metadata: self.metadata,
+            xattrs: self.xattrs,
+            ..hashed
+        }
+    }
+}
+
+/// The Shannon entropy of data with these counts of each byte value.
+fn shannon_entropy(counts: &[u64; 256]) -> f64 {
+    let total: u64 = counts.iter().sum();
+    if total == 0 {
+        return 0.0;
+    }
+    counts
+        .iter()
+        .filter(|&&count| count > 0)
+        .map(|&count| {
+            let p = count as f64 / total as f64;
+            -p * p.log2()
+        })
+        .sum()
+}
+
+impl Hasher {
+    /// Fails if [`Config::piecewise`] is 0, if [`Config::ssdeep`] is set without the `fuzzy`
+    /// feature, or if the algorithm is not [available](Algorithm::is_available).
+    pub(crate) fn new(config: &Config) -> Result<Self> {
+        #[cfg(feature = "extra-algorithms")]
+        use sha2::Digest;
+
+        if config.entropy {
+            let config = Config {
+                entropy: false,
+                ..config.clone()
+            };
+            return Ok(Hasher::Entropy(
+                Box::new(Hasher::new(&config)?),
+                Box::new([0; 256]),
+            ));
+        }
+        if config.ssdeep {
+            #[cfg(feature = "fuzzy")]
+            {
+                let config = Config {
+                    ssdeep: false,
+                    ..config.clone()
+                };
+                return Ok(Hasher::Ssdeep(
+                    Box::new(Hasher::new(&config)?),
+                    Box::new(Ssdeep::new()),
+                ));
+            }
+            #[cfg(not(feature = "fuzzy"))]
+            return Err("ssdeep requires the fuzzy feature".into());
+        }
+        if let Some(size) = config.piecewise {
+            if size == 0 {
+                return Err("pieces can't be empty".into());
+            }
+            let config = Config {
+                piecewise: None,
+                ..config.clone()
+            };
+            let pieces = Pieces {
+                size,
+                current: Hasher::new(&config)?,
+                config: config.clone(),
+                offset: 0,
+                length: 0,
+                done: Vec::new(),
+            };
+            return Ok(Hasher::Pieces(
+                Box::new(Hasher::new(&config)?),
+                Box::new(pieces),
+            ));
+        }
+
+        Ok(match config.algorithm {
+            Algorithm::Blake3 => {
+                Hasher::Blake3(Box::new(config.blake3_mode.hasher()), config.length)
+            }
+            Algorithm::Crc32 => Hasher::Crc32(crc32fast::Hasher::new()),
+            #[cfg(feature = "extra-algorithms")]
+            Algorithm::Md5 => Hasher::Md5(md5::Md5::new()),
+            #[cfg(feature = "extra-algorithms")]
+            Algorithm::Sha1 => Hasher::Sha1(sha1::Sha1::new()),
+            #[cfg(feature = "extra-algorithms")]
+            Algorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            Algorithm::Digest(digest) => Hasher::Digest((digest.new)()),
+            #[cfg(not(feature = "extra-algorithms"))]
+            Algorithm::Md5 | Algorithm::Sha1 | Algorithm::Sha256 => {
+                return Err(
+                    format!("{} requires the extra-algorithms feature", config.algorithm).into(),
+                )
+            }
+        })
+    }
+
+    /// Add data using one thread.
+    fn update(&mut self, buf: &[u8]) {
+        #[cfg(feature = "extra-algorithms")]
+        use sha2::Digest;
+
+        match self {
+            Hasher::Blake3(hasher, _) => {
+                hasher.update(buf);
+            }
+            Hasher::Crc32(hasher) => hasher.update(buf),
+            #[cfg(feature = "extra-algorithms")]
+            Hasher::Md5(hasher) => hasher.update(buf),
+            #[cfg(feature = "extra-algorithms")]
+            Hasher::Sha1(hasher) => hasher.update(buf),
+            #[cfg(feature = "extra-algorithms")]
+            Hasher::Sha256(hasher) => hasher.update(buf),
+            Hasher::Digest(hasher) => hasher.update(buf),
+            Hasher::Entropy(hasher, counts) => {
+                count_bytes(counts, buf);
+                hasher.update(buf);
+            }
+            Hasher::Pieces(hasher, pieces) => {
+                pieces.update(buf, false);
+                hasher.update(buf);
+            }
+            #[cfg(feature = "fuzzy")]
+            Hasher::Ssdeep(hasher, ssdeep) => {
+                ssdeep.update(buf);
+                hasher.update(buf);
+            }
+        }
+    }
+
+    /// Add data, computing the checksum with multiple threads if the algorithm allows it.
+    fn update_parallel(&mut self, buf: &[u8]) {
+        match self {
+            Hasher::Blake3(hasher, _) => {
+                hasher.update_with_join::<blake3::join::RayonJoin>(buf);
+            }
+            Hasher::Entropy(hasher, counts) => {
+                count_bytes(counts, buf);
+                hasher.update_parallel(buf);
+            }
+            Hasher::Pieces(hasher, pieces) => {
+                pieces.update(buf, true);
+                hasher.update_parallel(buf);
+            }
+            #[cfg(feature = "fuzzy")]
+            Hasher::Ssdeep(hasher, ssdeep) => {
+                ssdeep.update(buf);
+                hasher.update_parallel(buf);
+            }
+            _ => self.update(buf),
+        }
+    }
+
+    pub(crate) fn finalize(self) -> Checksum {
+        #[cfg(feature = "extra-algorithms")]
+        use sha2::Digest;
+
+        match self {
+            Hasher::Blake3(hasher, length) => {
+                let mut output = vec![0; length];
+                hasher.finalize_xof().fill(&mut output);
+                Checksum(output)
+            }
+            Hasher::Crc32(hasher) => Checksum(hasher.finalize().to_be_bytes().to_vec()),
+            #[cfg(feature = "extra-algorithms")]
+            Hasher::Md5(hasher) => Checksum(hasher.finalize().to_vec()),
+            #[cfg(feature = "extra-algorithms")]
+            Hasher::Sha1(hasher) => Checksum(hasher.finalize().to_vec()),
+            #[cfg(feature = "extra-algorithms")]
+            Hasher::Sha256(hasher) => Checksum(hasher.finalize().to_vec()),
+            Hasher::Digest(hasher) => Checksum(hasher.finalize().into_vec()),
+            Hasher::Entropy(hasher, _) | Hasher::Pieces(hasher, _) => hasher.finalize(),
+            #[cfg(feature = "fuzzy")]
+            Hasher::Ssdeep(hasher, _) => hasher.finalize(),
+        }
+    }
+
+    /// The checksum, with the entropy, pieces or ssdeep hash that were computed along with it.
+    pub(crate) fn finish(self) -> Hashed {
+        match self {
+            Hasher::Entropy(hasher, counts) => Hashed {
+                entropy: Some(shannon_entropy(&counts)),
+                ..hasher.finish()
+            },
+            Hasher::Pieces(hasher, mut pieces) => {
+                if pieces.length > 0 {
+                    pieces.finish_piece();
+                }
+                Hashed {
+                    pieces: pieces.done,
+                    ..hasher.finish()
+                }
+            }
+            #[cfg(feature = "fuzzy")]
+            Hasher::Ssdeep(hasher, ssdeep) => Hashed {
+                ssdeep: ssdeep.finish(),
+                ..hasher.finish()
+            },
+            hasher => hasher.finalize().into(),
+        }
+    }
+}
+
+fn count_bytes(counts: &mut [u64; 256], buf: &[u8]) {
+    for &byte in buf {
+        counts[byte as usize] += 1;
+    }
+}
+
+/// Updates smaller than this are hashed on one thread even by [`SmartHasher`], since BLAKE3 is
+/// slower with multithreading for them.
+const MIN_PARALLEL_UPDATE: usize = 128 * (1 << 10);
+
+/// A checksum computation for data of unknown size, which is fed with [`update`](Self::update)
+/// or [`Write`]. It is hashed on one thread until more than the small file limit has been fed,
+/// and with multithreading after that, as the scheduler does for files.
+pub struct SmartHasher {
+    hasher: Hasher,
+    fed: u64,
+}
+
+impl SmartHasher {
+    /// Fails if [`Config::piecewise`] is 0, if [`Config::ssdeep`] is set without the `fuzzy`
+    /// feature, or if the algorithm is not [available](Algorithm::is_available).
+    pub fn new(config: &Config) -> Result<Self> {
+        Ok(SmartHasher {
+            hasher: Hasher::new(config)?,
+            fed: 0,
+        })
+    }
+
+    /// Add data. Multithreading is only used for BLAKE3, and only for large updates, so data
+    /// should be fed in pieces of a few megabytes where possible.
+    pub fn update(&mut self, buf: &[u8]) -> &mut Self {
+        self.fed += buf.len() as u64;
+        if self.fed > small_file_limit() && buf.len() >= MIN_PARALLEL_UPDATE {
+            self.hasher.update_parallel(buf);
+        } else {
+            self.hasher.update(buf);
+        }
+        self
+    }
+
+    pub fn finalize(self) -> Checksum {
+        self.hasher.finalize()
+    }
+}
+
+impl Write for SmartHasher {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.update(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Something to checksum. Files are preferred, since they can be memory mapped or read by several
+/// threads at once, but anything else that can be read is hashed the same way.
+pub enum Input {
+    File(File),
+    /// A stream, with its size in bytes if it's known. Streams that are known to be small are
+    /// read whole into memory, and others are hashed as they're read.
+    Stream(Box<dyn Read + Send>, Option<u64>),
+}
+
+impl Input {
+    /// A stream that is read from `read`, which has `size` bytes if that's known.
+    pub fn from_reader<R: Read + Send + 'static>(read: R, size: Option<u64>) -> Input {
+        Input::Stream(Box::new(read), size)
+    }
+
+    /// A stream whose size is found by seeking to its end. It's read from its current position.
+    pub fn from_seekable<R: Read + Seek + Send + 'static>(mut read: R) -> Result<Input> {
+        let start = read.stream_position()?;
+        let end = read.seek(SeekFrom::End(0))?;
+        read.seek(SeekFrom::Start(start))?;
+        Ok(Input::Stream(
+            Box::new(read),
+            Some(end.saturating_sub(start)),
+        ))
+    }
+
+    /// The number of bytes that will be read, if it's known.
+    pub fn size_hint(&self) -> Option<u64> {
+        match self {
+            Input::File(file) => file_len(file).ok(),
+            Input::Stream(_, size) => *size,
+        }
+    }
+
+    /// Open a file, decompressing it if `compression` is given.
+    fn open(path: &Path, compression: Option<Compression>) -> Result<Input> {
+        Input::open_listed(path, compression, None, false)
+    }
+
+    /// Like [`Input::open`], but fails if the file isn't the one identified by `listed`. With
+    /// `lock`, a shared lock is taken on the file, which lasts until it is closed.
+    pub(crate) fn open_listed(
+        path: &Path,
+        compression: Option<Compression>,
+        listed: Option<FileId>,
+        lock: bool,
+    ) -> Result<Input> {
+        Input::from_file(open_sequential(path)?, compression, listed, lock)
+    }
+
+    /// Like [`Input::open_listed`], for a file that is already open.
+    pub(crate) fn from_file(
+        file: File,
+        compression: Option<Compression>,
+        listed: Option<FileId>,
+        lock: bool,
+    ) -> Result<Input> {
+        if listed.is_some() && file_id(&file.metadata()?) != listed {
+            return Err("the file was replaced after it was found".into());
+        }
+        if lock {
+            lock_shared(&file)?;
+        }
+        match compression {
+            Some(compression) => Ok(Input::Stream(compression.decoder(Box::new(file))?, None)),
+            None => Ok(Input::File(file)),
+        }
+    }
+}
+
+/// List the alternate data streams of an NTFS file, as paths like `file.txt:stream` that can be
+/// opened. The unnamed stream, which holds the contents of the file, isn't included.
+#[cfg(windows)]
+pub fn alternate_streams(path: &Path) -> Result<Vec<PathBuf>> {
+    use std::{ffi::OsString, os::windows::ffi::OsStringExt};
+
+    // Stream names look like ":name:$DATA", and the unnamed stream is "::$DATA".
+    let suffix: Vec<u16> = ":$DATA".encode_utf16().collect();
+    let mut streams = Vec::new();
+    for name in crate::sys::stream_names(path)? {
+        if name.ends_with(&suffix) && name.len() > suffix.len() + 1 {
+            let mut stream = path.as_os_str().to_owned();
+            stream.push(OsString::from_wide(&name[..name.len() - suffix.len()]));
+            streams.push(PathBuf::from(stream));
+        }
+    }
+    Ok(streams)
+}
+
+/// Alternate data streams only exist on Windows, so other files have none.
+#[cfg(not(windows))]
+pub fn alternate_streams(_path: &Path) -> Result<Vec<PathBuf>> {
+    Ok(Vec::new())
+}
+
+/// Whether [`xattrs_digest`] can read extended attributes on this platform.
+pub const XATTRS_SUPPORTED: bool = crate::sys::XATTRS_SUPPORTED;
+
+/// The BLAKE3 of the extended attributes of a file, for `--xattrs`, so that a security label or
+/// an ACL that was changed can be found even if the contents weren't. On Linux, POSIX ACLs are
+/// extended attributes. On macOS, the ACL is hashed as an attribute named `acl`, in its text form.
+/// The attributes are hashed in the order of their names, each as its name, a NUL byte, the length
+/// of its value as 8 bytes in little-endian order, and the value. A file on a filesystem without
+/// extended attributes has none. Extended attributes are only read on Linux and macOS, and on
+/// Linux, the `trusted.*` attributes are only seen by root, so a digest that's taken as root
+/// differs from one that isn't if the file has any.
+pub fn xattrs_digest(path: &Path) -> Result<Checksum> {
+    xattrs_digest_of(&File::open(path)?)
+}
+
+/// Like [`xattrs_digest`], for a file that's open, such as the one whose contents are read.
+pub(crate) fn xattrs_digest_of(file: &File) -> Result<Checksum> {
+    if !XATTRS_SUPPORTED {
+        return Err("extended attributes are only supported on Linux and macOS".into());
+    }
+    let (enotsup, enodata) = crate::sys::XATTR_ERRORS;
+    let list = match crate::sys::list_xattrs(file) {
+        Ok(list) => list,
+        Err(err) if err.raw_os_error() == Some(enotsup) => Vec::new(),
+        Err(err) => return Err(err.into()),
+    };
+    let mut attributes = Vec::new();
+    for name in list
+        .split(|&byte| byte == 0)
+        .filter(|name| !name.is_empty())
+    {
+        match crate::sys::get_xattr(file, name) {
+            Ok(value) => attributes.push((name.to_vec(), value)),
+            // It was removed after it was listed.
+            Err(err) if err.raw_os_error() == Some(enodata) => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    if let Some(acl) = crate::sys::acl_text(file)? {
+        attributes.push((b"acl".to_vec(), acl));
+    }
+    attributes.sort();
+    let mut hasher = blake3::Hasher::new();
+    for (name, value) in &attributes {
+        hasher.update(name);
+        hasher.update(&[0]);
+        hasher.update(&(value.len() as u64).to_le_bytes());
+        hasher.update(value);
+    }
+    Ok(Checksum(hasher.finalize().as_bytes().to_vec()))
+}
+
+/// A read-only snapshot of a Btrfs subvolume or ZFS dataset, so that files can be read as they
+/// were at one point in time while they are being changed. The snapshot is deleted when this is
+/// dropped.
+pub struct Snapshot {
+    /// The subvolume or dataset, as it was given.
+    root: PathBuf,
+    /// Where the files of the snapshot can be read.
+    dir: PathBuf,
+    /// The command that deletes the snapshot.
+    delete: Vec<String>,
+}
+
+// Magic numbers from linux/magic.h. ZFS isn't part of Linux, so its number is from OpenZFS.
+const BTRFS_SUPER_MAGIC: u32 = 0x9123_683e;
+const ZFS_SUPER_MAGIC: u32 = 0x2fc1_2fc1;
+
+impl Snapshot {
+    /// Snapshot the Btrfs subvolume or ZFS dataset mounted at `root`. This runs the `btrfs` or
+    /// `zfs` command, which usually needs root privileges.
+    pub fn create(root: &Path) -> Result<Snapshot> {
+        let name = format!("b3sum-ng-snapshot-{}", std::process::id());
+        match crate::sys::filesystem_magic(root) {
+            Some(BTRFS_SUPER_MAGIC) => {
+                // The snapshot is made inside the subvolume, so it's on the same filesystem.
+                let dir = root.join(format!(".{}", name));
+                let (root_arg, dir_arg) = (root.to_string_lossy(), dir.to_string_lossy());
+                run_command(&["btrfs", "subvolume", "snapshot", "-r", &root_arg, &dir_arg])?;
+                Ok(Snapshot {
+                    root: root.to_owned(),
+                    delete: vec!["btrfs", "subvolume", "delete", &dir_arg]
+                        .into_iter()
+                        .map(String::from)
+                        .collect(),
+                    dir,
+                })
+            }
+            Some(ZFS_SUPER_MAGIC) => {
+                let output =
+                    run_command(&["zfs", "list", "-H", "-o", "name", &root.to_string_lossy()])?;
+                let snapshot = format!("{}@{}", output.trim(), name);
+                run_command(&["zfs", "snapshot", &snapshot])?;
+                Ok(Snapshot {
+                    root: root.to_owned(),
+                    dir: root.join(".zfs/snapshot").join(&name),
+                    delete: vec!["zfs".to_owned(), "destroy".to_owned(), snapshot],
+                })
+            }
+            _ => Err("snapshots can only be made of Btrfs subvolumes and ZFS datasets".into()),
+        }
+    }
+
+    /// The path in the snapshot of a file under the root. Other paths are returned unchanged.
+    pub fn map(&self, path: &Path) -> PathBuf {
+        move_path(path, &self.root, &self.dir)
+    }
+
+    /// The original path of a file in the snapshot. Other paths are returned unchanged.
+    pub fn unmap(&self, path: &Path) -> PathBuf {
+        move_path(path, &self.dir, &self.root)
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        let args: Vec<&str> = self.delete.iter().map(String::as_str).collect();
+        if let Err(err) = run_command(&args) {
+            print_warning(&format!(
+                "the snapshot {} could not be deleted: {}",
+                self.dir.display(),
+                err
+            ));
+        }
+    }
+}
+
+/// Change the start of `path` from `from` to `to`, if it starts with `from`.
+fn move_path(path: &Path, from: &Path, to: &Path) -> PathBuf {
+    match path.strip_prefix(from) {
+        Ok(rest) => to.join(rest),
+        Err(_) => path.to_owned(),
+    }
+}
+
+/// Run a command, and return its standard output. Its standard error is part of the error if it
+/// fails.
+fn run_command(args: &[&str]) -> Result<String> {
+    let output = std::process::Command::new(args[0])
+        .args(&args[1..])
+        .output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        return Err(format!("{} failed: {}", args.join(" "), stderr.trim()).into());
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+/// A content-addressed store that `--cas-export` copies files into after they're hashed. Each
+/// file is stored once, named by its BLAKE3 digest in hex, in a directory named by the first two
+/// digits, like `ab/cdef...`, so that files with the same contents are only stored once. The
+/// paths of the files are listed with their digests in `index`, a checkfile in the b3sum format,
+/// which is added to by each run.
+pub struct CasExport {
+    dir: PathBuf,
+    index: Mutex<File>,
+}
+
+impl CasExport {
+    /// The name of the index, in the store's directory.
+    pub const INDEX: &'static str = "index";
+
+    /// Open a store, making its directory if it doesn't exist.
+    pub fn open(dir: &Path) -> Result<CasExport> {
+        std::fs::create_dir_all(dir)?;
+        let index = std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(dir.join(CasExport::INDEX))?;
+        Ok(CasExport {
+            dir: dir.to_owned(),
+            index: Mutex::new(index),
+        })
+    }
+
+    /// Where a file with this digest is stored.
+    pub fn path_of(&self, digest: &Checksum) -> PathBuf {
+        let hex = digest.to_string();
+        self.dir.join(&hex[..2]).join(&hex[2..])
+    }
+
+    /// Store the file at `source`, whose digest is `digest`, and list it in the index as `name`.
+    /// The file is copied under a temporary name, and its bytes are hashed as they're copied. The
+    /// copy is only renamed into the store if it has `digest`, so a file that was changed after it
+    /// was hashed is reported instead of being stored under the wrong name, and the store never
+    /// has a partial copy. Stored files are made read-only, since their names are their contents.
+    pub fn export(&self, name: &Path, source: &Path, digest: &Checksum) -> Result<()> {
+        static TEMP_ID: std::sync::atomic::AtomicU32 = std::sync::atomic::AtomicU32::new(0);
+
+        let target = self.path_of(digest);
+        if std::fs::symlink_metadata(&target).is_err() {
+            std::fs::create_dir_all(target.parent().unwrap())?;
+            let mut temp = target.as_os_str().to_owned();
+            let id = TEMP_ID.fetch_add(1, Ordering::Relaxed);
+            temp.push(format!(".{}-{}.tmp", std::process::id(), id));
+            let temp = PathBuf::from(temp);
+            let stored = match copy_hashed(source, &temp) {
+                Ok(hash) if hash.as_bytes()[..] == digest.0[..] => std::fs::rename(&temp, &target),
+                Ok(_) => {
+                    let _ = std::fs::remove_file(&temp);
+                    return Err("the file changed after it was hashed, so it wasn't stored".into());
+                }
+                Err(err) => Err(err),
+            };
+            if let Err(err) = stored {
+                let _ = std::fs::remove_file(&temp);
+                // Another thread may have stored the same contents first.
+                if std::fs::symlink_metadata(&target).is_err() {
+                    return Err(err.into());
+                }
+            }
+        }
+        let entry = crate::checkfile::Entry {
+            name: name.to_string_lossy().into_owned(),
+            checksum: digest.clone(),
+            algorithm: Algorithm::Blake3,
+            size: None,
+            timestamp: None,
+            metadata: None,
+            pieces: Vec::new(),
+        };
+        let line = crate::checkfile::format_line(&entry, crate::output::Format::B3sum)
+            .expect("the b3sum format lists every name");
+        // The line is written at once, so the lines of threads aren't mixed.
+        self.index
+            .lock()
+            .unwrap()
+            .write_all(format!("{}\n", line).as_bytes())?;
+        Ok(())
+    }
+}
+
+/// Copy `source` to a new file at `copy`, which is made read-only, and return the BLAKE3 of the
+/// bytes that were copied.
+fn copy_hashed(source: &Path, copy: &Path) -> std::io::Result<blake3::Hash> {
+    let mut copied = std::fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(copy)?;
+    let mut hasher = blake3::Hasher::new();
+    std::io::copy(
+        &mut TeeReader::new(File::open(source)?, &mut copied),
+        &mut hasher,
+    )?;
+    let mut permissions = copied.metadata()?.permissions();
+    permissions.set_readonly(true);
+    copied.set_permissions(permissions)?;
+    Ok(hasher.finalize())
+}
+
+/// Tell macOS how a file will be read. With `read_ahead`, the whole file is read ahead, which is
+/// done for large files. Other systems detect sequential reads well enough by themselves.
+pub(crate) fn read_hints(file: &File, config: &Config, read_ahead: bool) {
+    if !cfg!(target_os = "macos") {
+        return;
+    }
+    if config.no_cache {
+        crate::sys::no_cache(file);
+    }
+    if read_ahead {
+        if let Ok(metadata) = file.metadata() {
+            crate::sys::advise_will_need(file, 0, metadata.len());
+        }
+    }
+}
+
+/// Reads a file while asking the OS to read the next `window` bytes ahead, for `--readahead`.
+struct ReadAhead {
+    file: File,
+    window: u64,
+    /// How far has been read.
+    offset: u64,
+    /// How far the OS was asked to read.
+    advised: u64,
+}
+
+impl ReadAhead {
+    fn new(file: File, window: u64) -> ReadAhead {
+        ReadAhead {
+            file,
+            window,
+            offset: 0,
+            advised: 0,
+        }
+    }
+}
+
+impl Read for ReadAhead {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        // The advice is renewed when less than half of the window is left, rather than on every
+        // read.
+        if self.advised < self.offset + self.window / 2 {
+            let start = self.advised.max(self.offset);
+            let end = self.offset + self.window;
+            crate::sys::advise_will_need(&self.file, start, end - start);
+            self.advised = end;
+        }
+        let bytes_read = self.file.read(buf)?;
+        self.offset += bytes_read as u64;
+        Ok(bytes_read)
+    }
+}
+
+/// Open a file to be read once from start to end. On Windows, this tells the cache manager to read
+/// ahead aggressively, which makes large reads from NTFS much faster.
+pub(crate) fn open_sequential(path: &Path) -> std::io::Result<File> {
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::OpenOptionsExt;
+        // From winbase.h.
+        const FILE_FLAG_SEQUENTIAL_SCAN: u32 = 0x0800_0000;
+        std::fs::OpenOptions::new()
+            .read(true)
+            .custom_flags(FILE_FLAG_SEQUENTIAL_SCAN)
+            .open(path)
+    }
+    #[cfg(not(windows))]
+    {
+        File::open(path)
+    }
+}
+
+/// The bytes of a checksum. Displayed as lowercase hex, or uppercase hex with `{:X}`.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct Checksum(pub Vec<u8>);
+/// Write `bytes` in hex with these digits. This is much faster than formatting each byte, which
+/// matters when millions of checksums are printed: the digits are looked up, and written a block
+/// at a time.
+fn write_hex(f: &mut fmt::Formatter<'_>, bytes: &[u8], digits: &[u8; 16]) -> fmt::Result {
+    let mut buf = [0u8; 128];
+    for block in bytes.chunks(buf.len() / 2) {
+        for (byte, hex) in block.iter().zip(buf.chunks_mut(2)) {
+            hex[0] = digits[usize::from(byte >> 4)];
+            hex[1] = digits[usize::from(byte & 0xf)];
+        }
+        let hex = &buf[..block.len() * 2];
+        f.write_str(std::str::from_utf8(hex).expect("hex digits are ASCII"))?;
+    }
+    Ok(())
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_hex(f, &self.0, b"0123456789abcdef")
+    }
+}
+impl fmt::UpperHex for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write_hex(f, &self.0, b"0123456789ABCDEF")
+    }
+}
+impl Checksum {
+    /// Compare two checksums in constant time, so that the time taken doesn't reveal how many
+    /// bytes matched.
+    pub fn constant_time_eq(&self, other: &Checksum) -> bool {
+        self.0.len() == other.0.len()
+            && self
+                .0
+                .iter()
+                .zip(&other.0)
+                .fold(0, |difference, (a, b)| difference | (a ^ b))
+                == 0
+    }
+}
+
+/// Checksums are written as lowercase hex, and read from hex of either case.
+#[cfg(feature = "serde")]
+impl serde::Serialize for Checksum {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> serde::Deserialize<'de> for Checksum {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        hex.parse().map_err(serde::de::Error::custom)
+    }
+}
+
+impl FromStr for Checksum {
+    type Err = String;
+
+    /// Parse a checksum from hex digits of either case.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s.is_empty() || s.len() % 2 != 0 || !s.bytes().all(|b| b.is_ascii_hexdigit()) {
+            return Err(format!("invalid checksum: {}", s));
+        }
+        Ok(Checksum(
+            (0..s.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(&s[i..i + 2], 16).unwrap())
+                .collect(),
+        ))
+    }
+}
+
+#[test]
+fn b3_test_bytes() -> Result<()> {
+    assert_eq!(
+        "d74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24",
+        &format!(
+            "{}",
+            b3sum_small(
+                &mut std::io::Cursor::new(b"hello world"),
+                &Config::default()
+            )?
+        )
+    );
+    Ok(())
+}
+
+#[test]
+fn b3_test_bytes_empty() -> Result<()> {
+    assert_eq!(
+        "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262",
+        &format!(
+            "{}",
+            b3sum_small(&mut std::io::Cursor::new(b""), &Config::default())?
+        )
+    );
+    Ok(())
+}
+
+#[test]
+fn b3_test_file_small() -> Result<()> {
+    let (mut file, _path, _guard) = make_temp_file(b"hello world");
+    assert_eq!(
+        "d74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24",
+        &format!("{}", b3sum_small(&mut file, &Config::default())?)
+    );
+    Ok(())
+}
+
+#[test]
+fn b3_test_file_large() -> Result<()> {
+    let (file, _path, _guard) = make_temp_file(b"hello world");
+    assert_eq!(
+        "d74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24",
+        &format!("{}", b3sum_large(Input::File(file), &Config::default())?)
+    );
+    Ok(())
+}
+
+#[test]
+fn b3_test_file_large_2() -> Result<()> {
+    let (file, _path, _guard) = make_temp_file(&vec![0u8; 20_971_520]);
+    assert_eq!(
+        "bea89379ccc6ac7c6e1a2924643665501a7a6427877f2c6764f9813f8c9330b4",
+        &format!("{}", b3sum_large(Input::File(file), &Config::default())?)
+    );
+    Ok(())
+}
+
+#[test]
+fn b3_test_file_mmap() -> Result<()> {
+    let (file, _path, _guard) = make_temp_file(b"hello world");
+    assert_eq!(
+        "d74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24",
+        &format!(
+            "{}",
+            b3sum_large(
+                Input::File(file),
+                &Config {
+                    use_mmap: true,
+                    ..Config::default()
+                }
+            )?
+        )
+    );
+    Ok(())
+}
+
+#[test]
+fn b3_test_file_mmap_2() -> Result<()> {
+    let (file, _path, _guard) = make_temp_file(&vec![0u8; 20_971_520]);
+    assert_eq!(
+        "bea89379ccc6ac7c6e1a2924643665501a7a6427877f2c6764f9813f8c9330b4",
+        &format!(
+            "{}",
+            b3sum_large(
+                Input::File(file),
+                &Config {
+                    use_mmap: true,
+                    ..Config::default()
+                }
+            )?
+        )
+    );
+    Ok(())
+}
+
+#[test]
+/// Test that several files can be opened, some of which may be opened in background threads.
+fn b3_test_file_no_error_1() -> Result<()> {
+    let buffers: Vec<Vec<u8>> = vec![
+        vec![0u8; 20_971_520],
+        (b"hello, world").iter().map(|b| *b).collect(),
+        (b"hello, world").iter().map(|b| *b).collect(),
+        (b"hello, world").iter().map(|b| *b).collect(),
+        vec![],
+        vec![0u8; 900_00],
+        vec![0u8; 900_00],
+        vec![0u8; 900_00],
+        vec![0u8; 20_971_520],
+        vec![0u8; 900_00],
+        vec![0u8; 900_00],
+    ];
+    let temp_files: Vec<_> = buffers
+        .into_iter()
+        .map(|buf| make_temp_file(&buf))
+        // Close open handles
+        .map(|(file, path, guard)| {
+            drop(file);
+            (path, guard)
+        })
+        .collect();
+
+    let semaphore = Arc::new(Semaphore::new(16));
+    rayon::scope(|s| {
+        for (path, _) in &temp_files {
+            assert!(do_checksum(path.to_owned(), 16, Arc::clone(&semaphore), true, s).is_ok());
+        }
+        for (path, _) in &temp_files {
+            assert!(do_checksum(path.to_owned(), 16, Arc::clone(&semaphore), false, s).is_ok());
+        }
+    });
+    Ok(())
+}
+
+#[test]
+fn b3_test_crc32() -> Result<()> {
+    assert_eq!(
+        "0D4A1185",
+        &format!(
+            "{:X}",
+            b3sum_small(
+                &mut std::io::Cursor::new(b"hello world"),
+                &Config {
+                    algorithm: Algorithm::Crc32,
+                    ..Config::default()
+                }
+            )?
+        )
+    );
+    Ok(())
+}
+
+#[test]
+fn b3_test_digest_algorithm() -> Result<()> {
+    /// The XOR of all bytes, which is enough to show that the digest is used.
+    #[derive(Clone, Default)]
+    struct Xor(u8);
+    impl digest::DynDigest for Xor {
+        fn update(&mut self, data: &[u8]) {
+            self.0 = data.iter().fold(self.0, |xor, byte| xor ^ byte);
+        }
+        fn finalize_reset(&mut self) -> Box<[u8]> {
+            Box::new([std::mem::take(&mut self.0)])
+        }
+        fn finalize(self: Box<Self>) -> Box<[u8]> {
+            Box::new([self.0])
+        }
+        fn reset(&mut self) {
+            self.0 = 0;
+        }
+        fn output_size(&self) -> usize {
+            1
+        }
+        fn box_clone(&self) -> Box<dyn digest::DynDigest> {
+            Box::new(self.clone())
+        }
+    }
+
+    let algorithm = Algorithm::Digest(DigestAlgorithm::of::<Xor>("XOR"));
+    assert_eq!("XOR", algorithm.to_string());
+    assert!(algorithm.is_available());
+    let config = Config {
+        algorithm,
+        ..Config::default()
+    };
+    assert_eq!(
+        "20",
+        b3sum_small(&mut &b"hello world"[..], &config)?.to_string()
+    );
+    let (file, _path, _guard) = make_temp_file(b"hello world");
+    assert_eq!("20", b3sum_large(Input::File(file), &config)?.to_string());
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "extra-algorithms")]
+fn b3_test_legacy_algorithms() -> Result<()> {
+    let hash = |algorithm| -> Result<String> {
+        let config = Config {
+            algorithm,
+            ..Config::default()
+        };
+        Ok(b3sum_small(&mut std::io::Cursor::new(b"hello world"), &config)?.to_string())
+    };
+    assert_eq!("5eb63bbbe01eeed093cb22bb8f5acdc3", hash(Algorithm::Md5)?);
+    assert_eq!(
+        "2aae6c35c94fcfb415dbe95f408b9ce91ee846ed",
+        hash(Algorithm::Sha1)?
+    );
+    assert_eq!(
+        "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9",
+        hash(Algorithm::Sha256)?
+    );
+
+    let (_file, path, _guard) = make_temp_file(b"hello world");
+    let checkfile = format!("5eb63bbbe01eeed093cb22bb8f5acdc3  {}\n", path.display());
+    let (_file, checkfile, _checkfile_guard) = make_temp_file(checkfile.as_bytes());
+    assert!(check(
+        &[checkfile],
+        &Config::default(),
+        Arc::new(Semaphore::new(16)),
+        Format::B3sum,
+        &VerifyOptions::default()
+    ));
+    Ok(())
+}
+
+#[test]
+fn b3_test_tar_members() -> Result<()> {
+    let mut builder = tar::Builder::new(Vec::new());
+    let mut header = tar::Header::new_ustar();
+    header.set_entry_type(tar::EntryType::dir());
+    header.set_size(0);
+    builder.append_data(&mut header, "dir/", std::io::empty())?;
+    for (name, contents) in &[("dir/hello.txt", &b"hello world"[..]), ("empty", b"")] {
+        let mut header = tar::Header::new_ustar();
+        header.set_size(contents.len() as u64);
+        builder.append_data(&mut header, name, *contents)?;
+    }
+    let (_file, path, _guard) = make_temp_file(&builder.into_inner()?);
+
+    let mut results = Vec::new();
+    checksum_archive(
+        &path,
+        ArchiveFormat::Tar,
+        &Config::default(),
+        &Semaphore::new(16),
+        |path, hashed| results.push((path.to_owned(), hashed.unwrap().checksum.to_string())),
+    )?;
+    assert_eq!(
+        vec![
+            (
+                archive_member_path(&path, Path::new("dir/hello.txt")),
+                "d74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24".to_owned()
+            ),
+            (
+                archive_member_path(&path, Path::new("empty")),
+                "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262".to_owned()
+            ),
+        ],
+        results
+    );
+
+    // The temporary file has no extension, so it isn't taken for an archive.
+    let auto = checksum_archive(
+        &path,
+        ArchiveFormat::Auto,
+        &Config::default(),
+        &Semaphore::new(16),
+        |_, _| panic!("not an archive"),
+    );
+    assert!(auto.is_err());
+    Ok(())
+}
+
+#[test]
+fn b3_test_zip_members() -> Result<()> {
+    use std::io::Write;
+
+    let options =
+        zip::write::FileOptions::default().compression_method(zip::CompressionMethod::Stored);
+    let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    writer.add_directory("dir", options)?;
+    writer.start_file("dir/hello.txt", options)?;
+    writer.write_all(b"hello world")?;
+    let (_file, path, _guard) = make_temp_file(&writer.finish()?.into_inner());
+
+    let mut results = Vec::new();
+    checksum_archive(
+        &path,
+        ArchiveFormat::Zip,
+        &Config::default(),
+        &Semaphore::new(16),
+        |path, hashed| results.push((path.to_owned(), hashed.unwrap().checksum.to_string())),
+    )?;
+    assert_eq!(
+        vec![(
+            archive_member_path(&path, Path::new("dir/hello.txt")),
+            "d74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24".to_owned()
+        )],
+        results
+    );
+    Ok(())
+}
+
+#[test]
+#[cfg(feature = "decompress")]
+fn b3_test_decompress() -> Result<()> {
+    use std::io::Write;
+
+    let mut encoder = flate2::write::GzEncoder::new(Vec::new(), flate2::Compression::default());
+    encoder.write_all(b"hello world")?;
+    let (_file, path, guard) = make_temp_file(&encoder.finish()?);
+    // Compressed files are recognized by their extension.
+    let gz_path = path.with_extension("gz");
+    std::fs::rename(&path, &gz_path)?;
+    std::mem::forget(guard);
+    let _guard = TempFileGuard {
+        filename: gz_path.clone(),
+    };
+
+    let config = Config {
+        decompress: true,
+        ..Config::default()
+    };
+    let semaphore = Arc::new(Semaphore::new(16));
+    let mut results = Vec::new();
+    rayon::scope(|s| {
+        checksum_with(gz_path, &config, semaphore, s, |_, hashed| {
+            results.push(hashed.unwrap().checksum.to_string())
+        })
+        .unwrap();
+    });
+    assert_eq!(
+        vec!["d74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24".to_owned()],
+        results
+    );
+    Ok(())
+}
+
+#[test]
+fn b3_test_resolve_archive() {
+    let resolve = |path, decompress| ArchiveFormat::Auto.resolve(Path::new(path), decompress);
+    assert_eq!(Some(ArchiveFormat::Tar), resolve("backup.tar", false));
+    assert_eq!(Some(ArchiveFormat::Zip), resolve("release.ZIP", false));
+    assert_eq!(None, resolve("backup.tar.gz", false));
+    assert_eq!(Some(ArchiveFormat::Tar), resolve("backup.tar.gz", true));
+    assert_eq!(Some(ArchiveFormat::Tar), resolve("backup.tgz", true));
+    assert_eq!(None, resolve("notes.txt.xz", true));
+}
+
+#[test]
+fn b3_test_copy_and_verify() -> Result<()> {
+    let (_file, src, _guard) = make_temp_file(b"hello world");
+    let dst = src.with_extension("copy");
+    let _dst_guard = TempFileGuard {
+        filename: dst.clone(),
+    };
+    let (copy, checksum) = copy_and_verify(&src, &dst, &Config::default())?;
+    assert_eq!(dst, copy);
+    assert_eq!(
+        "d74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24",
+        checksum.to_string()
+    );
+    assert_eq!(b"hello world".to_vec(), std::fs::read(&dst)?);
+    assert!(copy_and_verify(&src, &src, &Config::default()).is_err());
+    Ok(())
+}
+
+#[test]
+fn b3_test_copy_to_hard_link() -> Result<()> {
+    let (_file, src, _guard) = make_temp_file(b"hello world");
+    let link = src.with_extension("link");
+    let _link_guard = TempFileGuard {
+        filename: link.clone(),
+    };
+    std::fs::hard_link(&src, &link)?;
+    // Elsewhere, the files can't be told apart.
+    if cfg!(any(unix, all(feature = "sys", windows))) {
+        assert!(copy_and_verify(&src, &link, &Config::default()).is_err());
+        assert_eq!(b"hello world".to_vec(), std::fs::read(&src)?);
+    }
+    Ok(())
+}
+
+#[test]
+fn b3_test_blocks() -> Result<()> {
+    let data: Vec<u8> = (0..2500u32).map(|i| (i % 251) as u8).collect();
+    let (_file, path, _guard) = make_temp_file(&data);
+    let mut blocks = Vec::new();
+    checksum_blocks(&path, &Config::default(), 1000, |block| {
+        blocks.push(block.unwrap())
+    })?;
+    let expected: Vec<Chunk> = [(0, 1000), (1000, 1000), (2000, 500)]
+        .iter()
+        .map(|&(offset, length)| block_of(offset as u64, &data[offset..offset + length]))
+        .collect();
+    assert_eq!(expected, blocks);
+    assert_eq!(
+        blake3::hash(&data[1000..2000]).as_bytes()[..],
+        blocks[1].checksum.0[..]
+    );
+
+    let (_file, empty, _guard) = make_temp_file(b"");
+    checksum_blocks(&empty, &Config::default(), 1000, |_| panic!("empty file"))?;
+    assert!(checksum_blocks(&path, &Config::default(), 0, |_| panic!("empty block")).is_err());
+    Ok(())
+}
+
+#[test]
+fn b3_test_chunks() -> Result<()> {
+    // Pseudo-random data, so that chunk boundaries are found.
+    let mut state = 1u32;
+    let data: Vec<u8> = (0..2_000_000)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect();
+    let chunks_of = |contents: &[u8]| {
+        let (_file, path, _guard) = make_temp_file(contents);
+        let mut chunks = Vec::new();
+        checksum_chunks(&path, &Config::default(), |chunk| chunks.push(chunk)).unwrap();
+        chunks
+    };
+
+    let chunks = chunks_of(&data);
+    assert!(chunks.len() > 2);
+    let mut offset = 0;
+    for (i, chunk) in chunks.iter().enumerate() {
+        assert_eq!(offset, chunk.offset);
+        assert!(chunk.length <= CHUNK_MAX);
+        assert!(chunk.length >= CHUNK_MIN || i == chunks.len() - 1);
+        offset += chunk.length as u64;
+    }
+    assert_eq!(data.len() as u64, offset);
+
+    // Inserting data near the start should only change the first chunk.
+    let mut changed = b"inserted".to_vec();
+    changed.extend_from_slice(&data);
+    let changed_chunks = chunks_of(&changed);
+    assert_ne!(chunks[0].checksum, changed_chunks[0].checksum);
+    let checksums = |chunks: &[Chunk]| {
+        chunks[1..]
+            .iter()
+            .map(|c| c.checksum.clone())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(checksums(&chunks), checksums(&changed_chunks));
+    Ok(())
+}
+
+#[test]
+fn b3_test_blake3_modes() -> Result<()> {
+    let hash = |config: Config| -> Result<String> {
+        Ok(b3sum_small(&mut std::io::Cursor::new(b"hello world"), &config)?.to_string())
+    };
+    let key = *b"whats the Elvish word for friend";
+    assert_eq!(
+        blake3::keyed_hash(&key, b"hello world")
+            .to_hex()
+            .to_string(),
+        hash(Config {
+            blake3_mode: Blake3Mode::Keyed(key),
+            ..Config::default()
+        })?
+    );
+    assert_eq!(
+        hex(&blake3::derive_key("b3sum-ng test context", b"hello world")),
+        hash(Config {
+            blake3_mode: Blake3Mode::DeriveKey("b3sum-ng test context".into()),
+            ..Config::default()
+        })?
+    );
+    let long = hash(Config {
+        length: 64,
+        ..Config::default()
+    })?;
+    assert_eq!(128, long.len());
+    assert!(long.starts_with(&hash(Config::default())?));
+    assert_eq!(
+        "d74981efa70a0c88",
+        hash(Config {
+            length: 8,
+            ..Config::default()
+        })?
+    );
+
+    assert_eq!(key, read_key(&mut &key[..])?);
+    assert!(read_key(&mut &key[..31]).is_err());
+    assert!(read_key(&mut &b"whats the Elvish word for friend?"[..]).is_err());
+    Ok(())
+}
+
+#[test]
+fn b3_test_expect() -> Result<()> {
+    let (_file, path, _guard) = make_temp_file(b"hello world");
+    let expected: Checksum =
+        "D74981EFA70A0C880B8D8C1985D075DBCBF679B99A5F9914E5AAF96B831A9E24".parse()?;
+    assert!(matches_expected(&path, &Config::default(), &expected)?);
+    let mut wrong = expected.clone();
+    wrong.0[31] ^= 1;
+    assert!(!matches_expected(&path, &Config::default(), &wrong)?);
+    wrong.0.truncate(16);
+    assert!(!matches_expected(&path, &Config::default(), &wrong)?);
+    Ok(())
+}
+
+#[test]
+fn b3_test_find_duplicates() -> Result<()> {
+    let root = std::env::temp_dir().join(format!("b3sum-dup-{}", std::process::id()));
+    let _guard = TempDirGuard { dir: root.clone() };
+    for (path, contents) in &[
+        ("a.txt", "hello world"),
+        ("sub/b.txt", "hello world"),
+        ("sub/c.txt", "hello World"),
+        ("d.txt", "different"),
+        ("empty-1", ""),
+        ("empty-2", ""),
+    ] {
+        let path = root.join(path);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(path, contents)?;
+    }
+
+    let mut duplicates = Vec::new();
+    find_duplicates(
+        std::slice::from_ref(&root),
+        &Config::default(),
+        Arc::new(Semaphore::new(16)),
+        true,
+        |checksum, files| {
+            let paths = files.iter().map(|file| file.path.clone()).collect();
+            duplicates.push((checksum.to_string(), paths))
+        },
+    );
+    assert_eq!(
+        vec![(
+            "d74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24".to_owned(),
+            vec![root.join("a.txt"), root.join("sub/b.txt")]
+        )],
+        duplicates
+    );
+    // Files that were just written don't share their data.
+    assert_eq!(None, shared_extents(&root.join("a.txt")));
+    Ok(())
+}
+
+#[test]
+fn b3_test_benchmark() -> Result<()> {
+    let (_file, path, _guard) = make_temp_file(&[0; 1000]);
+    let measurements = benchmark(&path, &Config::default())?;
+    let strategies: Vec<_> = measurements.iter().map(|m| m.strategy).collect();
+    let mut expected = vec!["in memory", "buffered"];
+    if MMAP_SUPPORTED {
+        expected.push("mmap");
+    }
+    assert_eq!(expected, strategies);
+    assert!(measurements.iter().all(|m| m.bytes == 1000));
+    Ok(())
+}
+
+#[test]
+fn b3_test_cas_export() -> Result<()> {
+    let root = std::env::temp_dir().join(format!("b3sum-cas-{}", std::process::id()));
+    let _guard = TempDirGuard { dir: root.clone() };
+    std::fs::create_dir_all(root.join("files"))?;
+    for (name, contents) in &[("a", "hello world"), ("b", "hello world"), ("c", "other")] {
+        std::fs::write(root.join("files").join(name), contents)?;
+    }
+
+    let store = CasExport::open(&root.join("store"))?;
+    let digest_of = |contents: &[u8]| Checksum(blake3::hash(contents).as_bytes().to_vec());
+    for name in &["a", "b", "c"] {
+        let path = root.join("files").join(name);
+        store.export(Path::new(name), &path, &digest_of(&std::fs::read(&path)?))?;
+    }
+    let digest = digest_of(b"hello world");
+    let stored = store.path_of(&digest);
+    assert_eq!(stored, root.join("store/d7").join(&digest.to_string()[2..]));
+    assert_eq!(b"hello world", &std::fs::read(&stored)?[..]);
+    assert!(std::fs::metadata(&stored)?.permissions().readonly());
+    // The index lists every file, but the store has each of the contents once.
+    let index = std::fs::read_to_string(root.join("store").join(CasExport::INDEX))?;
+    assert_eq!(3, index.lines().count());
+    assert!(index.contains(&format!("{}  b\n", digest)));
+    let stored_dirs = std::fs::read_dir(root.join("store"))?.count();
+    assert_eq!(3, stored_dirs, "two digests and the index");
+
+    // A file that changed after it was hashed isn't stored under the digest it had.
+    let changed = root.join("files").join("c");
+    let old_digest = digest_of(b"before");
+    assert!(store.export(Path::new("c"), &changed, &old_digest).is_err());
+    assert!(std::fs::symlink_metadata(store.path_of(&old_digest)).is_err());
+    assert_eq!(
+        3,
+        std::fs::read_to_string(root.join("store").join(CasExport::INDEX))?
+            .lines()
+            .count()
+    );
+    Ok(())
+}
+
+#[cfg(all(feature = "sys", any(target_os = "linux", target_os = "android")))]
+#[test]
+fn b3_test_xattrs_digest() -> Result<()> {
+    let (_file, path, _guard) = make_temp_file(b"hello world");
+    let before = xattrs_digest(&path)?;
+    assert_eq!(before, xattrs_digest(&path)?);
+    // Some filesystems, such as tmpfs on older kernels, have no user attributes.
+    if crate::sys::set_xattr(&path, "user.b3sum-test", b"label").is_ok() {
+        assert_ne!(before, xattrs_digest(&path)?);
+    }
+    assert!(xattrs_digest(Path::new("/nonexistent/b3sum-xattrs")).is_err());
+    Ok(())
+}
+
+#[test]
+fn b3_test_move_path() {
+    let from = Path::new("/data");
+    let to = Path::new("/data/.snapshot");
+    assert_eq!(
+        to.join("a/b.txt"),
+        move_path(Path::new("/data/a/b.txt"), from, to)
+    );
+    assert_eq!(
+        PathBuf::from("/other"),
+        move_path(Path::new("/other"), from, to)
+    );
+}
+
+#[test]
+#[cfg(all(feature = "mmap", not(target_os = "wasi")))]
+fn b3_test_mmap_windows() -> Result<()> {
+    let contents: Vec<u8> = (0..1_000_000).map(|i| (i % 251) as u8).collect();
+    let (file, _, _guard) = make_temp_file(&contents);
+    let config = Config::default();
+    let mut whole = Hasher::new(&config)?;
+    whole.update(&contents);
+    // Windows that don't line up with pages or with the end of the file.
+    let mut hasher = Hasher::new(&config)?;
+    hash_mmap(&file, &mut hasher, 300_000)?;
+    assert_eq!(whole.finalize(), hasher.finalize());
+    Ok(())
+}
+
+#[test]
+#[cfg(all(feature = "sys", windows))]
+fn b3_test_unbuffered_reads() -> Result<()> {
+    // Blocks are 8 MiB, so this ends with a short block, and the empty file with an empty one.
+    for &len in &[0, 17_000_000] {
+        let contents: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        let (file, _, _guard) = make_temp_file(&contents);
+        let config = Config::default();
+        let mut whole = Hasher::new(&config)?;
+        whole.update(&contents);
+        let mut hasher = Hasher::new(&config)?;
+        read_unbuffered(&file, &mut |block| hasher.update(block))?;
+        assert_eq!(whole.finalize(), hasher.finalize());
+    }
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn b3_test_replaced_file() -> Result<()> {
+    let (_, first, _guard) = make_temp_file(b"first");
+    let (_, second, _guard) = make_temp_file(b"second");
+    let listed = file_id(&metadata(&first)?);
+    assert!(Input::open_listed(&first, None, listed, false).is_ok());
+    assert!(Input::open_listed(&second, None, listed, false).is_err());
+    Ok(())
+}
+
+#[test]
+#[cfg(unix)]
+fn b3_test_listed_open() -> Result<()> {
+    use std::os::unix::fs::symlink;
+
+    let root = std::env::temp_dir().join(format!("b3sum-listed-{}", std::process::id()));
+    let _guard = TempDirGuard { dir: root.clone() };
+    std::fs::create_dir_all(root.join("tree/sub"))?;
+    std::fs::create_dir_all(root.join("elsewhere"))?;
+    std::fs::write(root.join("tree/sub/file.txt"), b"inside")?;
+    std::fs::write(root.join("elsewhere/file.txt"), b"inside")?;
+    symlink("../elsewhere", root.join("tree/link"))?;
+
+    let list = |path: &Path| {
+        let mut files = Vec::new();
+        assert!(list_files(path, &mut |listed, _| files.push(listed)));
+        files
+    };
+    let files = list(&root.join("tree"));
+    assert_eq!(1, files.len());
+    assert_eq!(root.join("tree/sub/file.txt"), files[0].path());
+    let opened = files[0].open()?;
+    assert_eq!(files[0].id(), file_id(&opened.metadata()?));
+
+    // The path that's searched is followed if it's a link.
+    symlink("tree", root.join("tree-link"))?;
+    symlink("tree/sub/file.txt", root.join("file-link"))?;
+    for link in &["tree-link", "file-link"] {
+        let linked = list(&root.join(link));
+        assert_eq!(1, linked.len());
+        assert_eq!(files[0].id(), file_id(&linked[0].open()?.metadata()?));
+    }
+
+    // A directory on the way that's replaced by a link isn't followed out of the tree.
+    std::fs::rename(root.join("tree/sub"), root.join("tree/moved"))?;
+    symlink("../elsewhere", root.join("tree/sub"))?;
+    if OPEN_AT_SUPPORTED {
+        assert!(files[0].open().is_err());
+    }
+    // Without openat, the file at the end of the link is opened, but it isn't the one found.
+    let hashed = Mutex::new(None);
+    rayon::scope(|s| {
+        checksum_listed(
+            files[0].path(),
+            Some(files[0].clone()),
+            None,
+            &Config::default(),
+            Arc::new(Semaphore::new(1)),
+            s,
+            |_, result| *hashed.lock().unwrap() = Some(result.is_ok()),
+        )
+        .unwrap();
+    });
+    assert_eq!(Some(false), hashed.into_inner().unwrap());
+    Ok(())
+}
+
+#[test]
+#[cfg(all(feature = "sys", any(unix, windows)))]
+fn b3_test_lock_shared() -> Result<()> {
+    let (_, path, _guard) = make_temp_file(b"hello world");
+    // Shared locks don't exclude each other.
+    let first = File::open(&path)?;
+    lock_shared(&first)?;
+    let second = File::open(&path)?;
+    lock_shared(&second)?;
+    Ok(())
+}
+
+#[test]
+#[cfg(all(feature = "sys", any(unix, windows)))]
+fn b3_test_run_lock() -> Result<()> {
+    let (_, path, _guard) = make_temp_file(b"");
+    let lock = RunLock::acquire(&path, false)?.unwrap();
+    assert_eq!(
+        format!("{}\n", std::process::id()),
+        std::fs::read_to_string(&path)?
+    );
+    // The lock is for each open file, so it excludes other locks in this process too.
+    assert!(RunLock::acquire(&path, false)?.is_none());
+    assert!(lock_shared(&File::open(&path)?).is_err());
+    drop(lock);
+    assert!(RunLock::acquire(&path, true)?.is_some());
+    Ok(())
+}
+
+#[test]
+fn b3_test_read_stable() -> Result<()> {
+    let (_, path, _guard) = make_temp_file(b"hello world");
+    let config = Config {
+        verify_stable: Some(2),
+        ..Config::default()
+    };
+    let read = || hash_small(&mut File::open(&path)?, &config);
+    let before = SystemTime::now();
+    let hashed = read_stable(&path, &config, &read, &read)?;
+    assert_eq!(read()?.checksum, hashed.checksum);
+    assert!(hashed.computed >= before && hashed.computed <= SystemTime::now());
+
+    // A file that grows every time it's read.
+    let changing = || {
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&path)?
+            .write_all(b"!")?;
+        read()
+    };
+    assert!(read_stable(&path, &config, &changing, &read).is_err());
+    assert_eq!(b"hello world!!!".len() as u64, metadata(&path)?.len());
+
+    // The second read is only compared with --paranoid.
+    let corrupted = || {
+        let mut hashed = read()?;
+        hashed.checksum.0[0] ^= 1;
+        Ok(hashed)
+    };
+    assert!(read_stable(&path, &config, &read, &corrupted).is_ok());
+    let paranoid = Config {
+        paranoid: true,
+        ..config.clone()
+    };
+    let err = read_stable(&path, &paranoid, &read, &corrupted).unwrap_err();
+    assert!(err.to_string().contains("read twice"), "{}", err);
+    assert!(read_stable(&path, &paranoid, &read, &read).is_ok());
+    Ok(())
+}
+
+#[test]
+#[cfg(any(unix, windows))]
+fn b3_test_parallel_reads() -> Result<()> {
+    let contents: Vec<u8> = (0..3_500_000).map(|i| (i % 253) as u8).collect();
+    let (file, _, _guard) = make_temp_file(&contents);
+    let config = Config::default();
+    let mut whole = Hasher::new(&config)?;
+    whole.update(&contents);
+    // Several windows, and pieces that don't divide them evenly.
+    let mut hasher = Hasher::new(&config)?;
+    hash_parallel_reads(&file, &mut hasher, 1_500_000)?;
+    assert_eq!(whole.finalize(), hasher.finalize());
+    Ok(())
+}
+
+#[test]
+fn b3_test_hash_pipelined() -> Result<()> {
+    let contents: Vec<u8> = (0..1_000_000).map(|i| (i % 251) as u8).collect();
+    let config = Config::default();
+    let mut whole = Hasher::new(&config)?;
+    whole.update(&contents);
+    // Many more buffers than the pipeline holds, and a partial one at the end.
+    let hasher = hash_pipelined(&mut &contents[..], Hasher::new(&config)?, 30_000)?;
+    assert_eq!(whole.finalize(), hasher.finalize());
+
+    struct Failing;
+    impl Read for Failing {
+        fn read(&mut self, _: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::ErrorKind::Other.into())
+        }
+    }
+    assert!(hash_pipelined(&mut Failing, Hasher::new(&config)?, 1000).is_err());
+    Ok(())
+}
+
+#[test]
+fn b3_test_read_ahead() -> Result<()> {
+    let contents: Vec<u8> = (0..100_000).map(|i| (i % 249) as u8).collect();
+    let (file, _, _guard) = make_temp_file(&contents);
+    let mut read = ReadAhead::new(file, 4096);
+    let mut buf = [0; 1000];
+    read.read_exact(&mut buf)?;
+    assert_eq!(4096, read.advised);
+    read.read_exact(&mut buf)?;
+    read.read_exact(&mut buf)?;
+    assert_eq!(4096, read.advised);
+    // Less than half of the window is left, so the advice is renewed.
+    read.read_exact(&mut buf)?;
+    assert_eq!(3000 + 4096, read.advised);
+    let mut rest = Vec::new();
+    read.read_to_end(&mut rest)?;
+    assert_eq!(&contents[4000..], &rest[..]);
+    Ok(())
+}
+
+#[test]
+fn b3_test_tree_digest() -> Result<()> {
+    let make_tree = |name: &str, b: &[u8]| -> Result<PathBuf> {
+        let root = std::env::temp_dir().join(format!("b3sum-tree-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(root.join("dir/sub"))?;
+        std::fs::create_dir_all(root.join("empty"))?;
+        std::fs::write(root.join("a"), b"a")?;
+        std::fs::write(root.join("dir/b"), b)?;
+        std::fs::write(root.join("dir/sub/c"), b"c")?;
+        Ok(root)
+    };
+    let first = make_tree("first", b"b")?;
+    let _first_guard = TempDirGuard { dir: first.clone() };
+    let second = make_tree("second", b"b")?;
+    let _second_guard = TempDirGuard {
+        dir: second.clone(),
+    };
+    let io_lock = Arc::new(Semaphore::new(16));
+    let digest = |root: &Path| tree_digest(root, &Config::default(), Arc::clone(&io_lock)).unwrap();
+    assert_eq!(digest(&first), digest(&second));
+    // Empty directories are left out.
+    std::fs::remove_dir(second.join("empty"))?;
+    assert_eq!(digest(&first), digest(&second));
+
+    std::fs::write(second.join("dir/b"), b"B")?;
+    assert_ne!(digest(&first), digest(&second));
+    std::fs::write(second.join("dir/b"), b"b")?;
+    std::fs::rename(second.join("dir/b"), second.join("dir/sub/b"))?;
+    assert_ne!(digest(&first), digest(&second));
+
+    let file = first.join("a");
+    assert_eq!(
+        b3sum_small(&mut &b"a"[..], &Config::default())?,
+        digest(&file)
+    );
+    Ok(())
+}
+
+#[test]
+fn b3_test_entropy() -> Result<()> {
+    let config = Config {
+        entropy: true,
+        ..Config::default()
+    };
+    let every_byte: Vec<u8> = (0..=255).collect();
+    for &(data, expected) in &[(&b""[..], 0.0), (&b"aaaa"[..], 0.0), (&b"abab"[..], 1.0)] {
+        assert_eq!(Some(expected), hash_small(&mut &data[..], &config)?.entropy);
+    }
+    let hashed = hash_small(&mut &every_byte[..], &config)?;
+    assert_eq!(Some(8.0), hashed.entropy);
+    let plain = hash_small(&mut &every_byte[..], &Config::default())?;
+    assert_eq!((None, &plain.checksum), (plain.entropy, &hashed.checksum));
+
+    // Large files are counted while they're hashed in parallel.
+    let data: Vec<u8> = (0..8 * (1 << 20)).map(|i| (i % 16) as u8).collect();
+    let (_file, path, _guard) = make_temp_file(&data);
+    let input = Input::open(&path, None)?;
+    let hashed = hash_large(input, &config)?;
+    assert_eq!(
+        hash_small(&mut &data[..], &config)?.checksum,
+        hashed.checksum
+    );
+    assert_eq!(Some(4.0), hashed.entropy);
+
+    // The entropy and the pieces are computed together.
+    let both = Config {
+        piecewise: Some(1 << 20),
+        ..config
+    };
+    let hashed = hash_large(Input::open(&path, None)?, &both)?;
+    assert_eq!((Some(4.0), 8), (hashed.entropy, hashed.pieces.len()));
+    Ok(())
+}
+
+#[test]
+fn b3_test_piecewise() -> Result<()> {
+    let blocks_of = |path: &Path, size| {
+        let mut blocks = Vec::new();
+        checksum_blocks(path, &Config::default(), size, |block| {
+            blocks.push(block.unwrap())
+        })
+        .unwrap();
+        blocks
+    };
+    for &(len, size) in &[(2500, 1000), (3000, 1000), (0, 1000), (8 << 20, 1 << 20)] {
+        let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        let (_file, path, _guard) = make_temp_file(&data);
+        let config = Config {
+            piecewise: Some(size),
+            ..Config::default()
+        };
+        let hashed = hash_large(Input::open(&path, None)?, &config)?;
+        assert_eq!(
+            b3sum_small(&mut &data[..], &Config::default())?,
+            hashed.checksum
+        );
+        assert_eq!(blocks_of(&path, size), hashed.pieces);
+    }
+
+    let empty_pieces = Config::builder().piecewise(Some(0)).build();
+    assert!(hash_small(&mut &b"data"[..], &empty_pieces).is_err());
+    Ok(())
+}
+
+#[test]
+fn b3_test_smart_hasher() -> Result<()> {
+    let config = Config::default();
+    for &len in &[0, 11, 16 * (1 << 20)] {
+        let data: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        let mut hasher = SmartHasher::new(&config)?;
+        for piece in data.chunks(3 * (1 << 20)) {
+            hasher.update(piece);
+        }
+        assert_eq!(b3sum_small(&mut &data[..], &config)?, hasher.finalize());
+
+        let mut hasher = SmartHasher::new(&config)?;
+        std::io::copy(&mut &data[..], &mut hasher)?;
+        assert_eq!(b3sum_small(&mut &data[..], &config)?, hasher.finalize());
+    }
+    Ok(())
+}
+
+#[test]
+fn b3_test_tee_reader() -> Result<()> {
+    let contents: Vec<u8> = (0..300_000u32).map(|i| (i % 253) as u8).collect();
+    let mut copy = Vec::new();
+    let mut input = TeeReader::new(&contents[..], &mut copy);
+    let checksum = b3sum_small(&mut input, &Config::default())?;
+    assert_eq!(
+        hex(blake3::hash(&contents).as_bytes()),
+        checksum.to_string()
+    );
+    assert_eq!(contents, copy);
+    Ok(())
+}
+
+#[test]
+fn b3_test_joined() -> Result<()> {
+    let (_file, first, _guard) = make_temp_file(b"hello ");
+    let (_file, empty, _guard) = make_temp_file(b"");
+    let (_file, last, _guard) = make_temp_file(b"world");
+    let checksum = checksum_joined(&[first.clone(), empty, last], &Config::default())?;
+    assert_eq!(blake3::hash(b"hello world").as_bytes()[..], checksum.0[..]);
+    assert!(checksum_joined(&[first, PathBuf::from("/nonexistent")], &Config::default()).is_err());
+    match open_joined(&[PathBuf::from("-")])? {
+        Input::Stream(_, size) => assert_eq!(None, size),
+        Input::File(_) => panic!("the parts weren't joined"),
+    }
+    Ok(())
+}
+
+#[test]
+fn b3_test_mmap_is_faster() -> Result<()> {
+    let (_file, path, _guard) = make_temp_file(&vec![1; 3 * (1 << 20)]);
+    let faster = mmap_is_faster(&path, &Config::default())?;
+    assert!(MMAP_SUPPORTED || !faster);
+    let (_file, empty, _guard) = make_temp_file(b"");
+    mmap_is_faster(&empty, &Config::default())?;
+    assert!(mmap_is_faster(&path.with_extension("missing"), &Config::default()).is_err());
+    Ok(())
+}
+
+/// Encode `contents` in the Bao format, with the tree apart from them if `outboard`, to test
+/// [`bao_decode`]. Returns the encoding and the root checksum.
+#[cfg(test)]
+fn bao_encode(contents: &[u8], outboard: bool) -> (Vec<u8>, blake3::Hash) {
+    use blake3::guts::{parent_cv, ChunkState, CHUNK_LEN};
+
+    fn subtree(
+        contents: &[u8],
+        chunk: u64,
+        is_root: bool,
+        outboard: bool,
+        out: &mut Vec<u8>,
+    ) -> blake3::Hash {
+        if contents.len() <= CHUNK_LEN {
+            if !outboard {
+                out.extend_from_slice(contents);
+            }
+            return ChunkState::new(chunk).update(contents).finalize(is_root);
+        }
+        let node = out.len();
+        out.extend_from_slice(&[0; 64]);
+        let chunks = (contents.len() as u64 - 1) / CHUNK_LEN as u64;
+        let left_len = (1 << (63 - chunks.leading_zeros())) * CHUNK_LEN;
+        let (left, right) = contents.split_at(left_len);
+        let left = subtree(left, chunk, false, outboard, out);
+        let right_chunk = chunk + (left_len / CHUNK_LEN) as u64;
+        let right = subtree(right, right_chunk, false, outboard, out);
+        out[node..node + 32].copy_from_slice(left.as_bytes());
+        out[node + 32..node + 64].copy_from_slice(right.as_bytes());
+        parent_cv(&left, &right, is_root)
+    }
+
+    let mut out = (contents.len() as u64).to_le_bytes().to_vec();
+    let root = subtree(contents, 0, true, outboard, &mut out);
+    (out, root)
+}
+
+#[test]
+fn b3_test_bao_decode() -> Result<()> {
+    let contents: Vec<u8> = (0..5000u32).map(|i| (i * 7 % 251) as u8).collect();
+    for len in &[0, 1, 1024, 1025, 5000] {
+        let contents = &contents[..*len];
+        let root = Checksum(blake3::hash(contents).as_bytes().to_vec());
+        let (encoded, encoded_root) = bao_encode(contents, false);
+        assert_eq!(root.0, encoded_root.as_bytes());
+        let mut output = Vec::new();
+        assert_eq!(
+            *len as u64,
+            bao_decode(&mut &encoded[..], None, &root, &mut output)?
+        );
+        assert_eq!(contents, &output[..]);
+
+        let (tree, _) = bao_encode(contents, true);
+        let mut output = Vec::new();
+        bao_decode(&mut &contents[..], Some(&mut &tree[..]), &root, &mut output)?;
+        assert_eq!(contents, &output[..]);
+    }
+
+    // Five chunks are a tree of four and one, and the four are two trees of two, so the fourth
+    // chunk comes after four parent nodes.
+    let root = Checksum(blake3::hash(&contents).as_bytes().to_vec());
+    let (mut encoded, _) = bao_encode(&contents, false);
+    encoded[8 + 4 * 64 + 3 * 1024 + 10] ^= 1;
+    let mut output = Vec::new();
+    let err = bao_decode(&mut &encoded[..], None, &root, &mut output).unwrap_err();
+    assert_eq!(
+        "chunk 3 is corrupt, at byte 3072 of the contents",
+        err.to_string()
+    );
+    assert_eq!(&contents[..3072], &output[..]);
+
+    let (encoded, _) = bao_encode(&contents, false);
+    let wrong = Checksum(vec![0; 32]);
+    assert!(bao_decode(&mut &encoded[..], None, &wrong, &mut Vec::new()).is_err());
+    let err = bao_decode(&mut &encoded[..2000], None, &root, &mut Vec::new()).unwrap_err();
+    assert_eq!("the encoding ends before its contents do", err.to_string());
+    Ok(())
+}
+
+#[test]
+fn b3_test_sample_digest() -> Result<()> {
+    let config = Config::default();
+    let sample = |contents: &[u8], length| {
+        let (_file, path, _guard) = make_temp_file(contents);
+        sample_digest(&path, length, &config)
+    };
+    let mut expected = 11u64.to_le_bytes().to_vec();
+    expected.extend_from_slice(b"hello world");
+    assert_eq!(
+        b3sum_small(&mut &expected[..], &config)?,
+        sample(b"hello world", 6)?
+    );
+
+    let mut expected = 11u64.to_le_bytes().to_vec();
+    expected.extend_from_slice(b"helrld");
+    assert_eq!(
+        b3sum_small(&mut &expected[..], &config)?,
+        sample(b"hello world", 3)?
+    );
+    // Only the middle differs.
+    assert_eq!(sample(b"hello world", 3)?, sample(b"hel--- -rld", 3)?);
+    assert_ne!(sample(b"hello world", 3)?, sample(b"hello worlds", 3)?);
+    Ok(())
+}
+
+#[cfg(not(feature = "fuzzy"))]
+#[test]
+fn b3_test_ssdeep_unavailable() {
+    let config = Config::builder().ssdeep(true).build();
+    assert!(hash_small(&mut &b"data"[..], &config).is_err());
+}
+
+#[cfg(not(feature = "extra-algorithms"))]
+#[test]
+fn b3_test_algorithm_unavailable() {
+    let config = Config::builder().algorithm(Algorithm::Md5).build();
+    assert!(hash_small(&mut &b"data"[..], &config).is_err());
+}
+
+#[cfg(feature = "fuzzy")]
+#[test]
+fn b3_test_ssdeep() -> Result<()> {
+    let config = Config {
+        ssdeep: true,
+        ..Config::default()
+    };
+    let text = b"Also called fuzzy hashes, Ctph can match inputs that have homologies.";
+    for &(data, expected) in &[
+        (&b""[..], "3::"),
+        (&text[..], "3:AXGBicFlgVNhBGcL6wCrFQEv:AXGHsNhxLsr2C"),
+    ] {
+        let hashed = hash_small(&mut &data[..], &config)?;
+        assert_eq!(Some(expected.to_owned()), hashed.ssdeep);
+        let plain = hash_small(&mut &data[..], &Config::default())?;
+        assert_eq!((None, hashed.checksum), (plain.ssdeep, plain.checksum));
+    }
+
+    // The hash doesn't depend on how the data is split when it's read.
+    let mut state = 1u32;
+    let data: Vec<u8> = (0..1_000_000)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            state as u8
+        })
+        .collect();
+    let mut whole = Ssdeep::new();
+    whole.update(&data);
+    let mut split = Ssdeep::new();
+    for piece in data.chunks(4093) {
+        split.update(piece);
+    }
+    let hash = whole.finish().unwrap();
+    assert_eq!(Some(&hash), split.finish().as_ref());
+    // The block size is the smallest for which 64 pieces are probably enough.
+    assert!(hash.starts_with("24576:"), "{}", hash);
+    Ok(())
+}
+
+#[test]
+fn b3_test_checksum_hex() {
+    let bytes: Vec<u8> = (0..=255).collect();
+    let lower: String = bytes.iter().map(|byte| format!("{:02x}", byte)).collect();
+    let upper: String = bytes.iter().map(|byte| format!("{:02X}", byte)).collect();
+    assert_eq!(lower, Checksum(bytes.clone()).to_string());
+    assert_eq!(upper, format!("{:X}", Checksum(bytes)));
+    assert_eq!("", Checksum(Vec::new()).to_string());
+}
@@ -6,232 +6,137 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
+//! The hashing engine of [b3sum-ng](https://github.com/lefth/b3sum-ng), a checksum utility that
+//! works well on spinning hard drives.
+//!
+//! The crate is split into these modules:
+//!
+//! - [`engine`] reads files and computes their checksums, given a [`Config`](engine::Config).
+//! - [`scheduler`] decides which files are read at the same time, so that small files are read
+//!   in parallel and large files are read alone, with a queue for each disk.
+//! - [`output`] prints checksums, errors and progress, and keeps the exit status.
+//! - [`verify`] checks the checksums listed in checkfiles.
+//! - [`checkfile`] reads and writes the lines of checkfiles, for tools that make or read them
+//!   without checking them.
+//! - `cli` has the command line options, and the process-wide settings of the command line
+//!   program, such as `--quiet` and `--timeout`. It is only built with the `cli` feature, which is
+//!   on by default, so that crates that only hash files can turn it off and don't depend on
+//!   structopt.
+//!
+//! The items that are `pub` in these modules are the public API, and they follow semver: they
+//! aren't removed or changed incompatibly except in a release that changes the major version
+//! (or the minor version, before 1.0). Everything else is private to the crate, and can change
+//! in any release. So is `cli`, which is only meant for the command line program: its settings
+//! change what every caller in the process prints. Fields may be added to
+//! [`Config`](engine::Config), so it's made with [`Config::builder`](engine::Config::builder).
+//!
+//! [`hash_path`](scheduler::hash_path) is the simplest way to checksum one file, and
+//! [`checksum_paths`](scheduler::checksum_paths) checksums many, reading them at the same time.
+//! Both have `_in` versions that run on a [`rayon::ThreadPool`] of the caller's instead of the
+//! global pool.
+//! This checksums two files, and prints their checksums in the format of `b3sum`:
+//!
+//! ```no_run
+//! use std::path::PathBuf;
+//!
+//! use b3sum_ng::{
+//!     engine::Config,
+//!     output::{print_checksum, Format},
+//!     scheduler::checksum_paths,
+//! };
+//!
+//! let paths = vec![PathBuf::from("song.mp3"), PathBuf::from("todo.txt")];
+//! checksum_paths(paths, &Config::default(), |path, checksum| {
+//!     print_checksum(path, checksum, Format::B3sum)
+//! });
+//! ```
+
 use std::{
-    convert::TryInto,
     error::Error,
-    fs::{metadata, File},
-    io::Read,
-    path::PathBuf,
-    sync::Arc,
+    marker::PhantomData,
+    path::Path,
+    sync::atomic::{AtomicPtr, AtomicU32, Ordering},
 };
+#[cfg(test)]
+use std::{fs::File, path::PathBuf};
 
-use blake3::OUT_LEN;
-use memmap::Mmap;
-use multi_semaphore::Semaphore;
-use rayon::Scope;
-use structopt::*;
-
-type Result<T, E = Box<dyn Error>> = std::result::Result<T, E>;
-
-/// Compute a checksum using different logic depending on input characteristics. This function
-/// handles locking to get the right amount of I/O parallelism.
-///
-/// `max_job_count` is the number of jobs that will be used for reading small files.  Small files
-/// are checksummed using one thread each, in parallel.  Large files will be read by just one
-/// thread but will use multiple threads for computing the checksum, irrespective of the
-/// `max_job_count` value.  It is commonly thought that using just one I/O thread gives fastest
-/// reads on spinning hard drives. I have not found that to be true when reading small files.
-///
-/// The semaphore's capacity should equal `max_job_count`.
-///
-/// The max number of threads used for checksumming large files is by default the number of logical
-/// CPU cores Rayon detects (via `num_cpus`). This can be overridden by setting the
-/// `RAYON_NUM_THREADS` environment variable. At least two threads must be used since one thread is
-/// needed to read files and another is needed to compute checksums.
-///
-/// Since this function may may spawn a background thread, `Ok(())` may be returned but an error
-/// may still be produced later. Callers should use [`print_error`] to handle errors, since that
-/// matches what will be invoked internally.
-///
-/// # Example
-///
-/// ```no_run
-/// use std::{error::Error, path::PathBuf, sync::Arc};
-/// use multi_semaphore::Semaphore;
-/// use rayon::Scope;
-/// # fn print_error(path: &PathBuf, err: Box<dyn Error>) { }
-/// # fn do_checksum( path: PathBuf, max_job_count: usize, io_lock: Arc<Semaphore>, use_mmap: bool, s: &Scope,) -> Result<(), Box<dyn Error>> { todo!() }
-///
-/// let paths = vec![PathBuf::from("song.mp3"), PathBuf::from("todo.txt")];
-/// let max_job_count = 32;
-/// let io_lock = Arc::new(Semaphore::new(max_job_count as isize));
-/// rayon::scope(|s| {
-///     for path in paths {
-///         if let Err(err) =
-///             do_checksum(path.clone(), max_job_count, Arc::clone(&io_lock), false, s)
-///         {
-///             print_error(&path, err);
-///         }
-///     }
-/// });
-/// ```
-pub fn do_checksum(
-    path: PathBuf,
-    max_job_count: usize,
-    io_lock: Arc<Semaphore>,
-    use_mmap: bool,
-    s: &Scope,
-) -> Result<()> {
-    if let Some(str) = path.to_str() {
-        if str == "-" {
-            let checksum = b3sum_large(Input::Stream(Box::new(std::io::stdin())), false);
-            print_checksum(&path, checksum);
-            return Ok(());
-        }
-    }
+#[cfg(test)]
+use crate::engine::Checksum;
 
-    // Get file size before locking because we won't know how many I/O resources to lock
-    // until we know how big it is. And locks can't be upgraded without probable deadlock.
-    let filesize = metadata(&path)?.len();
-    if filesize > 128 * (1 << 10) {
-        // Wait for all other I/O to be finished, and take all the I/O resources.
-        // Because concurrent reads of large files reduces performance.
-        let io_lock = io_lock.access_many(max_job_count as isize);
-        let file = File::open(&path)?;
-        let checksum = b3sum_large(Input::File(file), use_mmap);
-        drop(io_lock);
-        print_checksum(&path, checksum);
-    } else {
-        s.spawn(move |_| {
-            let io_lock = io_lock.access();
-            let file = File::open(&path);
-            let checksum = file
-                .map_err(|err| Box::new(err) as Box<dyn Error>)
-                .and_then(|mut file| b3sum_small(&mut file));
-            drop(io_lock);
-            print_checksum(&path, checksum);
-        });
+// Print a line to standard output like `println!`, but batched with the checksums once
+// `output::start_batched_output` is called. It's defined before the modules so they can use it.
+macro_rules! outln {
+    () => {
+        $crate::output::print_line(format_args!(""))
+    };
+    ($($arg:tt)*) => {
+        $crate::output::print_line(format_args!($($arg)*))
     };
-
-    Ok(())
 }
 
-/// Compute a checksum of a small file or stdin by reading it all into memory.
-pub(crate) fn b3sum_small(file: &mut dyn Read) -> Result<[u8; OUT_LEN]> {
-    let mut buf = Vec::new();
-    file.read_to_end(&mut buf)?;
-    return Ok(blake3::hash(&buf).try_into().unwrap());
-}
+pub mod checkfile;
+#[cfg(feature = "cli")]
+pub mod cli;
+pub mod engine;
+pub mod output;
+pub mod scheduler;
+mod sys;
+pub mod verify;
 
-/// Compute a multi-threaded checksum of a large file by buffering it or memory mapping it.
-pub(crate) fn b3sum_large(file: Input, use_mmap: bool) -> Result<[u8; OUT_LEN]> {
-    let mut hasher = blake3::Hasher::new();
-    // Note: if we use io::Cursor<Mmap> to treat all inputs as Read, it's slower on HDDs
-    match file {
-        Input::File(file) if use_mmap => {
-            // Iterating over chunks is faster than computing the whole buffer,
-            // even on SSDs. On spinning discs, mmap is still slower than normal file reads.
-            // TODO: the buffer size may need to be tuned based on the number of threads.
-            let buf = unsafe { Mmap::map(&file) }?;
-            for slice in buf.chunks(4 * (1 << 20)) {
-                hasher.update_with_join::<blake3::join::RayonJoin>(&slice);
-            }
-        }
-        _ => {
-            let mut file: Box<dyn Read> = match file {
-                Input::File(file) => Box::new(file),
-                Input::Stream(read) => read,
-            };
-            let mut buf = vec![0u8; 2 * (1 << 20)];
-            loop {
-                let bytes_read = file.read(&mut buf)?;
-                if bytes_read == 0 {
-                    break;
-                }
-                hasher.update_with_join::<blake3::join::RayonJoin>(&buf[0..bytes_read]);
-            }
-        }
-    }
-    Ok(hasher.finalize().try_into().unwrap())
+/// The result of most operations. Errors are boxed, since most of them are only printed.
+pub type Result<T, E = Box<dyn Error>> = std::result::Result<T, E>;
+
+/// A value that is set once, for the rest of the process, such as the state of an option that the
+/// command line turns on. It can be read without a lock once it's set.
+pub(crate) struct Global<T> {
+    value: AtomicPtr<T>,
+    // Makes this `Sync` only if `T` is, since the value is shared by every thread.
+    owned: PhantomData<Box<T>>,
 }
 
-/// Print a checksum or an error that was encountered.
-pub(crate) fn print_checksum(path: &PathBuf, result: Result<[u8; OUT_LEN]>) {
-    match result {
-        Ok(checksum) => {
-            println!("{}  {}", Checksum(checksum), path.display());
+impl<T> Global<T> {
+    pub(crate) const fn new() -> Self {
+        Global {
+            value: AtomicPtr::new(std::ptr::null_mut()),
+            owned: PhantomData,
         }
-        Err(err) => print_error(path, err),
     }
-}
-
-/// Print an error and the filename that caused it.
-pub fn print_error(path: &PathBuf, err: Box<dyn Error>) {
-    let binary_name = match std::env::current_exe() {
-        Ok(binary_name) => match binary_name.file_name() {
-            Some(binary_name) => binary_name.to_string_lossy().to_string(),
-            None => binary_name.display().to_string(),
-        },
-        Err(_) => "".to_owned(),
-    };
-    eprintln!("{}: {}: {}", binary_name, path.display(), err);
-}
-
-#[derive(StructOpt)]
-#[structopt()]
-pub(crate) struct Options {
-    #[structopt(
-        default_value = "-",
-        help = "Files to get the checksum of. When '-' is given, \
-            calculate the checksum of standard input."
-    )]
-    pub paths: Vec<PathBuf>,
-
-    #[structopt(
-        long,
-        // The author of rigrep says mmap causes random SIGSEGV or SIGBUS
-        // when files are changed during reading. Unlikely.
-        help = "Use mmap. This gives better performance on SSDs. It is possible that the program will crash \
-            if a file is modified while being read.",
-    )]
-    pub mmap: bool,
-
-    // Note: this number that was found to have good performance in testing
-    // on hard drives and SSDs.
-    #[structopt(
-        short,
-        long,
-        default_value = "16",
-        help = "The number of concurrent reads to allow. Regardless of this value, \
-            checksums of large files will still be computed one at a time with multithreading."
-    )]
-    pub job_count: usize,
-}
 
-pub(crate) enum Input {
-    File(File),            // Files are preferred, as the API is more flexible.
-    Stream(Box<dyn Read>), // If it's not a file, it should still be readable.
-}
+    /// The value, if it was set.
+    pub(crate) fn get(&self) -> Option<&'static T> {
+        // Safety: the pointer is either null or from `Box::into_raw` in `set`, and the box is
+        // never freed once it's stored.
+        unsafe { self.value.load(Ordering::Acquire).as_ref() }
+    }
 
-pub(crate) struct Checksum(pub [u8; OUT_LEN]);
-impl std::fmt::Display for Checksum {
-    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        for byte in self.0.iter() {
-            write!(f, "{:02x}", byte)?;
+    /// Set the value, unless it was set before, in which case `value` is given back.
+    pub(crate) fn set(&self, value: T) -> std::result::Result<(), T> {
+        let boxed = Box::into_raw(Box::new(value));
+        let null = std::ptr::null_mut();
+        match self
+            .value
+            .compare_exchange(null, boxed, Ordering::AcqRel, Ordering::Acquire)
+        {
+            Ok(_) => Ok(()),
+            // Safety: the box wasn't stored, so this is its only owner.
+            Err(_) => Err(*unsafe { Box::from_raw(boxed) }),
         }
-        Ok(())
     }
 }
 
-#[test]
-fn b3_test_bytes() -> Result<()> {
-    assert_eq!(
-        "d74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24",
-        &format!(
-            "{}",
-            Checksum(b3sum_small(&mut std::io::Cursor::new(b"hello world"))?)
-        )
-    );
-    Ok(())
-}
+/// Replace a file with `contents` at once, by writing them to a temporary file next to it and
+/// renaming that over it, so that a reader or a crash never leaves part of a file.
+pub(crate) fn write_atomically(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    static TEMP_ID: AtomicU32 = AtomicU32::new(0);
 
-#[test]
-fn b3_test_bytes_empty() -> Result<()> {
-    assert_eq!(
-        "af1349b9f5f9a1a6a0404dea36dcc9499bcb25c9adc112b7cc9a93cae41f3262",
-        &format!("{}", Checksum(b3sum_small(&mut std::io::Cursor::new(b""))?))
-    );
-    Ok(())
+    let mut temp = path.as_os_str().to_owned();
+    let id = TEMP_ID.fetch_add(1, Ordering::Relaxed);
+    temp.push(format!(".{}-{}.tmp", std::process::id(), id));
+    let written = std::fs::write(&temp, contents).and_then(|()| std::fs::rename(&temp, path));
+    if written.is_err() {
+        let _ = std::fs::remove_file(&temp);
+    }
+    written
 }
 
 #[cfg(test)]
@@ -245,6 +150,17 @@ impl Drop for TempFileGuard {
     }
 }
 
+#[cfg(test)]
+struct TempDirGuard {
+    pub dir: PathBuf,
+}
+#[cfg(test)]
+impl Drop for TempDirGuard {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.dir);
+    }
+}
+
 #[cfg(test)]
 fn make_temp_file(contents: &[u8]) -> (File, PathBuf, TempFileGuard) {
     use std::io::Write;
@@ -267,90 +183,21 @@ fn make_temp_file(contents: &[u8]) -> (File, PathBuf, TempFileGuard) {
     )
 }
 
-#[test]
-fn b3_test_file_small() -> Result<()> {
-    let (mut file, _path, _guard) = make_temp_file(b"hello world");
-    assert_eq!(
-        "d74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24",
-        &format!("{}", Checksum(b3sum_small(&mut file)?))
-    );
-    Ok(())
-}
-
-#[test]
-fn b3_test_file_large() -> Result<()> {
-    let (file, _path, _guard) = make_temp_file(b"hello world");
-    assert_eq!(
-        "d74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24",
-        &format!("{}", Checksum(b3sum_large(Input::File(file), false)?))
-    );
-    Ok(())
-}
-
-#[test]
-fn b3_test_file_large_2() -> Result<()> {
-    let (file, _path, _guard) = make_temp_file(&vec![0u8; 20_971_520]);
-    assert_eq!(
-        "bea89379ccc6ac7c6e1a2924643665501a7a6427877f2c6764f9813f8c9330b4",
-        &format!("{}", Checksum(b3sum_large(Input::File(file), false)?))
-    );
-    Ok(())
-}
-
-#[test]
-fn b3_test_file_mmap() -> Result<()> {
-    let (file, _path, _guard) = make_temp_file(b"hello world");
-    assert_eq!(
-        "d74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24",
-        &format!("{}", Checksum(b3sum_large(Input::File(file), true)?))
-    );
-    Ok(())
-}
-
-#[test]
-fn b3_test_file_mmap_2() -> Result<()> {
-    let (file, _path, _guard) = make_temp_file(&vec![0u8; 20_971_520]);
-    assert_eq!(
-        "bea89379ccc6ac7c6e1a2924643665501a7a6427877f2c6764f9813f8c9330b4",
-        &format!("{}", Checksum(b3sum_large(Input::File(file), true)?))
-    );
-    Ok(())
+#[cfg(test)]
+fn hex(bytes: &[u8]) -> String {
+    Checksum(bytes.to_vec()).to_string()
 }
 
 #[test]
-/// Test that several files can be opened, some of which may be opened in background threads.
-fn b3_test_file_no_error_1() -> Result<()> {
-    let buffers: Vec<Vec<u8>> = vec![
-        vec![0u8; 20_971_520],
-        (b"hello, world").iter().map(|b| *b).collect(),
-        (b"hello, world").iter().map(|b| *b).collect(),
-        (b"hello, world").iter().map(|b| *b).collect(),
-        vec![],
-        vec![0u8; 900_00],
-        vec![0u8; 900_00],
-        vec![0u8; 900_00],
-        vec![0u8; 20_971_520],
-        vec![0u8; 900_00],
-        vec![0u8; 900_00],
-    ];
-    let temp_files: Vec<_> = buffers
-        .into_iter()
-        .map(|buf| make_temp_file(&buf))
-        // Close open handles
-        .map(|(file, path, guard)| {
-            drop(file);
-            (path, guard)
-        })
-        .collect();
-
-    let semaphore = Arc::new(Semaphore::new(16));
-    rayon::scope(|s| {
-        for (path, _) in &temp_files {
-            assert!(do_checksum(path.to_owned(), 16, Arc::clone(&semaphore), true, s).is_ok());
-        }
-        for (path, _) in &temp_files {
-            assert!(do_checksum(path.to_owned(), 16, Arc::clone(&semaphore), false, s).is_ok());
-        }
-    });
+fn b3_test_global() -> Result<()> {
+    static VALUE: Global<u32> = Global::new();
+    assert_eq!(None, VALUE.get());
+    assert_eq!(Ok(()), VALUE.set(1));
+    assert_eq!(Err(2), VALUE.set(2));
+    assert_eq!(Some(&1), VALUE.get());
+
+    let (_file, path, _guard) = make_temp_file(b"old");
+    write_atomically(&path, b"new")?;
+    assert_eq!(b"new", &std::fs::read(&path)?[..]);
     Ok(())
 }
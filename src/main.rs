@@ -6,31 +6,1154 @@
 // option. This file may not be copied, modified, or distributed
 // except according to those terms.
 
-use std::sync::Arc;
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{BufReader, BufWriter, Cursor, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    process::Stdio,
+    sync::{Arc, Condvar, Mutex},
+    time::{Duration, Instant},
+};
 
 use multi_semaphore::Semaphore;
 use structopt::*;
 
-mod lib;
-use lib::*;
+use b3sum_ng::{
+    checkfile::{format_line, read_chain_head, Entry, KnownHashes},
+    cli::*,
+    engine::*,
+    output::*,
+    scheduler::*,
+    verify::*,
+};
 
 fn main() {
-    let opts: Options = Options::from_args();
-    let paths = opts.paths;
-    let max_job_count = opts.job_count;
-    let io_lock = Arc::new(Semaphore::new(max_job_count as isize));
-    let use_mmap = opts.mmap;
-    rayon::scope(|s| {
+    let mut opts: Options = Options::from_args_safe().unwrap_or_else(|err| exit_usage(err));
+    opts.global.apply_env();
+    let global = &opts.global;
+    set_color(global.color);
+    set_quiet(global.quiet);
+    set_error_format(global.errors);
+    start_batched_output();
+    // Only Unix has signals. Elsewhere, the lines that are held are lost if the run is interrupted.
+    let _ = flush_on_termination();
+    if let Some(target) = global.log {
+        if let Err(err) = start_log(target) {
+            print_warning(&format!("the system log can't be used: {}", err));
+        }
+    }
+    if let Some(path) = &global.summary {
+        start_summary(path.clone());
+    }
+    if let Some(TimeLimit(limit)) = global.timeout {
+        set_deadline(Instant::now() + limit);
+    }
+    set_verbose(global.verbose);
+    init_tracing();
+    set_path_normalization(global.normalize_paths, global.unicode_form);
+    if global.mmap && !MMAP_SUPPORTED {
+        print_warning("mmap is not supported by this build, so files will be read normally");
+    }
+    if global.auto_mmap && !MMAP_SUPPORTED {
+        print_warning("mmap is not supported by this build, so --auto-mmap has no effect");
+    } else if global.auto_mmap {
+        match global.mmap_cache.clone().or_else(default_mmap_cache) {
+            Some(cache) => {
+                if let Err(err) = start_auto_mmap(cache.clone()) {
+                    print_warning(&format!("{}: {}", cache.display(), err));
+                }
+            }
+            None => print_warning("there's no cache directory, so --auto-mmap has no effect"),
+        }
+    }
+    if global.no_cache && !cfg!(target_os = "macos") {
+        usage_error("--no-cache is only supported on macOS");
+    }
+    let supports_readahead = cfg!(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos"
+    ));
+    if global.readahead.is_some() && !supports_readahead {
+        usage_error("--readahead is only supported on Linux and macOS");
+    }
+    if global.ssdeep && !cfg!(feature = "fuzzy") {
+        usage_error("--ssdeep requires the fuzzy feature");
+    }
+    raise_open_file_limit();
+    // Before any threads are started, which keep the priority that they started with on Linux.
+    if global.background {
+        if let Err(err) = lower_priority() {
+            print_warning(&format!("the priority can't be lowered: {}", err));
+        }
+    }
+    // Use only the CPUs that the process may use, unless the number of threads is given.
+    let num_threads = global
+        .num_threads
+        .or_else(|| match std::env::var_os("RAYON_NUM_THREADS") {
+            Some(_) => None,
+            None => available_cpus(),
+        });
+    if let Some(num_threads) = num_threads {
+        let pool = rayon::ThreadPoolBuilder::new().num_threads(num_threads);
+        if let Err(err) = pool.build_global() {
+            print_warning(&format!("the number of threads can't be set: {}", err));
+        }
+    }
+    let io_lock = Arc::new(Semaphore::new(global.job_count as isize));
+    let progress = global
+        .progress
+        .map(|_| ProgressReporter::start(Duration::from_secs(1)));
+    let systemd = SystemdNotifier::start();
+    let _load_monitor = global.background.then(|| {
+        LoadMonitor::start(PauseThresholds {
+            cpu_percent: global.pause_cpu,
+            io_bytes_per_second: global.pause_io,
+        })
+    });
+
+    if opts.verify.is_used() && !opts.check {
+        usage_error(
+            "--strip-prefix, --path-map, --ignore-case, --only, --fail-fast, --root, \
+             --verified-cache, --report, --notify-url, --on-fail, --last-verified, --budget, \
+             --every and --spot-check can only be used with --check",
+        );
+    }
+
+    // Held until the process exits.
+    let _lock =
+        match lockfile(&opts).map(|path| (RunLock::acquire(&path, global.wait_for_lock), path)) {
+            None => None,
+            Some((Ok(Some(lock)), _)) => Some(lock),
+            Some((Ok(None), path)) => {
+                let message = format!(
+                    "{} is locked by another scan, so this one isn't run",
+                    path.display()
+                );
+                print_warning(&message);
+                finish(progress, systemd, 0);
+            }
+            Some((Err(err), path)) => {
+                print_error(&path, err);
+                finish(progress, systemd, EXIT_IO_ERROR);
+            }
+        };
+
+    let status = match opts.command {
+        None if opts.check => check_command(
+            &opts.hash.paths,
+            opts.hash.format,
+            &opts.verify,
+            global,
+            read_blake3_mode(
+                opts.hash.keyed,
+                opts.hash.derive_key.as_deref(),
+                opts.hash
+                    .paths
+                    .iter()
+                    .any(|path| path.to_str() == Some("-")),
+            ),
+            io_lock,
+        ),
+        None => hash_command(&opts.hash, global, io_lock),
+        Some(Command::Hash(ref hash)) => hash_command(hash, global, io_lock),
+        Some(Command::Check(ref check)) => check_command(
+            &check.checkfiles,
+            check.format,
+            &check.verify,
+            global,
+            read_blake3_mode(
+                check.keyed,
+                check.derive_key.as_deref(),
+                check
+                    .checkfiles
+                    .iter()
+                    .any(|path| path.to_str() == Some("-")),
+            ),
+            io_lock,
+        ),
+        Some(ref command) => {
+            // The other subcommands compute checksums the same way as when hashing.
+            let reads_stdin = matches!(command, Command::Serve { .. });
+            let (config, format) = hash_settings(&opts.hash, global, reads_stdin);
+            match command {
+                Command::Diff { first, second } => {
+                    let mut status = 0;
+                    compare_paths(first, second, &config, &mut |difference| {
+                        status = match difference {
+                            Difference::Unreadable(..) => status.max(EXIT_IO_ERROR),
+                            _ => status.max(EXIT_FAILURE),
+                        };
+                        print_difference(difference);
+                    });
+                    finish(progress, systemd, status);
+                }
+                Command::Dup {
+                    paths,
+                    skip_reflinks,
+                } => {
+                    find_duplicates(paths, &config, io_lock, *skip_reflinks, print_duplicates);
+                }
+                Command::Bench { paths } => {
+                    for path in paths {
+                        match benchmark(path, &config) {
+                            Ok(measurements) => {
+                                for measurement in &measurements {
+                                    print_measurement(path, measurement);
+                                }
+                            }
+                            Err(err) => print_error(path, err),
+                        }
+                    }
+                }
+                Command::Serve { nul } => finish(progress, systemd, serve_requests(&config, *nul)),
+                Command::Cp { src, dst } => match copy_and_verify(src, dst, &config) {
+                    Ok((copy, checksum)) => print_checksum(&copy, Ok(checksum), format),
+                    Err(err) => print_error(src, err),
+                },
+                Command::Chunks { paths, block_size } => {
+                    if *block_size == Some(0) {
+                        usage_error("--block-size must be at least 1");
+                    }
+                    for path in paths {
+                        let result = match block_size {
+                            Some(block_size) => {
+                                checksum_blocks(path, &config, *block_size, |block| {
+                                    print_block(path, block)
+                                })
+                            }
+                            None => {
+                                checksum_chunks(path, &config, |chunk| print_chunk(path, &chunk))
+                            }
+                        };
+                        if let Err(err) = result {
+                            print_error(path, err);
+                        }
+                    }
+                }
+                Command::Decode {
+                    hash,
+                    input,
+                    outboard,
+                } => {
+                    if let Err((path, err)) = decode(hash, input, outboard.as_deref()) {
+                        print_error(path, err);
+                    }
+                }
+                Command::RemoteVerify {
+                    checkfiles,
+                    command,
+                    connect,
+                    serve,
+                    listen,
+                } => {
+                    let status = if *serve {
+                        serve_stdio(&config)
+                    } else if let Some(address) = listen {
+                        listen_remote(address, &config)
+                    } else {
+                        let remote = Remote {
+                            command: command.as_deref(),
+                            connect: connect.as_deref(),
+                        };
+                        verify_remote(checkfiles, format, remote)
+                    };
+                    finish(progress, systemd, status);
+                }
+                Command::Completions { shell } => {
+                    Options::clap().gen_completions_to(
+                        env!("CARGO_PKG_NAME"),
+                        *shell,
+                        &mut std::io::stdout(),
+                    );
+                }
+                Command::Hash(_) | Command::Check(_) => unreachable!(),
+            }
+            0
+        }
+    };
+    finish(progress, systemd, status);
+}
+
+/// The lockfile of `--lockfile`, or the one that's named after the file that the run writes, if
+/// there is one.
+fn lockfile(opts: &Options) -> Option<PathBuf> {
+    if let Some(path) = &opts.global.lockfile {
+        return Some(path.clone());
+    }
+    let written = match &opts.command {
+        None if opts.check => opts
+            .verify
+            .last_verified
+            .as_ref()
+            .or(opts.verify.verified_cache.as_ref()),
+        None => opts.hash.chain.as_ref(),
+        Some(Command::Hash(hash)) => hash.chain.as_ref(),
+        Some(Command::Check(check)) => check
+            .verify
+            .last_verified
+            .as_ref()
+            .or(check.verify.verified_cache.as_ref()),
+        Some(_) => None,
+    }?;
+    let mut path = written.as_os_str().to_owned();
+    path.push(".lock");
+    Some(PathBuf::from(path))
+}
+
+/// Print the final progress event, tell systemd that it's stopping, and exit with `status`, or
+/// the status of the errors that were printed if it's higher.
+fn finish(progress: Option<ProgressReporter>, systemd: Option<SystemdNotifier>, status: i32) -> ! {
+    drop(progress);
+    drop(systemd);
+    flush_output();
+    sync_output();
+    print_rollup();
+    let mut status = status.max(error_status());
+    if timed_out() {
+        print_warning("the run stopped at its --timeout, so some files weren't read");
+        status = status.max(EXIT_TIMEOUT);
+    }
+    log_summary(status);
+    if let Err(err) = write_summary(status) {
+        print_warning(&format!("the summary can't be written: {}", err));
+    }
+    std::process::exit(status);
+}
+
+/// Print the checksums of files, or compare one with `--expect`. Returns the exit status.
+fn hash_command(hash: &HashOptions, global: &GlobalOptions, io_lock: Arc<Semaphore>) -> i32 {
+    let listed;
+    let paths = match &hash.from_manifest {
+        Some(manifest) => {
+            if hash.paths.iter().any(|path| path.to_str() != Some("-")) {
+                usage_error("paths can't be given with --from-manifest");
+            }
+            // --format is the format of the new manifest, so the old one's is found by its name.
+            listed = match listed_paths(manifest, Format::B3sum) {
+                Ok((listed, 0)) => listed,
+                Ok((listed, bad_lines)) => {
+                    print_warning(&format!(
+                        "{}: {} lines couldn't be read, so their files are left out",
+                        manifest.display(),
+                        bad_lines
+                    ));
+                    listed
+                }
+                Err(err) => {
+                    print_error(manifest, err);
+                    return EXIT_FAILURE;
+                }
+            };
+            &listed
+        }
+        None => &hash.paths,
+    };
+    if !hash.strings.is_empty() && paths.iter().any(|path| path.to_str() != Some("-")) {
+        usage_error("paths can't be given with --string");
+    }
+    let reads_stdin =
+        hash.strings.is_empty() && paths.iter().any(|path| path.to_str() == Some("-"));
+    let (config, format) = hash_settings(hash, global, reads_stdin);
+    let inputs = if hash.combine { 1 } else { paths.len() };
+    if hash.raw && (inputs > 1 || hash.strings.len() > 1 || hash.archive.is_some() || hash.ads) {
+        usage_error("--raw can only be used with one input");
+    }
+    if hash.ads && !cfg!(windows) {
+        usage_error("--ads is only supported on Windows");
+    }
+    if hash.xattrs && !XATTRS_SUPPORTED {
+        usage_error("--xattrs is only supported on Linux and macOS, with the sys feature");
+    }
+    if hash.cas_export.is_some() {
+        if reads_stdin {
+            usage_error("--cas-export can't be used with standard input");
+        }
+        if format.algorithm() != Algorithm::Blake3 {
+            usage_error("--cas-export stores files by their BLAKE3, so it needs the b3sum format");
+        }
+    }
+
+    if hash.dry_run {
+        return dry_run(paths, hash, config.decompress);
+    }
+    if hash.server {
+        if paths.iter().any(|path| path.to_str() != Some("-")) {
+            usage_error("--server reads paths from standard input, so none can be given");
+        }
+        return serve_requests(&config, hash.server_nul);
+    }
+
+    if hash.tee {
+        if paths.iter().any(|path| path.to_str() != Some("-")) {
+            usage_error("--tee only reads standard input, so no paths can be given");
+        }
+        return tee(hash, &config, format);
+    }
+
+    let parts = if hash.join {
+        if reads_stdin {
+            usage_error("--join can't be used with standard input");
+        }
+        paths.iter().flat_map(|path| expand_glob(path)).collect()
+    } else if hash.combine {
+        if paths
+            .iter()
+            .filter(|path| path.to_str() == Some("-"))
+            .count()
+            > 1
+        {
+            usage_error("standard input can only be given once with --combine");
+        }
+        paths.clone()
+    } else {
+        Vec::new()
+    };
+
+    if let Some(expected) = &hash.expect {
+        if paths.len() != 1 && !hash.join && !hash.combine {
+            usage_error("--expect can only be used with one input");
+        }
+        let matches = if hash.join || hash.combine {
+            checksum_joined(&parts, &config).map(|checksum| checksum.constant_time_eq(expected))
+        } else {
+            matches_expected(&paths[0], &config, expected)
+        };
+        return match matches {
+            Ok(true) => 0,
+            Ok(false) => {
+                let message = "the checksum does not match the expected checksum";
+                print_error(&paths[0], message.into());
+                EXIT_FAILURE
+            }
+            Err(err) => {
+                print_error(&paths[0], err);
+                EXIT_IO_ERROR
+            }
+        };
+    }
+
+    if hash.seal {
+        start_seal(config.blake3_mode.clone());
+    }
+    set_timestamps(hash.timestamps);
+    set_metadata(hash.metadata);
+    set_xattrs(hash.xattrs);
+    if let Some(interval) = hash.fsync_every {
+        start_output_sync(interval);
+    }
+    if let Some(by) = hash.summary_by {
+        start_rollup(by);
+    }
+    if let Some(root) = &hash.grouped {
+        if matches!(format, Format::Nsrl | Format::Json | Format::Csv) {
+            usage_error("--grouped can't be used with the nsrl, json or csv formats");
+        }
+        start_grouping(root.clone());
+    }
+    if let Some(manifest) = &hash.chain {
+        match read_chain_head(manifest) {
+            Ok(head) => start_chain(head),
+            Err(err) => {
+                print_error(manifest, err);
+                return EXIT_FAILURE;
+            }
+        }
+    }
+    if let Some(path) = &hash.known_hashes {
+        match KnownHashes::load(path, config.checksum_length()) {
+            Ok(known) => set_known_hashes(known, hash.known),
+            Err(err) => {
+                print_error(path, err);
+                return EXIT_FAILURE;
+            }
+        }
+    }
+    if hash.tree_digest {
+        if reads_stdin {
+            usage_error("--tree-digest can't be used with standard input");
+        }
+        if hash.format != Format::B3sum {
+            usage_error("--tree-digest can only be used with the b3sum format");
+        }
+        if global.entropy || global.ssdeep || hash.piecewise.is_some() {
+            usage_error("--entropy, --ssdeep and --piecewise can't be used with --tree-digest");
+        }
+        for path in paths {
+            let digest = tree_digest(path, &config, Arc::clone(&io_lock));
+            print_checksum(path, digest, format);
+        }
+        print_seal(format);
+        print_chain_head();
+        return 0;
+    }
+    if let Some(length) = hash.sample {
+        if reads_stdin {
+            usage_error("--sample can't be used with standard input");
+        }
+        if hash.format != Format::B3sum {
+            usage_error("--sample can only be used with the b3sum format");
+        }
         for path in paths {
-            if let Err(err) = do_checksum(
-                path.clone(),
-                max_job_count,
-                Arc::clone(&io_lock),
-                use_mmap,
+            sample_tree(path, length, &config, |file, digest| {
+                print_sample(file, length, digest)
+            });
+        }
+        return 0;
+    }
+    print_header(format);
+    if !hash.strings.is_empty() {
+        for string in &hash.strings {
+            // The label is quoted, so that it can't be mistaken for a file.
+            let label = format!("{:?}", string);
+            let input = Input::from_reader(Cursor::new(string.clone()), Some(string.len() as u64));
+            print_checksum(Path::new(&label), hash_input(input, &config), format);
+        }
+        print_seal(format);
+        print_chain_head();
+        return 0;
+    }
+    if hash.join {
+        print_checksum(&parts[0], checksum_joined(&parts, &config), format);
+        print_seal(format);
+        print_chain_head();
+        return 0;
+    }
+    if hash.combine {
+        let names: Vec<String> = parts
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect();
+        let label = PathBuf::from(names.join(" + "));
+        print_checksum(&label, checksum_joined(&parts, &config), format);
+        print_seal(format);
+        print_chain_head();
+        return 0;
+    }
+
+    let cas = match &hash.cas_export {
+        Some(dir) => match CasExport::open(dir) {
+            Ok(cas) => Some(cas),
+            Err(err) => {
+                print_error(dir, err);
+                return EXIT_FAILURE;
+            }
+        },
+        None => None,
+    };
+    let cas = cas.as_ref();
+    let numbered = paths
+        .iter()
+        .enumerate()
+        .map(|(index, path)| Numbered { index, path })
+        .collect();
+    let mut inputs = gather_inputs(numbered, global.job_count);
+    // The totals are also used by the status that systemd shows.
+    for input in &inputs {
+        if let Some((strategy, size)) = input.planned {
+            if strategy != "stdin" {
+                add_progress_total(1, size.unwrap_or(0));
+            }
+        }
+    }
+
+    let snapshot = match &hash.snapshot {
+        Some(root) => match Snapshot::create(root) {
+            Ok(snapshot) => Some(snapshot),
+            Err(err) => {
+                print_error(root, err);
+                return EXIT_FAILURE;
+            }
+        },
+        None => None,
+    };
+    let snapshot = snapshot.as_ref();
+    if let (Some(root), Some(snapshot)) = (&hash.snapshot, snapshot) {
+        for path in paths
+            .iter()
+            .filter(|&path| path.to_str() != Some("-") && snapshot.map(path) == *path)
+        {
+            print_warning(&format!(
+                "{} is not under {}, so it is not read from the snapshot",
+                path.display(),
+                root.display()
+            ));
+        }
+    }
+
+    // Small files are read several at a time, so with --small-first, they're all queued before
+    // a large file can take all of the I/O.
+    if hash.small_first {
+        inputs.sort_by_key(|input| !matches!(input.planned, Some(("small", _))));
+    }
+
+    // Each disk has its own queue and its own limit on reads, so that one disk isn't left idle
+    // while a large file is read from another.
+    let queues = queues_by_disk(inputs);
+    let separate = queues.len() > 1;
+    let in_order = hash.from_manifest.as_ref().map(|_| InOrder::new(format));
+    let in_order = in_order.as_ref();
+    let config = &config;
+    rayon::scope(|s| {
+        for queue in queues {
+            let io_lock = if separate {
+                Arc::new(Semaphore::new(global.job_count as isize))
+            } else {
+                Arc::clone(&io_lock)
+            };
+            s.spawn(move |s| {
+                // The next few files are opened while this one is started, but archives aren't,
+                // since they're read differently.
+                let read_path = |path: &Path| {
+                    snapshot.map_or_else(|| path.to_owned(), |snapshot| snapshot.map(path))
+                };
+                let mut opening = VecDeque::new();
+                for (i, input) in queue.iter().enumerate() {
+                    while opening.len() < OPEN_AHEAD.min(queue.len() - i) {
+                        let ahead = queue[i + opening.len()].path;
+                        let is_archive = hash
+                            .archive
+                            .and_then(|archive| archive.resolve(ahead, config.decompress))
+                            .is_some();
+                        opening.push_back((!is_archive).then(|| open_ahead(read_path(ahead), s)));
+                    }
+                    // A file that isn't open yet is opened again rather than waited for.
+                    let opened = opening
+                        .pop_front()
+                        .flatten()
+                        .and_then(|receiver| receiver.try_recv().ok());
+                    let order = in_order.map(|in_order| (input.index, in_order));
+                    let path = input.path;
+                    hash_path(
+                        path, opened, hash, config, format, order, snapshot, cas, &io_lock, s,
+                    );
+                }
+            });
+        }
+    });
+    if let Some(in_order) = in_order {
+        in_order.finish();
+    }
+    print_seal(format);
+    print_chain_head();
+    0
+}
+
+/// Print the checksum of one input, or of each member of an archive, reading it from the snapshot
+/// if there is one, and store it with `--cas-export`. `opened` is the input if it was opened
+/// ahead.
+#[allow(clippy::too_many_arguments)]
+fn hash_path<'scope>(
+    path: &Path,
+    opened: Option<File>,
+    hash: &'scope HashOptions,
+    config: &Config,
+    format: Format,
+    order: Option<(usize, &'scope InOrder)>,
+    snapshot: Option<&Snapshot>,
+    cas: Option<&'scope CasExport>,
+    io_lock: &Arc<Semaphore>,
+    s: &rayon::Scope<'scope>,
+) {
+    // Files are read from the snapshot, but printed with the paths that were given.
+    let read_path = snapshot.map_or_else(|| path.to_owned(), |snapshot| snapshot.map(path));
+    if let Some(archive) = hash
+        .archive
+        .and_then(|archive| archive.resolve(path, config.decompress))
+    {
+        if let Err(err) =
+            checksum_archive(&read_path, archive, config, io_lock, |member, checksum| {
+                let member =
+                    snapshot.map_or_else(|| member.to_owned(), |snapshot| snapshot.unmap(member));
+                print_checksum(&member, checksum, format)
+            })
+        {
+            print_error(path, err);
+        }
+        if let Some((index, in_order)) = order {
+            in_order.skip(index);
+        }
+        return;
+    }
+    let shown = path.to_owned();
+    if let Err(err) = checksum_listed(
+        read_path,
+        None,
+        opened,
+        config,
+        Arc::clone(io_lock),
+        s,
+        move |read_path, checksum| {
+            if let (Some(cas), Ok(hashed)) = (cas, &checksum) {
+                if let Err(err) = cas.export(&shown, read_path, &hashed.checksum) {
+                    print_error(&shown, err);
+                }
+            }
+            match order {
+                Some((index, in_order)) => in_order.print(index, &shown, checksum),
+                None => print_checksum(&shown, checksum, format),
+            }
+        },
+    ) {
+        match order {
+            Some((index, in_order)) => in_order.print(index, path, Err(err)),
+            None => print_error(path, err),
+        }
+    }
+    if hash.ads && path.to_str() != Some("-") {
+        let streams = alternate_streams(path).unwrap_or_else(|err| {
+            print_error(path, err);
+            Vec::new()
+        });
+        for stream in streams {
+            if let Err(err) = checksum_with(
+                stream.clone(),
+                config,
+                Arc::clone(io_lock),
                 s,
+                move |path, checksum| print_checksum(path, checksum, format),
             ) {
-                print_error(&path, err);
+                print_error(&stream, err);
             }
         }
-    });
+    }
+}
+
+/// Copy standard input to standard output while checksumming it, for `--tee`, and print the
+/// checksum line to standard error, or to `--tee-checksum`. Returns the exit status.
+fn tee(hash: &HashOptions, config: &Config, format: Format) -> i32 {
+    let stdin = Path::new("-");
+    let output = BufWriter::new(std::io::stdout());
+    let input = Input::from_reader(TeeReader::new(std::io::stdin(), output), None);
+    let checksum = match hash_input(input, config) {
+        Ok(checksum) => checksum,
+        Err(err) => {
+            print_error(stdin, err);
+            return EXIT_IO_ERROR;
+        }
+    };
+    let entry = Entry {
+        name: "-".to_owned(),
+        checksum,
+        algorithm: format.algorithm(),
+        size: None,
+        timestamp: None,
+        metadata: None,
+        pieces: Vec::new(),
+    };
+    // Only --no-names has no line of its own, since --raw can't be given with --tee.
+    let line = format_line(&entry, format).unwrap_or_else(|| entry.checksum.to_string());
+    match &hash.tee_checksum {
+        Some(path) => {
+            if let Err(err) = std::fs::write(path, format!("{}\n", line)) {
+                print_error(path, err.into());
+                return EXIT_IO_ERROR;
+            }
+        }
+        None => eprintln!("{}", line),
+    }
+    0
+}
+
+/// Answer checksum requests on standard input, for `--server` and `serve`, with each request and
+/// response ending with NUL if `nul` is set. Returns the exit status.
+fn serve_requests(config: &Config, nul: bool) -> i32 {
+    let delimiter = if nul { 0 } else { b'\n' };
+    let stdin = std::io::stdin();
+    let stdout = std::io::stdout();
+    match serve(stdin.lock(), stdout.lock(), config, delimiter) {
+        Ok(()) => 0,
+        Err(err) => {
+            print_error(Path::new("-"), err);
+            EXIT_IO_ERROR
+        }
+    }
+}
+
+/// Answer the requests of `remote-verify` on standard input, about the files under the current
+/// directory. Returns the exit status.
+fn serve_stdio(config: &Config) -> i32 {
+    let stdin = std::io::stdin();
+    let stdout = std::io::stdout();
+    match serve_remote(stdin.lock(), stdout.lock(), Path::new("."), config) {
+        Ok(()) => 0,
+        Err(err) => {
+            print_error(Path::new("-"), err);
+            EXIT_IO_ERROR
+        }
+    }
+}
+
+/// The most connections that `remote-verify --listen` answers at once. The others wait to be
+/// accepted until one of them closes.
+const MAX_REMOTE_CONNECTIONS: usize = 16;
+
+/// Answer the requests of `remote-verify` over TCP, for `--listen`, about the files under the
+/// current directory, with a thread for each connection. It only returns if `address` can't be
+/// listened on.
+fn listen_remote(address: &str, config: &Config) -> i32 {
+    let listener = match TcpListener::bind(address) {
+        Ok(listener) => listener,
+        Err(err) => {
+            print_error(Path::new(address), err.into());
+            return EXIT_IO_ERROR;
+        }
+    };
+    // The number of connections that are being answered.
+    let open = Arc::new((Mutex::new(0), Condvar::new()));
+    loop {
+        {
+            let (count, closed) = &*open;
+            let count = count.lock().unwrap();
+            let mut count = closed
+                .wait_while(count, |count| *count >= MAX_REMOTE_CONNECTIONS)
+                .unwrap();
+            *count += 1;
+        }
+        let config = config.clone();
+        let open = Arc::clone(&open);
+        let connection = listener.accept().and_then(|(stream, peer)| {
+            let peer = peer.to_string();
+            Ok((BufReader::new(stream.try_clone()?), stream, peer))
+        });
+        match connection {
+            Ok((requests, responses, peer)) => {
+                std::thread::spawn(move || {
+                    if let Err(err) = serve_remote(requests, responses, Path::new("."), &config) {
+                        print_error(Path::new(&peer), err);
+                    }
+                    let (count, closed) = &*open;
+                    *count.lock().unwrap() -= 1;
+                    closed.notify_one();
+                });
+            }
+            Err(err) => {
+                *open.0.lock().unwrap() -= 1;
+                print_warning(&format!("a connection failed: {}", err));
+            }
+        }
+    }
+}
+
+/// How `remote-verify` reaches the other machine.
+struct Remote<'a> {
+    command: Option<&'a str>,
+    connect: Option<&'a str>,
+}
+
+/// Verify the files listed in `checkfiles` against the other machine's copies. Returns the exit
+/// status.
+fn verify_remote(checkfiles: &[PathBuf], format: Format, remote: Remote) -> i32 {
+    let passed = if let Some(address) = remote.connect {
+        let stream = TcpStream::connect(address).and_then(|stream| {
+            let responses = BufReader::new(stream.try_clone()?);
+            Ok((stream, responses))
+        });
+        match stream {
+            Ok((requests, responses)) => remote_verify(checkfiles, format, requests, responses),
+            Err(err) => {
+                print_error(Path::new(address), err.into());
+                return EXIT_IO_ERROR;
+            }
+        }
+    } else {
+        // The command is required without --connect.
+        let command = remote.command.unwrap();
+        let mut shell = if cfg!(windows) {
+            let mut shell = std::process::Command::new("cmd");
+            shell.arg("/C");
+            shell
+        } else {
+            let mut shell = std::process::Command::new("sh");
+            shell.arg("-c");
+            shell
+        };
+        let spawned = shell
+            .arg(command)
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .spawn();
+        let mut child = match spawned {
+            Ok(child) => child,
+            Err(err) => {
+                print_error(Path::new(command), err.into());
+                return EXIT_IO_ERROR;
+            }
+        };
+        let requests = child.stdin.take().unwrap();
+        let responses = BufReader::new(child.stdout.take().unwrap());
+        let passed = remote_verify(checkfiles, format, requests, responses);
+        match child.wait() {
+            Ok(status) if status.success() => passed,
+            Ok(status) => {
+                print_warning(&format!("the command exited with {}", status));
+                false
+            }
+            Err(err) => {
+                print_error(Path::new(command), err.into());
+                false
+            }
+        }
+    };
+    if passed {
+        0
+    } else {
+        EXIT_FAILURE
+    }
+}
+
+/// Decode the Bao encoding in `input` to standard output, for the decode subcommand. Errors are
+/// returned with the file that they're about.
+fn decode<'a>(
+    root: &Checksum,
+    input: &'a Path,
+    outboard: Option<&'a Path>,
+) -> Result<(), (&'a Path, Box<dyn std::error::Error>)> {
+    let open = |path: &Path| -> std::io::Result<Box<dyn Read>> {
+        if path.to_str() == Some("-") {
+            Ok(Box::new(std::io::stdin()))
+        } else {
+            Ok(Box::new(File::open(path)?))
+        }
+    };
+    let mut encoded = open(input).map_err(|err| (input, err.into()))?;
+    let mut tree = match outboard {
+        Some(path) => Some(open(path).map_err(|err| (path, err.into()))?),
+        None => None,
+    };
+    let stdout = std::io::stdout();
+    let mut output = BufWriter::new(stdout.lock());
+    let decoded = bao_decode(
+        &mut encoded,
+        tree.as_mut().map(|tree| tree as &mut dyn Read),
+        root,
+        &mut output,
+    );
+    // The chunks that were verified are written even if a later one is corrupt.
+    let flushed = output.flush();
+    decoded.map_err(|err| (input, err))?;
+    flushed.map_err(|err| (Path::new("-"), err.into()))
+}
+
+/// An input and its place among the inputs, so that its checksum can be printed in order.
+#[derive(Clone, Copy)]
+struct Numbered<'a> {
+    index: usize,
+    path: &'a PathBuf,
+}
+
+impl AsRef<Path> for Numbered<'_> {
+    fn as_ref(&self) -> &Path {
+        self.path
+    }
+}
+
+/// List the inputs and how they would be read, and their total size. Returns the exit status.
+fn dry_run(paths: &[PathBuf], hash: &HashOptions, decompress: bool) -> i32 {
+    let mut files = 0;
+    let mut bytes = 0;
+    for path in paths {
+        let planned = planned_read(path).map(|(strategy, size)| {
+            let is_archive = size.is_some()
+                && hash
+                    .archive
+                    .and_then(|archive| archive.resolve(path, decompress))
+                    .is_some();
+            (if is_archive { "archive" } else { strategy }, size)
+        });
+        match planned {
+            Ok((strategy, size)) => {
+                files += 1;
+                bytes += size.unwrap_or(0);
+                print_planned(path, strategy, size);
+            }
+            Err(err) => print_error(path, err),
+        }
+    }
+    flush_output();
+    println!("{:>12}  total, {} files", bytes, files);
+    0
+}
+
+/// Verify checkfiles, with BLAKE3 checksums and seals in `blake3_mode`. Returns the exit status,
+/// which is at least 1 if anything failed.
+fn check_command(
+    checkfiles: &[PathBuf],
+    format: Format,
+    verify: &VerifyArgs,
+    global: &GlobalOptions,
+    blake3_mode: Blake3Mode,
+    io_lock: Arc<Semaphore>,
+) -> i32 {
+    if format == Format::Nsrl {
+        usage_error("NSRL files can't be checked");
+    }
+    let config = Config::builder()
+        .max_job_count(global.job_count)
+        .use_mmap(global.mmap)
+        .blake3_mode(blake3_mode)
+        .decompress(global.decompress)
+        .no_cache(global.no_cache)
+        .readahead(global.readahead)
+        .lock(global.lock)
+        .entropy(global.entropy)
+        .ssdeep(global.ssdeep)
+        .verify_stable(global.verify_stable)
+        .paranoid(global.paranoid)
+        .exclusive_large(global.io_exclusive_large)
+        .io_strategy(global.io_strategy)
+        .build();
+    let options = verify.into();
+    if let Some(every) = verify.every {
+        if every == 0 {
+            usage_error("--every must be at least 1");
+        }
+        // Standard input is empty after the first check.
+        if checkfiles.iter().any(|path| path.to_str() == Some("-")) {
+            usage_error("--every can't check standard input, since it can only be read once");
+        }
+        // The failures of each check are printed, and the checks go on, until the deadline of
+        // --timeout. The status is a failure if any of the checks failed.
+        let mut passed = true;
+        loop {
+            passed &= check(checkfiles, &config, Arc::clone(&io_lock), format, &options);
+            let wake = Instant::now() + Duration::from_secs(every);
+            if deadline().map_or(false, |deadline| deadline <= wake) {
+                return if passed { 0 } else { EXIT_FAILURE };
+            }
+            // The results of this check are shown before waiting for the next.
+            flush_output();
+            std::thread::sleep(Duration::from_secs(every));
+        }
+    }
+    if check(checkfiles, &config, io_lock, format, &options) {
+        0
+    } else {
+        EXIT_FAILURE
+    }
+}
+
+/// Work out how checksums are computed and printed from the hashing options. `reads_stdin` means
+/// that standard input will be checksummed, so it can't be used for the key of `--keyed`.
+fn hash_settings(
+    hash: &HashOptions,
+    global: &GlobalOptions,
+    reads_stdin: bool,
+) -> (Config, Format) {
+    if hash.format != Format::B3sum && (hash.raw || hash.no_names || hash.tag) {
+        usage_error("--raw, --no-names and --tag can only be used with the b3sum format");
+    }
+    let format = if hash.raw {
+        Format::Raw
+    } else if hash.no_names {
+        Format::NoNames
+    } else if hash.tag {
+        Format::Tag
+    } else {
+        hash.format
+    };
+
+    let rows = matches!(format, Format::Nsrl | Format::Json | Format::Csv);
+    if rows && (hash.seal || hash.timestamps || hash.metadata || hash.chain.is_some()) {
+        usage_error(
+            "--seal, --timestamps, --metadata and --chain can't be used with --format nsrl, json \
+             or csv",
+        );
+    }
+    if format == Format::Nsrl && hash.xattrs {
+        usage_error("--xattrs can't be used with --format nsrl, which has no column for it");
+    }
+    if format == Format::Csv && hash.piecewise.is_some() {
+        usage_error("--piecewise can't be used with --format csv, which has no column for pieces");
+    }
+    if format == Format::Nsrl {
+        if !Algorithm::Sha1.is_available() {
+            usage_error("--format nsrl needs SHA-1, which requires the extra-algorithms feature");
+        }
+        // The sizes in the rows are of the files on disk.
+        if global.decompress || hash.archive.is_some() {
+            usage_error("--decompress and --archive can't be used with --format nsrl");
+        }
+    }
+
+    let flags_known = hash.known_hashes.is_some() && hash.known == KnownAction::Flag;
+    if flags_known && (rows || matches!(format, Format::Raw | Format::NoNames)) {
+        usage_error(
+            "--known flag can't be used with --raw, --no-names or --format nsrl, json or csv",
+        );
+    }
+
+    if hash.piecewise == Some(0) {
+        usage_error("--piecewise must be at least 1");
+    }
+
+    let uses_blake3_options = hash.keyed || hash.derive_key.is_some() || hash.length.is_some();
+    if uses_blake3_options && format.algorithm() != Algorithm::Blake3 {
+        usage_error("--keyed, --derive-key and --length can only be used with BLAKE3 checksums");
+    }
+    let blake3_mode = read_blake3_mode(hash.keyed, hash.derive_key.as_deref(), reads_stdin);
+
+    let config = Config::builder()
+        .max_job_count(global.job_count)
+        .use_mmap(global.mmap)
+        .algorithm(format.algorithm())
+        .blake3_mode(blake3_mode)
+        .length(hash.length.unwrap_or(Config::default().length))
+        .decompress(global.decompress)
+        .no_cache(global.no_cache)
+        .readahead(global.readahead)
+        .lock(global.lock)
+        .entropy(global.entropy)
+        .ssdeep(global.ssdeep)
+        .piecewise(hash.piecewise)
+        .xattrs(hash.xattrs)
+        .verify_stable(global.verify_stable)
+        .paranoid(global.paranoid)
+        .exclusive_large(global.io_exclusive_large)
+        .io_strategy(global.io_strategy)
+        .build();
+    (config, format)
+}
+
+/// The mode of BLAKE3 that `--keyed` and `--derive-key` choose. With `--keyed`, the key is read
+/// from standard input, so `reads_stdin` means that it's also an input, which is a usage error.
+fn read_blake3_mode(keyed: bool, derive_key: Option<&str>, reads_stdin: bool) -> Blake3Mode {
+    if keyed {
+        if reads_stdin {
+            usage_error("--keyed reads the key from standard input, so it can't also be an input");
+        }
+        match read_key(&mut std::io::stdin()) {
+            Ok(key) => Blake3Mode::Keyed(key),
+            Err(err) => {
+                print_error(Path::new("-"), err);
+                finish(None, None, EXIT_FAILURE);
+            }
+        }
+    } else if let Some(context) = derive_key {
+        Blake3Mode::DeriveKey(context.to_owned().into())
+    } else {
+        Blake3Mode::Hash
+    }
+}
+
+/// Print an error about the combination of arguments that were given, and exit.
+fn usage_error(message: &str) -> ! {
+    exit_usage(clap::Error::with_description(
+        message,
+        clap::ErrorKind::ArgumentConflict,
+    ))
+}
+
+/// Print an error about the command line and exit with [`EXIT_USAGE`]. `--help` and `--version`
+/// exit successfully instead.
+fn exit_usage(err: clap::Error) -> ! {
+    match err.kind {
+        clap::ErrorKind::HelpDisplayed | clap::ErrorKind::VersionDisplayed => err.exit(),
+        _ => {
+            eprintln!("{}", err.message);
+            std::process::exit(EXIT_USAGE)
+        }
+    }
 }
@@ -0,0 +1,2477 @@
+// Copyright 2021 Daniel Zwell.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Printing checksums, errors, warnings, diagnostics and progress, and the exit status that
+//! results from them.
+
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt,
+    io::{BufWriter, Read, Stdout, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicI32, AtomicU64, AtomicUsize, Ordering},
+        mpsc, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use crate::{
+    checkfile::{
+        chain_line, chain_link, dir_line, entropy_line, known_line, nsrl_columns, piece_line,
+        ssdeep_line, timestamp_line, FileMetadata, KnownHashes, Seal, NSRL_HEADER,
+    },
+    engine::{
+        directory_digest, Algorithm, Blake3Mode, BlockError, Checksum, Chunk, Config, Duplicate,
+        Hashed, Measurement,
+    },
+    verify::Difference,
+    write_atomically, Global, Result,
+};
+
+#[cfg(test)]
+use crate::make_temp_file;
+
+/// Print a checksum or an error that was encountered. A [`Hashed`] is printed with its entropy,
+/// ssdeep hash and pieces.
+pub fn print_checksum<C: Into<Hashed>>(path: &Path, result: Result<C>, format: Format) {
+    print_hashed(path, result.map(Into::into), format);
+}
+
+fn print_hashed(path: &Path, result: Result<Hashed>, format: Format) {
+    record_status(if result.is_ok() { "ok" } else { "error" });
+    match result {
+        Ok(_) if QUIET.load(Ordering::Relaxed) => {}
+        Ok(Hashed {
+            checksum,
+            computed,
+            entropy,
+            ssdeep,
+            pieces,
+            metadata,
+            xattrs,
+        }) => {
+            let known = KNOWN
+                .get()
+                .filter(|(known, _)| known.contains(&checksum))
+                .map(|(_, action)| *action);
+            if known == Some(KnownAction::Exclude) {
+                return;
+            }
+            // The metadata and the attributes were taken from the file when it was read, so they're
+            // those of the file whose checksum this is. Standard input has none.
+            let size = metadata.as_ref().map(|metadata| metadata.len());
+            if let Some(rollup) = ROLLUP.get() {
+                rollup
+                    .lock()
+                    .unwrap()
+                    .record(&normalized(path), &checksum, size);
+            }
+            let xattrs = xattrs.filter(|_| XATTRS.load(Ordering::Relaxed));
+            let metadata = metadata.filter(|_| {
+                (METADATA.load(Ordering::Relaxed) || xattrs.is_some()) && has_comments(format)
+                    || format == Format::Nsrl
+            });
+            let path = normalized(path);
+            // The grouping is kept locked while the line is printed, so that a directory's header
+            // is printed before the files that follow it.
+            let mut grouping = GROUPING.get().map(|grouping| grouping.lock().unwrap());
+            let (header, path) = match &mut grouping {
+                Some(grouping) if has_comments(format) => grouping.place(&path, format),
+                _ => (None, path),
+            };
+            // The parts of the line before and after the digest.
+            let (before, digest, after) = match format {
+                Format::B3sum => (
+                    String::new(),
+                    checksum.to_string(),
+                    format!("  {}", path.display()),
+                ),
+                Format::Sfv => (
+                    format!("{} ", path.display()),
+                    format!("{:X}", checksum),
+                    String::new(),
+                ),
+                Format::Tag => (
+                    format!("{} ({}) = ", format.algorithm(), path.display()),
+                    checksum.to_string(),
+                    String::new(),
+                ),
+                Format::NoNames => (String::new(), checksum.to_string(), String::new()),
+                Format::Nsrl => {
+                    // The size is unknown for standard input.
+                    (
+                        "\"".to_owned(),
+                        format!("{:X}", checksum),
+                        format!("\"{}", nsrl_columns(&path.to_string_lossy(), size)),
+                    )
+                }
+                Format::Json => (
+                    format!(
+                        "{{\"path\":{},\"algorithm\":{},\"checksum\":\"",
+                        json_string(&path.to_string_lossy()),
+                        json_string(&format.algorithm().to_string())
+                    ),
+                    checksum.to_string(),
+                    format!(
+                        "\",{}}}",
+                        json_details(entropy, ssdeep.as_deref(), xattrs.as_ref(), &pieces)
+                    ),
+                ),
+                Format::Csv => (
+                    format!("{},", csv_field(&path.to_string_lossy())),
+                    checksum.to_string(),
+                    format!(
+                        ",{},{},{}",
+                        entropy.map_or_else(String::new, |entropy| format!("{:.4}", entropy)),
+                        ssdeep.as_deref().map_or_else(String::new, csv_field),
+                        xattrs
+                            .as_ref()
+                            .map_or_else(String::new, |xattrs| xattrs.to_string())
+                    ),
+                ),
+                Format::Raw => {
+                    let written = match BATCHED_OUTPUT.get() {
+                        Some(output) => output.lock().unwrap().write_raw(&checksum.0),
+                        None => {
+                            let mut stdout = std::io::stdout();
+                            stdout.write_all(&checksum.0).and_then(|()| stdout.flush())
+                        }
+                    };
+                    if let Err(err) = written {
+                        print_error(&path, err.into());
+                    }
+                    return;
+                }
+            };
+            let before = if TIMESTAMPS.load(Ordering::Relaxed) && has_comments(format) {
+                format!("{}\n{}", timestamp_line(computed, format), before)
+            } else {
+                before
+            };
+            let before = match metadata {
+                Some(metadata) if path.to_str() != Some("-") && has_comments(format) => {
+                    let mut recorded = if METADATA.load(Ordering::Relaxed) {
+                        FileMetadata::of(&metadata)
+                    } else {
+                        FileMetadata::default()
+                    };
+                    recorded.xattrs = xattrs;
+                    // Nothing is recorded if only the attributes were asked for, and they
+                    // couldn't be read.
+                    if recorded == FileMetadata::default() {
+                        before
+                    } else {
+                        format!("{}\n{}", recorded.to_line(format), before)
+                    }
+                }
+                _ => before,
+            };
+            let before = match entropy {
+                Some(entropy) if has_comments(format) => {
+                    format!("{}\n{}", entropy_line(entropy, format), before)
+                }
+                _ => before,
+            };
+            let before = match ssdeep {
+                Some(ssdeep) if has_comments(format) => {
+                    format!("{}\n{}", ssdeep_line(&ssdeep, format), before)
+                }
+                _ => before,
+            };
+            let before = if !pieces.is_empty() && has_comments(format) {
+                let mut lines: Vec<String> = pieces
+                    .iter()
+                    .map(|piece| piece_line(piece, format))
+                    .collect();
+                lines.push(before);
+                lines.join("\n")
+            } else {
+                before
+            };
+            let before = if known == Some(KnownAction::Flag) && has_comments(format) {
+                format!("{}\n{}", known_line(format), before)
+            } else {
+                before
+            };
+            let before = match header {
+                Some(header) => format!("{}\n{}", header, before),
+                None => before,
+            };
+            // The chain is kept locked while the record is printed, so that each record links to
+            // the one that was printed before it.
+            let chain = CHAIN.get().map(|chain| chain.lock().unwrap());
+            let before = match &chain {
+                Some(previous) => format!("{}\n{}", chain_line(previous, format), before),
+                None => before,
+            };
+            let record = format!("{}{}{}\n", before, digest, after);
+            // The seal is kept locked while the line is printed, so that lines are hashed in
+            // the order they're printed.
+            let _seal = SEAL.get().map(|(seal, _)| {
+                let mut seal = seal.lock().unwrap();
+                seal.update(record.as_bytes());
+                seal
+            });
+            outln!("{}{}{}", before, stdout_color(digest, DIGEST), after);
+            if let Some(mut previous) = chain {
+                *previous = chain_link(record.as_bytes());
+            }
+            if let Some(sync) = OUTPUT_SYNC.get() {
+                let mut sync = sync.lock().unwrap();
+                sync.unsynced += 1;
+                if sync.is_due() {
+                    sync.sync();
+                }
+            }
+        }
+        Err(err) => print_error(path, err),
+    }
+}
+
+/// The header row of [`Format::Csv`].
+const CSV_HEADER: &str = "path,blake3,entropy,ssdeep,xattrs";
+
+/// The fields of a [`Format::Json`] object after the checksum.
+fn json_details(
+    entropy: Option<f64>,
+    ssdeep: Option<&str>,
+    xattrs: Option<&Checksum>,
+    pieces: &[Chunk],
+) -> String {
+    let pieces: Vec<String> = pieces
+        .iter()
+        .map(|piece| {
+            format!(
+                "{{\"offset\":{},\"length\":{},\"checksum\":\"{}\"}}",
+                piece.offset, piece.length, piece.checksum
+            )
+        })
+        .collect();
+    format!(
+        "\"entropy\":{},\"ssdeep\":{},\"xattrs\":{},\"pieces\":[{}]",
+        entropy.map_or("null".to_owned(), |entropy| format!("{:.4}", entropy)),
+        ssdeep.map_or("null".to_owned(), json_string),
+        xattrs.map_or("null".to_owned(), |xattrs| format!("\"{}\"", xattrs)),
+        pieces.join(",")
+    )
+}
+
+/// A field of a CSV row, which is quoted if it has a comma, a quote or a line break. Quotes are
+/// doubled.
+fn csv_field(field: &str) -> String {
+    if field.contains(&[',', '"', '\n', '\r'][..]) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_owned()
+    }
+}
+
+/// Prints checksums in the order that their inputs were given, though they're computed in any
+/// order, for `--from-manifest`. Each input is numbered from 0, and its checksum is held until the
+/// ones before it have been printed. Errors are printed at once, since they aren't part of the
+/// output. An input that has no checksum of its own must be passed to [`InOrder::skip`], and
+/// [`InOrder::finish`] prints whatever is still held once all of the inputs are done.
+pub struct InOrder {
+    format: Format,
+    /// The number of the next input to print, and the checksums that are waiting for it.
+    pending: Mutex<(usize, BTreeMap<usize, Held>)>,
+}
+
+/// A checksum that is held until it can be printed by [`InOrder`], or `None` for an input whose
+/// error was printed.
+type Held = Option<(PathBuf, Hashed)>;
+
+impl InOrder {
+    pub fn new(format: Format) -> InOrder {
+        InOrder {
+            format,
+            pending: Mutex::new((0, BTreeMap::new())),
+        }
+    }
+
+    /// Print the checksum of the input numbered `index` if the ones before it have been
+    /// printed, and otherwise keep it until they are, like [`print_checksum`]. If an input has
+    /// more than one checksum, such as a directory, the ones after the first are printed at once.
+    pub fn print(&self, index: usize, path: &Path, result: Result<Hashed>) {
+        let mut pending = self.pending.lock().unwrap();
+        let (next, waiting) = &mut *pending;
+        if index < *next || waiting.contains_key(&index) {
+            print_hashed(path, result, self.format);
+            return;
+        }
+        let checksum = match result {
+            Ok(hashed) => Some((path.to_owned(), hashed)),
+            Err(err) => {
+                print_hashed(path, Err(err), self.format);
+                None
+            }
+        };
+        waiting.insert(index, checksum);
+        self.print_ready(next, waiting);
+    }
+
+    /// Let the inputs after the one numbered `index` be printed without waiting for it, because it
+    /// has nothing to print in order, such as an archive whose members were printed at once, or a
+    /// file that was skipped.
+    pub fn skip(&self, index: usize) {
+        let mut pending = self.pending.lock().unwrap();
+        let (next, waiting) = &mut *pending;
+        if index >= *next {
+            waiting.entry(index).or_insert(None);
+            self.print_ready(next, waiting);
+        }
+    }
+
+    /// Print the checksums that are still held, in order, as if the inputs that never came, such
+    /// as the ones that weren't read because the run was cancelled, were skipped.
+    pub fn finish(&self) {
+        let mut pending = self.pending.lock().unwrap();
+        let (next, waiting) = &mut *pending;
+        for (index, checksum) in std::mem::take(waiting) {
+            if let Some((path, hashed)) = checksum {
+                print_hashed(&path, Ok(hashed), self.format);
+            }
+            *next = index + 1;
+        }
+    }
+
+    fn print_ready(&self, next: &mut usize, waiting: &mut BTreeMap<usize, Held>) {
+        while let Some(checksum) = waiting.remove(next) {
+            if let Some((path, hashed)) = checksum {
+                print_hashed(&path, Ok(hashed), self.format);
+            }
+            *next += 1;
+        }
+    }
+}
+
+/// Whether the timestamps and metadata of checksums can be printed as comments in a format.
+fn has_comments(format: Format) -> bool {
+    !matches!(
+        format,
+        Format::NoNames | Format::Nsrl | Format::Json | Format::Csv
+    )
+}
+
+/// Whether [`print_checksum`] prints when each checksum was computed. Set by [`set_timestamps`].
+static TIMESTAMPS: AtomicBool = AtomicBool::new(false);
+
+/// Print a comment with the time before each checksum line, such as
+/// `# computed 2026-10-14T09:30:12.123Z`. See
+/// [`Entry::timestamp`](crate::checkfile::Entry::timestamp).
+#[cfg_attr(not(feature = "cli"), allow(dead_code))]
+pub(crate) fn set_timestamps(timestamps: bool) {
+    TIMESTAMPS.store(timestamps, Ordering::Relaxed);
+}
+
+/// Whether [`print_checksum`] prints the metadata of each file. Set by [`set_metadata`].
+static METADATA: AtomicBool = AtomicBool::new(false);
+
+/// Print a comment with the size, mode, owner and modification time of each file before its
+/// checksum line, so that `--check` can tell if they changed. See [`FileMetadata`].
+#[cfg_attr(not(feature = "cli"), allow(dead_code))]
+pub(crate) fn set_metadata(metadata: bool) {
+    METADATA.store(metadata, Ordering::Relaxed);
+}
+
+/// Whether [`print_checksum`] prints the digest of each file's extended attributes. Set by
+/// [`set_xattrs`].
+static XATTRS: AtomicBool = AtomicBool::new(false);
+
+/// Print the [`Hashed::xattrs`] of each file in the metadata comment before its checksum line,
+/// after the other metadata if [`set_metadata`] was called too. They're only taken with
+/// [`Config::xattrs`](crate::engine::Config::xattrs).
+#[cfg_attr(not(feature = "cli"), allow(dead_code))]
+pub(crate) fn set_xattrs(xattrs: bool) {
+    XATTRS.store(xattrs, Ordering::Relaxed);
+}
+
+/// The known checksums and what is done with them, once [`set_known_hashes`] is called.
+static KNOWN: Global<(KnownHashes, KnownAction)> = Global::new();
+
+/// Flag or leave out the files that [`print_checksum`] prints whose checksums are in `known`.
+/// This should only be called once, before any checksums are printed.
+#[cfg_attr(not(feature = "cli"), allow(dead_code))]
+pub(crate) fn set_known_hashes(known: KnownHashes, action: KnownAction) {
+    let _ = KNOWN.set((known, action));
+}
+
+/// The hash of the lines printed by [`print_checksum`], and the mode it's hashed in, once
+/// [`start_seal`] is called.
+static SEAL: Global<(Mutex<blake3::Hasher>, Blake3Mode)> = Global::new();
+
+/// Start hashing the lines that [`print_checksum`] prints in `mode`, for [`print_seal`]. This
+/// should only be called once, before any checksums are printed.
+#[cfg_attr(not(feature = "cli"), allow(dead_code))]
+pub(crate) fn start_seal(mode: Blake3Mode) {
+    let _ = SEAL.set((Mutex::new(mode.hasher()), mode));
+}
+
+/// The directory of the last checksum that [`print_checksum`] printed with `--grouped`, and the
+/// root that directories are named relative to.
+#[derive(Debug)]
+struct Grouping {
+    root: PathBuf,
+    dir: Option<String>,
+}
+
+impl Grouping {
+    /// The header to print before the checksum of `path`, if its directory isn't the last one,
+    /// and the name to print it with. The names of directories that can't be written on one
+    /// line, and of those with backslashes, aren't grouped: their files are in the `.` group with
+    /// the rest of their path.
+    fn place(&mut self, path: &Path, format: Format) -> (Option<String>, PathBuf) {
+        let relative = path.strip_prefix(&self.root).unwrap_or(path);
+        let relative = relative.strip_prefix(".").unwrap_or(relative);
+        let (dir, name) = match (relative.parent(), relative.file_name()) {
+            (Some(dir), Some(name)) if !dir.as_os_str().is_empty() => {
+                let dir = dir.to_string_lossy();
+                let dir = if cfg!(windows) {
+                    dir.replace('\\', "/")
+                } else {
+                    dir.into_owned()
+                };
+                if dir.contains(&['\\', '\n', '\r'][..]) {
+                    (".".to_owned(), relative)
+                } else {
+                    (dir, Path::new(name))
+                }
+            }
+            _ => (".".to_owned(), relative),
+        };
+        let name = name.to_owned();
+        if self.dir.as_ref() == Some(&dir) {
+            return (None, name);
+        }
+        let header = dir_line(&dir, format);
+        self.dir = Some(dir);
+        (Some(header), name)
+    }
+}
+
+/// The grouping of `--grouped`, once [`start_grouping`] is called.
+static GROUPING: Global<Mutex<Grouping>> = Global::new();
+
+/// Group the checksums that [`print_checksum`] prints by directory: when the directory changes, a
+/// comment like `# dir photos/2021` is printed, and each checksum line only has the file's name.
+/// Directories are named relative to `root`, so that the output can be checked from it, and
+/// files directly in it are in the `.` group. See [`Parser`](crate::checkfile::Parser). This
+/// should only be called once, before any checksums are printed.
+#[cfg_attr(not(feature = "cli"), allow(dead_code))]
+pub(crate) fn start_grouping(root: PathBuf) {
+    let grouping = Grouping {
+        root: normalized(&root),
+        dir: None,
+    };
+    let _ = GROUPING.set(Mutex::new(grouping));
+}
+
+/// The link to the last record that [`print_checksum`] printed, once [`start_chain`] is called.
+static CHAIN: Global<Mutex<Checksum>> = Global::new();
+
+/// Start each record that [`print_checksum`] prints with a `# chain` line that links it to the
+/// record before it, so that the output can be appended to a chained manifest whose head is
+/// `head`. See [`check_chain`](crate::checkfile::check_chain). This should only be called once,
+/// before any checksums are printed.
+#[cfg_attr(not(feature = "cli"), allow(dead_code))]
+pub(crate) fn start_chain(head: Checksum) {
+    let _ = CHAIN.set(Mutex::new(head));
+}
+
+/// How often the checksums that are printed are synced to disk, as given by `--fsync-every`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SyncInterval {
+    /// After this many checksums, given as a number like `1000`.
+    Checksums(u64),
+    /// After a checksum is printed once this many seconds have passed since the last sync, given
+    /// like `30s`.
+    Seconds(u64),
+}
+
+impl FromStr for SyncInterval {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (number, interval): (_, fn(u64) -> SyncInterval) = match s.strip_suffix('s') {
+            Some(seconds) => (seconds, SyncInterval::Seconds),
+            None => (s, SyncInterval::Checksums),
+        };
+        match number.parse() {
+            Ok(number) if number > 0 => Ok(interval(number)),
+            _ => Err(format!(
+                "expected a number of checksums or of seconds like 30s: {}",
+                s
+            )),
+        }
+    }
+}
+
+/// When the output was last synced, once [`start_output_sync`] is called.
+struct OutputSync {
+    interval: SyncInterval,
+    unsynced: u64,
+    last: Instant,
+}
+
+impl OutputSync {
+    fn is_due(&self) -> bool {
+        match self.interval {
+            SyncInterval::Checksums(count) => self.unsynced >= count,
+            SyncInterval::Seconds(seconds) => self.last.elapsed() >= Duration::from_secs(seconds),
+        }
+    }
+
+    fn sync(&mut self) {
+        if let Err(err) = sync_stdout() {
+            print_error(Path::new("-"), err.into());
+        }
+        self.unsynced = 0;
+        self.last = Instant::now();
+    }
+}
+
+/// The state of `--fsync-every`, once [`start_output_sync`] is called.
+static OUTPUT_SYNC: Global<Mutex<OutputSync>> = Global::new();
+
+/// Sync standard output to disk every `interval`, if it's a file, so that the checksums that were
+/// printed before a crash or a power loss are kept, as whole lines. Syncing with [`sync_output`]
+/// at the end keeps the rest. This should only be called once, before any checksums are printed.
+#[cfg_attr(not(feature = "cli"), allow(dead_code))]
+pub(crate) fn start_output_sync(interval: SyncInterval) {
+    let sync = OutputSync {
+        interval,
+        unsynced: 0,
+        last: Instant::now(),
+    };
+    let _ = OUTPUT_SYNC.set(Mutex::new(sync));
+}
+
+/// Sync standard output to disk now, if `--fsync-every` is syncing it.
+pub fn sync_output() {
+    if let Some(sync) = OUTPUT_SYNC.get() {
+        sync.lock().unwrap().sync();
+    }
+}
+
+/// Flush standard output, and if it's a file, sync it to disk. Pipes and terminals are only
+/// flushed.
+fn sync_stdout() -> std::io::Result<()> {
+    if let Some(output) = BATCHED_OUTPUT.get() {
+        output.lock().unwrap().flush()?;
+    }
+    std::io::stdout().flush()?;
+    crate::sys::sync_stdout()
+}
+
+/// How many lines are batched before they're written, unless standard output is a terminal.
+const LINES_PER_FLUSH: usize = 1024;
+
+/// The longest that a line is kept before it's written.
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Standard output, with the lines batched so that it's written and locked once for many lines,
+/// instead of once for each. A terminal is flushed after every line, so results show as they're
+/// found.
+struct BatchedOutput {
+    writer: BufWriter<Stdout>,
+    terminal: bool,
+    unflushed: usize,
+    last_flush: Instant,
+    /// Whether writing failed. The error is printed once, and the rest of the output is dropped.
+    failed: bool,
+}
+
+impl BatchedOutput {
+    fn write_line(&mut self, line: fmt::Arguments) {
+        if self.failed {
+            return;
+        }
+        let written = self
+            .writer
+            .write_fmt(line)
+            .and_then(|()| self.writer.write_all(b"\n"));
+        self.unflushed += 1;
+        let due = self.terminal
+            || self.unflushed >= LINES_PER_FLUSH
+            || self.last_flush.elapsed() >= FLUSH_INTERVAL;
+        let written = written.and_then(|()| if due { self.flush() } else { Ok(()) });
+        if let Err(err) = written {
+            self.failed = true;
+            print_error(Path::new("-"), err.into());
+        }
+    }
+
+    /// Write bytes that aren't a line, and flush them, as [`Format::Raw`] always has.
+    fn write_raw(&mut self, bytes: &[u8]) -> std::io::Result<()> {
+        self.writer.write_all(bytes)?;
+        self.flush()
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.unflushed = 0;
+        self.last_flush = Instant::now();
+        self.writer.flush()
+    }
+
+    /// Flush, and print the error if it fails, after which the rest of the output is dropped.
+    fn flush_or_fail(&mut self) {
+        if !self.failed {
+            if let Err(err) = self.flush() {
+                self.failed = true;
+                print_error(Path::new("-"), err.into());
+            }
+        }
+    }
+}
+
+/// The batched standard output, once [`start_batched_output`] is called.
+static BATCHED_OUTPUT: Global<Mutex<BatchedOutput>> = Global::new();
+
+/// Batch the checksums and results printed to standard output, so that printing millions of
+/// them isn't slowed by writing each line. The lines are written in batches of
+/// 1024 lines, or once they're a second old, or after each line if standard output is a terminal,
+/// so [`flush_output`] must be called before exiting. This should only be called once, before
+/// anything is printed.
+#[cfg_attr(not(feature = "cli"), allow(dead_code))]
+pub(crate) fn start_batched_output() {
+    let output = BatchedOutput {
+        writer: BufWriter::with_capacity(64 * 1024, std::io::stdout()),
+        terminal: atty::is(atty::Stream::Stdout),
+        unflushed: 0,
+        last_flush: Instant::now(),
+        failed: false,
+    };
+    if BATCHED_OUTPUT.set(Mutex::new(output)).is_err() {
+        return;
+    }
+    // Lines are written when they're old even if nothing is printed after them, such as while a
+    // large file is read. Without threads, as on WASI, they wait for the next line.
+    let _ = std::thread::Builder::new().spawn(|| loop {
+        std::thread::sleep(FLUSH_INTERVAL);
+        if let Some(output) = BATCHED_OUTPUT.get() {
+            let mut output = output.lock().unwrap();
+            if output.unflushed > 0 && output.last_flush.elapsed() >= FLUSH_INTERVAL {
+                output.flush_or_fail();
+            }
+        }
+    });
+}
+
+/// Write the lines that are held, if the command line program is batching standard output.
+pub fn flush_output() {
+    if let Some(output) = BATCHED_OUTPUT.get() {
+        output.lock().unwrap().flush_or_fail();
+    }
+}
+
+/// The longest that the lines that are held are waited for when the process gets SIGINT or
+/// SIGTERM, before it exits without them.
+const TERMINATION_FLUSH_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Write the lines that are held, and sync standard output if [`start_output_sync`] was called,
+/// when the process gets SIGINT or SIGTERM, before the signal ends it, so that the checksums that
+/// were computed aren't lost. The process ends after [`TERMINATION_FLUSH_TIMEOUT`] even if they
+/// couldn't be written, such as when another thread holds the output, or when standard output is
+/// a pipe that's full because nothing reads it. This is only supported on Unix.
+#[cfg_attr(not(feature = "cli"), allow(dead_code))]
+pub(crate) fn flush_on_termination() -> std::io::Result<()> {
+    let mut signals = crate::sys::catch_termination()?;
+    std::thread::Builder::new().spawn(move || {
+        let mut signum = [0];
+        if signals.read_exact(&mut signum).is_ok() {
+            let (flushed, done) = mpsc::channel();
+            let flushing = std::thread::Builder::new().spawn(move || {
+                flush_output();
+                sync_output();
+                let _ = flushed.send(());
+            });
+            if flushing.is_ok() {
+                let _ = done.recv_timeout(TERMINATION_FLUSH_TIMEOUT);
+            }
+            crate::sys::terminate(signum[0].into());
+        }
+    })?;
+    Ok(())
+}
+
+/// Print a line to standard output, in a batch if [`start_batched_output`] was called. The
+/// `outln!` macro calls this.
+pub(crate) fn print_line(line: fmt::Arguments) {
+    match BATCHED_OUTPUT.get() {
+        Some(output) => output.lock().unwrap().write_line(line),
+        None => println!("{}", line),
+    }
+}
+
+/// Print the head of the chain to standard error, once the records have been printed, so that it
+/// can be kept somewhere else, and compared with `--chain-head` to find records that were removed
+/// from the end.
+pub fn print_chain_head() {
+    if let Some(chain) = CHAIN.get() {
+        if !QUIET.load(Ordering::Relaxed) {
+            eprintln!("{}: chain head: {}", binary_name(), chain.lock().unwrap());
+        }
+    }
+}
+
+/// Print the first line of a file in this format, if it has one. Only [`Format::Nsrl`] and
+/// [`Format::Csv`] do.
+pub fn print_header(format: Format) {
+    if QUIET.load(Ordering::Relaxed) {
+        return;
+    }
+    match format {
+        Format::Nsrl => outln!("{}", NSRL_HEADER),
+        Format::Csv => outln!("{}", CSV_HEADER),
+        _ => {}
+    }
+}
+
+/// Print the [`Seal`] of the lines printed since `--seal` started hashing them, as the last line
+/// of a checkfile.
+pub fn print_seal(format: Format) {
+    if let Some((hasher, mode)) = SEAL.get() {
+        let seal = Seal::from_hash(hasher.lock().unwrap().finalize(), mode);
+        outln!("{}", seal.to_line(format));
+    }
+}
+
+/// Print the location and checksum of a block of a file, or if it couldn't be read, an error and
+/// a line that marks it as unreadable, such as `UNREADABLE  1048576 1048576  disk.img`.
+pub fn print_block(path: &Path, block: Result<Chunk, BlockError>) {
+    match block {
+        Ok(chunk) => print_chunk(path, &chunk),
+        Err(err) => {
+            outln!(
+                "{}  {} {}  {}",
+                stdout_color("UNREADABLE", FAILED),
+                err.offset,
+                err.length,
+                path.display()
+            );
+            print_error(path, err.into());
+        }
+    }
+}
+
+/// Print the location and checksum of a chunk of a file.
+pub fn print_chunk(path: &Path, chunk: &Chunk) {
+    if QUIET.load(Ordering::Relaxed) {
+        return;
+    }
+    outln!(
+        "{}  {} {}  {}",
+        stdout_color(&chunk.checksum, DIGEST),
+        chunk.offset,
+        chunk.length,
+        path.display()
+    );
+}
+
+/// Print the sample digest of a file for `--sample`, labeled so that it isn't mistaken for a
+/// checksum, like `sample 1048576 <digest>  song.mp3`, or an error.
+pub fn print_sample(path: &Path, length: u64, digest: Result<Checksum>) {
+    match digest {
+        Ok(_) if QUIET.load(Ordering::Relaxed) => {}
+        Ok(digest) => outln!(
+            "sample {} {}  {}",
+            length,
+            stdout_color(digest.to_string(), DIGEST),
+            normalized(path).display()
+        ),
+        Err(err) => print_error(path, err),
+    }
+}
+
+/// Print the size of a file and how it would be read, for `--dry-run`. The size of standard input
+/// is unknown, so it is printed as `-`.
+pub fn print_planned(path: &Path, strategy: &str, size: Option<u64>) {
+    let size = size.map_or_else(|| "-".to_owned(), |size| size.to_string());
+    outln!("{:>12}  {:<7}  {}", size, strategy, path.display());
+}
+
+/// Print a set of identical files as checksum lines, followed by an empty line. Files that share
+/// their data through reflinks are marked with "(reflink)".
+pub fn print_duplicates(checksum: &Checksum, duplicates: &[Duplicate]) {
+    if QUIET.load(Ordering::Relaxed) {
+        return;
+    }
+    for duplicate in duplicates {
+        let reflink = if duplicate.reflink { " (reflink)" } else { "" };
+        outln!(
+            "{}  {}{}",
+            stdout_color(checksum, DIGEST),
+            duplicate.path.display(),
+            reflink
+        );
+    }
+    outln!();
+}
+
+/// Print the throughput of one way of reading a file.
+pub fn print_measurement(path: &Path, measurement: &Measurement) {
+    if QUIET.load(Ordering::Relaxed) {
+        return;
+    }
+    let seconds = measurement.elapsed.as_secs_f64();
+    let rate = measurement.bytes as f64 / (1 << 20) as f64 / seconds.max(1e-9);
+    outln!(
+        "{}: {:<9}  {:>9.1} MiB/s  {:.3} s",
+        path.display(),
+        measurement.strategy,
+        rate,
+        seconds
+    );
+}
+
+/// Print a difference between two paths, in the style of `diff -r`.
+pub fn print_difference(difference: Difference) {
+    match difference {
+        Difference::Content(..) | Difference::OnlyIn(..) if QUIET.load(Ordering::Relaxed) => {}
+        Difference::Content(first, second) => {
+            outln!("{} and {} differ", first.display(), second.display())
+        }
+        Difference::OnlyIn(dir, name) => {
+            outln!("Only in {}: {}", dir.display(), Path::new(&name).display())
+        }
+        Difference::Unreadable(path, err) => print_error(&path, err),
+    }
+}
+
+/// Print an error and the filename that caused it. Errors are counted by [`error_count`].
+///
+/// With [`ErrorFormat::Json`], the error is printed as a JSON object instead, like
+/// `{"event":"error","path":"a.txt","phase":"open","kind":"not_found","errno":2,"retries":0,
+/// "message":"No such file or directory (os error 2)"}`. `phase` is what was being done when it
+/// failed, `kind` and `errno` are `null` for errors that aren't I/O errors, and `retries` is the
+/// number of times the file was read again because it changed, with `--verify-stable`.
+pub fn print_error(path: &Path, err: Box<dyn Error>) {
+    ERROR_COUNT.fetch_add(1, Ordering::Relaxed);
+    ERROR_STATUS.fetch_max(error_status_of(&*err), Ordering::Relaxed);
+    log_message(LOG_ERR, &format!("{}: {}", path.display(), err));
+    if let Some(summary) = SUMMARY.get() {
+        let error = (path.display().to_string(), err.to_string());
+        summary.lock().unwrap().errors.push(error);
+    }
+    if JSON_ERRORS.load(Ordering::Relaxed) {
+        eprintln!("{}", error_event(path, &*err));
+        return;
+    }
+    let message = format!("{}: {}: {}", binary_name(), path.display(), err);
+    eprintln!("{}", stderr_color(message, ERROR));
+}
+
+/// Like [`print_error`], for an error that happened in `phase`.
+pub(crate) fn print_error_in(phase: Phase, path: &Path, err: Box<dyn Error>) {
+    print_error(path, in_phase(phase, err));
+}
+
+/// Whether errors are printed as JSON. Set by [`set_error_format`].
+static JSON_ERRORS: AtomicBool = AtomicBool::new(false);
+
+/// Print errors as text or as JSON, for `--errors`.
+#[cfg_attr(not(feature = "cli"), allow(dead_code))]
+pub(crate) fn set_error_format(format: ErrorFormat) {
+    JSON_ERRORS.store(format == ErrorFormat::Json, Ordering::Relaxed);
+}
+
+/// What was being done with a file when it failed, for the JSON errors of [`print_error`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Phase {
+    /// Finding the size of the file.
+    Stat,
+    Open,
+    Read,
+    /// Listing the files of a directory.
+    Walk,
+    /// Reading a checkfile, with `--check`.
+    Checkfile,
+}
+
+impl Phase {
+    fn name(self) -> &'static str {
+        match self {
+            Phase::Stat => "stat",
+            Phase::Open => "open",
+            Phase::Read => "read",
+            Phase::Walk => "walk",
+            Phase::Checkfile => "checkfile",
+        }
+    }
+}
+
+/// An error with the phase in which it happened, and how many times the file was read again
+/// before it. It's displayed like the error it wraps, and [`io_error`] looks through it.
+#[derive(Debug)]
+pub(crate) struct PhaseError {
+    phase: Phase,
+    retries: usize,
+    err: Box<dyn Error>,
+}
+
+impl fmt::Display for PhaseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.err.fmt(f)
+    }
+}
+
+impl Error for PhaseError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.err.source()
+    }
+}
+
+/// Record that `err` happened in `phase`, unless an earlier phase was already recorded.
+pub(crate) fn in_phase(phase: Phase, err: Box<dyn Error>) -> Box<dyn Error> {
+    if err.is::<PhaseError>() {
+        return err;
+    }
+    Box::new(PhaseError {
+        phase,
+        retries: 0,
+        err,
+    })
+}
+
+/// Record that `result` failed in `phase`, if it's an error.
+pub(crate) fn error_phase<T>(phase: Phase, result: Result<T>) -> Result<T> {
+    result.map_err(|err| in_phase(phase, err))
+}
+
+/// Record that a read failed after `retries` retries. It failed while reading unless an earlier
+/// phase was recorded with [`error_phase`].
+pub(crate) fn read_error(retries: usize, err: Box<dyn Error>) -> Box<dyn Error> {
+    match err.downcast::<PhaseError>() {
+        Ok(mut err) => {
+            err.retries = retries;
+            err
+        }
+        Err(err) => Box::new(PhaseError {
+            phase: Phase::Read,
+            retries,
+            err,
+        }),
+    }
+}
+
+/// The I/O error that caused `err`, if it is one, looking through a [`PhaseError`].
+pub(crate) fn io_error<'a>(err: &'a (dyn Error + 'static)) -> Option<&'a std::io::Error> {
+    match err.downcast_ref::<PhaseError>() {
+        Some(err) => err.err.downcast_ref(),
+        None => err.downcast_ref(),
+    }
+}
+
+/// Format an error as a JSON object for [`print_error`].
+fn error_event(path: &Path, err: &(dyn Error + 'static)) -> String {
+    let context = err.downcast_ref::<PhaseError>();
+    let io_error = io_error(err);
+    let kind = io_error.map_or("null".to_owned(), |err| {
+        json_string(&snake_case(&format!("{:?}", err.kind())))
+    });
+    let errno = io_error
+        .and_then(|err| err.raw_os_error())
+        .map_or("null".to_owned(), |errno| errno.to_string());
+    format!(
+        "{{\"event\":\"error\",\"path\":{},\"phase\":{},\"kind\":{},\"errno\":{},\"retries\":{},\
+        \"message\":{}}}",
+        json_string(&path.to_string_lossy()),
+        context.map_or("null".to_owned(), |context| json_string(
+            context.phase.name()
+        )),
+        kind,
+        errno,
+        context.map_or(0, |context| context.retries),
+        json_string(&err.to_string())
+    )
+}
+
+/// Convert a name like `NotFound` to `not_found`.
+fn snake_case(name: &str) -> String {
+    let mut snake = String::new();
+    for c in name.chars() {
+        if c.is_uppercase() && !snake.is_empty() {
+            snake.push('_');
+        }
+        snake.extend(c.to_lowercase());
+    }
+    snake
+}
+
+/// With `--verbose`, print how a file was read, how long it took, and the throughput. `bytes` is
+/// the size of the file, which is unknown for standard input. The same is always recorded as a
+/// `tracing` event.
+pub(crate) fn print_diagnostic(path: &Path, strategy: &str, bytes: Option<u64>, elapsed: Duration) {
+    tracing::debug!(path = %path.display(), strategy, bytes = ?bytes, elapsed = ?elapsed, "read");
+    if VERBOSE.load(Ordering::Relaxed) {
+        let message = format!(
+            "{}: {}",
+            binary_name(),
+            diagnostic(path, strategy, bytes, elapsed)
+        );
+        eprintln!("{}", stderr_color(message, DIAGNOSTIC));
+    }
+}
+
+/// Format a line for [`print_diagnostic`].
+fn diagnostic(path: &Path, strategy: &str, bytes: Option<u64>, elapsed: Duration) -> String {
+    let seconds = elapsed.as_secs_f64();
+    match bytes {
+        Some(bytes) => format!(
+            "{}: {}, {} bytes in {:.3} s, {:.1} MiB/s",
+            path.display(),
+            strategy,
+            bytes,
+            seconds,
+            bytes as f64 / (1 << 20) as f64 / seconds.max(1e-9)
+        ),
+        None => format!("{}: {}, {:.3} s", path.display(), strategy, seconds),
+    }
+}
+
+/// Print a warning that doesn't relate to one specific file.
+pub fn print_warning(message: &str) {
+    log_message(LOG_WARNING, message);
+    let message = format!("{}: WARNING: {}", binary_name(), message);
+    eprintln!("{}", stderr_color(message, WARNING));
+}
+
+/// The system log that errors, warnings and run summaries are also sent to, as given by `--log`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogTarget {
+    /// The syslog socket, `/dev/log`.
+    Syslog,
+    /// The native socket of the systemd journal.
+    Journald,
+}
+
+impl FromStr for LogTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "syslog" => Ok(LogTarget::Syslog),
+            "journald" => Ok(LogTarget::Journald),
+            _ => Err(format!("unknown log target: {}", s)),
+        }
+    }
+}
+
+// The syslog severities of the messages that are logged.
+const LOG_ERR: u8 = 3;
+const LOG_WARNING: u8 = 4;
+const LOG_INFO: u8 = 6;
+
+/// A connection to the system log, once [`start_log`] is called.
+struct SystemLog {
+    target: LogTarget,
+    #[cfg(unix)]
+    socket: std::os::unix::net::UnixDatagram,
+}
+
+impl SystemLog {
+    #[cfg(unix)]
+    fn connect(target: LogTarget, path: &Path) -> Result<Self> {
+        let socket = std::os::unix::net::UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        Ok(SystemLog { target, socket })
+    }
+
+    /// Send a message. Messages that can't be sent are dropped, since there's nowhere else to
+    /// report that.
+    fn send(&self, severity: u8, message: &str) {
+        let identifier = binary_name();
+        let pid = std::process::id();
+        let datagram = match self.target {
+            LogTarget::Syslog => syslog_datagram(severity, &identifier, pid, message),
+            LogTarget::Journald => journald_datagram(severity, &identifier, pid, message),
+        };
+        #[cfg(unix)]
+        let _ = self.socket.send(&datagram);
+        #[cfg(not(unix))]
+        drop(datagram);
+    }
+}
+
+/// The system log of `--log`, once [`start_log`] is called.
+static LOG: Global<SystemLog> = Global::new();
+
+/// Also send errors, warnings and the summary of [`log_summary`] to the system log, so that they
+/// aren't lost when stderr is discarded, as cron does. Fails if the log's socket can't be
+/// connected to. It's only supported on Unix. This should only be called once, before anything
+/// is printed.
+#[cfg_attr(not(feature = "cli"), allow(dead_code))]
+pub(crate) fn start_log(target: LogTarget) -> Result<()> {
+    #[cfg(unix)]
+    {
+        let path = match target {
+            LogTarget::Syslog => "/dev/log",
+            LogTarget::Journald => "/run/systemd/journal/socket",
+        };
+        let log = SystemLog::connect(target, Path::new(path))
+            .map_err(|err| format!("{} can't be connected to: {}", path, err))?;
+        let _ = LOG.set(log);
+        Ok(())
+    }
+    #[cfg(not(unix))]
+    {
+        drop(target);
+        Err("the system log is only supported on Unix".into())
+    }
+}
+
+fn log_message(severity: u8, message: &str) {
+    if let Some(log) = LOG.get() {
+        log.send(severity, message);
+    }
+}
+
+/// Log a summary of the run to the system log, if `--log` is on: the files and bytes
+/// that were read, the number of errors, and the exit status. It's logged as an error if the
+/// status isn't 0.
+pub fn log_summary(status: i32) {
+    let message = format!(
+        "finished with exit status {}: {} files and {} bytes read, {} errors",
+        status,
+        FILES_DONE.load(Ordering::Relaxed),
+        BYTES_DONE.load(Ordering::Relaxed),
+        error_count()
+    );
+    log_message(if status == 0 { LOG_INFO } else { LOG_ERR }, &message);
+}
+
+/// A message for the syslog socket, in the format of RFC 3164 without the time and host, which
+/// the syslog daemon adds, like `<11>b3sum-ng[42]: message`. The facility is `user`.
+fn syslog_datagram(severity: u8, identifier: &str, pid: u32, message: &str) -> Vec<u8> {
+    const LOG_USER: u8 = 1 << 3;
+    // A newline would start another message in some daemons.
+    let message = message.replace('\n', " ");
+    format!(
+        "<{}>{}[{}]: {}",
+        LOG_USER | severity,
+        identifier,
+        pid,
+        message
+    )
+    .into_bytes()
+}
+
+/// A message for the native journal protocol. The message is sent with its length, so that it
+/// may contain newlines.
+fn journald_datagram(severity: u8, identifier: &str, pid: u32, message: &str) -> Vec<u8> {
+    let mut datagram = format!(
+        "PRIORITY={}\nSYSLOG_IDENTIFIER={}\nSYSLOG_PID={}\nMESSAGE\n",
+        severity, identifier, pid
+    )
+    .into_bytes();
+    datagram.extend_from_slice(&(message.len() as u64).to_le_bytes());
+    datagram.extend_from_slice(message.as_bytes());
+    datagram.push(b'\n');
+    datagram
+}
+
+/// How the checksums are grouped by `--summary-by`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SummaryBy {
+    /// By the directory that each file is in.
+    Dir,
+    /// By the extension of each file, ignoring case.
+    Ext,
+}
+
+impl FromStr for SummaryBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "dir" => Ok(SummaryBy::Dir),
+            "ext" => Ok(SummaryBy::Ext),
+            _ => Err(format!("unknown summary grouping: {}", s)),
+        }
+    }
+}
+
+/// The files and bytes of one group of [`Rollup`], and for a directory, the names and checksums
+/// of its files.
+#[derive(Default)]
+struct RollupGroup {
+    files: u64,
+    bytes: u64,
+    checksums: Vec<(Vec<String>, Checksum)>,
+}
+
+/// The checksums that were printed, grouped for `--summary-by`.
+struct Rollup {
+    by: SummaryBy,
+    groups: BTreeMap<String, RollupGroup>,
+}
+
+impl Rollup {
+    /// Count a file of `size` bytes, or 0 if its size isn't known, in its group.
+    fn record(&mut self, path: &Path, checksum: &Checksum, size: Option<u64>) {
+        // Standard input isn't in a directory, and has no size or extension.
+        if path.to_str() == Some("-") {
+            return;
+        }
+        let key = match self.by {
+            SummaryBy::Dir => match path.parent() {
+                Some(parent) if parent != Path::new("") => parent.display().to_string(),
+                _ => ".".to_owned(),
+            },
+            SummaryBy::Ext => match path.extension() {
+                Some(extension) => format!(".{}", extension.to_string_lossy().to_lowercase()),
+                None => "(none)".to_owned(),
+            },
+        };
+        let group = self.groups.entry(key).or_default();
+        group.files += 1;
+        group.bytes += size.unwrap_or(0);
+        if let (SummaryBy::Dir, Some(name)) = (self.by, path.file_name()) {
+            let name = name.to_string_lossy().into_owned();
+            group.checksums.push((vec![name], checksum.clone()));
+        }
+    }
+
+    /// A line for each group, like `dir photos: 120 files, 3.2 GB, tree <digest>`.
+    fn lines(&mut self) -> Vec<String> {
+        let by = self.by;
+        self.groups
+            .iter_mut()
+            .map(|(key, group)| {
+                let counts = format!("{} files, {}", group.files, format_size(group.bytes));
+                match by {
+                    SummaryBy::Dir => {
+                        group.checksums.sort_by(|a, b| a.0.cmp(&b.0));
+                        let digest = directory_digest(&group.checksums);
+                        format!("dir {}: {}, tree {}", key, counts, digest)
+                    }
+                    SummaryBy::Ext => format!("ext {}: {}", key, counts),
+                }
+            })
+            .collect()
+    }
+}
+
+/// The groups of `--summary-by`, once [`start_rollup`] is called.
+static ROLLUP: Global<Mutex<Rollup>> = Global::new();
+
+/// Group the checksums that are printed by directory or by extension, so that
+/// [`print_rollup`] can print the files and bytes of each group. This should only be called
+/// once, before any checksums are printed.
+#[cfg_attr(not(feature = "cli"), allow(dead_code))]
+pub(crate) fn start_rollup(by: SummaryBy) {
+    let rollup = Rollup {
+        by,
+        groups: BTreeMap::new(),
+    };
+    let _ = ROLLUP.set(Mutex::new(rollup));
+}
+
+/// Print the groups of `--summary-by` to standard error, sorted, after the checksums. Each
+/// directory has the digest of the files directly in it, the way `--tree-digest` computes it.
+pub fn print_rollup() {
+    if let Some(rollup) = ROLLUP.get() {
+        if !QUIET.load(Ordering::Relaxed) {
+            for line in rollup.lock().unwrap().lines() {
+                eprintln!("{}: {}", binary_name(), line);
+            }
+        }
+    }
+}
+
+/// What happened in the run, for the summary file of `--summary`.
+struct RunSummary {
+    path: PathBuf,
+    start: Instant,
+    /// The number of files with each status, such as `ok` or `failed`.
+    statuses: BTreeMap<&'static str, u64>,
+    /// The path and the message of each error that was printed.
+    errors: Vec<(String, String)>,
+}
+
+/// The summary of `--summary`, once [`start_summary`] is called.
+static SUMMARY: Global<Mutex<RunSummary>> = Global::new();
+
+/// Collect the statuses of the files and the errors of the run, so that [`write_summary`] can
+/// write them to `path` at the end. This should only be called once, at the start of the run.
+#[cfg_attr(not(feature = "cli"), allow(dead_code))]
+pub(crate) fn start_summary(path: PathBuf) {
+    let summary = RunSummary {
+        path,
+        start: Instant::now(),
+        statuses: BTreeMap::new(),
+        errors: Vec::new(),
+    };
+    let _ = SUMMARY.set(Mutex::new(summary));
+}
+
+/// Count a file with the status `name` in the summary, if `--summary` is on.
+/// Checksums count as `ok` or `error`, and checked files by the name of their status in a report.
+pub fn record_status(name: &'static str) {
+    if let Some(summary) = SUMMARY.get() {
+        *summary.lock().unwrap().statuses.entry(name).or_insert(0) += 1;
+    }
+}
+
+/// Write the summary of the run, with its exit status, if `--summary` is on. The file
+/// is replaced at once, so that a tool that reads it never sees part of it.
+pub fn write_summary(status: i32) -> Result<()> {
+    let summary = match SUMMARY.get() {
+        Some(summary) => summary.lock().unwrap(),
+        None => return Ok(()),
+    };
+    let contents = summary_json(&summary, status, metrics(), summary.start.elapsed());
+    Ok(write_atomically(&summary.path, contents.as_bytes())?)
+}
+
+/// Format a summary as JSON, like `{"exit_status":0,"files":2,"statuses":{"ok":2},
+/// "bytes_read":11,"seconds":0.002,"bytes_per_second":5500,"errors":[]}`.
+fn summary_json(summary: &RunSummary, status: i32, metrics: Metrics, elapsed: Duration) -> String {
+    let statuses: Vec<String> = summary
+        .statuses
+        .iter()
+        .map(|(name, count)| format!("\"{}\":{}", name, count))
+        .collect();
+    let errors: Vec<String> = summary
+        .errors
+        .iter()
+        .map(|(path, message)| {
+            format!(
+                "{{\"path\":{},\"message\":{}}}",
+                json_string(path),
+                json_string(message)
+            )
+        })
+        .collect();
+    let seconds = elapsed.as_secs_f64();
+    let speed = if seconds > 0.0 {
+        metrics.bytes_hashed as f64 / seconds
+    } else {
+        0.0
+    };
+    format!(
+        "{{\"exit_status\":{},\"files\":{},\"statuses\":{{{}}},\"bytes_read\":{},\
+        \"seconds\":{:.6},\"bytes_per_second\":{:.0},\"errors\":[{}]}}\n",
+        status,
+        summary.statuses.values().sum::<u64>(),
+        statuses.join(","),
+        metrics.bytes_hashed,
+        seconds,
+        speed,
+        errors.join(",")
+    )
+}
+
+// Progress counters, which are updated as files are checksummed and read by `ProgressReporter`.
+static FILES_TOTAL: AtomicU64 = AtomicU64::new(0);
+static BYTES_TOTAL: AtomicU64 = AtomicU64::new(0);
+static FILES_DONE: AtomicU64 = AtomicU64::new(0);
+static BYTES_DONE: AtomicU64 = AtomicU64::new(0);
+
+/// Add to the number of files and bytes that are expected to be checksummed, for the ETA of
+/// progress events. Bytes may be left out if they aren't known.
+pub fn add_progress_total(files: u64, bytes: u64) {
+    FILES_TOTAL.fetch_add(files, Ordering::Relaxed);
+    BYTES_TOTAL.fetch_add(bytes, Ordering::Relaxed);
+}
+
+/// Count one file of `bytes` bytes as done.
+pub(crate) fn record_progress(bytes: u64) {
+    FILES_DONE.fetch_add(1, Ordering::Relaxed);
+    BYTES_DONE.fetch_add(bytes, Ordering::Relaxed);
+    #[cfg(unix)]
+    ping_systemd_watchdog();
+}
+
+// The files of the scheduler that are waiting for the I/O lock, and that are being read.
+static JOBS_QUEUED: AtomicU64 = AtomicU64::new(0);
+static JOBS_IN_FLIGHT: AtomicU64 = AtomicU64::new(0);
+
+/// The counters of the files that have been checksummed, and of the files that the scheduler is
+/// working on, for charting throughput. [`metrics`] returns those of the whole process, and
+/// [`MetricsCounters::get`] those of one run.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Metrics {
+    /// The number of bytes read from the files that are done.
+    pub bytes_hashed: u64,
+    /// The number of files that are done, including those that couldn't be read.
+    pub files_completed: u64,
+    /// The number of files that are being read.
+    pub in_flight: u64,
+    /// The number of files that are waiting for the I/O lock.
+    pub queued: u64,
+}
+
+/// The current values of the counters of everything in the process, including the progress of
+/// files that the command line program counts without reading them. See also
+/// [`Config::metrics`] for those of one run.
+pub fn metrics() -> Metrics {
+    Metrics {
+        bytes_hashed: BYTES_DONE.load(Ordering::Relaxed),
+        files_completed: FILES_DONE.load(Ordering::Relaxed),
+        in_flight: JOBS_IN_FLIGHT.load(Ordering::Relaxed),
+        queued: JOBS_QUEUED.load(Ordering::Relaxed),
+    }
+}
+
+/// The counters of [`Metrics`] for one run of the scheduler, which [`Config::metrics`] holds, so
+/// that runs with different configs don't count each other's files.
+#[derive(Debug, Default)]
+pub struct MetricsCounters {
+    bytes_hashed: AtomicU64,
+    files_completed: AtomicU64,
+    in_flight: AtomicU64,
+    queued: AtomicU64,
+}
+
+impl MetricsCounters {
+    /// The current values of the counters.
+    pub fn get(&self) -> Metrics {
+        Metrics {
+            bytes_hashed: self.bytes_hashed.load(Ordering::Relaxed),
+            files_completed: self.files_completed.load(Ordering::Relaxed),
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+            queued: self.queued.load(Ordering::Relaxed),
+        }
+    }
+}
+
+/// How a file moved through the scheduler, for [`Metrics`].
+#[derive(Clone, Copy, Debug)]
+pub(crate) enum JobEvent {
+    Queued,
+    Started,
+    /// The file was queued, but it was cancelled before it was read.
+    Skipped,
+    /// The file of this many bytes was read, or failed.
+    Finished(u64),
+}
+
+/// Update the counters of the process and those of the run of `config`, and pass the new values
+/// of the run's to its [`Config::on_metrics`].
+pub(crate) fn record_job(event: JobEvent, config: &Config) {
+    let run = &config.metrics;
+    for (queued, in_flight) in &[
+        (&JOBS_QUEUED, &JOBS_IN_FLIGHT),
+        (&run.queued, &run.in_flight),
+    ] {
+        match event {
+            JobEvent::Queued => queued.fetch_add(1, Ordering::Relaxed),
+            JobEvent::Started => {
+                queued.fetch_sub(1, Ordering::Relaxed);
+                in_flight.fetch_add(1, Ordering::Relaxed)
+            }
+            JobEvent::Skipped => queued.fetch_sub(1, Ordering::Relaxed),
+            JobEvent::Finished(_) => in_flight.fetch_sub(1, Ordering::Relaxed),
+        };
+    }
+    if let JobEvent::Finished(bytes) = event {
+        run.files_completed.fetch_add(1, Ordering::Relaxed);
+        run.bytes_hashed.fetch_add(bytes, Ordering::Relaxed);
+    }
+    if let Some(hook) = &config.on_metrics {
+        hook(run.get());
+    }
+}
+
+/// Prints progress events to standard error as JSON lines, from a background thread. A final
+/// event is printed when it is dropped. Without threads, as on WASI, nothing is printed.
+///
+/// Each event looks like `{"event":"progress","files_done":3,"files_total":10,"bytes_done":4096,
+/// "bytes_total":16384,"bytes_per_second":2048.0,"elapsed_seconds":2.0,"eta_seconds":6.0}`. The
+/// final event is the same, but with `"event":"done"`. `bytes_per_second` is the rate since the
+/// previous event. `eta_seconds` is `null` if it can't be estimated.
+pub struct ProgressReporter {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl ProgressReporter {
+    /// Start printing an event every `interval`.
+    pub fn start(interval: Duration) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        let thread = std::thread::Builder::new().spawn(move || {
+            let start = Instant::now();
+            let mut last = (start, 0);
+            loop {
+                let done = !matches!(
+                    stopped.recv_timeout(interval),
+                    Err(mpsc::RecvTimeoutError::Timeout)
+                );
+                let now = Instant::now();
+                let bytes_done = BYTES_DONE.load(Ordering::Relaxed);
+                let rate = (bytes_done - last.1) as f64
+                    / now.duration_since(last.0).as_secs_f64().max(1e-9);
+                last = (now, bytes_done);
+                eprintln!("{}", progress_event(done, start.elapsed(), rate));
+                if done {
+                    return;
+                }
+            }
+        });
+        ProgressReporter {
+            stop: Some(stop),
+            thread: thread.ok(),
+        }
+    }
+}
+
+impl Drop for ProgressReporter {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Reports to systemd, when b3sum-ng runs in a service with `Type=notify`, from a background
+/// thread. It's ready as soon as it starts, and `systemctl status` shows its progress, like
+/// `42% of 3.1 TB, 12 of 40 files`. If the service has a `WatchdogSec`, the watchdog is pinged as
+/// files are done, at most four times per `WatchdogSec`, so that a long scan isn't killed, but one
+/// that stops making progress, such as on a disk that hangs, is. The messages are sent to the
+/// socket in `NOTIFY_SOCKET`, as `sd_notify` does, and the last one, when it's dropped, says that
+/// it's stopping.
+pub struct SystemdNotifier {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// How often the status is sent to systemd.
+const SYSTEMD_STATUS_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The watchdog of the service, once [`SystemdNotifier`] has started, which is pinged by
+/// [`record_progress`].
+#[cfg(unix)]
+static SYSTEMD_WATCHDOG: Global<SystemdWatchdog> = Global::new();
+
+#[cfg(unix)]
+struct SystemdWatchdog {
+    send: std::sync::Arc<dyn Fn(String) + Send + Sync>,
+    /// The shortest time between pings.
+    interval: Duration,
+    last_ping: Mutex<Instant>,
+}
+
+impl SystemdNotifier {
+    /// Start reporting, if `NOTIFY_SOCKET` is set. It's only supported on Unix, and abstract
+    /// sockets, whose names start with `@`, aren't supported.
+    pub fn start() -> Option<Self> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::net::UnixDatagram;
+
+            let path = std::env::var_os("NOTIFY_SOCKET")?;
+            if path.to_string_lossy().starts_with('@') {
+                print_warning("systemd can't be notified through an abstract socket");
+                return None;
+            }
+            let socket = match UnixDatagram::unbound() {
+                Ok(socket) => socket,
+                Err(err) => {
+                    print_error(Path::new(&path), err.into());
+                    return None;
+                }
+            };
+            // Messages that can't be sent are dropped, as with `sd_notify`.
+            let send = std::sync::Arc::new(move |message: String| {
+                let _ = socket.send_to(message.as_bytes(), &path);
+            });
+            send(format!("READY=1\nSTATUS={}", systemd_status(false)));
+            if let Some(watchdog) = systemd_watchdog() {
+                let _ = SYSTEMD_WATCHDOG.set(SystemdWatchdog {
+                    send: send.clone(),
+                    interval: watchdog / 4,
+                    last_ping: Mutex::new(Instant::now()),
+                });
+            }
+            let (stop, stopped) = mpsc::channel();
+            let thread = std::thread::spawn(move || loop {
+                let done = !matches!(
+                    stopped.recv_timeout(SYSTEMD_STATUS_INTERVAL),
+                    Err(mpsc::RecvTimeoutError::Timeout)
+                );
+                let status = systemd_status(done);
+                if done {
+                    send(format!("STOPPING=1\nSTATUS={}", status));
+                    return;
+                }
+                send(format!("STATUS={}", status));
+            });
+            Some(SystemdNotifier {
+                stop: Some(stop),
+                thread: Some(thread),
+            })
+        }
+        #[cfg(not(unix))]
+        None
+    }
+}
+
+impl Drop for SystemdNotifier {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Ping the systemd watchdog, if there is one, since a file was done, unless it was pinged less
+/// than its interval ago.
+#[cfg(unix)]
+fn ping_systemd_watchdog() {
+    let watchdog = match SYSTEMD_WATCHDOG.get() {
+        Some(watchdog) => watchdog,
+        None => return,
+    };
+    // If another thread has the lock, it's pinging it.
+    if let Ok(mut last_ping) = watchdog.last_ping.try_lock() {
+        if last_ping.elapsed() >= watchdog.interval {
+            *last_ping = Instant::now();
+            (watchdog.send)(format!("WATCHDOG=1\nSTATUS={}", systemd_status(false)));
+        }
+    }
+}
+
+/// The timeout of the systemd watchdog of this process, from `WATCHDOG_USEC`, if there is one.
+#[cfg(unix)]
+fn systemd_watchdog() -> Option<Duration> {
+    let usec: u64 = std::env::var("WATCHDOG_USEC").ok()?.parse().ok()?;
+    let pid = std::env::var("WATCHDOG_PID").ok();
+    if usec == 0 || pid.map_or(false, |pid| pid != std::process::id().to_string()) {
+        return None;
+    }
+    Some(Duration::from_micros(usec))
+}
+
+/// The status that is sent to systemd, from the current counters.
+fn systemd_status(done: bool) -> String {
+    let files_done = FILES_DONE.load(Ordering::Relaxed);
+    let files_total = FILES_TOTAL.load(Ordering::Relaxed).max(files_done);
+    let bytes_done = BYTES_DONE.load(Ordering::Relaxed);
+    let bytes_total = BYTES_TOTAL.load(Ordering::Relaxed).max(bytes_done);
+    format_status(done, (files_done, files_total), (bytes_done, bytes_total))
+}
+
+/// Format a status like `42% of 3.1 TB, 12 of 40 files`, from the files and bytes that are done and
+/// their totals, which are 0 if they aren't known.
+fn format_status(done: bool, files: (u64, u64), bytes: (u64, u64)) -> String {
+    let (files_done, files_total) = files;
+    let (bytes_done, bytes_total) = bytes;
+    let plural = |count: u64| if count == 1 { "" } else { "s" };
+    if done {
+        return format!(
+            "Done: {} file{}, {}",
+            files_done,
+            plural(files_done),
+            format_size(bytes_done)
+        );
+    }
+    let files = if files_total > 0 {
+        format!(
+            "{} of {} file{}",
+            files_done,
+            files_total,
+            plural(files_total)
+        )
+    } else {
+        format!("{} file{}", files_done, plural(files_done))
+    };
+    if bytes_total > 0 {
+        let percent = (bytes_done as f64 / bytes_total as f64 * 100.0) as u64;
+        format!("{}% of {}, {}", percent, format_size(bytes_total), files)
+    } else {
+        files
+    }
+}
+
+/// Format a number of bytes with decimal units, like `3.1 TB`.
+fn format_size(bytes: u64) -> String {
+    const UNITS: [&str; 6] = ["kB", "MB", "GB", "TB", "PB", "EB"];
+    if bytes < 1000 {
+        return format!("{} B", bytes);
+    }
+    let mut size = bytes as f64 / 1000.0;
+    let mut unit = 0;
+    while size >= 999.95 && unit < UNITS.len() - 1 {
+        size /= 1000.0;
+        unit += 1;
+    }
+    format!("{:.1} {}", size, UNITS[unit])
+}
+
+/// Format a progress event from the current counters. `rate` is the recent rate in bytes per
+/// second.
+fn progress_event(done: bool, elapsed: Duration, rate: f64) -> String {
+    let files_done = FILES_DONE.load(Ordering::Relaxed);
+    let files_total = FILES_TOTAL.load(Ordering::Relaxed).max(files_done);
+    let bytes_done = BYTES_DONE.load(Ordering::Relaxed);
+    let bytes_total = BYTES_TOTAL.load(Ordering::Relaxed).max(bytes_done);
+    let elapsed = elapsed.as_secs_f64();
+
+    // The ETA is estimated from the average rate so far, by bytes if the total size is known.
+    let fraction_done = if bytes_total > 0 {
+        bytes_done as f64 / bytes_total as f64
+    } else if files_total > 0 {
+        files_done as f64 / files_total as f64
+    } else {
+        0.0
+    };
+    let eta = if done {
+        "0.0".to_owned()
+    } else if fraction_done > 0.0 {
+        format!("{:.1}", elapsed / fraction_done - elapsed)
+    } else {
+        "null".to_owned()
+    };
+    format!(
+        "{{\"event\":\"{}\",\"files_done\":{},\"files_total\":{},\"bytes_done\":{},\
+        \"bytes_total\":{},\"bytes_per_second\":{:.1},\"elapsed_seconds\":{:.1},\"eta_seconds\":{}}}",
+        if done { "done" } else { "progress" },
+        files_done,
+        files_total,
+        bytes_done,
+        bytes_total,
+        rate,
+        elapsed,
+        eta
+    )
+}
+
+/// The number of errors printed by [`print_error`].
+static ERROR_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// The number of errors printed so far.
+pub fn error_count() -> usize {
+    ERROR_COUNT.load(Ordering::Relaxed)
+}
+
+// Exit statuses, so that scripts can tell what went wrong. If several things went wrong, the
+// highest status is used.
+/// A checksum didn't match, or something failed other than reading a file.
+pub const EXIT_FAILURE: i32 = 1;
+/// The command line was invalid.
+pub const EXIT_USAGE: i32 = 2;
+/// A file could not be read.
+pub const EXIT_IO_ERROR: i32 = 3;
+/// A file could not be found.
+pub const EXIT_MISSING: i32 = 4;
+/// The run stopped at its `--timeout`, before every file was read.
+pub const EXIT_TIMEOUT: i32 = 5;
+
+/// The highest exit status of the errors printed by [`print_error`], or 0 if there were none.
+static ERROR_STATUS: AtomicI32 = AtomicI32::new(0);
+
+/// The exit status for the errors printed so far.
+pub fn error_status() -> i32 {
+    ERROR_STATUS.load(Ordering::Relaxed)
+}
+
+/// The exit status for an error.
+pub(crate) fn error_status_of(err: &(dyn Error + 'static)) -> i32 {
+    match io_error(err) {
+        Some(err) if err.kind() == std::io::ErrorKind::NotFound => EXIT_MISSING,
+        Some(_) => EXIT_IO_ERROR,
+        None => EXIT_FAILURE,
+    }
+}
+
+/// Whether only errors, warnings and failures are printed. Set by [`set_quiet`].
+pub(crate) static QUIET: AtomicBool = AtomicBool::new(false);
+
+/// Stop printing checksums and other results, except for failures. Errors and warnings are
+/// still printed.
+#[cfg_attr(not(feature = "cli"), allow(dead_code))]
+pub(crate) fn set_quiet(quiet: bool) {
+    QUIET.store(quiet, Ordering::Relaxed);
+}
+
+/// Whether paths are normalized, and to which Unicode form. Set by [`set_path_normalization`].
+static NORMALIZE_PATHS: AtomicBool = AtomicBool::new(false);
+static UNICODE_FORM: AtomicUsize = AtomicUsize::new(0);
+
+/// Normalize the paths that are printed and the paths in checkfiles, for `--normalize-paths` and
+/// `--unicode-form`.
+#[cfg_attr(not(feature = "cli"), allow(dead_code))]
+pub(crate) fn set_path_normalization(normalize_paths: bool, unicode_form: Option<UnicodeForm>) {
+    NORMALIZE_PATHS.store(normalize_paths, Ordering::Relaxed);
+    let form = match unicode_form {
+        None => 0,
+        Some(UnicodeForm::Nfc) => 1,
+        Some(UnicodeForm::Nfd) => 2,
+    };
+    UNICODE_FORM.store(form, Ordering::Relaxed);
+}
+
+/// Normalize a path as set by [`set_path_normalization`].
+pub(crate) fn normalized(path: &Path) -> PathBuf {
+    let unicode_form = match UNICODE_FORM.load(Ordering::Relaxed) {
+        1 => Some(UnicodeForm::Nfc),
+        2 => Some(UnicodeForm::Nfd),
+        _ => None,
+    };
+    normalize_path(path, NORMALIZE_PATHS.load(Ordering::Relaxed), unicode_form)
+}
+
+/// Remove leading `./` and repeated separators from a path if `normalize_paths` is set, and
+/// convert it to `unicode_form`. `-`, for standard input, is left alone, and so are paths that
+/// aren't Unicode.
+pub(crate) fn normalize_path(
+    path: &Path,
+    normalize_paths: bool,
+    unicode_form: Option<UnicodeForm>,
+) -> PathBuf {
+    use unicode_normalization::UnicodeNormalization;
+
+    if path.to_str() == Some("-") {
+        return path.to_owned();
+    }
+    let path = match (unicode_form, path.to_str()) {
+        (Some(UnicodeForm::Nfc), Some(str)) => PathBuf::from(str.nfc().collect::<String>()),
+        (Some(UnicodeForm::Nfd), Some(str)) => PathBuf::from(str.nfd().collect::<String>()),
+        _ => path.to_owned(),
+    };
+    if !normalize_paths {
+        return path;
+    }
+    // Components already skip repeated separators, and `.` anywhere but at the start.
+    let normalized: PathBuf = path
+        .components()
+        .filter(|component| *component != std::path::Component::CurDir)
+        .collect();
+    if normalized.as_os_str().is_empty() {
+        PathBuf::from(".")
+    } else {
+        normalized
+    }
+}
+
+/// Whether diagnostics are printed for each file. Set by [`set_verbose`].
+static VERBOSE: AtomicBool = AtomicBool::new(false);
+
+/// Print to standard error how each file is read, and how long it takes.
+#[cfg_attr(not(feature = "cli"), allow(dead_code))]
+pub(crate) fn set_verbose(verbose: bool) {
+    VERBOSE.store(verbose, Ordering::Relaxed);
+}
+
+/// Whether standard output is colored. Set by [`set_color`].
+static STDOUT_COLOR: AtomicBool = AtomicBool::new(false);
+/// Whether standard error is colored. Set by [`set_color`].
+static STDERR_COLOR: AtomicBool = AtomicBool::new(false);
+
+// ANSI escape codes for the parts of the output that are colored.
+const DIGEST: &str = "36";
+pub(crate) const OK: &str = "32";
+pub(crate) const FAILED: &str = "1;31";
+const ERROR: &str = "31";
+pub(crate) const WARNING: &str = "33";
+const DIAGNOSTIC: &str = "2";
+
+/// Decide whether standard output and standard error are colored. With [`ColorChoice::Auto`],
+/// they are colored if they are terminals, unless the `NO_COLOR` environment variable is set.
+#[cfg_attr(not(feature = "cli"), allow(dead_code))]
+pub(crate) fn set_color(choice: ColorChoice) {
+    let no_color = std::env::var_os("NO_COLOR").map_or(false, |value| !value.is_empty());
+    let enabled = |stream| match choice {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        ColorChoice::Auto => !no_color && atty::is(stream),
+    };
+    STDOUT_COLOR.store(enabled(atty::Stream::Stdout), Ordering::Relaxed);
+    STDERR_COLOR.store(enabled(atty::Stream::Stderr), Ordering::Relaxed);
+}
+
+pub(crate) fn stdout_color<T: fmt::Display>(value: T, color: &'static str) -> Colored<T> {
+    Colored {
+        value,
+        color: Some(color).filter(|_| STDOUT_COLOR.load(Ordering::Relaxed)),
+    }
+}
+
+fn stderr_color<T: fmt::Display>(value: T, color: &'static str) -> Colored<T> {
+    Colored {
+        value,
+        color: Some(color).filter(|_| STDERR_COLOR.load(Ordering::Relaxed)),
+    }
+}
+
+/// Displays a value in a color, or without color if `color` is `None`.
+pub(crate) struct Colored<T> {
+    value: T,
+    color: Option<&'static str>,
+}
+
+impl<T: fmt::Display> fmt::Display for Colored<T> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.color {
+            Some(color) => write!(f, "\x1b[{}m{}\x1b[0m", color, self.value),
+            None => self.value.fmt(f),
+        }
+    }
+}
+
+pub(crate) fn binary_name() -> String {
+    match std::env::current_exe() {
+        Ok(binary_name) => match binary_name.file_name() {
+            Some(binary_name) => binary_name.to_string_lossy().to_string(),
+            None => binary_name.display().to_string(),
+        },
+        Err(_) => "".to_owned(),
+    }
+}
+
+/// Quote a string for JSON.
+pub(crate) fn json_string(s: &str) -> String {
+    let mut quoted = String::with_capacity(s.len() + 2);
+    quoted.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => quoted.push_str("\\\""),
+            '\\' => quoted.push_str("\\\\"),
+            '\n' => quoted.push_str("\\n"),
+            c if (c as u32) < 0x20 => quoted.push_str(&format!("\\u{:04x}", c as u32)),
+            c => quoted.push(c),
+        }
+    }
+    quoted.push('"');
+    quoted
+}
+
+/// How checksums are written, and which kind of checkfile is read.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    /// `<BLAKE3 hex>  <filename>`, like b3sum and sha256sum.
+    B3sum,
+    /// `<filename> <CRC32 hex>`, the Simple File Verification format.
+    Sfv,
+    /// `BLAKE3 (<filename>) = <BLAKE3 hex>`, the BSD style of `--tag`.
+    Tag,
+    /// Only `<BLAKE3 hex>`, for `--no-names`.
+    NoNames,
+    /// The checksum as bytes rather than hex, for `--raw`.
+    Raw,
+    /// The CSV rows of an NSRL RDS `NSRLFile.txt`, with a SHA-1, the file's name without its
+    /// directory, and its size. The MD5 and CRC32 columns are empty, and the product code is `0`.
+    /// [`print_header`] prints the header row.
+    Nsrl,
+    /// A JSON object for each file, on a line of its own, like
+    /// `{"path":"a.txt","algorithm":"BLAKE3","checksum":"<hex>","entropy":7.9993,"ssdeep":null,
+    /// "pieces":[]}`. The entropy and ssdeep hash are `null`, and the pieces are empty, unless
+    /// they were computed.
+    Json,
+    /// CSV rows of the filename, the checksum, the entropy and the ssdeep hash, after the header
+    /// row that [`print_header`] prints. The entropy and ssdeep hash are empty unless they were
+    /// computed.
+    Csv,
+}
+
+impl Format {
+    /// The algorithm that is written in this format.
+    pub fn algorithm(self) -> Algorithm {
+        match self {
+            Format::Sfv => Algorithm::Crc32,
+            Format::Nsrl => Algorithm::Sha1,
+            Format::B3sum
+            | Format::Tag
+            | Format::NoNames
+            | Format::Raw
+            | Format::Json
+            | Format::Csv => Algorithm::Blake3,
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "b3sum" => Ok(Format::B3sum),
+            "sfv" => Ok(Format::Sfv),
+            "nsrl" => Ok(Format::Nsrl),
+            "json" => Ok(Format::Json),
+            "csv" => Ok(Format::Csv),
+            _ => Err(format!("unknown format: {}", s)),
+        }
+    }
+}
+
+/// How progress is reported, as given by `--progress`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgressFormat {
+    /// JSON lines on standard error. See [`ProgressReporter`].
+    Json,
+}
+
+impl FromStr for ProgressFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "json" => Ok(ProgressFormat::Json),
+            _ => Err(format!("unknown progress format: {}", s)),
+        }
+    }
+}
+
+/// How errors are printed, as given by `--errors`. See [`print_error`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ErrorFormat {
+    /// A line of text, with the binary name and the path.
+    Text,
+    /// A JSON object on each line.
+    Json,
+}
+
+impl FromStr for ErrorFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(ErrorFormat::Text),
+            "json" => Ok(ErrorFormat::Json),
+            _ => Err(format!("unknown error format: {}", s)),
+        }
+    }
+}
+
+/// What is done with the files that are in a [`KnownHashes`] set, as given by `--known`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum KnownAction {
+    /// Print a `# known` comment before the checksum line.
+    Flag,
+    /// Don't print the checksum.
+    Exclude,
+}
+
+impl FromStr for KnownAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "flag" => Ok(KnownAction::Flag),
+            "exclude" => Ok(KnownAction::Exclude),
+            _ => Err(format!("unknown action for known files: {}", s)),
+        }
+    }
+}
+
+/// A Unicode normalization form for paths, as given by `--unicode-form`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnicodeForm {
+    Nfc,
+    Nfd,
+}
+
+impl FromStr for UnicodeForm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "nfc" => Ok(UnicodeForm::Nfc),
+            "nfd" => Ok(UnicodeForm::Nfd),
+            _ => Err(format!("unknown Unicode form: {}", s)),
+        }
+    }
+}
+
+/// When to color output, as given by `--color`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorChoice {
+    Auto,
+    Always,
+    Never,
+}
+
+impl FromStr for ColorChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            _ => Err(format!("unknown color choice: {}", s)),
+        }
+    }
+}
+
+#[test]
+fn b3_test_colored() {
+    let colored = |color| Colored { value: "OK", color }.to_string();
+    assert_eq!("\x1b[32mOK\x1b[0m", colored(Some(OK)));
+    assert_eq!("OK", colored(None));
+}
+
+#[test]
+fn b3_test_progress_event() {
+    // The counters are shared with other tests, so only the format is checked.
+    let event = progress_event(true, Duration::from_secs(2), 1024.0);
+    assert!(event.starts_with("{\"event\":\"done\",\"files_done\":"));
+    assert!(event
+        .ends_with(",\"bytes_per_second\":1024.0,\"elapsed_seconds\":2.0,\"eta_seconds\":0.0}"));
+}
+
+#[test]
+fn b3_test_systemd_status() {
+    assert_eq!(
+        "42% of 3.1 TB, 12 of 40 files",
+        format_status(false, (12, 40), (1_302_000_000_000, 3_100_000_000_000))
+    );
+    assert_eq!("1 of 1 file", format_status(false, (1, 1), (0, 0)));
+    assert_eq!("3 files", format_status(false, (3, 0), (0, 0)));
+    assert_eq!(
+        "Done: 1 file, 999 B",
+        format_status(true, (1, 1), (999, 999))
+    );
+    assert_eq!("1.0 MB", format_size(999_999));
+    assert_eq!("1.5 kB", format_size(1500));
+}
+
+#[cfg(unix)]
+#[test]
+fn b3_test_systemd_notifier() {
+    use std::os::unix::net::UnixDatagram;
+
+    let path = std::env::temp_dir().join(format!("b3sum-notify-{}", std::process::id()));
+    let socket = UnixDatagram::bind(&path).unwrap();
+    socket
+        .set_read_timeout(Some(Duration::from_secs(10)))
+        .unwrap();
+    let receive = || {
+        let mut buf = [0; 1024];
+        let length = socket.recv(&mut buf).unwrap();
+        String::from_utf8(buf[..length].to_vec()).unwrap()
+    };
+    std::env::set_var("NOTIFY_SOCKET", &path);
+    std::env::set_var("WATCHDOG_USEC", "100000");
+    let notifier = SystemdNotifier::start().unwrap();
+    std::env::remove_var("NOTIFY_SOCKET");
+    std::env::remove_var("WATCHDOG_USEC");
+    assert!(receive().starts_with("READY=1\nSTATUS="));
+    // The watchdog is only pinged when a file is done, and no more than every 25 ms.
+    std::thread::sleep(Duration::from_millis(50));
+    record_progress(0);
+    assert!(receive().starts_with("WATCHDOG=1\nSTATUS="));
+    drop(notifier);
+    let mut last = receive();
+    while last.starts_with("WATCHDOG=1") {
+        last = receive();
+    }
+    assert!(last.starts_with("STOPPING=1\nSTATUS=Done: "), "{}", last);
+    std::fs::remove_file(&path).unwrap();
+}
+
+#[test]
+fn b3_test_json_csv() {
+    let piece = Chunk {
+        offset: 0,
+        length: 3,
+        checksum: Checksum(vec![0xab; 2]),
+    };
+    assert_eq!(
+        r#""entropy":1.5850,"ssdeep":"3:a:b","xattrs":"cdcd","pieces":[{"offset":0,"length":3,"checksum":"abab"}]"#,
+        json_details(
+            Some(3f64.log2()),
+            Some("3:a:b"),
+            Some(&Checksum(vec![0xcd; 2])),
+            &[piece]
+        )
+    );
+    assert_eq!(
+        r#""entropy":null,"ssdeep":null,"xattrs":null,"pieces":[]"#,
+        json_details(None, None, None, &[])
+    );
+    assert_eq!("a.txt", csv_field("a.txt"));
+    assert_eq!("\"a, \"\"b\"\"\nc\"", csv_field("a, \"b\"\nc"));
+    assert_eq!(Ok(Format::Csv), "csv".parse());
+    assert!(!has_comments(Format::Json));
+}
+
+#[test]
+fn b3_test_error_event() {
+    let err = std::io::Error::from_raw_os_error(2);
+    let message = err.to_string();
+    let err = read_error(1, in_phase(Phase::Open, err.into()));
+    assert_eq!(
+        format!(
+            r#"{{"event":"error","path":"a \"b\".txt","phase":"open","kind":"not_found","errno":2,"retries":1,"message":{}}}"#,
+            json_string(&message)
+        ),
+        error_event(Path::new("a \"b\".txt"), &*err)
+    );
+    assert_eq!(EXIT_MISSING, error_status_of(&*err));
+    let err: Box<dyn Error> = "the file kept changing while it was read".into();
+    assert_eq!(
+        r#"{"event":"error","path":"a","phase":null,"kind":null,"errno":null,"retries":0,"message":"the file kept changing while it was read"}"#,
+        error_event(Path::new("a"), &*err)
+    );
+    assert_eq!("unexpected_eof", snake_case("UnexpectedEof"));
+
+    let err = error_phase::<()>(Phase::Open, Err("".into())).unwrap_err();
+    let err = read_error(2, err);
+    let context = err.downcast_ref::<PhaseError>().unwrap();
+    assert_eq!((Phase::Open, 2), (context.phase, context.retries));
+    let err = read_error(0, "".into());
+    let context = err.downcast_ref::<PhaseError>().unwrap();
+    assert_eq!(Phase::Read, context.phase);
+    let err = in_phase(Phase::Walk, in_phase(Phase::Stat, "".into()));
+    assert_eq!(Phase::Stat, err.downcast_ref::<PhaseError>().unwrap().phase);
+}
+
+#[test]
+fn b3_test_sync_interval() {
+    assert_eq!(Ok(SyncInterval::Checksums(1000)), "1000".parse());
+    assert_eq!(Ok(SyncInterval::Seconds(30)), "30s".parse());
+    assert!("0".parse::<SyncInterval>().is_err());
+    assert!("30m".parse::<SyncInterval>().is_err());
+
+    let mut sync = OutputSync {
+        interval: SyncInterval::Checksums(2),
+        unsynced: 1,
+        last: Instant::now(),
+    };
+    assert!(!sync.is_due());
+    sync.unsynced += 1;
+    assert!(sync.is_due());
+    sync.sync();
+    assert!(!sync.is_due());
+    let sync = OutputSync {
+        interval: SyncInterval::Seconds(1),
+        unsynced: 1,
+        last: Instant::now() - Duration::from_secs(2),
+    };
+    assert!(sync.is_due());
+}
+
+#[test]
+fn b3_test_rollup() {
+    let a = Checksum(vec![1; 32]);
+    let b = Checksum(vec![2; 32]);
+    let (_, path, _guard) = make_temp_file(b"hello");
+    let dir = path.parent().unwrap();
+    let mut rollup = Rollup {
+        by: SummaryBy::Dir,
+        groups: BTreeMap::new(),
+    };
+    rollup.record(&path, &a, Some(5));
+    rollup.record(Path::new("top.txt"), &b, None);
+    rollup.record(Path::new("-"), &b, None);
+    let digest = directory_digest(&[(
+        vec![path.file_name().unwrap().to_string_lossy().into_owned()],
+        a.clone(),
+    )]);
+    let mut expected = vec![
+        format!("dir {}: 1 files, 5 B, tree {}", dir.display(), digest),
+        format!(
+            "dir .: 1 files, 0 B, tree {}",
+            directory_digest(&[(vec!["top.txt".to_owned()], b.clone())])
+        ),
+    ];
+    expected.sort();
+    assert_eq!(expected, rollup.lines());
+
+    let mut rollup = Rollup {
+        by: SummaryBy::Ext,
+        groups: BTreeMap::new(),
+    };
+    rollup.record(Path::new("a.JPG"), &a, None);
+    rollup.record(Path::new("b.jpg"), &b, None);
+    rollup.record(Path::new("README"), &b, None);
+    assert_eq!(
+        vec!["ext (none): 1 files, 0 B", "ext .jpg: 2 files, 0 B"],
+        rollup.lines()
+    );
+    assert_eq!(Ok(SummaryBy::Ext), "ext".parse());
+    assert!("size".parse::<SummaryBy>().is_err());
+}
+
+#[test]
+fn b3_test_grouping() {
+    let mut grouping = Grouping {
+        root: PathBuf::from("/archive"),
+        dir: None,
+    };
+    let mut place = |path: &str| {
+        let (header, name) = grouping.place(Path::new(path), Format::B3sum);
+        (header, name.to_string_lossy().into_owned())
+    };
+    let line = |dir: &str| Some(format!("# dir {}", dir));
+    assert_eq!(
+        (line("photos/2021"), "a.jpg".to_owned()),
+        place("/archive/photos/2021/a.jpg")
+    );
+    assert_eq!(
+        (None, "b.jpg".to_owned()),
+        place("/archive/photos/2021/b.jpg")
+    );
+    assert_eq!(
+        (line("."), "notes.txt".to_owned()),
+        place("/archive/notes.txt")
+    );
+    assert_eq!((line("/tmp"), "x".to_owned()), place("/tmp/x"));
+    if cfg!(unix) {
+        assert_eq!(
+            (line("."), "odd\\dir/y".to_owned()),
+            place("/archive/odd\\dir/y")
+        );
+    }
+
+    let mut grouping = Grouping {
+        root: PathBuf::from("."),
+        dir: None,
+    };
+    let (header, name) = grouping.place(Path::new("./docs/a.txt"), Format::Sfv);
+    assert_eq!(
+        (Some("; dir docs".to_owned()), PathBuf::from("a.txt")),
+        (header, name)
+    );
+}
+
+#[test]
+fn b3_test_in_order() {
+    let in_order = InOrder::new(Format::B3sum);
+    let waiting = || {
+        let pending = in_order.pending.lock().unwrap();
+        (pending.0, pending.1.keys().copied().collect::<Vec<_>>())
+    };
+    let checksum = || Ok(Checksum(vec![0; 32]).into());
+    in_order.print(2, Path::new("c"), checksum());
+    in_order.print(1, Path::new("b"), Err("unreadable".into()));
+    assert_eq!((0, vec![1, 2]), waiting());
+    in_order.print(0, Path::new("a"), checksum());
+    assert_eq!((3, vec![]), waiting());
+    in_order.print(1, Path::new("b/more"), checksum());
+    in_order.print(4, Path::new("e"), checksum());
+    assert_eq!((3, vec![4]), waiting());
+    in_order.skip(3);
+    assert_eq!((5, vec![]), waiting());
+    in_order.skip(3);
+    in_order.print(7, Path::new("h"), checksum());
+    in_order.skip(6);
+    assert_eq!((5, vec![6, 7]), waiting());
+    // Input 5 never came.
+    in_order.finish();
+    assert_eq!((8, vec![]), waiting());
+}
+
+#[test]
+fn b3_test_summary_json() {
+    let mut summary = RunSummary {
+        path: PathBuf::from("summary.json"),
+        start: Instant::now(),
+        statuses: BTreeMap::new(),
+        errors: vec![("a \"b\".txt".to_owned(), "it failed".to_owned())],
+    };
+    summary.statuses.insert("ok", 3);
+    summary.statuses.insert("failed", 1);
+    let metrics = Metrics {
+        bytes_hashed: 3000,
+        ..Metrics::default()
+    };
+    assert_eq!(
+        r#"{"exit_status":1,"files":4,"statuses":{"failed":1,"ok":3},"bytes_read":3000,"seconds":2.000000,"bytes_per_second":1500,"errors":[{"path":"a \"b\".txt","message":"it failed"}]}
+"#,
+        summary_json(&summary, 1, metrics, Duration::from_secs(2))
+    );
+}
+
+#[test]
+fn b3_test_system_log() {
+    assert_eq!(
+        b"<11>b3sum-ng[42]: a.txt: it failed".to_vec(),
+        syslog_datagram(LOG_ERR, "b3sum-ng", 42, "a.txt: it failed")
+    );
+    assert_eq!(
+        b"<14>b3sum-ng[42]: two lines".to_vec(),
+        syslog_datagram(LOG_INFO, "b3sum-ng", 42, "two\nlines")
+    );
+    assert_eq!(
+        b"PRIORITY=4\nSYSLOG_IDENTIFIER=b3sum-ng\nSYSLOG_PID=42\nMESSAGE\n\x09\0\0\0\0\0\0\0two\nlines\n"
+            .to_vec(),
+        journald_datagram(LOG_WARNING, "b3sum-ng", 42, "two\nlines")
+    );
+    assert_eq!(Ok(LogTarget::Journald), "journald".parse());
+    assert!("stderr".parse::<LogTarget>().is_err());
+
+    #[cfg(unix)]
+    {
+        use std::os::unix::net::UnixDatagram;
+
+        let path = std::env::temp_dir().join(format!("b3sum-log-{}", std::process::id()));
+        let socket = UnixDatagram::bind(&path).unwrap();
+        let log = SystemLog::connect(LogTarget::Syslog, &path).unwrap();
+        log.send(LOG_WARNING, "hello");
+        let mut buf = [0; 256];
+        let length = socket.recv(&mut buf).unwrap();
+        let received = String::from_utf8_lossy(&buf[..length]).into_owned();
+        assert!(received.starts_with("<12>"), "{}", received);
+        assert!(received.ends_with(&format!("[{}]: hello", std::process::id())));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
+
+#[test]
+fn b3_test_diagnostic() {
+    let path = Path::new("big.iso");
+    let line = diagnostic(path, "large, mmap", Some(3 << 20), Duration::from_secs(2));
+    assert_eq!(
+        "big.iso: large, mmap, 3145728 bytes in 2.000 s, 1.5 MiB/s",
+        line
+    );
+    let line = diagnostic(
+        Path::new("-"),
+        "stdin, buffered",
+        None,
+        Duration::from_millis(5),
+    );
+    assert_eq!("-: stdin, buffered, 0.005 s", line);
+}
+
+#[test]
+fn b3_test_json_string() {
+    assert_eq!(r#""a \"b\" \\ c\n\u0009""#, json_string("a \"b\" \\ c\n\t"));
+}
+
+#[test]
+fn b3_test_error_status() {
+    let not_found = std::io::Error::new(std::io::ErrorKind::NotFound, "missing");
+    assert_eq!(EXIT_MISSING, error_status_of(&not_found));
+    let denied = std::io::Error::new(std::io::ErrorKind::PermissionDenied, "denied");
+    assert_eq!(EXIT_IO_ERROR, error_status_of(&denied));
+    let other: Box<dyn Error> = "the checksum does not match".into();
+    assert_eq!(EXIT_FAILURE, error_status_of(&*other));
+}
+
+#[test]
+fn b3_test_normalize_path() {
+    let normalize = |path: &str, form| normalize_path(Path::new(path), true, form);
+    assert_eq!(PathBuf::from("a/b"), normalize("./a//b/", None));
+    assert_eq!(PathBuf::from("a/b"), normalize("a/./b", None));
+    assert_eq!(PathBuf::from("/a/b"), normalize("//a/b", None));
+    assert_eq!(PathBuf::from("."), normalize("./", None));
+    assert_eq!(PathBuf::from("-"), normalize("-", None));
+    assert_eq!(
+        PathBuf::from("./a//b"),
+        normalize_path(Path::new("./a//b"), false, None)
+    );
+    assert_eq!(
+        PathBuf::from("caf\u{e9}"),
+        normalize("./cafe\u{301}", Some(UnicodeForm::Nfc))
+    );
+    assert_eq!(
+        PathBuf::from("cafe\u{301}"),
+        normalize("caf\u{e9}", Some(UnicodeForm::Nfd))
+    );
+}
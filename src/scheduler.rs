@@ -0,0 +1,1603 @@
+// Copyright 2021 Daniel Zwell.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! When and how each input is read: small files several at a time and large files alone, a queue
+//! for each disk, and the limits on open files and concurrent reads.
+
+use std::{
+    cell::Cell,
+    collections::HashMap,
+    error::Error,
+    fs::{metadata, File},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    thread::JoinHandle,
+    time::{Duration, Instant},
+};
+
+use multi_semaphore::Semaphore;
+use rayon::{Scope, ThreadPool};
+
+use crate::{
+    engine::{
+        b3sum_large, b3sum_small, hash_large, hash_reader, hash_small, input_size, mmap_is_faster,
+        open_sequential, read_hints, read_stable, Checksum, Compression, Config, FileDetails,
+        Hashed, Hasher, Input, Listed, MMAP_SUPPORTED,
+    },
+    output::{
+        error_phase, io_error, print_checksum, print_diagnostic, print_warning, record_job,
+        record_progress, Format, JobEvent, Phase,
+    },
+    write_atomically, Global, Result,
+};
+
+#[cfg(test)]
+use crate::{make_temp_file, output::Metrics};
+
+/// Compute a checksum using different logic depending on input characteristics. This function
+/// handles locking to get the right amount of I/O parallelism.
+///
+/// `max_job_count` is the number of jobs that will be used for reading small files.  Small files
+/// are checksummed using one thread each, in parallel.  Large files will be read by just one
+/// thread but will use multiple threads for computing the checksum, irrespective of the
+/// `max_job_count` value.  It is commonly thought that using just one I/O thread gives fastest
+/// reads on spinning hard drives. I have not found that to be true when reading small files.
+///
+/// The semaphore's capacity should equal `max_job_count`.
+///
+/// The max number of threads used for checksumming large files is by default the number of logical
+/// CPU cores Rayon detects (via `num_cpus`). This can be overridden by setting the
+/// `RAYON_NUM_THREADS` environment variable. At least two threads must be used since one thread is
+/// needed to read files and another is needed to compute checksums.
+///
+/// Since this function may may spawn a background thread, `Ok(())` may be returned but an error
+/// may still be produced later. Callers should use [`print_error`](crate::output::print_error) to
+/// handle errors, since that matches what will be invoked internally.
+///
+/// [`checksum_paths`] does the same without a scope or a semaphore from the caller, and returns
+/// when all of the files are done.
+///
+/// # Example
+///
+/// ```no_run
+/// use std::{error::Error, path::PathBuf, sync::Arc};
+/// use multi_semaphore::Semaphore;
+/// use rayon::Scope;
+/// # fn print_error(path: &PathBuf, err: Box<dyn Error>) { }
+/// # fn do_checksum( path: PathBuf, max_job_count: usize, io_lock: Arc<Semaphore>, use_mmap: bool, s: &Scope,) -> Result<(), Box<dyn Error>> { todo!() }
+///
+/// let paths = vec![PathBuf::from("song.mp3"), PathBuf::from("todo.txt")];
+/// let max_job_count = 32;
+/// let io_lock = Arc::new(Semaphore::new(max_job_count as isize));
+/// rayon::scope(|s| {
+///     for path in paths {
+///         if let Err(err) =
+///             do_checksum(path.clone(), max_job_count, Arc::clone(&io_lock), false, s)
+///         {
+///             print_error(&path, err);
+///         }
+///     }
+/// });
+/// ```
+pub fn do_checksum(
+    path: PathBuf,
+    max_job_count: usize,
+    io_lock: Arc<Semaphore>,
+    use_mmap: bool,
+    s: &Scope,
+) -> Result<()> {
+    let config = Config {
+        max_job_count,
+        use_mmap,
+        ..Config::default()
+    };
+    checksum_with(path, &config, io_lock, s, |path, checksum| {
+        print_checksum(path, checksum, Format::B3sum)
+    })
+}
+
+/// Checksum one file, and return its checksum. It's read the same way as by [`checksum_with`],
+/// so small files are read whole, and large files are read with the strategy that suits their
+/// storage and hashed with multithreading. This is for callers that only want one file hashed,
+/// without a [`Scope`] or a [`Semaphore`] of their own.
+///
+/// ```no_run
+/// use b3sum_ng::{engine::Config, scheduler::hash_path};
+///
+/// let checksum = hash_path("song.mp3".as_ref(), &Config::default()).unwrap();
+/// println!("{}", checksum);
+/// ```
+pub fn hash_path(path: &Path, config: &Config) -> Result<Checksum> {
+    let result = Mutex::new(None);
+    checksum_paths(vec![path.to_owned()], config, |_, checksum| {
+        let checksum = checksum.map(|hashed| hashed.checksum);
+        *result.lock().unwrap() = Some(checksum.map_err(sendable_error));
+    });
+    match result.into_inner().unwrap() {
+        Some(checksum) => checksum.map_err(|err| err as Box<dyn Error>),
+        None => Err("the file wasn't read, because reading was cancelled".into()),
+    }
+}
+
+/// Checksum an [`Input`], such as a network stream or data in memory. It's read the way
+/// [`checksum_with`] reads files: if it's known to be small, it's read whole into memory, and
+/// otherwise it's hashed with multithreading as it's read. Files are memory mapped if
+/// `config.use_mmap` is set.
+pub fn hash_input(input: Input, config: &Config) -> Result<Checksum> {
+    match input.size_hint() {
+        Some(size) if size <= SMALL_FILE_LIMIT.load(Ordering::Relaxed) => match input {
+            Input::File(mut file) => b3sum_small(&mut file, config),
+            Input::Stream(mut read, _) => b3sum_small(&mut read, config),
+        },
+        _ => b3sum_large(input, config),
+    }
+}
+
+/// Checksum files, and pass the result for each one to `on_result`, which may be called from
+/// several threads at once. This starts them the same way as [`checksum_with`], in a
+/// [`rayon::scope`] of its own with `config.max_job_count` small files read at once, and returns
+/// when all of them are done. Errors are passed to `on_result` too, including the ones that
+/// happen before a file is read.
+///
+/// ```no_run
+/// use std::path::PathBuf;
+///
+/// use b3sum_ng::{
+///     engine::Config,
+///     output::{print_checksum, Format},
+///     scheduler::checksum_paths,
+/// };
+///
+/// let paths = vec![PathBuf::from("song.mp3"), PathBuf::from("todo.txt")];
+/// checksum_paths(paths, &Config::default(), |path, checksum| {
+///     print_checksum(path, checksum, Format::B3sum)
+/// });
+/// ```
+pub fn checksum_paths<I, F>(paths: I, config: &Config, on_result: F)
+where
+    I: IntoIterator<Item = PathBuf> + Send,
+    F: Fn(&Path, Result<Hashed>) + Sync,
+{
+    let io_lock = Arc::new(Semaphore::new(config.max_job_count as isize));
+    let on_result = &on_result;
+    rayon::scope(|s| {
+        for path in paths {
+            if let Err(err) =
+                checksum_with(path.clone(), config, Arc::clone(&io_lock), s, on_result)
+            {
+                on_result(&path, Err(err));
+            }
+        }
+    });
+}
+
+/// Like [`hash_path`], but the file is read and hashed on the threads of `pool` instead of rayon's
+/// global pool, so that an application can keep the work within its own thread budget.
+pub fn hash_path_in(pool: &ThreadPool, path: &Path, config: &Config) -> Result<Checksum> {
+    pool.install(|| hash_path(path, config).map_err(sendable_error))
+        .map_err(|err| err as Box<dyn Error>)
+}
+
+/// Answer checksum requests, for a program that runs b3sum-ng as a co-process instead of starting
+/// it for each file. Each request is a path that ends with `delimiter`, a newline or NUL, and the
+/// response to it is `ok <checksum>` or `error <message>`, with the same delimiter, which is
+/// flushed before the next request is read. Returns when `requests` ends or the response can't be
+/// written.
+pub fn serve<R, W>(mut requests: R, mut responses: W, config: &Config, delimiter: u8) -> Result<()>
+where
+    R: std::io::BufRead,
+    W: std::io::Write,
+{
+    let mut request = Vec::new();
+    loop {
+        request.clear();
+        if requests.read_until(delimiter, &mut request)? == 0 {
+            return Ok(());
+        }
+        if request.last() == Some(&delimiter) {
+            request.pop();
+        }
+        let result = if request.is_empty() || request == b"-" {
+            Err("the request isn't the path of a file".into())
+        } else {
+            hash_path(&request_path(request.clone()), config)
+        };
+        let response = match result {
+            Ok(checksum) => format!("ok {}", checksum),
+            // The delimiter can't be part of a response.
+            Err(err) => format!("error {}", err).replace(delimiter as char, " "),
+        };
+        responses.write_all(response.as_bytes())?;
+        responses.write_all(&[delimiter])?;
+        responses.flush()?;
+    }
+}
+
+#[cfg(unix)]
+fn request_path(request: Vec<u8>) -> PathBuf {
+    use std::{ffi::OsString, os::unix::ffi::OsStringExt};
+    PathBuf::from(OsString::from_vec(request))
+}
+
+#[cfg(not(unix))]
+fn request_path(request: Vec<u8>) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(&request).into_owned())
+}
+
+/// Like [`checksum_paths`], but the files are read and hashed on the threads of `pool` instead of
+/// rayon's global pool. `on_result` is called on the threads of the pool.
+///
+/// ```no_run
+/// use std::path::PathBuf;
+///
+/// use b3sum_ng::{
+///     engine::Config,
+///     output::{print_checksum, Format},
+///     scheduler::checksum_paths_in,
+/// };
+///
+/// let pool = rayon::ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+/// let paths = vec![PathBuf::from("song.mp3"), PathBuf::from("todo.txt")];
+/// checksum_paths_in(&pool, paths, &Config::default(), |path, checksum| {
+///     print_checksum(path, checksum, Format::B3sum)
+/// });
+/// ```
+pub fn checksum_paths_in<I, F>(pool: &ThreadPool, paths: I, config: &Config, on_result: F)
+where
+    I: IntoIterator<Item = PathBuf> + Send,
+    F: Fn(&Path, Result<Hashed>) + Send + Sync,
+{
+    pool.install(|| checksum_paths(paths, config, on_result))
+}
+
+/// Errors aren't `Send`, so this makes one with the same message that is. I/O errors keep their
+/// kind, so that the exit status for them is the same.
+fn sendable_error(err: Box<dyn Error>) -> Box<dyn Error + Send + Sync> {
+    match io_error(&*err) {
+        Some(io_err) => Box::new(std::io::Error::new(io_err.kind(), err.to_string())),
+        None => err.to_string().into(),
+    }
+}
+
+/// Files larger than this are checksummed one at a time, with multithreading. Smaller files are
+/// read whole into memory, several at a time. This is where the limit starts, before it is tuned
+/// by [`tune_small_file_limit`].
+const LARGE_FILE_SIZE: u64 = 128 * (1 << 10);
+
+/// The current limit on the size of small files.
+static SMALL_FILE_LIMIT: AtomicU64 = AtomicU64::new(LARGE_FILE_SIZE);
+
+/// How long it should take to read a small file, at the speed that large files are read. Files
+/// that take longer than this are worth reading with multithreading.
+const SMALL_FILE_READ_TIME: Duration = Duration::from_millis(1);
+
+/// The current limit on the size of small files, which are hashed without multithreading.
+pub(crate) fn small_file_limit() -> u64 {
+    SMALL_FILE_LIMIT.load(Ordering::Relaxed)
+}
+
+/// The range that the small file limit is tuned in.
+const MIN_SMALL_FILE_LIMIT: u64 = 32 * (1 << 10);
+const MAX_SMALL_FILE_LIMIT: u64 = 8 * (1 << 20);
+
+/// Adjust the small file limit after a large file of `bytes` bytes was read in `elapsed`. The
+/// limit moves towards the amount that's read in [`SMALL_FILE_READ_TIME`], so it rises on fast
+/// SSDs and falls on slow network filesystems.
+fn tune_small_file_limit(bytes: u64, elapsed: Duration) {
+    let limit = SMALL_FILE_LIMIT.load(Ordering::Relaxed);
+    let tuned = tuned_limit(limit, bytes, elapsed);
+    tracing::trace!(limit, tuned, "tuned the small file limit");
+    SMALL_FILE_LIMIT.store(tuned, Ordering::Relaxed);
+}
+
+/// The small file limit after a read of `bytes` bytes that took `elapsed`. Each read moves the
+/// limit a quarter of the way to its target, so one unusual file doesn't change it much.
+fn tuned_limit(limit: u64, bytes: u64, elapsed: Duration) -> u64 {
+    if elapsed.as_secs_f64() == 0.0 {
+        return limit;
+    }
+    let bytes_per_second = bytes as f64 / elapsed.as_secs_f64();
+    let target = (bytes_per_second * SMALL_FILE_READ_TIME.as_secs_f64()) as u64;
+    let target = target.clamp(MIN_SMALL_FILE_LIMIT, MAX_SMALL_FILE_LIMIT);
+    (limit * 3 + target) / 4
+}
+
+/// Like [`do_checksum`], but configurable, and passes the result to `on_result` instead of
+/// printing it. `on_result` may be called from a background thread.
+pub fn checksum_with<'scope, F>(
+    path: PathBuf,
+    config: &Config,
+    io_lock: Arc<Semaphore>,
+    s: &Scope<'scope>,
+    on_result: F,
+) -> Result<()>
+where
+    F: FnOnce(&Path, Result<Hashed>) + Send + 'scope,
+{
+    checksum_listed(path, None, None, config, io_lock, s, on_result)
+}
+
+/// Like [`checksum_with`], for a file that was found by searching a directory. `listed` is the
+/// file that was found, which is opened with [`Listed::open`], so that a directory on the way
+/// that was replaced by a symbolic link isn't followed. If it leads to a different file by the
+/// time it's opened, an error is passed to `on_result` instead of the checksum of the wrong file.
+///
+/// `opened` is the file at `path` if it was already opened by [`open_ahead`]. It is read instead
+/// of opening the path again, unless the file has to be read twice.
+pub fn checksum_listed<'scope, F>(
+    path: PathBuf,
+    listed: Option<Listed>,
+    opened: Option<File>,
+    config: &Config,
+    io_lock: Arc<Semaphore>,
+    s: &Scope<'scope>,
+    on_result: F,
+) -> Result<()>
+where
+    F: FnOnce(&Path, Result<Hashed>) + Send + 'scope,
+{
+    let max_job_count = config.max_job_count;
+    let mut config = config.clone();
+    if let Some(str) = path.to_str() {
+        if str == "-" {
+            let start = Instant::now();
+            let checksum = hash_large(Input::Stream(Box::new(std::io::stdin()), None), &config);
+            print_diagnostic(&path, "stdin, buffered", None, start.elapsed());
+            record_progress(0);
+            READ_TIME.with(|read_time| read_time.set(start.elapsed()));
+            on_result(&path, checksum);
+            return Ok(());
+        }
+    }
+
+    let compression = if config.decompress {
+        Compression::detect(&path)
+    } else {
+        None
+    };
+
+    // Reads over a network are limited by latency rather than by the disk, and mmap makes each
+    // page fault a round trip.
+    let network = config.io_strategy == IoStrategy::Auto && is_network_filesystem(&path);
+    if network {
+        config.use_mmap = false;
+        config.network = true;
+    }
+
+    // Get file size before locking because we won't know how many I/O resources to lock
+    // until we know how big it is. And locks can't be upgraded without probable deadlock.
+    let filesize = error_phase(Phase::Stat, input_size(&path))?;
+    // Each file has a span, which is entered on the thread that reads it.
+    let span = tracing::debug_span!("checksum", path = %path.display(), bytes = filesize);
+    record_job(JobEvent::Queued, &config);
+    let opened = Mutex::new(opened);
+    let lock = config.lock;
+    let xattrs = config.xattrs;
+    // The metadata is taken from the file that's opened, rather than from whatever is at the
+    // path when the checksum is printed.
+    let open = move |path: &Path, opened: &Mutex<Option<File>>| -> Result<(Input, FileDetails)> {
+        let file = match opened.lock().unwrap().take() {
+            Some(file) => file,
+            None => match &listed {
+                Some(listed) => error_phase(Phase::Open, listed.open())?,
+                None => error_phase(Phase::Open, open_sequential(path).map_err(Into::into))?,
+            },
+        };
+        let details = FileDetails::of(path, &file, xattrs);
+        let input = Input::from_file(
+            file,
+            compression,
+            listed.as_ref().and_then(Listed::id),
+            lock,
+        );
+        Ok((error_phase(Phase::Open, input)?, details))
+    };
+    if filesize > SMALL_FILE_LIMIT.load(Ordering::Relaxed) {
+        // Wait for all other I/O to be finished, and take all the I/O resources.
+        // Because concurrent reads of large files reduces performance, on hard drives at least.
+        let rotational = match config.io_strategy {
+            IoStrategy::Auto => is_rotational(&path),
+            IoStrategy::Hdd => Some(true),
+            IoStrategy::Ssd => Some(false),
+        };
+        let exclusive = match config.exclusive_large {
+            ExclusiveLarge::Always => true,
+            ExclusiveLarge::Never => false,
+            ExclusiveLarge::Auto => rotational.unwrap_or(true),
+        };
+        // Hard drives would have to seek between the reads.
+        config.parallel_reads = rotational == Some(false);
+        let read = move || {
+            let _span = span.enter();
+            tracing::debug!(exclusive, rotational = ?rotational, network, "waiting for the I/O lock");
+            let io_lock = io_lock.access_many(if exclusive { max_job_count } else { 1 } as isize);
+            wait_while_paused();
+            if skip_file(&config) {
+                tracing::debug!("skipped, because the run was cancelled");
+                record_job(JobEvent::Skipped, &config);
+                return;
+            }
+            let mut config = config;
+            if !network && compression.is_none() {
+                config.use_mmap = mmap_chosen_for(&path, &config);
+            }
+            record_job(JobEvent::Started, &config);
+            let start = Instant::now();
+            // With --paranoid, the file is read again from the disk, with mmap if it was buffered
+            // and buffered if it was mapped, so that the two reads take different paths.
+            let read_again = || {
+                let mut again = config.clone();
+                again.use_mmap =
+                    !config.use_mmap && MMAP_SUPPORTED && !network && compression.is_none();
+                let (input, _) = open(&path, &opened)?;
+                if let Input::File(file) = &input {
+                    crate::sys::drop_cached(file);
+                }
+                hash_large(input, &again)
+            };
+            let checksum = read_stable(
+                &path,
+                &config,
+                &|| {
+                    let (input, details) = open(&path, &opened)?;
+                    hash_large(input, &config).map(|hashed| details.add_to(hashed))
+                },
+                &read_again,
+            );
+            drop(io_lock);
+            let strategy = match (compression, config.use_mmap) {
+                (Some(_), _) => "large, decompressed",
+                (None, true) => "large, mmap",
+                (None, false) if config.parallel_reads => "large, parallel reads",
+                (None, false) if network => "large, buffered, network",
+                (None, false) => "large, buffered",
+            };
+            // Decompressing is limited by the CPU, so it doesn't say how fast the disk is.
+            if checksum.is_ok() && compression.is_none() {
+                tune_small_file_limit(filesize, start.elapsed());
+            }
+            print_diagnostic(&path, strategy, Some(filesize), start.elapsed());
+            record_progress(filesize);
+            record_job(JobEvent::Finished(filesize), &config);
+            READ_TIME.with(|read_time| read_time.set(start.elapsed()));
+            on_result(&path, checksum);
+        };
+        // Other files can be started while a file that isn't read exclusively is being read.
+        if exclusive {
+            read();
+        } else {
+            s.spawn(move |_| read());
+        }
+    } else {
+        s.spawn(move |_| {
+            let _span = span.enter();
+            // Small files over a network aren't limited by --job-count, since reading more of
+            // them at once hides the latency. SSDs don't need to be protected from seeking.
+            let io_lock = if network || config.io_strategy == IoStrategy::Ssd {
+                None
+            } else {
+                Some(io_lock.access())
+            };
+            wait_while_paused();
+            if skip_file(&config) {
+                tracing::debug!("skipped, because the run was cancelled");
+                record_job(JobEvent::Skipped, &config);
+                return;
+            }
+            record_job(JobEvent::Started, &config);
+            let start = Instant::now();
+            let read = || {
+                let (input, details) = open(&path, &opened)?;
+                let hashed = match input {
+                    Input::File(mut file) => {
+                        read_hints(&file, &config, false);
+                        hash_small(&mut file, &config)
+                    }
+                    // Decompressed data may be much larger than the file, so don't buffer all
+                    // of it.
+                    Input::Stream(mut read, _) => {
+                        let mut hasher = Hasher::new(&config)?;
+                        hash_reader(&mut read, &mut hasher).map(|()| hasher.finish())
+                    }
+                };
+                hashed.map(|hashed| details.add_to(hashed))
+            };
+            // With --paranoid, the file is read again from the disk by the buffered reads of
+            // large files, rather than all at once.
+            let read_again = || {
+                let (input, _) = open(&path, &opened)?;
+                if let Input::File(file) = &input {
+                    crate::sys::drop_cached(file);
+                }
+                hash_large(
+                    input,
+                    &Config {
+                        use_mmap: false,
+                        ..config.clone()
+                    },
+                )
+            };
+            let checksum = read_stable(&path, &config, &read, &read_again);
+            drop(io_lock);
+            let strategy = match compression {
+                Some(_) => "small, decompressed",
+                None if network => "small, in memory, network",
+                None => "small, in memory",
+            };
+            print_diagnostic(&path, strategy, Some(filesize), start.elapsed());
+            record_progress(filesize);
+            record_job(JobEvent::Finished(filesize), &config);
+            READ_TIME.with(|read_time| read_time.set(start.elapsed()));
+            on_result(&path, checksum);
+        });
+    };
+
+    Ok(())
+}
+
+/// How many inputs are opened ahead of the one that is being started, by [`open_ahead`].
+pub const OPEN_AHEAD: usize = 4;
+
+/// Start opening a file in the background, so that the time it takes to open, which is a round
+/// trip on network filesystems, overlaps with reading other files. The file can be taken from the
+/// receiver once it's open, or the receiver can be dropped to close it.
+pub fn open_ahead<'scope>(path: PathBuf, s: &Scope<'scope>) -> mpsc::Receiver<File> {
+    let (sender, receiver) = mpsc::sync_channel(1);
+    if path.to_str() != Some("-") {
+        s.spawn(move |_| {
+            if let Ok(file) = open_sequential(&path) {
+                let _ = sender.send(file);
+            }
+        });
+    }
+    receiver
+}
+
+/// How many files may be open for reading at once. This is set from the limit on open files by
+/// [`raise_open_file_limit`], and is unlimited until then.
+static DESCRIPTOR_BUDGET: AtomicUsize = AtomicUsize::new(usize::MAX);
+
+/// How many files are open for reading.
+static OPEN_DESCRIPTORS: AtomicUsize = AtomicUsize::new(0);
+
+/// Descriptors that are left for everything other than reading inputs, like checkfiles, standard
+/// streams and rayon's threads.
+const RESERVED_DESCRIPTORS: usize = 64;
+
+/// A place in the budget of open files, which is given back when this is dropped.
+pub(crate) struct Descriptor;
+
+impl Descriptor {
+    /// Wait until a file can be opened without going over the budget, so that reads are put off
+    /// instead of failing with "too many open files".
+    pub(crate) fn reserve() -> Descriptor {
+        loop {
+            let open = OPEN_DESCRIPTORS.load(Ordering::Relaxed);
+            if open < DESCRIPTOR_BUDGET.load(Ordering::Relaxed)
+                && OPEN_DESCRIPTORS
+                    .compare_exchange(open, open + 1, Ordering::Relaxed, Ordering::Relaxed)
+                    .is_ok()
+            {
+                return Descriptor;
+            }
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+impl Drop for Descriptor {
+    fn drop(&mut self) {
+        OPEN_DESCRIPTORS.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Raise the soft limit on open files as far as the hard limit allows, and budget the descriptors
+/// that reads may use. Returns the new limit, if there is one. Windows has no limit on open files
+/// that reads are likely to reach, so it's `None` there.
+pub fn raise_open_file_limit() -> Option<u64> {
+    let current = crate::sys::raise_open_file_limit().ok()?;
+    let reserved = RESERVED_DESCRIPTORS.min(current as usize / 2);
+    DESCRIPTOR_BUDGET.store((current as usize - reserved).max(1), Ordering::Relaxed);
+    Some(current)
+}
+
+/// Work out how [`checksum_with`] would read a path, without reading it, for `--dry-run`.
+/// Returns "stdin", "small" or "large", and the size if it is known.
+pub fn planned_read(path: &Path) -> Result<(&'static str, Option<u64>)> {
+    if path.to_str() == Some("-") {
+        return Ok(("stdin", None));
+    }
+    if metadata(path)?.is_dir() {
+        return Err("Is a directory".into());
+    }
+    let size = input_size(path)?;
+    let strategy = if size > LARGE_FILE_SIZE {
+        "large"
+    } else {
+        "small"
+    };
+    Ok((strategy, Some(size)))
+}
+
+thread_local! {
+    /// See [`read_time`].
+    static READ_TIME: Cell<Duration> = Cell::new(Duration::default());
+}
+
+/// How long it took to read and checksum the file whose result is being passed to the `on_result`
+/// of [`checksum_listed`]. This is only meaningful inside `on_result`.
+pub(crate) fn read_time() -> Duration {
+    READ_TIME.with(Cell::get)
+}
+
+/// Whether a large file is read while nothing else is, as given by `--io-exclusive-large`. With
+/// `Auto`, that is only done for hard drives, and for devices whose type isn't known.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExclusiveLarge {
+    Auto,
+    Always,
+    Never,
+}
+
+impl FromStr for ExclusiveLarge {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ExclusiveLarge::Auto),
+            "always" => Ok(ExclusiveLarge::Always),
+            "never" => Ok(ExclusiveLarge::Never),
+            _ => Err(format!("unknown value for --io-exclusive-large: {}", s)),
+        }
+    }
+}
+
+/// How files are read, as given by `--io-strategy`. `Hdd` and `Ssd` replace the detection of the
+/// storage that each file is on.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum IoStrategy {
+    Auto,
+    Hdd,
+    Ssd,
+}
+
+impl FromStr for IoStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(IoStrategy::Auto),
+            "hdd" => Ok(IoStrategy::Hdd),
+            "ssd" => Ok(IoStrategy::Ssd),
+            _ => Err(format!("unknown value for --io-strategy: {}", s)),
+        }
+    }
+}
+
+/// Whether a file is on a spinning disk, if that can be found out. This is only supported on
+/// Linux, where the kernel tells whether each block device is rotational.
+#[cfg(target_os = "linux")]
+fn is_rotational(path: &Path) -> Option<bool> {
+    let rotational = std::fs::read_to_string(sysfs_disk(path)?.join("queue/rotational")).ok()?;
+    match rotational.trim() {
+        "0" => Some(false),
+        "1" => Some(true),
+        _ => None,
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn is_rotational(_path: &Path) -> Option<bool> {
+    None
+}
+
+/// The directory in /sys of the disk that a file is on. Partitions don't have a queue, but the
+/// disk they're on does.
+#[cfg(target_os = "linux")]
+fn sysfs_disk(path: &Path) -> Option<PathBuf> {
+    use std::os::unix::fs::MetadataExt;
+
+    let dev = metadata(path).ok()?.dev();
+    let major = ((dev >> 8) & 0xfff) | ((dev >> 32) & !0xfff);
+    let minor = (dev & 0xff) | ((dev >> 12) & !0xff);
+    let device = PathBuf::from(format!("/sys/dev/block/{}:{}", major, minor));
+    if device.join("queue").exists() {
+        Some(device)
+    } else if device.join("../queue").exists() {
+        Some(device.join(".."))
+    } else {
+        None
+    }
+}
+
+/// How many CPUs this process can use, if that can be found out. On Linux, this is
+/// limited by the CPUs it may run on and by the CPU quota of its cgroup, which is how containers
+/// are limited.
+#[cfg(target_os = "linux")]
+pub fn available_cpus() -> Option<usize> {
+    let affinity = crate::sys::affinity_cpus();
+    let quota = std::fs::read_to_string("/proc/self/cgroup")
+        .ok()
+        .and_then(|cgroups| cgroup_cpu_quota(&cgroups));
+    match (affinity, quota) {
+        (Some(affinity), Some(quota)) => Some(affinity.min(quota)),
+        (affinity, quota) => affinity.or(quota),
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn available_cpus() -> Option<usize> {
+    None
+}
+
+/// The CPU quota of the cgroup that is described by `cgroups`, the contents of /proc/self/cgroup,
+/// rounded up to whole CPUs. Both cgroup v2 and v1 are supported. In a container, the cgroup's
+/// path may not be visible, so the root cgroup is checked too.
+#[cfg(target_os = "linux")]
+fn cgroup_cpu_quota(cgroups: &str) -> Option<usize> {
+    for line in cgroups.lines() {
+        let mut fields = line.splitn(3, ':');
+        let (id, controllers, path) = (fields.next()?, fields.next()?, fields.next()?);
+        let path = path.trim_start_matches('/');
+        if id == "0" && controllers.is_empty() {
+            for dir in &[
+                Path::new("/sys/fs/cgroup").join(path),
+                PathBuf::from("/sys/fs/cgroup"),
+            ] {
+                if let Ok(max) = std::fs::read_to_string(dir.join("cpu.max")) {
+                    return parse_cpu_max(&max);
+                }
+            }
+        } else if controllers.split(',').any(|controller| controller == "cpu") {
+            let root = Path::new("/sys/fs/cgroup/cpu");
+            for dir in &[root.join(path), root.to_owned()] {
+                let read = |name| std::fs::read_to_string(dir.join(name)).ok();
+                if let (Some(quota), Some(period)) =
+                    (read("cpu.cfs_quota_us"), read("cpu.cfs_period_us"))
+                {
+                    return parse_cpu_max(&format!("{} {}", quota.trim(), period.trim()));
+                }
+            }
+        }
+    }
+    None
+}
+
+/// Parse a CPU quota like cgroup v2's cpu.max: the time that may be used in each period, and the
+/// period, or "max" for no limit. cgroup v1 uses -1 for no limit. Returns whole CPUs, rounded up.
+#[cfg(target_os = "linux")]
+fn parse_cpu_max(max: &str) -> Option<usize> {
+    let mut fields = max.split_whitespace();
+    let quota: i64 = fields.next()?.parse().ok()?;
+    let period: i64 = fields.next()?.parse().ok()?;
+    if quota <= 0 || period <= 0 {
+        return None;
+    }
+    Some(((quota + period - 1) / period) as usize)
+}
+
+/// Identifies the disk that a file is on, so that each disk can be kept busy. On Linux, partitions
+/// of one disk are the same disk. Elsewhere, and for filesystems that aren't on one block device,
+/// this is the filesystem's device number.
+#[cfg(unix)]
+fn disk_id(path: &Path) -> Option<u64> {
+    use std::os::unix::fs::MetadataExt;
+
+    #[cfg(target_os = "linux")]
+    if let Some(disk) = sysfs_disk(path) {
+        let dev = std::fs::read_to_string(disk.join("dev")).ok()?;
+        let (major, minor) = dev.trim().split_once(':')?;
+        // The top bit keeps these apart from device numbers, which can't be that large.
+        return Some(1 << 63 | major.parse::<u64>().ok()? << 32 | minor.parse::<u64>().ok()?);
+    }
+    Some(metadata(path).ok()?.dev())
+}
+
+#[cfg(not(unix))]
+fn disk_id(_path: &Path) -> Option<u64> {
+    None
+}
+
+/// Whether mmap was faster than buffered reads on each disk, for `--auto-mmap`, as recorded in
+/// `path`. Each line is a disk ID from [`disk_id`] and `mmap` or `buffered`.
+#[derive(Debug, Default)]
+pub(crate) struct MmapChoices {
+    path: PathBuf,
+    disks: HashMap<u64, bool>,
+}
+
+impl MmapChoices {
+    /// Read the choices that were recorded in `path`. A file that doesn't exist has none, and
+    /// lines that can't be read are ignored.
+    fn load(path: PathBuf) -> Result<Self> {
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => String::new(),
+            Err(err) => return Err(err.into()),
+        };
+        let disks = contents
+            .lines()
+            .filter_map(|line| {
+                let (disk, method) = line.split_once(' ')?;
+                let mmap = match method {
+                    "mmap" => true,
+                    "buffered" => false,
+                    _ => return None,
+                };
+                Some((disk.parse().ok()?, mmap))
+            })
+            .collect();
+        Ok(MmapChoices { path, disks })
+    }
+
+    /// Write the choices, replacing the file at once.
+    fn save(&self) -> Result<()> {
+        let mut disks: Vec<_> = self.disks.iter().collect();
+        disks.sort();
+        let contents: String = disks
+            .into_iter()
+            .map(|(disk, &mmap)| format!("{} {}\n", disk, if mmap { "mmap" } else { "buffered" }))
+            .collect();
+        if let Some(dir) = self.path.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        Ok(write_atomically(&self.path, contents.as_bytes())?)
+    }
+}
+
+/// The choices of `--auto-mmap`, if [`start_auto_mmap`] was called.
+static MMAP_CHOICES: Global<Mutex<MmapChoices>> = Global::new();
+
+/// Choose between mmap and buffered reads for the large files of each disk, instead of following
+/// [`Config::use_mmap`]. The first time a large file is read from a disk, both ways are timed on
+/// the start of the file, and the faster one is recorded in `cache` for that disk, so that later
+/// runs use it without timing again. Disks can only be told apart on Unix. This should only be
+/// called once, before any files are read.
+#[cfg_attr(not(feature = "cli"), allow(dead_code))]
+pub(crate) fn start_auto_mmap(cache: PathBuf) -> Result<()> {
+    let _ = MMAP_CHOICES.set(Mutex::new(MmapChoices::load(cache)?));
+    Ok(())
+}
+
+/// Where `--auto-mmap` records its choices by default: `b3sum-ng/mmap` in the cache directory,
+/// which is `$XDG_CACHE_HOME`, `~/.cache` or `%LOCALAPPDATA%`.
+pub fn default_mmap_cache() -> Option<PathBuf> {
+    let var = |name| std::env::var_os(name).filter(|value| !value.is_empty());
+    let dir = var("XDG_CACHE_HOME")
+        .map(PathBuf::from)
+        .or_else(|| var("HOME").map(|home| Path::new(&home).join(".cache")))
+        .or_else(|| var("LOCALAPPDATA").map(PathBuf::from))?;
+    Some(dir.join("b3sum-ng").join("mmap"))
+}
+
+/// Whether a large file is read with mmap: as recorded for its disk with `--auto-mmap`, after
+/// timing both ways if they weren't timed on it before, or otherwise as `config` says.
+fn mmap_chosen_for(path: &Path, config: &Config) -> bool {
+    let choices = match MMAP_CHOICES.get() {
+        Some(choices) => choices,
+        None => return config.use_mmap,
+    };
+    let disk = match disk_id(path) {
+        Some(disk) => disk,
+        None => return config.use_mmap,
+    };
+    // Other files wait while a disk is timed, so that it's only timed once.
+    let mut choices = choices.lock().unwrap();
+    if let Some(&mmap) = choices.disks.get(&disk) {
+        return mmap;
+    }
+    match mmap_is_faster(path, config) {
+        Ok(mmap) => {
+            tracing::debug!(disk, mmap, "timed mmap and buffered reads");
+            choices.disks.insert(disk, mmap);
+            if let Err(err) = choices.save() {
+                print_warning(&format!("{}: {}", choices.path.display(), err));
+            }
+            mmap
+        }
+        Err(_) => config.use_mmap,
+    }
+}
+
+/// Lower the CPU and I/O priority of this process as far as it can go, for `--background`, so
+/// that a scan only uses time that nothing else wants. It's nice 19 and the idle I/O class on
+/// Linux, nice 19 with throttled I/O on macOS, and the background mode on Windows. Threads that
+/// are started before this is called may keep their priority on Linux, where priorities are
+/// for each thread.
+pub fn lower_priority() -> Result<()> {
+    Ok(crate::sys::lower_priority()?)
+}
+
+/// Whether new files are kept from being started, because other processes are busy. Set by
+/// [`LoadMonitor`].
+static PAUSED: AtomicBool = AtomicBool::new(false);
+
+/// Wait until the [`LoadMonitor`] lets new files be started, or the deadline passes.
+fn wait_while_paused() {
+    while PAUSED.load(Ordering::Relaxed) && !deadline_passed() {
+        std::thread::sleep(Duration::from_millis(100));
+    }
+}
+
+/// How long a run may take, for `--timeout`, such as `90`, `90s`, `30m` or `2h`. A number without
+/// a unit is in seconds.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct TimeLimit(pub Duration);
+
+impl FromStr for TimeLimit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (number, unit) = match s.char_indices().find(|(_, c)| !c.is_ascii_digit()) {
+            Some((index, _)) => s.split_at(index),
+            None => (s, "s"),
+        };
+        let unit = match unit {
+            "s" => 1,
+            "m" | "min" => 60,
+            "h" => 60 * 60,
+            _ => return Err(format!("unknown unit of time: {}", unit)),
+        };
+        let number: u64 = number
+            .parse()
+            .map_err(|_| format!("invalid time limit: {}", s))?;
+        Ok(TimeLimit(Duration::from_secs(number.saturating_mul(unit))))
+    }
+}
+
+/// When files stop being started, if [`set_deadline`] was called.
+static DEADLINE: Global<Instant> = Global::new();
+
+/// Whether a file was skipped because the deadline had passed.
+static TIMED_OUT: AtomicBool = AtomicBool::new(false);
+
+/// Stop starting files at `deadline`, for `--timeout`. The files that are being read are
+/// finished, and the others are skipped as if the run was [cancelled](Config::cancel). This should
+/// only be called once, before any files are read.
+#[cfg_attr(not(feature = "cli"), allow(dead_code))]
+pub(crate) fn set_deadline(deadline: Instant) {
+    let _ = DEADLINE.set(deadline);
+}
+
+/// The deadline of `--timeout`, if there is one.
+pub fn deadline() -> Option<Instant> {
+    DEADLINE.get().copied()
+}
+
+fn deadline_passed() -> bool {
+    deadline().map_or(false, |deadline| Instant::now() >= deadline)
+}
+
+/// Whether files were skipped because the deadline of `--timeout` passed.
+pub fn timed_out() -> bool {
+    TIMED_OUT.load(Ordering::Relaxed)
+}
+
+/// Whether the file that is about to be read should be skipped, because the run was cancelled or
+/// its deadline passed.
+fn skip_file(config: &Config) -> bool {
+    if config.is_cancelled() {
+        return true;
+    }
+    if deadline_passed() {
+        TIMED_OUT.store(true, Ordering::Relaxed);
+        return true;
+    }
+    false
+}
+
+/// How busy other processes may keep the system before a [`LoadMonitor`] pauses.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct PauseThresholds {
+    /// The share of all CPUs that other processes use, from 0 to 100.
+    pub cpu_percent: f64,
+    /// The bytes per second that other processes read from and write to the disks.
+    pub io_bytes_per_second: u64,
+}
+
+/// Pauses the scan while other processes are busy, for `--background`. Every second, a background
+/// thread measures how much CPU time and disk I/O the rest of the system used, leaving out this
+/// process's own, and no new files are started while either is above its threshold. Files that
+/// are being read are finished. It's dropped to stop measuring, and it only measures on Linux.
+pub struct LoadMonitor {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl LoadMonitor {
+    pub fn start(thresholds: PauseThresholds) -> Self {
+        let (stop, stopped) = mpsc::channel();
+        let thread = std::thread::Builder::new().spawn(move || {
+            let mut before = LoadSample::take();
+            while let Err(mpsc::RecvTimeoutError::Timeout) =
+                stopped.recv_timeout(Duration::from_secs(1))
+            {
+                let after = LoadSample::take();
+                if let (Some(before), Some(after)) = (&before, &after) {
+                    let (cpu, io) = others_load(before, after);
+                    let busy = cpu * 100.0 > thresholds.cpu_percent
+                        || io > thresholds.io_bytes_per_second as f64;
+                    if busy != PAUSED.swap(busy, Ordering::Relaxed) {
+                        tracing::info!(cpu, io, busy, "other processes changed how busy they are");
+                    }
+                }
+                before = after;
+            }
+            PAUSED.store(false, Ordering::Relaxed);
+        });
+        LoadMonitor {
+            stop: Some(stop),
+            thread: thread.ok(),
+        }
+    }
+}
+
+impl Drop for LoadMonitor {
+    fn drop(&mut self) {
+        drop(self.stop.take());
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// The counters of the whole system and of this process, for [`LoadMonitor`]. CPU times are in
+/// clock ticks.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct LoadSample {
+    when: Option<Instant>,
+    cpu_total: u64,
+    cpu_busy: u64,
+    own_cpu: u64,
+    disk_bytes: u64,
+    own_disk_bytes: u64,
+}
+
+impl LoadSample {
+    #[cfg(target_os = "linux")]
+    fn take() -> Option<Self> {
+        let read = |path| std::fs::read_to_string(path).ok();
+        let (cpu_total, cpu_busy) = parse_proc_stat(&read("/proc/stat")?)?;
+        let is_disk = |name: &str| Path::new("/sys/block").join(name).exists();
+        Some(LoadSample {
+            when: Some(Instant::now()),
+            cpu_total,
+            cpu_busy,
+            own_cpu: parse_own_cpu(&read("/proc/self/stat")?)?,
+            disk_bytes: parse_diskstats(&read("/proc/diskstats")?, is_disk),
+            // This needs no privileges, but it's missing without task I/O accounting.
+            own_disk_bytes: read("/proc/self/io").map_or(0, |io| parse_own_io(&io)),
+        })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn take() -> Option<Self> {
+        None
+    }
+}
+
+/// The share of all CPUs that other processes used between two samples, from 0 to 1, and the
+/// bytes per second that they read and wrote.
+fn others_load(before: &LoadSample, after: &LoadSample) -> (f64, f64) {
+    let total = after.cpu_total.saturating_sub(before.cpu_total);
+    let busy = after.cpu_busy.saturating_sub(before.cpu_busy);
+    let own = after.own_cpu.saturating_sub(before.own_cpu);
+    let cpu = if total > 0 {
+        busy.saturating_sub(own) as f64 / total as f64
+    } else {
+        0.0
+    };
+    let bytes = after.disk_bytes.saturating_sub(before.disk_bytes);
+    let own_bytes = after.own_disk_bytes.saturating_sub(before.own_disk_bytes);
+    let seconds = match (before.when, after.when) {
+        (Some(before), Some(after)) => (after - before).as_secs_f64(),
+        _ => 0.0,
+    };
+    let io = if seconds > 0.0 {
+        bytes.saturating_sub(own_bytes) as f64 / seconds
+    } else {
+        0.0
+    };
+    (cpu, io)
+}
+
+/// The total and busy clock ticks of all CPUs, from /proc/stat. Idle and iowait time isn't busy.
+#[cfg(target_os = "linux")]
+fn parse_proc_stat(stat: &str) -> Option<(u64, u64)> {
+    let line = stat.lines().find(|line| line.starts_with("cpu "))?;
+    let ticks: Vec<u64> = line
+        .split_whitespace()
+        .skip(1)
+        .map(|field| field.parse().ok())
+        .collect::<Option<_>>()?;
+    // Guest time is already counted in user time.
+    let total: u64 = ticks.iter().take(8).sum();
+    let idle = ticks.get(3)? + ticks.get(4).unwrap_or(&0);
+    Some((total, total - idle))
+}
+
+/// The user and system clock ticks of this process, from /proc/self/stat.
+#[cfg(target_os = "linux")]
+fn parse_own_cpu(stat: &str) -> Option<u64> {
+    // The command name is in parentheses, and may contain spaces.
+    let fields: Vec<&str> = stat.rsplit_once(')')?.1.split_whitespace().collect();
+    let user: u64 = fields.get(11)?.parse().ok()?;
+    let system: u64 = fields.get(12)?.parse().ok()?;
+    Some(user + system)
+}
+
+/// The bytes read from and written to the disks, from /proc/diskstats. Partitions are left out,
+/// since they're counted in their disks too.
+#[cfg(target_os = "linux")]
+fn parse_diskstats(diskstats: &str, is_disk: impl Fn(&str) -> bool) -> u64 {
+    const SECTOR_SIZE: u64 = 512;
+    let mut sectors = 0;
+    for line in diskstats.lines() {
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.len() < 10 || !is_disk(fields[2]) {
+            continue;
+        }
+        let field = |index: usize| fields[index].parse::<u64>().unwrap_or(0);
+        sectors += field(5) + field(9);
+    }
+    sectors * SECTOR_SIZE
+}
+
+/// The bytes that this process read from and wrote to storage, from /proc/self/io.
+#[cfg(target_os = "linux")]
+fn parse_own_io(io: &str) -> u64 {
+    io.lines()
+        .filter_map(|line| line.split_once(": "))
+        .filter(|(name, _)| *name == "read_bytes" || *name == "write_bytes")
+        .filter_map(|(_, bytes)| bytes.trim().parse::<u64>().ok())
+        .sum()
+}
+
+/// What is known about an input before it is read, for scheduling the reads.
+pub struct InputInfo<P> {
+    pub path: P,
+    /// How it would be read, and its size, as found by [`planned_read`]. This is `None` if that
+    /// failed, and the error is found again when the input is read.
+    pub planned: Option<(&'static str, Option<u64>)>,
+    /// The disk it's on, as found by `disk_id`.
+    pub disk: Option<u64>,
+}
+
+/// Stat the inputs with up to `workers` threads, so that waiting for metadata, which is slow on
+/// network filesystems and with cold caches, overlaps. The inputs are returned in order.
+pub fn gather_inputs<P: AsRef<Path> + Send>(paths: Vec<P>, workers: usize) -> Vec<InputInfo<P>> {
+    let mut inputs: Vec<InputInfo<P>> = paths
+        .into_iter()
+        .map(|path| InputInfo {
+            path,
+            planned: None,
+            disk: None,
+        })
+        .collect();
+    let workers = workers.max(1);
+    let chunk_size = ((inputs.len() + workers - 1) / workers).max(1);
+    rayon::scope(|s| {
+        for chunk in inputs.chunks_mut(chunk_size) {
+            s.spawn(move |_| {
+                for input in chunk {
+                    let path = input.path.as_ref();
+                    input.planned = planned_read(path).ok();
+                    if path.to_str() != Some("-") {
+                        input.disk = disk_id(path);
+                    }
+                }
+            });
+        }
+    });
+    inputs
+}
+
+/// Split inputs into one queue for each disk, so that the disks can be read at the same time. The
+/// order of the inputs is kept within each queue, and the queues are in the order that their
+/// first inputs were given. Inputs whose disk isn't known, including stdin, are queued together.
+pub fn queues_by_disk<P>(inputs: Vec<InputInfo<P>>) -> Vec<Vec<P>> {
+    let mut queues: Vec<Vec<P>> = Vec::new();
+    let mut disks = HashMap::new();
+    for input in inputs {
+        let index = *disks.entry(input.disk).or_insert_with(|| {
+            queues.push(Vec::new());
+            queues.len() - 1
+        });
+        queues[index].push(input.path);
+    }
+    queues
+}
+
+/// Whether a file is on a network filesystem (NFS, SMB or FUSE, which is often remote). This is
+/// only detected on Linux.
+fn is_network_filesystem(path: &Path) -> bool {
+    // Magic numbers from linux/magic.h and the kernel's SMB and FUSE sources.
+    const NFS_SUPER_MAGIC: u32 = 0x6969;
+    const SMB_SUPER_MAGIC: u32 = 0x517b;
+    const CIFS_MAGIC_NUMBER: u32 = 0xff53_4d42;
+    const SMB2_MAGIC_NUMBER: u32 = 0xfe53_4d42;
+    const FUSE_SUPER_MAGIC: u32 = 0x6573_5546;
+
+    matches!(
+        crate::sys::filesystem_magic(path),
+        Some(NFS_SUPER_MAGIC)
+            | Some(SMB_SUPER_MAGIC)
+            | Some(CIFS_MAGIC_NUMBER)
+            | Some(SMB2_MAGIC_NUMBER)
+            | Some(FUSE_SUPER_MAGIC)
+    )
+}
+
+#[test]
+fn b3_test_planned_read() -> Result<()> {
+    let (_, small, _guard) = make_temp_file(b"hello world");
+    assert_eq!(("small", Some(11)), planned_read(&small)?);
+    let (_, large, _guard) = make_temp_file(&vec![0; 200_000]);
+    assert_eq!(("large", Some(200_000)), planned_read(&large)?);
+    assert_eq!(("stdin", None), planned_read(Path::new("-"))?);
+    assert!(planned_read(&std::env::temp_dir()).is_err());
+    Ok(())
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn b3_test_network_filesystem() {
+    assert!(!is_network_filesystem(Path::new("/proc")));
+    assert!(!is_network_filesystem(Path::new("/nonexistent/b3sum-ng")));
+}
+
+#[test]
+fn b3_test_queues_by_disk() {
+    let dir = std::env::temp_dir();
+    let (_, first, _first_guard) = make_temp_file(b"first");
+    let (_, second, _second_guard) = make_temp_file(b"second");
+    let inputs = gather_inputs(
+        vec![
+            first.clone(),
+            PathBuf::from("-"),
+            second.clone(),
+            dir.join("b3sum-ng-missing"),
+        ],
+        2,
+    );
+    assert_eq!(Some(("small", Some(5))), inputs[0].planned);
+    assert_eq!(Some(("stdin", None)), inputs[1].planned);
+    assert_eq!(None, inputs[3].planned);
+    let queues = queues_by_disk(inputs);
+    let expected = if cfg!(unix) {
+        // Both files are on the same disk, and the other inputs have no known disk.
+        vec![
+            vec![first, second],
+            vec![PathBuf::from("-"), dir.join("b3sum-ng-missing")],
+        ]
+    } else {
+        vec![vec![
+            first,
+            PathBuf::from("-"),
+            second,
+            dir.join("b3sum-ng-missing"),
+        ]]
+    };
+    assert_eq!(queues, expected);
+}
+
+#[test]
+fn b3_test_tuned_limit() {
+    let limit = LARGE_FILE_SIZE;
+    // 4 GB/s, so the target is 4 MB.
+    let faster = tuned_limit(limit, 4_000_000_000, Duration::from_secs(1));
+    assert_eq!((limit * 3 + 4_000_000) / 4, faster);
+    // 1 MB/s is below the minimum.
+    let slower = tuned_limit(limit, 1_000_000, Duration::from_secs(1));
+    assert_eq!((limit * 3 + MIN_SMALL_FILE_LIMIT) / 4, slower);
+    assert_eq!(limit, tuned_limit(limit, 1_000_000, Duration::from_secs(0)));
+
+    let mut limit = limit;
+    for _ in 0..100 {
+        limit = tuned_limit(limit, u64::MAX / 4, Duration::from_millis(1));
+    }
+    assert!(limit <= MAX_SMALL_FILE_LIMIT);
+}
+
+#[test]
+#[cfg(all(feature = "sys", unix))]
+fn b3_test_raise_open_file_limit() {
+    let limit = raise_open_file_limit().unwrap();
+    assert!(limit > 0);
+    let budget = DESCRIPTOR_BUDGET.load(Ordering::Relaxed);
+    assert!(budget > 0 && budget as u64 <= limit);
+    let _descriptor = Descriptor::reserve();
+    assert!(OPEN_DESCRIPTORS.load(Ordering::Relaxed) > 0);
+}
+
+#[test]
+fn b3_test_open_ahead() -> Result<()> {
+    let (_, path, _guard) = make_temp_file(b"opened ahead");
+    let opened = rayon::scope(|s| open_ahead(path.clone(), s)).recv()?;
+    let checksums = Mutex::new(Vec::new());
+    rayon::scope(|s| {
+        checksum_listed(
+            path.clone(),
+            None,
+            Some(opened),
+            &Config::default(),
+            Arc::new(Semaphore::new(1)),
+            s,
+            |_, hashed| checksums.lock().unwrap().push(hashed.unwrap().checksum),
+        )
+        .unwrap();
+    });
+    let expected = b3sum_small(&mut &b"opened ahead"[..], &Config::default())?;
+    assert_eq!(vec![expected], checksums.into_inner().unwrap());
+    assert!(rayon::scope(|s| open_ahead(PathBuf::from("-"), s))
+        .recv()
+        .is_err());
+    Ok(())
+}
+
+#[test]
+fn b3_test_metrics() -> Result<()> {
+    // The counters are those of this run, so other tests that read files don't change them.
+    let calls = Arc::new(Mutex::new(Vec::new()));
+    let recorded = Arc::clone(&calls);
+    let config = Config::builder()
+        .on_metrics(Some(Arc::new(move |metrics: Metrics| {
+            recorded.lock().unwrap().push(metrics)
+        })))
+        .build();
+    let (_, path, _guard) = make_temp_file(b"hello world");
+    hash_path(&path, &config)?;
+    let queued = Metrics {
+        queued: 1,
+        ..Metrics::default()
+    };
+    let started = Metrics {
+        in_flight: 1,
+        ..Metrics::default()
+    };
+    let finished = Metrics {
+        bytes_hashed: 11,
+        files_completed: 1,
+        ..Metrics::default()
+    };
+    assert_eq!(vec![queued, started, finished], *calls.lock().unwrap());
+    assert_eq!(finished, config.metrics.get());
+    // Another run has its own counters.
+    assert_eq!(Metrics::default(), Config::default().metrics.get());
+    Ok(())
+}
+
+#[test]
+fn b3_test_hash_path() -> Result<()> {
+    let config = Config::default();
+    let (_, path, _guard) = make_temp_file(b"hello world");
+    assert_eq!(
+        "d74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24",
+        hash_path(&path, &config)?.to_string()
+    );
+
+    // Larger than any small file limit, so it's read as a large file.
+    let contents = vec![7; MAX_SMALL_FILE_LIMIT as usize + 1];
+    let (_, path, _guard) = make_temp_file(&contents);
+    assert_eq!(
+        b3sum_small(&mut &contents[..], &config)?,
+        hash_path(&path, &config)?
+    );
+    // Both reads of --paranoid agree on a file that isn't corrupted.
+    let paranoid = Config {
+        paranoid: true,
+        ..config.clone()
+    };
+    assert_eq!(hash_path(&path, &config)?, hash_path(&path, &paranoid)?);
+
+    let err = hash_path(&path.with_extension("missing"), &config).unwrap_err();
+    assert_eq!(
+        Some(std::io::ErrorKind::NotFound),
+        io_error(&*err).map(|err| err.kind())
+    );
+    Ok(())
+}
+
+#[test]
+fn b3_test_mmap_choices() -> Result<()> {
+    let (_file, path, _guard) = make_temp_file(b"12 mmap\n34 buffered\nbad line\n56 neither\n");
+    let mut choices = MmapChoices::load(path.clone())?;
+    assert_eq!(2, choices.disks.len());
+    assert_eq!(Some(&true), choices.disks.get(&12));
+    assert_eq!(Some(&false), choices.disks.get(&34));
+    choices.disks.insert(7, true);
+    choices.save()?;
+    assert_eq!(
+        "7 mmap\n12 mmap\n34 buffered\n",
+        std::fs::read_to_string(&path)?
+    );
+    let missing = MmapChoices::load(path.with_extension("missing"))?;
+    assert!(missing.disks.is_empty());
+    Ok(())
+}
+
+#[test]
+fn b3_test_time_limit() {
+    assert_eq!(Ok(TimeLimit(Duration::from_secs(90))), "90".parse());
+    assert_eq!(Ok(TimeLimit(Duration::from_secs(90))), "90s".parse());
+    assert_eq!(Ok(TimeLimit(Duration::from_secs(30 * 60))), "30m".parse());
+    assert_eq!(
+        Ok(TimeLimit(Duration::from_secs(2 * 60 * 60))),
+        "2h".parse()
+    );
+    assert!("2d".parse::<TimeLimit>().is_err());
+    assert!("h".parse::<TimeLimit>().is_err());
+    assert!("1.5h".parse::<TimeLimit>().is_err());
+}
+
+#[test]
+fn b3_test_serve() -> Result<()> {
+    let config = Config::default();
+    let (_, path, _guard) = make_temp_file(b"hello world");
+    let missing = path.with_extension("missing");
+    let requests = format!(
+        "{}\n{}\n-\n{}",
+        path.display(),
+        missing.display(),
+        path.display()
+    );
+    let mut responses = Vec::new();
+    serve(requests.as_bytes(), &mut responses, &config, b'\n')?;
+    let responses = String::from_utf8(responses)?;
+    let lines: Vec<_> = responses.lines().collect();
+    let checksum = "ok d74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24";
+    assert_eq!(4, lines.len(), "{}", responses);
+    assert_eq!(checksum, lines[0]);
+    assert!(lines[1].starts_with("error "), "{}", lines[1]);
+    assert!(lines[2].starts_with("error "), "{}", lines[2]);
+    assert_eq!(checksum, lines[3]);
+
+    let requests = format!("{}\0", path.display());
+    let mut responses = Vec::new();
+    serve(requests.as_bytes(), &mut responses, &config, 0)?;
+    assert_eq!(format!("{}\0", checksum).into_bytes(), responses);
+    Ok(())
+}
+
+#[test]
+fn b3_test_hash_input() -> Result<()> {
+    use std::io::Cursor;
+
+    let config = Config::default();
+    let input = Input::from_seekable(Cursor::new(b"hello world".to_vec()))?;
+    assert_eq!(Some(11), input.size_hint());
+    assert_eq!(
+        "d74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24",
+        hash_input(input, &config)?.to_string()
+    );
+
+    // Only the rest of a seekable stream is read.
+    let mut cursor = Cursor::new(b"hello world".to_vec());
+    cursor.set_position(6);
+    let input = Input::from_seekable(cursor)?;
+    assert_eq!(Some(5), input.size_hint());
+    assert_eq!(
+        b3sum_small(&mut &b"world"[..], &config)?,
+        hash_input(input, &config)?
+    );
+
+    let contents = vec![7; MAX_SMALL_FILE_LIMIT as usize + 1];
+    let expected = b3sum_small(&mut &contents[..], &config)?;
+    let input = Input::from_reader(Cursor::new(contents), None);
+    assert_eq!(expected, hash_input(input, &config)?);
+    Ok(())
+}
+
+#[test]
+fn b3_test_checksum_paths() {
+    let (_, path, _guard) = make_temp_file(b"hello world");
+    let missing = path.with_extension("missing");
+    let results = Mutex::new(Vec::new());
+    checksum_paths(
+        vec![path.clone(), missing.clone()],
+        &Config::default(),
+        |path, checksum| {
+            results
+                .lock()
+                .unwrap()
+                .push((path.to_owned(), checksum.is_ok()))
+        },
+    );
+    let mut results = results.into_inner().unwrap();
+    results.sort();
+    assert_eq!(vec![(path, true), (missing, false)], results);
+}
+
+#[test]
+fn b3_test_file_details() -> Result<()> {
+    let (_, path, _guard) = make_temp_file(b"hello world");
+    let config = Config {
+        xattrs: true,
+        ..Config::default()
+    };
+    let details = Mutex::new(None);
+    checksum_paths(vec![path.clone()], &config, |path, hashed| {
+        // What's printed is what was read, even if the file has changed by the time it's printed.
+        std::fs::write(path, b"changed").unwrap();
+        let hashed = hashed.unwrap();
+        *details.lock().unwrap() = Some((hashed.metadata, hashed.xattrs));
+    });
+    let (metadata, xattrs) = details.into_inner().unwrap().unwrap();
+    assert_eq!(Some(11), metadata.map(|m| m.len()));
+    #[cfg(all(feature = "sys", any(target_os = "linux", target_os = "android")))]
+    assert!(xattrs.is_some());
+    #[cfg(not(all(feature = "sys", any(target_os = "linux", target_os = "android"))))]
+    let _ = xattrs;
+    Ok(())
+}
+
+#[test]
+fn b3_test_thread_pool() -> Result<()> {
+    let pool = rayon::ThreadPoolBuilder::new().num_threads(2).build()?;
+    let config = Config::default();
+    let contents = vec![7; MAX_SMALL_FILE_LIMIT as usize + 1];
+    let (_, path, _guard) = make_temp_file(&contents);
+    let expected = b3sum_small(&mut &contents[..], &config)?;
+    assert_eq!(expected, hash_path_in(&pool, &path, &config)?);
+
+    let checksums = Mutex::new(Vec::new());
+    checksum_paths_in(&pool, vec![path.clone()], &config, |_, hashed| {
+        checksums.lock().unwrap().push(hashed.unwrap().checksum)
+    });
+    assert_eq!(vec![expected], checksums.into_inner().unwrap());
+    Ok(())
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn b3_test_others_load() {
+    let stat = "cpu  100 5 50 800 20 3 2 0 7 0\ncpu0 50 2 25 400 10 1 1 0 3 0\n";
+    assert_eq!(Some((980, 160)), parse_proc_stat(stat));
+    let own = "4242 (b3sum ng) R 1 2 3 4 5 6 7 8 9 10 30 10 0 0 20 0 8";
+    assert_eq!(Some(40), parse_own_cpu(own));
+    let diskstats = "   8       0 sda 10 0 100 5 20 0 300 7 0 9 12\n\
+                     8       1 sda1 10 0 100 5 20 0 300 7 0 9 12\n";
+    assert_eq!(400 * 512, parse_diskstats(diskstats, |name| name == "sda"));
+    let io = "rchar: 5000\nwchar: 10\nread_bytes: 4096\nwrite_bytes: 512\n";
+    assert_eq!(4608, parse_own_io(io));
+
+    let start = Instant::now();
+    let before = LoadSample {
+        when: Some(start),
+        ..LoadSample::default()
+    };
+    let after = LoadSample {
+        when: Some(start + Duration::from_secs(2)),
+        cpu_total: 1000,
+        cpu_busy: 600,
+        own_cpu: 400,
+        disk_bytes: 10_000_000,
+        own_disk_bytes: 9_000_000,
+    };
+    // This process's own CPU time and I/O aren't counted.
+    assert_eq!((0.2, 500_000.0), others_load(&before, &after));
+    assert_eq!(
+        Some((0.0, 0.0)),
+        LoadSample::take().map(|sample| others_load(&sample, &sample))
+    );
+}
+
+#[test]
+#[cfg(target_os = "linux")]
+fn b3_test_parse_cpu_max() {
+    assert_eq!(Some(2), parse_cpu_max("200000 100000\n"));
+    assert_eq!(Some(2), parse_cpu_max("150000 100000"));
+    assert_eq!(Some(1), parse_cpu_max("50000 100000"));
+    assert_eq!(None, parse_cpu_max("max 100000"));
+    assert_eq!(None, parse_cpu_max("-1 100000"));
+    assert_eq!(None, parse_cpu_max(""));
+}
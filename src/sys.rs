@@ -0,0 +1,1321 @@
+// Copyright 2021 Daniel Zwell.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! The calls into the OS that the standard library doesn't have: file locks, read hints, unbuffered
+//! reads, extents, extended attributes, opening files relative to a directory, limits, priorities
+//! and signals. This is the only module that declares `extern` functions or uses raw file
+//! descriptors, and they're only compiled with the `sys` feature. Without it, each of these
+//! functions does nothing if it's only a hint, and otherwise fails as if the platform didn't
+//! support it.
+
+use std::{fs::File, io, path::Path};
+
+/// The error of a call that isn't supported on this platform, or without the `sys` feature.
+#[allow(dead_code)]
+fn unsupported(what: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::Other,
+        format!("{} isn't supported on this platform", what),
+    )
+}
+
+/// Take an advisory lock on a file, shared or `exclusive`. If another process has a lock that
+/// conflicts, this waits for it with `wait`, and otherwise returns false at once.
+#[cfg(all(feature = "sys", unix))]
+pub(crate) fn lock_file(file: &File, exclusive: bool, wait: bool) -> io::Result<bool> {
+    use std::os::unix::io::AsRawFd;
+
+    extern "C" {
+        fn flock(fd: i32, operation: i32) -> i32;
+    }
+    const LOCK_SH: i32 = 1;
+    const LOCK_EX: i32 = 2;
+    const LOCK_NB: i32 = 4;
+    const EWOULDBLOCK: i32 = if cfg!(any(target_os = "linux", target_os = "android")) {
+        11
+    } else {
+        35
+    };
+
+    let operation = if exclusive { LOCK_EX } else { LOCK_SH } | if wait { 0 } else { LOCK_NB };
+    if unsafe { flock(file.as_raw_fd(), operation) } != 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() == Some(EWOULDBLOCK) {
+            return Ok(false);
+        }
+        return Err(err);
+    }
+    Ok(true)
+}
+
+#[cfg(all(feature = "sys", windows))]
+pub(crate) fn lock_file(file: &File, exclusive: bool, wait: bool) -> io::Result<bool> {
+    use std::os::windows::io::AsRawHandle;
+
+    /// `OVERLAPPED`, which gives the offset of the locked range.
+    #[allow(dead_code)]
+    #[repr(C)]
+    struct Overlapped {
+        internal: usize,
+        internal_high: usize,
+        offset: u32,
+        offset_high: u32,
+        event: *mut std::ffi::c_void,
+    }
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn LockFileEx(
+            file: *mut std::ffi::c_void,
+            flags: u32,
+            reserved: u32,
+            length_low: u32,
+            length_high: u32,
+            overlapped: *mut Overlapped,
+        ) -> i32;
+    }
+    const LOCKFILE_FAIL_IMMEDIATELY: u32 = 0x1;
+    const LOCKFILE_EXCLUSIVE_LOCK: u32 = 0x2;
+    const ERROR_LOCK_VIOLATION: i32 = 33;
+
+    let mut overlapped = Overlapped {
+        internal: 0,
+        internal_high: 0,
+        offset: 0,
+        offset_high: 0,
+        event: std::ptr::null_mut(),
+    };
+    let flags = if exclusive {
+        LOCKFILE_EXCLUSIVE_LOCK
+    } else {
+        0
+    } | if wait { 0 } else { LOCKFILE_FAIL_IMMEDIATELY };
+    let handle = file.as_raw_handle() as *mut std::ffi::c_void;
+    let locked = unsafe { LockFileEx(handle, flags, 0, u32::MAX, u32::MAX, &mut overlapped) };
+    if locked == 0 {
+        let err = io::Error::last_os_error();
+        if err.raw_os_error() == Some(ERROR_LOCK_VIOLATION) {
+            return Ok(false);
+        }
+        return Err(err);
+    }
+    Ok(true)
+}
+
+#[cfg(not(all(feature = "sys", any(unix, windows))))]
+pub(crate) fn lock_file(_file: &File, _exclusive: bool, _wait: bool) -> io::Result<bool> {
+    Err(unsupported("locking files"))
+}
+
+/// A part of a file on disk, as listed by [`fiemap`].
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct FiemapExtent {
+    pub logical: u64,
+    pub physical: u64,
+    pub length: u64,
+    pub flags: u32,
+}
+
+/// Flags of a [`FiemapExtent`], from linux/fiemap.h.
+pub(crate) const FIEMAP_EXTENT_LAST: u32 = 0x1;
+pub(crate) const FIEMAP_EXTENT_SHARED: u32 = 0x2000;
+/// Extents without a known place on disk: unknown, delayed allocation, unaligned, inline and
+/// tail-packed extents.
+pub(crate) const FIEMAP_EXTENT_UNPLACED: u32 = 0x2 | 0x4 | 0x100 | 0x200 | 0x400;
+
+/// List up to 64 of the extents of a file from the logical offset `start`, after its pending
+/// writes are flushed. This is only supported on Linux, with the `FS_IOC_FIEMAP` ioctl.
+#[cfg(all(feature = "sys", target_os = "linux"))]
+pub(crate) fn fiemap(file: &File, start: u64) -> io::Result<Vec<FiemapExtent>> {
+    use std::os::{raw::c_ulong, unix::io::AsRawFd};
+
+    // From linux/fiemap.h. The fields are read by the kernel.
+    #[allow(dead_code)]
+    #[repr(C)]
+    struct Fiemap {
+        start: u64,
+        length: u64,
+        flags: u32,
+        mapped_extents: u32,
+        extent_count: u32,
+        reserved: u32,
+        extents: [Extent; 64],
+    }
+    #[allow(dead_code)]
+    #[repr(C)]
+    #[derive(Clone, Copy)]
+    struct Extent {
+        logical: u64,
+        physical: u64,
+        length: u64,
+        reserved64: [u64; 2],
+        flags: u32,
+        reserved: [u32; 3],
+    }
+    extern "C" {
+        fn ioctl(fd: i32, request: c_ulong, ...) -> i32;
+    }
+    const FS_IOC_FIEMAP: c_ulong = 0xc020_660b;
+    const FIEMAP_FLAG_SYNC: u32 = 0x1;
+
+    let mut map = Fiemap {
+        start,
+        length: u64::MAX - start,
+        flags: FIEMAP_FLAG_SYNC,
+        mapped_extents: 0,
+        extent_count: 64,
+        reserved: 0,
+        extents: [Extent {
+            logical: 0,
+            physical: 0,
+            length: 0,
+            reserved64: [0; 2],
+            flags: 0,
+            reserved: [0; 3],
+        }; 64],
+    };
+    if unsafe { ioctl(file.as_raw_fd(), FS_IOC_FIEMAP, &mut map as *mut Fiemap) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(map.extents[..map.mapped_extents as usize]
+        .iter()
+        .map(|extent| FiemapExtent {
+            logical: extent.logical,
+            physical: extent.physical,
+            length: extent.length,
+            flags: extent.flags,
+        })
+        .collect())
+}
+
+#[cfg(not(all(feature = "sys", target_os = "linux")))]
+pub(crate) fn fiemap(_file: &File, _start: u64) -> io::Result<Vec<FiemapExtent>> {
+    Err(unsupported("listing extents"))
+}
+
+/// If standard output is a file, sync it to disk. It should be flushed first.
+#[cfg(all(feature = "sys", any(unix, windows)))]
+pub(crate) fn sync_stdout() -> io::Result<()> {
+    let stdout = io::stdout();
+    #[cfg(unix)]
+    let file = {
+        use std::os::unix::io::{AsRawFd, FromRawFd};
+        // Safety: the file is never dropped, so standard output isn't closed.
+        std::mem::ManuallyDrop::new(unsafe { File::from_raw_fd(stdout.as_raw_fd()) })
+    };
+    #[cfg(windows)]
+    let file = {
+        use std::os::windows::io::{AsRawHandle, FromRawHandle};
+        // Safety: the file is never dropped, so standard output isn't closed.
+        std::mem::ManuallyDrop::new(unsafe { File::from_raw_handle(stdout.as_raw_handle()) })
+    };
+    if file.metadata()?.is_file() {
+        file.sync_data()?;
+    }
+    Ok(())
+}
+
+/// Without the `sys` feature, standard output is only flushed.
+#[cfg(not(all(feature = "sys", any(unix, windows))))]
+pub(crate) fn sync_stdout() -> io::Result<()> {
+    Ok(())
+}
+
+/// The names of the alternate data streams of an NTFS file, like `:name:$DATA`, including the
+/// unnamed stream `::$DATA`.
+#[cfg(all(feature = "sys", windows))]
+pub(crate) fn stream_names(path: &Path) -> io::Result<Vec<Vec<u16>>> {
+    use std::os::windows::ffi::OsStrExt;
+
+    /// `WIN32_FIND_STREAM_DATA`, which has room for `MAX_PATH + 36` UTF-16 code units.
+    #[repr(C)]
+    struct FindStreamData {
+        stream_size: i64,
+        stream_name: [u16; 260 + 36],
+    }
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn FindFirstStreamW(
+            file_name: *const u16,
+            info_level: u32,
+            data: *mut FindStreamData,
+            flags: u32,
+        ) -> isize;
+        fn FindNextStreamW(find: isize, data: *mut FindStreamData) -> i32;
+        fn FindClose(find: isize) -> i32;
+    }
+    const INVALID_HANDLE_VALUE: isize = -1;
+    // Returned when there are no more streams.
+    const ERROR_HANDLE_EOF: i32 = 38;
+
+    let file_name: Vec<u16> = path.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut data = FindStreamData {
+        stream_size: 0,
+        stream_name: [0; 260 + 36],
+    };
+    let find = unsafe { FindFirstStreamW(file_name.as_ptr(), 0, &mut data, 0) };
+    if find == INVALID_HANDLE_VALUE {
+        let err = io::Error::last_os_error();
+        return match err.raw_os_error() {
+            Some(ERROR_HANDLE_EOF) => Ok(Vec::new()),
+            _ => Err(err),
+        };
+    }
+    let mut names = Vec::new();
+    loop {
+        let len = data.stream_name.iter().position(|&c| c == 0);
+        names.push(data.stream_name[..len.unwrap_or(data.stream_name.len())].to_vec());
+        if unsafe { FindNextStreamW(find, &mut data) } == 0 {
+            let err = io::Error::last_os_error();
+            unsafe { FindClose(find) };
+            return match err.raw_os_error() {
+                Some(ERROR_HANDLE_EOF) => Ok(names),
+                _ => Err(err),
+            };
+        }
+    }
+}
+
+#[cfg(all(not(feature = "sys"), windows))]
+pub(crate) fn stream_names(_path: &Path) -> io::Result<Vec<Vec<u16>>> {
+    Err(unsupported("listing alternate data streams"))
+}
+
+/// The volume serial number and the file index of an open file, which together identify it, the
+/// way a device and an inode do on Unix.
+#[cfg(all(feature = "sys", windows))]
+pub(crate) fn file_index(file: &File) -> io::Result<(u32, u64)> {
+    use std::os::windows::io::AsRawHandle;
+
+    /// `BY_HANDLE_FILE_INFORMATION`, with each `FILETIME` as two `u32`s.
+    #[allow(dead_code)]
+    #[repr(C)]
+    #[derive(Default)]
+    struct FileInformation {
+        attributes: u32,
+        times: [u32; 6],
+        volume_serial_number: u32,
+        size_high: u32,
+        size_low: u32,
+        links: u32,
+        index_high: u32,
+        index_low: u32,
+    }
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn GetFileInformationByHandle(
+            file: *mut std::ffi::c_void,
+            information: *mut FileInformation,
+        ) -> i32;
+    }
+
+    let mut information = FileInformation::default();
+    let handle = file.as_raw_handle() as *mut std::ffi::c_void;
+    if unsafe { GetFileInformationByHandle(handle, &mut information) } == 0 {
+        return Err(io::Error::last_os_error());
+    }
+    let index = u64::from(information.index_high) << 32 | u64::from(information.index_low);
+    Ok((information.volume_serial_number, index))
+}
+
+#[cfg(all(not(feature = "sys"), windows))]
+pub(crate) fn file_index(_file: &File) -> io::Result<(u32, u64)> {
+    Err(unsupported("identifying files"))
+}
+
+/// Whether [`list_xattrs`] and [`get_xattr`] can read extended attributes. The attributes are
+/// read from an open file, so that they're those of the file whose contents are read.
+pub(crate) const XATTRS_SUPPORTED: bool = cfg!(all(
+    feature = "sys",
+    any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos"
+    )
+));
+
+#[cfg(all(
+    feature = "sys",
+    any(target_os = "linux", target_os = "android", target_os = "macos")
+))]
+mod xattr {
+    use std::{
+        ffi::CString,
+        fs::File,
+        io,
+        os::{
+            raw::{c_char, c_int, c_void},
+            unix::io::AsRawFd,
+        },
+    };
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    extern "C" {
+        fn flistxattr(fd: c_int, list: *mut c_char, size: usize) -> isize;
+        fn fgetxattr(fd: c_int, name: *const c_char, value: *mut c_void, size: usize) -> isize;
+    }
+    #[cfg(target_os = "macos")]
+    extern "C" {
+        fn flistxattr(fd: c_int, list: *mut c_char, size: usize, options: i32) -> isize;
+        fn fgetxattr(
+            fd: c_int,
+            name: *const c_char,
+            value: *mut c_void,
+            size: usize,
+            position: u32,
+            options: i32,
+        ) -> isize;
+    }
+    // The same calls on both, since macOS has more arguments: an offset into resource forks, and
+    // options.
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    unsafe fn list(fd: c_int, buf: *mut u8, size: usize) -> isize {
+        flistxattr(fd, buf as *mut c_char, size)
+    }
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    unsafe fn get(fd: c_int, name: *const c_char, buf: *mut u8, size: usize) -> isize {
+        fgetxattr(fd, name, buf as *mut c_void, size)
+    }
+    #[cfg(target_os = "macos")]
+    unsafe fn list(fd: c_int, buf: *mut u8, size: usize) -> isize {
+        flistxattr(fd, buf as *mut c_char, size, 0)
+    }
+    #[cfg(target_os = "macos")]
+    unsafe fn get(fd: c_int, name: *const c_char, buf: *mut u8, size: usize) -> isize {
+        fgetxattr(fd, name, buf as *mut c_void, size, 0, 0)
+    }
+    const ERANGE: i32 = 34;
+
+    /// Read a list or a value whose size is asked for first, again if it grew in between.
+    fn read_sized(read: &dyn Fn(*mut u8, usize) -> isize) -> io::Result<Vec<u8>> {
+        loop {
+            let size = read(std::ptr::null_mut(), 0);
+            if size < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            let mut buf = vec![0u8; size as usize];
+            let read_size = read(buf.as_mut_ptr(), buf.len());
+            if read_size >= 0 {
+                buf.truncate(read_size as usize);
+                return Ok(buf);
+            }
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(ERANGE) {
+                return Err(err);
+            }
+        }
+    }
+
+    fn c_string(bytes: &[u8]) -> io::Result<CString> {
+        CString::new(bytes).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))
+    }
+
+    pub(crate) fn list_xattrs(file: &File) -> io::Result<Vec<u8>> {
+        let fd = file.as_raw_fd();
+        // Safety: the descriptor is open while `file` is, and the buffer has the size given.
+        read_sized(&|buf, size| unsafe { list(fd, buf, size) })
+    }
+
+    pub(crate) fn get_xattr(file: &File, name: &[u8]) -> io::Result<Vec<u8>> {
+        let fd = file.as_raw_fd();
+        let c_name = c_string(name)?;
+        // Safety: the name is NUL-terminated, and the buffer has the size that's given.
+        read_sized(&|buf, size| unsafe { get(fd, c_name.as_ptr(), buf, size) })
+    }
+}
+
+/// The names of the extended attributes of a file, each followed by a NUL byte. The error is
+/// `ENOTSUP` if its filesystem has none. On Linux, the `trusted.*` attributes are only listed for
+/// root.
+#[cfg(all(
+    feature = "sys",
+    any(target_os = "linux", target_os = "android", target_os = "macos")
+))]
+pub(crate) use xattr::{get_xattr, list_xattrs};
+
+#[cfg(not(all(
+    feature = "sys",
+    any(target_os = "linux", target_os = "android", target_os = "macos")
+)))]
+pub(crate) fn list_xattrs(_file: &File) -> io::Result<Vec<u8>> {
+    Err(unsupported("reading extended attributes"))
+}
+
+#[cfg(not(all(
+    feature = "sys",
+    any(target_os = "linux", target_os = "android", target_os = "macos")
+)))]
+pub(crate) fn get_xattr(_file: &File, _name: &[u8]) -> io::Result<Vec<u8>> {
+    Err(unsupported("reading extended attributes"))
+}
+
+/// The `ENOTSUP` and `ENODATA` errors of [`list_xattrs`] and [`get_xattr`], for a filesystem
+/// without extended attributes and for an attribute that isn't there.
+pub(crate) const XATTR_ERRORS: (i32, i32) = if cfg!(target_os = "macos") {
+    (45, 93)
+} else {
+    (95, 61)
+};
+
+/// Whether [`open_nofollow`] can open the entries of a directory relative to it. The flags of
+/// `openat` differ between architectures on Linux, so it's only used on the common ones.
+pub(crate) const OPEN_AT_SUPPORTED: bool = cfg!(all(
+    feature = "sys",
+    any(
+        all(
+            any(target_os = "linux", target_os = "android"),
+            any(
+                target_arch = "x86",
+                target_arch = "x86_64",
+                target_arch = "arm",
+                target_arch = "aarch64",
+                target_arch = "riscv64"
+            )
+        ),
+        target_os = "macos"
+    )
+));
+
+/// Open `name` for reading, relative to the directory `dir`, or to the working directory without
+/// one, the way that a tree that others can write to is searched: if `name` is a symbolic link,
+/// it isn't followed, and the error is one that [`is_symlink_error`] matches. A FIFO is opened
+/// without waiting for a writer, and a terminal doesn't become the controlling terminal. With
+/// `directory`, anything but a directory fails without being opened, and otherwise the caller
+/// checks what was opened by its metadata before reading it.
+///
+/// Only the last component of `name` isn't followed, so a tree is opened a name at a time, each
+/// relative to the directory before it.
+#[cfg(all(
+    feature = "sys",
+    any(
+        all(
+            any(target_os = "linux", target_os = "android"),
+            any(
+                target_arch = "x86",
+                target_arch = "x86_64",
+                target_arch = "arm",
+                target_arch = "aarch64",
+                target_arch = "riscv64"
+            )
+        ),
+        target_os = "macos"
+    )
+))]
+pub(crate) fn open_nofollow(
+    dir: Option<&File>,
+    name: &std::ffi::OsStr,
+    directory: bool,
+) -> io::Result<File> {
+    use std::{
+        ffi::CString,
+        os::{
+            raw::c_char,
+            unix::{
+                ffi::OsStrExt,
+                io::{AsRawFd, FromRawFd},
+            },
+        },
+    };
+
+    extern "C" {
+        fn openat(dirfd: i32, path: *const c_char, flags: i32, ...) -> i32;
+    }
+    // From fcntl.h. On Linux, Arm has O_DIRECTORY and O_NOFOLLOW where the other architectures
+    // have O_DIRECTORY and O_NOFOLLOW shifted left by one.
+    const MACOS: bool = cfg!(target_os = "macos");
+    const ARM: bool = cfg!(any(target_arch = "arm", target_arch = "aarch64"));
+    const O_NONBLOCK: i32 = if MACOS { 0x4 } else { 0o4000 };
+    const O_NOCTTY: i32 = if MACOS { 0x20000 } else { 0o400 };
+    const O_CLOEXEC: i32 = if MACOS { 0x100_0000 } else { 0o2000000 };
+    const O_NOFOLLOW: i32 = if MACOS {
+        0x100
+    } else if ARM {
+        0o100000
+    } else {
+        0o400000
+    };
+    const O_DIRECTORY: i32 = if MACOS {
+        0x10_0000
+    } else if ARM {
+        0o40000
+    } else {
+        0o200000
+    };
+    // Without it, 32-bit Linux fails to open files over 2 GiB. It's implied on 64-bit.
+    const O_LARGEFILE: i32 = if cfg!(all(target_os = "linux", target_arch = "x86")) {
+        0o100000
+    } else if cfg!(all(target_os = "linux", target_arch = "arm")) {
+        0o400000
+    } else {
+        0
+    };
+    const AT_FDCWD: i32 = if MACOS { -2 } else { -100 };
+
+    let c_name = CString::new(name.as_bytes())
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    let dirfd = dir.map_or(AT_FDCWD, |dir| dir.as_raw_fd());
+    let flags = O_NOFOLLOW
+        | O_NONBLOCK
+        | O_NOCTTY
+        | O_CLOEXEC
+        | O_LARGEFILE
+        | if directory { O_DIRECTORY } else { 0 };
+    // Safety: the name is NUL-terminated, and `dir` stays open during the call.
+    let fd = unsafe { openat(dirfd, c_name.as_ptr(), flags) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // Safety: the descriptor was just opened, and nothing else owns it.
+    Ok(unsafe { File::from_raw_fd(fd) })
+}
+
+#[cfg(not(all(
+    feature = "sys",
+    any(
+        all(
+            any(target_os = "linux", target_os = "android"),
+            any(
+                target_arch = "x86",
+                target_arch = "x86_64",
+                target_arch = "arm",
+                target_arch = "aarch64",
+                target_arch = "riscv64"
+            )
+        ),
+        target_os = "macos"
+    )
+)))]
+pub(crate) fn open_nofollow(
+    _dir: Option<&File>,
+    _name: &std::ffi::OsStr,
+    _directory: bool,
+) -> io::Result<File> {
+    Err(unsupported("opening files relative to a directory"))
+}
+
+/// The names of the entries of the directory `dir`, which was opened by [`open_nofollow`], without
+/// `.` and `..`. They're read from the open directory, so that they're those of the directory that
+/// was opened, even if its path now leads somewhere else.
+#[cfg(all(
+    feature = "sys",
+    any(
+        all(
+            any(target_os = "linux", target_os = "android"),
+            any(
+                target_arch = "x86",
+                target_arch = "x86_64",
+                target_arch = "arm",
+                target_arch = "aarch64",
+                target_arch = "riscv64"
+            )
+        ),
+        target_os = "macos"
+    )
+))]
+pub(crate) fn list_dir(dir: &File) -> io::Result<Vec<std::ffi::OsString>> {
+    use std::{
+        ffi::{c_void, CStr, OsStr},
+        os::{
+            raw::c_char,
+            unix::{
+                ffi::OsStrExt,
+                io::{FromRawFd, IntoRawFd},
+            },
+        },
+    };
+
+    extern "C" {
+        #[cfg_attr(
+            all(target_os = "macos", target_arch = "x86_64"),
+            link_name = "fdopendir$INODE64"
+        )]
+        fn fdopendir(fd: i32) -> *mut c_void;
+        // The `dirent` of `readdir64` on 32-bit glibc has the same layout as `readdir`'s elsewhere.
+        #[cfg_attr(
+            all(target_os = "linux", target_env = "gnu", target_pointer_width = "32"),
+            link_name = "readdir64"
+        )]
+        #[cfg_attr(
+            all(target_os = "macos", target_arch = "x86_64"),
+            link_name = "readdir$INODE64"
+        )]
+        fn readdir(dir: *mut c_void) -> *const u8;
+        fn closedir(dir: *mut c_void) -> i32;
+        #[cfg_attr(target_os = "linux", link_name = "__errno_location")]
+        #[cfg_attr(target_os = "android", link_name = "__errno")]
+        #[cfg_attr(target_os = "macos", link_name = "__error")]
+        fn errno_location() -> *mut i32;
+    }
+    // Where `d_name` is in `struct dirent`: after a 64-bit inode and offset, a 16-bit record
+    // length, on macOS a 16-bit name length, and an 8-bit type.
+    const NAME_OFFSET: usize = if cfg!(target_os = "macos") { 21 } else { 19 };
+
+    // The directory stream owns the descriptor it's given, and closes it, so it's given a copy.
+    let fd = dir.try_clone()?.into_raw_fd();
+    // Safety: the descriptor is open, and the stream takes it over.
+    let stream = unsafe { fdopendir(fd) };
+    if stream.is_null() {
+        let err = io::Error::last_os_error();
+        drop(unsafe { File::from_raw_fd(fd) });
+        return Err(err);
+    }
+    let mut names = Vec::new();
+    let result = loop {
+        // Safety: errno is thread-local, and the stream is open until it's closed below.
+        let entry = unsafe {
+            *errno_location() = 0;
+            readdir(stream)
+        };
+        if entry.is_null() {
+            let err = io::Error::last_os_error();
+            break match err.raw_os_error() {
+                Some(0) => Ok(names),
+                _ => Err(err),
+            };
+        }
+        // Safety: `d_name` is a NUL-terminated name in the entry, which is valid until the next
+        // call on the stream.
+        let name = unsafe { CStr::from_ptr(entry.add(NAME_OFFSET) as *const c_char) }.to_bytes();
+        if name != b"." && name != b".." {
+            names.push(OsStr::from_bytes(name).to_owned());
+        }
+    };
+    unsafe { closedir(stream) };
+    result
+}
+
+#[cfg(not(all(
+    feature = "sys",
+    any(
+        all(
+            any(target_os = "linux", target_os = "android"),
+            any(
+                target_arch = "x86",
+                target_arch = "x86_64",
+                target_arch = "arm",
+                target_arch = "aarch64",
+                target_arch = "riscv64"
+            )
+        ),
+        target_os = "macos"
+    )
+)))]
+pub(crate) fn list_dir(_dir: &File) -> io::Result<Vec<std::ffi::OsString>> {
+    Err(unsupported("listing an open directory"))
+}
+
+/// Whether `err` is the error of [`open_nofollow`] for a symbolic link, `ELOOP`.
+pub(crate) fn is_symlink_error(err: &io::Error) -> bool {
+    const ELOOP: i32 = if cfg!(target_os = "macos") { 62 } else { 40 };
+    err.raw_os_error() == Some(ELOOP)
+}
+
+/// Set an extended attribute of a file, for tests.
+#[cfg(all(test, feature = "sys", any(target_os = "linux", target_os = "android")))]
+pub(crate) fn set_xattr(path: &Path, name: &str, value: &[u8]) -> io::Result<()> {
+    use std::{
+        ffi::CString,
+        os::{
+            raw::{c_char, c_void},
+            unix::ffi::OsStrExt,
+        },
+    };
+    extern "C" {
+        fn setxattr(
+            path: *const c_char,
+            name: *const c_char,
+            value: *const c_void,
+            size: usize,
+            flags: i32,
+        ) -> i32;
+    }
+
+    let c_path = CString::new(path.as_os_str().as_bytes())?;
+    let name = CString::new(name)?;
+    // Safety: the strings are NUL-terminated, and the value has the size that's given.
+    let set = unsafe {
+        setxattr(
+            c_path.as_ptr(),
+            name.as_ptr(),
+            value.as_ptr() as *const c_void,
+            value.len(),
+            0,
+        )
+    };
+    if set != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// The extended ACL of a file in its text form, or `None` if it has none. This is only read on
+/// macOS, where ACLs aren't extended attributes.
+#[cfg(all(feature = "sys", target_os = "macos"))]
+pub(crate) fn acl_text(file: &File) -> io::Result<Option<Vec<u8>>> {
+    use std::{
+        ffi::CStr,
+        os::{
+            raw::{c_char, c_int, c_void},
+            unix::io::AsRawFd,
+        },
+    };
+    extern "C" {
+        fn acl_get_fd_np(fd: c_int, kind: i32) -> *mut c_void;
+        fn acl_to_text(acl: *mut c_void, len: *mut isize) -> *mut c_char;
+        fn acl_free(object: *mut c_void) -> i32;
+    }
+    const ACL_TYPE_EXTENDED: i32 = 0x100;
+
+    let mut text_bytes = None;
+    // Safety: the descriptor is open while `file` is, and what's allocated is freed with
+    // `acl_free`.
+    unsafe {
+        let acl = acl_get_fd_np(file.as_raw_fd(), ACL_TYPE_EXTENDED);
+        // A file without an ACL has none, rather than an error.
+        if !acl.is_null() {
+            let text = acl_to_text(acl, std::ptr::null_mut());
+            if !text.is_null() {
+                text_bytes = Some(CStr::from_ptr(text).to_bytes().to_vec());
+                acl_free(text as *mut c_void);
+            }
+            acl_free(acl);
+        }
+    }
+    Ok(text_bytes)
+}
+
+#[cfg(not(all(feature = "sys", target_os = "macos")))]
+pub(crate) fn acl_text(_file: &File) -> io::Result<Option<Vec<u8>>> {
+    Ok(None)
+}
+
+/// Tell the OS not to cache what's read from a file. This is only done on macOS, where the
+/// cache can be turned off for a file, and is only a hint, so errors are ignored.
+#[cfg(all(feature = "sys", target_os = "macos"))]
+pub(crate) fn no_cache(file: &File) {
+    use std::os::unix::io::AsRawFd;
+
+    extern "C" {
+        fn fcntl(fd: i32, cmd: i32, ...) -> i32;
+    }
+    const F_NOCACHE: i32 = 48;
+    unsafe { fcntl(file.as_raw_fd(), F_NOCACHE, 1) };
+}
+
+#[cfg(not(all(feature = "sys", target_os = "macos")))]
+pub(crate) fn no_cache(_file: &File) {}
+
+/// Ask the OS to read part of a file into the cache, without waiting for it. This is only a hint,
+/// so errors are ignored.
+#[cfg(all(feature = "sys", any(target_os = "linux", target_os = "android")))]
+pub(crate) fn advise_will_need(file: &File, offset: u64, len: u64) {
+    const POSIX_FADV_WILLNEED: i32 = 3;
+    fadvise(file, offset, len, POSIX_FADV_WILLNEED);
+}
+
+#[cfg(all(feature = "sys", target_os = "macos"))]
+pub(crate) fn advise_will_need(file: &File, offset: u64, len: u64) {
+    use std::os::unix::io::AsRawFd;
+
+    /// `struct radvisory` from fcntl.h.
+    #[repr(C)]
+    struct ReadAdvisory {
+        offset: i64,
+        count: i32,
+    }
+    extern "C" {
+        fn fcntl(fd: i32, cmd: i32, ...) -> i32;
+    }
+    const F_RDADVISE: i32 = 44;
+
+    let advisory = ReadAdvisory {
+        offset: offset.min(i64::MAX as u64) as i64,
+        count: len.min(i32::MAX as u64) as i32,
+    };
+    unsafe {
+        fcntl(
+            file.as_raw_fd(),
+            F_RDADVISE,
+            &advisory as *const ReadAdvisory,
+        )
+    };
+}
+
+#[cfg(not(all(
+    feature = "sys",
+    any(target_os = "linux", target_os = "android", target_os = "macos")
+)))]
+pub(crate) fn advise_will_need(_file: &File, _offset: u64, _len: u64) {}
+
+/// Ask the OS to drop the cached pages of a file, so that the next read is from the disk. Pages
+/// that haven't been written yet are kept. This is only done on Linux, so elsewhere the next read
+/// may be from the cache.
+#[cfg(all(feature = "sys", any(target_os = "linux", target_os = "android")))]
+pub(crate) fn drop_cached(file: &File) {
+    const POSIX_FADV_DONTNEED: i32 = 4;
+    fadvise(file, 0, 0, POSIX_FADV_DONTNEED);
+}
+
+#[cfg(not(all(feature = "sys", any(target_os = "linux", target_os = "android"))))]
+pub(crate) fn drop_cached(_file: &File) {}
+
+/// Give `posix_fadvise` advice about part of a file. A length of 0 is to the end of the file.
+/// This is only a hint, so errors are ignored.
+#[cfg(all(feature = "sys", any(target_os = "linux", target_os = "android")))]
+fn fadvise(file: &File, offset: u64, len: u64, advice: i32) {
+    use std::os::unix::io::AsRawFd;
+
+    extern "C" {
+        fn posix_fadvise(fd: i32, offset: i64, len: i64, advice: i32) -> i32;
+    }
+    let clamp = |n: u64| n.min(i64::MAX as u64) as i64;
+    unsafe { posix_fadvise(file.as_raw_fd(), clamp(offset), clamp(len), advice) };
+}
+
+/// Whether [`read_unbuffered`] can read files.
+pub(crate) const UNBUFFERED_READS_SUPPORTED: bool = cfg!(all(feature = "sys", windows));
+
+/// Read a whole file from the start with unbuffered, overlapped reads on a second handle to it,
+/// and pass each block that was read to `on_block`, in order. Several large reads are in flight
+/// while a block is passed on, and the data isn't copied through the cache, which is much faster
+/// for large files on NTFS. Unbuffered reads need sector-aligned buffers, offsets and lengths, so
+/// the buffers are aligned to 4 KiB, which is a multiple of the sector size of common disks.
+#[cfg(all(feature = "sys", windows))]
+pub(crate) fn read_unbuffered(file: &File, on_block: &mut dyn FnMut(&[u8])) -> io::Result<()> {
+    use std::os::windows::io::{AsRawHandle, FromRawHandle};
+
+    type Handle = *mut std::ffi::c_void;
+    /// `OVERLAPPED`, which gives the offset of a read and the event set when it's done.
+    #[allow(dead_code)]
+    #[repr(C)]
+    struct Overlapped {
+        internal: usize,
+        internal_high: usize,
+        offset: u32,
+        offset_high: u32,
+        event: Handle,
+    }
+    #[link(name = "kernel32")]
+    extern "system" {
+        fn ReOpenFile(original: Handle, access: u32, share_mode: u32, flags: u32) -> Handle;
+        fn CreateEventW(
+            attributes: *mut std::ffi::c_void,
+            manual_reset: i32,
+            initial_state: i32,
+            name: *const u16,
+        ) -> Handle;
+        fn CloseHandle(handle: Handle) -> i32;
+        fn ReadFile(
+            file: Handle,
+            buffer: *mut u8,
+            length: u32,
+            read: *mut u32,
+            overlapped: *mut Overlapped,
+        ) -> i32;
+        fn GetOverlappedResult(
+            file: Handle,
+            overlapped: *mut Overlapped,
+            transferred: *mut u32,
+            wait: i32,
+        ) -> i32;
+        fn CancelIoEx(file: Handle, overlapped: *mut Overlapped) -> i32;
+    }
+    const GENERIC_READ: u32 = 0x8000_0000;
+    const FILE_SHARE_READ_WRITE_DELETE: u32 = 0x1 | 0x2 | 0x4;
+    const FILE_FLAG_SEQUENTIAL_SCAN: u32 = 0x0800_0000;
+    const FILE_FLAG_NO_BUFFERING: u32 = 0x2000_0000;
+    const FILE_FLAG_OVERLAPPED: u32 = 0x4000_0000;
+    const INVALID_HANDLE_VALUE: Handle = -1isize as Handle;
+    const ERROR_HANDLE_EOF: i32 = 38;
+    const ERROR_IO_PENDING: i32 = 997;
+    const ALIGNMENT: usize = 4096;
+    const BLOCK_SIZE: usize = 8 * (1 << 20);
+    const READS_IN_FLIGHT: usize = 4;
+
+    /// A read into an aligned block of `buffer`, which the OS may write to while it's `pending`.
+    struct BlockRead {
+        overlapped: Box<Overlapped>,
+        buffer: Vec<u8>,
+        pending: bool,
+    }
+    /// The reads, which are cancelled and waited for when dropped, since their buffers can't be
+    /// freed while the OS writes to them.
+    struct Reads {
+        handle: Handle,
+        reads: Vec<BlockRead>,
+    }
+    impl Drop for Reads {
+        fn drop(&mut self) {
+            for read in &mut self.reads {
+                if read.pending {
+                    let mut transferred = 0;
+                    unsafe {
+                        CancelIoEx(self.handle, &mut *read.overlapped);
+                        GetOverlappedResult(
+                            self.handle,
+                            &mut *read.overlapped,
+                            &mut transferred,
+                            1,
+                        );
+                    }
+                }
+                unsafe { CloseHandle(read.overlapped.event) };
+            }
+        }
+    }
+    fn block(buffer: &mut [u8]) -> &mut [u8] {
+        let start = buffer.as_ptr().align_offset(ALIGNMENT);
+        &mut buffer[start..start + BLOCK_SIZE]
+    }
+    fn start(handle: Handle, read: &mut BlockRead, offset: u64) -> io::Result<()> {
+        read.overlapped.internal = 0;
+        read.overlapped.internal_high = 0;
+        read.overlapped.offset = offset as u32;
+        read.overlapped.offset_high = (offset >> 32) as u32;
+        let block = block(&mut read.buffer);
+        let done = unsafe {
+            ReadFile(
+                handle,
+                block.as_mut_ptr(),
+                BLOCK_SIZE as u32,
+                std::ptr::null_mut(),
+                &mut *read.overlapped,
+            )
+        };
+        if done == 0 {
+            let err = io::Error::last_os_error();
+            match err.raw_os_error() {
+                Some(ERROR_IO_PENDING) => {}
+                Some(ERROR_HANDLE_EOF) => return Ok(()),
+                _ => return Err(err),
+            }
+        }
+        read.pending = true;
+        Ok(())
+    }
+
+    let flags = FILE_FLAG_SEQUENTIAL_SCAN | FILE_FLAG_NO_BUFFERING | FILE_FLAG_OVERLAPPED;
+    let original = file.as_raw_handle() as Handle;
+    let handle = unsafe { ReOpenFile(original, GENERIC_READ, FILE_SHARE_READ_WRITE_DELETE, flags) };
+    if handle == INVALID_HANDLE_VALUE {
+        return Err(io::Error::last_os_error());
+    }
+    // Declared before the reads, so that it's closed after they're dropped.
+    let _unbuffered = unsafe { File::from_raw_handle(handle as _) };
+    let mut reads = Reads {
+        handle,
+        reads: Vec::with_capacity(READS_IN_FLIGHT),
+    };
+    for _ in 0..READS_IN_FLIGHT {
+        let event = unsafe { CreateEventW(std::ptr::null_mut(), 1, 0, std::ptr::null()) };
+        if event.is_null() {
+            return Err(io::Error::last_os_error());
+        }
+        reads.reads.push(BlockRead {
+            overlapped: Box::new(Overlapped {
+                internal: 0,
+                internal_high: 0,
+                offset: 0,
+                offset_high: 0,
+                event,
+            }),
+            buffer: vec![0; BLOCK_SIZE + ALIGNMENT],
+            pending: false,
+        });
+    }
+    let mut offset = 0u64;
+    for read in &mut reads.reads {
+        start(handle, read, offset)?;
+        offset += BLOCK_SIZE as u64;
+    }
+    // The reads finish in the order they were started, so the blocks are passed on in order. A
+    // short block is the end of the file, and nothing after it is passed on, even if the file
+    // grew while the reads after it were in flight.
+    let mut ended = false;
+    for next in (0..READS_IN_FLIGHT).cycle() {
+        let read = &mut reads.reads[next];
+        if !read.pending {
+            break;
+        }
+        let mut transferred = 0;
+        let done =
+            unsafe { GetOverlappedResult(handle, &mut *read.overlapped, &mut transferred, 1) };
+        read.pending = false;
+        if done == 0 {
+            let err = io::Error::last_os_error();
+            if err.raw_os_error() != Some(ERROR_HANDLE_EOF) {
+                return Err(err);
+            }
+            transferred = 0;
+        }
+        if !ended {
+            on_block(&block(&mut read.buffer)[..transferred as usize]);
+            ended = (transferred as usize) < BLOCK_SIZE;
+        }
+        if !ended {
+            start(handle, read, offset)?;
+            offset += BLOCK_SIZE as u64;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(all(feature = "sys", windows)))]
+pub(crate) fn read_unbuffered(_file: &File, _on_block: &mut dyn FnMut(&[u8])) -> io::Result<()> {
+    Err(unsupported("unbuffered reads"))
+}
+
+/// Raise the soft limit on open files as far as the hard limit allows. Returns the new soft limit.
+#[cfg(all(feature = "sys", unix))]
+pub(crate) fn raise_open_file_limit() -> io::Result<u64> {
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    type RlimT = std::os::raw::c_ulong;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    type RlimT = u64;
+    #[repr(C)]
+    struct Rlimit {
+        current: RlimT,
+        max: RlimT,
+    }
+    extern "C" {
+        fn getrlimit(resource: i32, rlim: *mut Rlimit) -> i32;
+        fn setrlimit(resource: i32, rlim: *const Rlimit) -> i32;
+    }
+    const RLIMIT_NOFILE: i32 = if cfg!(any(target_os = "linux", target_os = "android")) {
+        7
+    } else {
+        8
+    };
+
+    let mut limit = Rlimit { current: 0, max: 0 };
+    if unsafe { getrlimit(RLIMIT_NOFILE, &mut limit) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // macOS rejects soft limits above OPEN_MAX, even when the hard limit is unlimited.
+    #[cfg(target_os = "macos")]
+    let max = limit.max.min(10240);
+    #[cfg(not(target_os = "macos"))]
+    let max = limit.max;
+    let raised = Rlimit {
+        current: max,
+        max: limit.max,
+    };
+    if raised.current > limit.current && unsafe { setrlimit(RLIMIT_NOFILE, &raised) } == 0 {
+        limit.current = raised.current;
+    }
+    // rlim_t is narrower on 32-bit Linux.
+    #[allow(clippy::unnecessary_cast)]
+    Ok(limit.current as u64)
+}
+
+#[cfg(not(all(feature = "sys", unix)))]
+pub(crate) fn raise_open_file_limit() -> io::Result<u64> {
+    Err(unsupported("limiting open files"))
+}
+
+/// How many CPUs this process may run on. This is only supported on Linux, where it's from
+/// `sched_getaffinity`, with room for 1024 CPUs like `cpu_set_t`.
+#[cfg(all(feature = "sys", target_os = "linux"))]
+pub(crate) fn affinity_cpus() -> Option<usize> {
+    extern "C" {
+        fn sched_getaffinity(pid: i32, size: usize, mask: *mut u64) -> i32;
+    }
+    let mut mask = [0u64; 16];
+    if unsafe { sched_getaffinity(0, std::mem::size_of_val(&mask), mask.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    Some(
+        mask.iter()
+            .map(|word| word.count_ones() as usize)
+            .sum::<usize>(),
+    )
+}
+
+#[cfg(not(all(feature = "sys", target_os = "linux")))]
+pub(crate) fn affinity_cpus() -> Option<usize> {
+    None
+}
+
+/// Lower the CPU and I/O priority of this process as far as it can go. It's nice 19 and the idle
+/// I/O class on Linux, nice 19 with throttled I/O on macOS, and the background mode on Windows.
+#[cfg(feature = "sys")]
+pub(crate) fn lower_priority() -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        extern "C" {
+            fn setpriority(which: i32, who: u32, priority: i32) -> i32;
+        }
+        const PRIO_PROCESS: i32 = 0;
+        if unsafe { setpriority(PRIO_PROCESS, 0, 19) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    #[cfg(all(
+        target_os = "linux",
+        any(
+            target_arch = "x86_64",
+            target_arch = "x86",
+            target_arch = "aarch64",
+            target_arch = "arm",
+            target_arch = "riscv64"
+        )
+    ))]
+    {
+        use std::os::raw::c_long;
+
+        extern "C" {
+            fn syscall(number: c_long, ...) -> c_long;
+        }
+        #[cfg(target_arch = "x86_64")]
+        const SYS_IOPRIO_SET: c_long = 251;
+        #[cfg(target_arch = "x86")]
+        const SYS_IOPRIO_SET: c_long = 289;
+        #[cfg(any(target_arch = "aarch64", target_arch = "riscv64"))]
+        const SYS_IOPRIO_SET: c_long = 30;
+        #[cfg(target_arch = "arm")]
+        const SYS_IOPRIO_SET: c_long = 314;
+        const IOPRIO_WHO_PROCESS: i32 = 1;
+        const IOPRIO_CLASS_IDLE: i32 = 3;
+        const IOPRIO_CLASS_SHIFT: i32 = 13;
+        let priority = IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT;
+        if unsafe { syscall(SYS_IOPRIO_SET, IOPRIO_WHO_PROCESS, 0, priority) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    #[cfg(target_os = "macos")]
+    {
+        extern "C" {
+            fn setiopolicy_np(iotype: i32, scope: i32, policy: i32) -> i32;
+        }
+        const IOPOL_TYPE_DISK: i32 = 0;
+        const IOPOL_SCOPE_PROCESS: i32 = 0;
+        const IOPOL_THROTTLE: i32 = 3;
+        if unsafe { setiopolicy_np(IOPOL_TYPE_DISK, IOPOL_SCOPE_PROCESS, IOPOL_THROTTLE) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    #[cfg(windows)]
+    {
+        #[link(name = "kernel32")]
+        extern "system" {
+            fn GetCurrentProcess() -> *mut std::ffi::c_void;
+            fn SetPriorityClass(process: *mut std::ffi::c_void, class: u32) -> i32;
+        }
+        const PROCESS_MODE_BACKGROUND_BEGIN: u32 = 0x0010_0000;
+        if unsafe { SetPriorityClass(GetCurrentProcess(), PROCESS_MODE_BACKGROUND_BEGIN) } == 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(feature = "sys"))]
+pub(crate) fn lower_priority() -> io::Result<()> {
+    Err(unsupported("lowering the priority"))
+}
+
+/// The type of the filesystem that a file is on, as a magic number from linux/magic.h. This is
+/// only supported on Linux.
+#[cfg(all(feature = "sys", target_os = "linux"))]
+pub(crate) fn filesystem_magic(path: &Path) -> Option<u32> {
+    use std::os::{raw::c_long, unix::ffi::OsStrExt};
+
+    extern "C" {
+        fn statfs(path: *const std::os::raw::c_char, buf: *mut c_long) -> i32;
+    }
+
+    let path = std::ffi::CString::new(path.as_os_str().as_bytes()).ok()?;
+    // `struct statfs` starts with `f_type`, and is smaller than this on every architecture.
+    let mut buf = [0 as c_long; 32];
+    if unsafe { statfs(path.as_ptr(), buf.as_mut_ptr()) } != 0 {
+        return None;
+    }
+    Some(buf[0] as u32)
+}
+
+#[cfg(not(all(feature = "sys", target_os = "linux")))]
+pub(crate) fn filesystem_magic(_path: &Path) -> Option<u32> {
+    None
+}
+
+/// The write end of the pipe that [`catch_termination`] makes, or -1 before it's called.
+#[cfg(all(feature = "sys", unix))]
+static TERMINATION_PIPE: std::sync::atomic::AtomicI32 = std::sync::atomic::AtomicI32::new(-1);
+
+/// Catch SIGINT and SIGTERM. The handler only writes the number of the signal to a pipe, as a
+/// byte, and the read end of the pipe is returned, so that a thread can wait for a signal and
+/// clean up before it calls [`terminate`]. This is only supported on Unix.
+#[cfg(all(feature = "sys", unix))]
+pub(crate) fn catch_termination() -> io::Result<File> {
+    use std::{os::unix::io::FromRawFd, sync::atomic::Ordering};
+
+    extern "C" {
+        fn pipe(fds: *mut i32) -> i32;
+        fn fcntl(fd: i32, cmd: i32, ...) -> i32;
+        fn signal(signum: i32, handler: usize) -> usize;
+    }
+    extern "C" fn on_signal(signum: i32) {
+        extern "C" {
+            fn write(fd: i32, buf: *const u8, count: usize) -> isize;
+        }
+        // Only async-signal-safe calls can be made here.
+        let byte = signum as u8;
+        unsafe { write(TERMINATION_PIPE.load(Ordering::Relaxed), &byte, 1) };
+    }
+    const SIGINT: i32 = 2;
+    const SIGTERM: i32 = 15;
+    const SIG_ERR: usize = usize::MAX;
+    const F_SETFD: i32 = 2;
+    const FD_CLOEXEC: i32 = 1;
+
+    let mut fds = [-1; 2];
+    if unsafe { pipe(fds.as_mut_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    // The pipe isn't passed on to the processes that are started, such as by `--exec`. macOS has
+    // no `pipe2`, so the flag is set after the pipe is made.
+    for &fd in &fds {
+        if unsafe { fcntl(fd, F_SETFD, FD_CLOEXEC) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    TERMINATION_PIPE.store(fds[1], Ordering::Relaxed);
+    for &signum in &[SIGINT, SIGTERM] {
+        if unsafe { signal(signum, on_signal as extern "C" fn(i32) as usize) } == SIG_ERR {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    // Safety: the read end of the pipe was just made, and nothing else owns it.
+    Ok(unsafe { File::from_raw_fd(fds[0]) })
+}
+
+#[cfg(not(all(feature = "sys", unix)))]
+pub(crate) fn catch_termination() -> io::Result<File> {
+    Err(unsupported("catching signals"))
+}
+
+/// Exit because of a signal that [`catch_termination`] caught, by raising it again with its
+/// default action, so that the parent sees that the process was killed by it.
+#[cfg(all(feature = "sys", unix))]
+pub(crate) fn terminate(signum: i32) -> ! {
+    extern "C" {
+        fn signal(signum: i32, handler: usize) -> usize;
+        fn raise(signum: i32) -> i32;
+    }
+    const SIG_DFL: usize = 0;
+    unsafe {
+        signal(signum, SIG_DFL);
+        raise(signum);
+    }
+    // The signal is only raised on this thread, but its default action ends the process.
+    std::process::exit(128 + signum)
+}
+
+#[cfg(not(all(feature = "sys", unix)))]
+pub(crate) fn terminate(signum: i32) -> ! {
+    std::process::exit(128 + signum)
+}
@@ -0,0 +1,3455 @@
+// Copyright 2021 Daniel Zwell.
+//
+// Licensed under the Apache License, Version 2.0 <LICENSE-APACHE or
+// http://www.apache.org/licenses/LICENSE-2.0> or the MIT license
+// <LICENSE-MIT or http://opensource.org/licenses/MIT>, at your
+// option. This file may not be copied, modified, or distributed
+// except according to those terms.
+
+//! Verifying the checksums listed in checkfiles, and comparing files and directories.
+
+use std::{
+    collections::{hash_map::Entry, BTreeSet, HashMap, HashSet},
+    error::Error,
+    ffi::OsString,
+    fs::{metadata, read_link, File, Metadata},
+    io::{BufRead, Read, Write},
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        mpsc, Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime},
+};
+
+use multi_semaphore::Semaphore;
+
+use crate::{
+    checkfile::{check_chain, check_seal, format_line, parse, Dialect, FileMetadata, Parser},
+    engine::{
+        b3sum_large, file_id, list_files, open_entry, open_sequential, xattrs_digest, Algorithm,
+        Blake3Mode, Checksum, Chunk, Compression, Config, Found, Input,
+    },
+    output::{
+        add_progress_total, binary_name, error_status_of, json_string, normalize_path, normalized,
+        print_error, print_error_in, print_warning, record_progress, record_status, stdout_color,
+        Format, Phase, EXIT_MISSING, FAILED, OK, QUIET, WARNING,
+    },
+    scheduler::{checksum_with, hash_path, read_time, TimeLimit},
+    write_atomically, Result,
+};
+
+#[cfg(test)]
+use std::fmt;
+
+#[cfg(test)]
+use crate::{
+    checkfile::{chain_link, chain_start, Seal},
+    engine::{b3sum_small, hash_small},
+    make_temp_file, TempDirGuard,
+};
+
+/// Compare two files, or two directories recursively, by their checksums. Each difference that is
+/// found is passed to `on_difference`.
+///
+/// The two files of each pair are read and checksummed at the same time, so this is faster than
+/// checksumming one after the other when they are on different drives. Files with different
+/// sizes are not read. `first` and `second` are followed if they're symlinks, but the symlinks
+/// inside the directories aren't: two of them are the same if they have the same target. Other
+/// files that aren't regular files, such as FIFOs and devices, are only compared by their type.
+///
+/// The entries of the directories are opened as [`list_files`] opens them, relative to the
+/// directory they're in, so a directory that's replaced by a symbolic link while it's compared
+/// isn't followed, and the files that are read are the ones whose metadata was compared.
+pub fn compare_paths<F>(first: &Path, second: &Path, config: &Config, on_difference: &mut F)
+where
+    F: FnMut(Difference),
+{
+    compare_entries((None, first), (None, second), true, config, on_difference)
+}
+
+/// Compare two entries as [`compare_paths`] does. Each is the directory it's in, if it's under
+/// one of the paths that were given, and its path. With `follow`, they're followed if they're
+/// symlinks.
+fn compare_entries<F>(
+    (first_dir, first): (Option<&File>, &Path),
+    (second_dir, second): (Option<&File>, &Path),
+    follow: bool,
+    config: &Config,
+    on_difference: &mut F,
+) where
+    F: FnMut(Difference),
+{
+    let (first_found, second_found) = match (
+        open_entry(first_dir, first, follow),
+        open_entry(second_dir, second, follow),
+    ) {
+        (Ok(first_found), Ok(second_found)) => (first_found, second_found),
+        (Err(err), _) => {
+            return on_difference(Difference::Unreadable(first.to_owned(), err.into()))
+        }
+        (_, Err(err)) => {
+            return on_difference(Difference::Unreadable(second.to_owned(), err.into()))
+        }
+    };
+    let (first_opened, first_metadata, second_opened, second_metadata) =
+        match (first_found, second_found) {
+            (Found::Symlink, Found::Symlink) => {
+                match (read_link(first), read_link(second)) {
+                    (Ok(first_target), Ok(second_target)) => {
+                        if first_target != second_target {
+                            on_difference(Difference::Content(first.to_owned(), second.to_owned()));
+                        }
+                    }
+                    (Err(err), _) => {
+                        on_difference(Difference::Unreadable(first.to_owned(), err.into()))
+                    }
+                    (_, Err(err)) => {
+                        on_difference(Difference::Unreadable(second.to_owned(), err.into()))
+                    }
+                }
+                return;
+            }
+            (Found::Other(first_type), Found::Other(second_type)) if first_type == second_type => {
+                return
+            }
+            (
+                Found::Entry(first_opened, first_metadata),
+                Found::Entry(second_opened, second_metadata),
+            ) => (first_opened, first_metadata, second_opened, second_metadata),
+            _ => return on_difference(Difference::Content(first.to_owned(), second.to_owned())),
+        };
+    match (first_metadata.is_dir(), second_metadata.is_dir()) {
+        (true, true) => {
+            let (first_names, second_names) = match (dir_names(first), dir_names(second)) {
+                (Ok(first_names), Ok(second_names)) => (first_names, second_names),
+                (Err(err), _) => {
+                    return on_difference(Difference::Unreadable(first.to_owned(), err))
+                }
+                (_, Err(err)) => {
+                    return on_difference(Difference::Unreadable(second.to_owned(), err))
+                }
+            };
+            for name in first_names.union(&second_names) {
+                if !second_names.contains(name) {
+                    on_difference(Difference::OnlyIn(first.to_owned(), name.clone()));
+                } else if !first_names.contains(name) {
+                    on_difference(Difference::OnlyIn(second.to_owned(), name.clone()));
+                } else {
+                    compare_entries(
+                        (first_opened.as_ref(), &first.join(name)),
+                        (second_opened.as_ref(), &second.join(name)),
+                        false,
+                        config,
+                        on_difference,
+                    );
+                }
+            }
+        }
+        (false, false) if first_metadata.len() == second_metadata.len() => {
+            // The file that was opened is read, and otherwise it's opened by its path, and must
+            // still be the file whose metadata was compared. Errors aren't `Send`, so they're
+            // passed between threads as strings.
+            let checksum = |path: &Path, opened: Option<File>, metadata: &Metadata| {
+                let file = match opened {
+                    Some(file) => Ok(file),
+                    None => open_sequential(path).map_err(Into::into),
+                };
+                file.and_then(|file| Input::from_file(file, None, file_id(metadata), config.lock))
+                    .and_then(|input| b3sum_large(input, config))
+                    .map_err(|err| err.to_string())
+            };
+            match rayon::join(
+                || checksum(first, first_opened, &first_metadata),
+                || checksum(second, second_opened, &second_metadata),
+            ) {
+                (Ok(first_checksum), Ok(second_checksum)) => {
+                    if first_checksum != second_checksum {
+                        on_difference(Difference::Content(first.to_owned(), second.to_owned()));
+                    }
+                }
+                (Err(err), _) => {
+                    on_difference(Difference::Unreadable(first.to_owned(), err.into()))
+                }
+                (_, Err(err)) => {
+                    on_difference(Difference::Unreadable(second.to_owned(), err.into()))
+                }
+            }
+        }
+        _ => on_difference(Difference::Content(first.to_owned(), second.to_owned())),
+    }
+}
+
+/// The names of the entries in a directory, in sorted order.
+fn dir_names(dir: &Path) -> Result<BTreeSet<OsString>> {
+    let mut names = BTreeSet::new();
+    for entry in std::fs::read_dir(dir)? {
+        names.insert(entry?.file_name());
+    }
+    Ok(names)
+}
+
+/// A difference found by [`compare_paths`].
+#[derive(Debug)]
+pub enum Difference {
+    /// The files have different contents, or one is a directory and the other isn't, or they're
+    /// symlinks with different targets, or only one is a symlink.
+    Content(PathBuf, PathBuf),
+    /// A directory contains an entry with this name, but the other directory doesn't.
+    OnlyIn(PathBuf, OsString),
+    /// A file couldn't be read, so it couldn't be compared.
+    Unreadable(PathBuf, Box<dyn Error>),
+}
+
+/// Verify every entry of each checkfile, printing `OK` or `FAILED` for each one. Returns true if
+/// all checksums matched and no errors were encountered.
+///
+/// Checkfiles may be given as patterns with `*` and `?`, which are expanded. The entries of all
+/// checkfiles are merged, so a file that is listed more than once with the same checksum is only
+/// checked once. If checkfiles list different checksums for the same file, that is an error, and
+/// the file isn't checked.
+///
+/// Only the entries that `verify` selects are checked, and the paths that they're read from are
+/// rewritten by it first. With `--fail-fast`, the files that haven't been read yet are skipped
+/// once one fails. With `--root`, the files in that directory that no checkfile lists are
+/// reported too, whether or not `--only` selects them.
+///
+/// If the checkfile lists the size of a file, as hashdeep files do, a file of a different size is
+/// reported without being read. With `--verified-cache`, files that matched before and haven't
+/// changed since aren't read either. With `--report`, the result of each file is also written to
+/// a report.
+///
+/// With `--on-mismatch`, the files whose contents or size don't match are moved, renamed or
+/// deleted once they've been reported.
+///
+/// With `--last-verified`, the time that each file matched is recorded, and with `--budget`, only
+/// the files that were verified longest ago are checked, up to that many bytes, so that a large
+/// archive can be verified a part at a time.
+///
+/// Listed files that are missing are looked for among the files that `--root` finds that no
+/// checkfile lists. If one of them has the same checksum, the file is reported as moved there
+/// instead of as missing, and it doesn't fail the check.
+///
+/// Entries are checksummed with the same scheduling as
+/// [`do_checksum`](crate::scheduler::do_checksum).
+pub fn check(
+    checkfiles: &[PathBuf],
+    config: &Config,
+    io_lock: Arc<Semaphore>,
+    format: Format,
+    verify: &VerifyOptions,
+) -> bool {
+    let start = Instant::now();
+    // The bytes of the files that this check read, for the report's speed. Files that are
+    // skipped as unchanged, and those read by anything else in the process, aren't counted.
+    let bytes_read = AtomicU64::new(0);
+    let reported =
+        verify.report.is_some() || verify.notify_url.is_some() || verify.on_fail.is_some();
+    let report = if reported {
+        Some(VerifyReport::default())
+    } else {
+        None
+    };
+    let report = report.as_ref();
+    // The listed files that were checked, and the ones of them that failed, for --spot-check.
+    let checked = AtomicUsize::new(0);
+    let failures = AtomicUsize::new(0);
+    let (checked_count, failure_count) = (&checked, &failures);
+    let record = move |name: &str,
+                       path: &Path,
+                       status: FileStatus,
+                       read_time,
+                       entropy,
+                       ssdeep: Option<&str>| {
+        record_status(status.name());
+        if status != FileStatus::NotListed {
+            checked_count.fetch_add(1, Ordering::Relaxed);
+            if status.is_failure() {
+                failure_count.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        if let Some(report) = report {
+            report.record(name, path, status, read_time, entropy, ssdeep);
+        }
+    };
+    let mismatches = AtomicUsize::new(0);
+    let metadata_changes = AtomicUsize::new(0);
+    let read_errors = AtomicUsize::new(0);
+    let mut bad_lines = 0;
+    let mut conflicts = 0;
+    // The paths of every listed file, if `--root` is given. They're compared normalized.
+    let mut covered = HashSet::new();
+    // The listed files that are missing, and the errors when they were opened, if `--root` is
+    // given. They're reported once it's known whether they were moved.
+    let missing_files = verify.root.as_ref().map(|_| Mutex::new(Vec::new()));
+    let missing = missing_files.as_ref();
+
+    // Where each file and algorithm was first listed, and the index of its entry.
+    let mut listed: HashMap<(PathBuf, Algorithm), (&Path, usize)> = HashMap::new();
+    let mut entries: Vec<Option<CheckEntry>> = Vec::new();
+    let checkfiles: Vec<PathBuf> = checkfiles
+        .iter()
+        .flat_map(|path| expand_glob(path))
+        .collect();
+    for checkfile in &checkfiles {
+        match read_checkfile(checkfile, format, verify, &config.blake3_mode) {
+            Ok((checkfile_entries, bad_line_count)) => {
+                bad_lines += bad_line_count;
+                for mut entry in checkfile_entries {
+                    let selected = verify.selects(&entry.name);
+                    if !selected && verify.root.is_none() {
+                        continue;
+                    }
+                    entry.path = verify.apply(&normalized(&entry.path));
+                    if verify.root.is_some() {
+                        covered.insert(normalize_path(&entry.path, true, None));
+                    }
+                    if !selected {
+                        continue;
+                    }
+                    match listed.entry((entry.path.clone(), entry.algorithm)) {
+                        Entry::Vacant(vacant) => {
+                            vacant.insert((checkfile, entries.len()));
+                            entries.push(Some(entry));
+                        }
+                        Entry::Occupied(occupied) => {
+                            let (first_checkfile, index) = *occupied.get();
+                            let first = match &entries[index] {
+                                Some(first) => first,
+                                // The conflict was already reported.
+                                None => continue,
+                            };
+                            if first.expected != entry.expected {
+                                let message = format!(
+                                    "{} and {} list different checksums",
+                                    first_checkfile.display(),
+                                    checkfile.display()
+                                );
+                                print_error(&entry.path, message.into());
+                                outln!("{}: {}", entry.name, stdout_color("FAILED", FAILED));
+                                record(
+                                    &entry.name,
+                                    &entry.path,
+                                    FileStatus::Conflict,
+                                    None,
+                                    None,
+                                    None,
+                                );
+                                entries[index] = None;
+                                conflicts += 1;
+                            }
+                        }
+                    }
+                }
+            }
+            Err(err) => {
+                print_error_in(Phase::Checkfile, checkfile, err);
+                read_errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+
+    let entries: Vec<CheckEntry> = entries.into_iter().flatten().collect();
+    let last_verified = verify.last_verified.as_ref().map(|path| {
+        LastVerified::load(path).unwrap_or_else(|err| {
+            print_error(path, err);
+            LastVerified::default()
+        })
+    });
+    let entries = match (&last_verified, verify.budget) {
+        (Some(last_verified), Some(budget)) => last_verified.schedule(entries, budget),
+        _ => entries,
+    };
+    let listed_count = entries.len();
+    let seed = verify.seed.unwrap_or_else(random_seed);
+    let entries = match verify.spot_check {
+        Some(spot_check) => spot_check.choose(entries, seed),
+        None => entries,
+    };
+    let last_verified = last_verified.map(Mutex::new);
+    let last_verified = last_verified.as_ref();
+    add_progress_total(entries.len() as u64, 0);
+    let verified = verify.verified_cache.as_ref().map(|path| {
+        Mutex::new(VerifiedCache::load(path).unwrap_or_else(|err| {
+            print_error(path, err);
+            VerifiedCache::default()
+        }))
+    });
+    let verified = verified.as_ref();
+    // This is only set with --fail-fast.
+    let failed = AtomicBool::new(false);
+    // The files that haven't been started are skipped once this is set, with --fail-fast or
+    // when the time of --spot-check is up.
+    let cancel = Arc::new(AtomicBool::new(false));
+    let fail = {
+        let (failed, cancel) = (&failed, &*cancel);
+        move || {
+            if verify.fail_fast {
+                failed.store(true, Ordering::Relaxed);
+                cancel.store(true, Ordering::Relaxed);
+            }
+        }
+    };
+    // It's stopped when the check returns, if its time isn't up by then.
+    let _timer = match verify.spot_check {
+        Some(SpotCheck::Time(time)) => Some(Timer::start(time, Arc::clone(&cancel))),
+        _ => None,
+    };
+    if conflicts > 0 || read_errors.load(Ordering::Relaxed) > 0 {
+        fail();
+    }
+    // Whether the metadata that was recorded for a file whose contents matched has changed. The
+    // change is reported instead of the match.
+    let metadata_changed = |entry: &CheckEntry,
+                            path: &Path,
+                            actual: Option<FileMetadata>,
+                            read_time,
+                            entropy,
+                            ssdeep: Option<&str>| {
+        let changes = match (&entry.metadata, actual) {
+            (Some(recorded), Some(actual)) => recorded.differences(&actual),
+            _ => return false,
+        };
+        if changes.is_empty() {
+            return false;
+        }
+        let status = stdout_color("METADATA CHANGED", FAILED);
+        outln!("{}: {} ({})", entry.name, status, changes.join(", "));
+        record(
+            &entry.name,
+            path,
+            FileStatus::MetadataChanged,
+            read_time,
+            entropy,
+            ssdeep,
+        );
+        metadata_changes.fetch_add(1, Ordering::Relaxed);
+        fail();
+        true
+    };
+    // Moves, renames or deletes a file that failed, with `--on-mismatch`.
+    let quarantine = |name: &str, path: &Path| match verify
+        .on_mismatch
+        .as_ref()
+        .map(|action| action.apply(path))
+    {
+        Some(Ok(Some(target))) => {
+            let status = stdout_color("QUARANTINED", WARNING);
+            outln!("{}: {} to {}", name, status, target.display());
+        }
+        Some(Ok(None)) => outln!("{}: {}", name, stdout_color("DELETED", WARNING)),
+        Some(Err(err)) => print_error(path, err),
+        None => {}
+    };
+    rayon::scope(|s| {
+        for entry in entries {
+            if cancel.load(Ordering::Relaxed) {
+                break;
+            }
+            let mismatches = &mismatches;
+            let read_errors = &read_errors;
+            let bytes_read = &bytes_read;
+            let metadata_changed = &metadata_changed;
+            let quarantine = &quarantine;
+
+            // If the file can't be found, it's reported when it's read.
+            let stamp = if entry.size.is_some() || verified.is_some() {
+                metadata(&entry.path)
+                    .ok()
+                    .and_then(|metadata| FileStamp::of(&metadata))
+            } else {
+                None
+            };
+            let decompressed = config.decompress && Compression::detect(&entry.path).is_some();
+            match (entry.size, stamp) {
+                (Some(size), Some(stamp)) if size != stamp.size && !decompressed => {
+                    outln!("{}: {}", entry.name, stdout_color("SIZE MISMATCH", FAILED));
+                    record(
+                        &entry.name,
+                        &entry.path,
+                        FileStatus::SizeMismatch,
+                        None,
+                        None,
+                        None,
+                    );
+                    record_progress(0);
+                    mismatches.fetch_add(1, Ordering::Relaxed);
+                    if let Some(verified) = verified {
+                        verified.lock().unwrap().forget(&entry.path);
+                    }
+                    // The file isn't quarantined, since its contents weren't compared, and the
+                    // size that was listed may be the one that's wrong.
+                    fail();
+                    continue;
+                }
+                _ => {}
+            }
+            if let (Some(verified), Some(stamp)) = (verified, stamp) {
+                if verified
+                    .lock()
+                    .unwrap()
+                    .is_unchanged(&entry.path, stamp, &entry.expected)
+                {
+                    let actual = actual_metadata(&entry, metadata(&entry.path).ok(), || {
+                        xattrs_digest(&entry.path).ok()
+                    });
+                    if !metadata_changed(&entry, &entry.path, actual, None, None, None) {
+                        if !QUIET.load(Ordering::Relaxed) {
+                            outln!("{}: {}", entry.name, stdout_color("OK (unchanged)", OK));
+                        }
+                        record(
+                            &entry.name,
+                            &entry.path,
+                            FileStatus::Unchanged,
+                            None,
+                            None,
+                            None,
+                        );
+                    }
+                    record_progress(stamp.size);
+                    continue;
+                }
+            }
+
+            let name = entry.name.clone();
+            let listed_path = entry.path.clone();
+            let listed = entry.clone();
+            let expected = entry.expected.clone();
+            // The pieces are only compared if the file doesn't match.
+            let config = Config {
+                algorithm: entry.algorithm,
+                cancel: Some(Arc::clone(&cancel)),
+                piecewise: entry.pieces.first().map(|piece| piece.length),
+                xattrs: entry
+                    .metadata
+                    .as_ref()
+                    .map_or(false, |metadata| metadata.xattrs.is_some()),
+                ..config.clone()
+            };
+            let result = checksum_with(
+                entry.path.clone(),
+                &config,
+                Arc::clone(&io_lock),
+                s,
+                move |path, result| {
+                    let read_time = Some(read_time());
+                    let (result, entropy, ssdeep, pieces, actual) = match result {
+                        Ok(hashed) => {
+                            // The metadata was taken from the file that was read.
+                            let size = hashed.metadata.as_ref().map_or(0, Metadata::len);
+                            bytes_read.fetch_add(size, Ordering::Relaxed);
+                            let xattrs = hashed.xattrs;
+                            let actual = actual_metadata(&listed, hashed.metadata, || xattrs);
+                            (
+                                Ok(hashed.checksum),
+                                hashed.entropy,
+                                hashed.ssdeep,
+                                hashed.pieces,
+                                actual,
+                            )
+                        }
+                        Err(err) => (Err(err), None, None, Vec::new(), None),
+                    };
+                    let matched = match result {
+                        Ok(checksum) if checksum == expected => {
+                            if !metadata_changed(
+                                &listed,
+                                path,
+                                actual,
+                                read_time,
+                                entropy,
+                                ssdeep.as_deref(),
+                            ) {
+                                if !QUIET.load(Ordering::Relaxed) {
+                                    outln!("{}: {}", name, stdout_color("OK", OK));
+                                }
+                                record(
+                                    &name,
+                                    path,
+                                    FileStatus::Ok,
+                                    read_time,
+                                    entropy,
+                                    ssdeep.as_deref(),
+                                );
+                            }
+                            // The contents are still verified, even if the metadata changed.
+                            true
+                        }
+                        Ok(_) if !listed.pieces.is_empty() => {
+                            let changed = changed_ranges(&listed.pieces, &pieces);
+                            let failed = stdout_color("FAILED", FAILED);
+                            outln!("{}: {} (changed bytes: {})", name, failed, changed);
+                            record(
+                                &name,
+                                path,
+                                FileStatus::Failed,
+                                read_time,
+                                entropy,
+                                ssdeep.as_deref(),
+                            );
+                            mismatches.fetch_add(1, Ordering::Relaxed);
+                            quarantine(&name, path);
+                            fail();
+                            false
+                        }
+                        Ok(_) => {
+                            outln!("{}: {}", name, stdout_color("FAILED", FAILED));
+                            record(
+                                &name,
+                                path,
+                                FileStatus::Failed,
+                                read_time,
+                                entropy,
+                                ssdeep.as_deref(),
+                            );
+                            mismatches.fetch_add(1, Ordering::Relaxed);
+                            quarantine(&name, path);
+                            fail();
+                            false
+                        }
+                        Err(err) if missing.is_some() && is_not_found(&*err) => {
+                            let missing = missing.unwrap();
+                            missing.lock().unwrap().push((listed, err.to_string()));
+                            false
+                        }
+                        Err(err) => {
+                            record(
+                                &name,
+                                path,
+                                FileStatus::of_error(&*err),
+                                read_time,
+                                None,
+                                None,
+                            );
+                            print_error(path, err);
+                            outln!("{}: {}", name, stdout_color("FAILED open or read", FAILED));
+                            read_errors.fetch_add(1, Ordering::Relaxed);
+                            fail();
+                            false
+                        }
+                    };
+                    if let Some(last_verified) = last_verified.filter(|_| matched) {
+                        last_verified.lock().unwrap().record(&listed_path);
+                    }
+                    if let Some(verified) = verified {
+                        let mut verified = verified.lock().unwrap();
+                        match stamp {
+                            // The stamp is from before the file was read, so a change while it was
+                            // read is seen next time.
+                            Some(stamp) if matched => {
+                                verified.record(&listed_path, stamp, expected)
+                            }
+                            _ => verified.forget(&listed_path),
+                        }
+                    }
+                },
+            );
+            match (result, missing) {
+                (Err(err), Some(missing)) if is_not_found(&*err) => {
+                    missing.lock().unwrap().push((entry, err.to_string()));
+                }
+                (Err(err), _) => {
+                    record(
+                        &entry.name,
+                        &entry.path,
+                        FileStatus::of_error(&*err),
+                        None,
+                        None,
+                        None,
+                    );
+                    print_error(&entry.path, err);
+                    let failed = stdout_color("FAILED open or read", FAILED);
+                    outln!("{}: {}", entry.name, failed);
+                    read_errors.fetch_add(1, Ordering::Relaxed);
+                    fail();
+                }
+                (Ok(()), _) => {}
+            }
+        }
+    });
+
+    if let (Some(path), Some(verified)) = (&verify.verified_cache, verified) {
+        if let Err(err) = verified.lock().unwrap().save(path) {
+            print_error(path, err);
+        }
+    }
+    if let (Some(path), Some(last_verified)) = (&verify.last_verified, last_verified) {
+        if let Err(err) = last_verified.lock().unwrap().save(path) {
+            print_error(path, err);
+        }
+    }
+
+    let mut unlisted = Vec::new();
+    if let Some(root) = verify
+        .root
+        .as_deref()
+        .filter(|_| !failed.load(Ordering::Relaxed))
+    {
+        let checkfiles: HashSet<PathBuf> = checkfiles
+            .iter()
+            .map(|path| normalize_path(path, true, None))
+            .collect();
+        list_files(root, &mut |listed, _| {
+            let path = listed.path();
+            let normalized = normalize_path(&path, true, None);
+            if !covered.contains(&normalized) && !checkfiles.contains(&normalized) {
+                unlisted.push(path);
+            }
+        });
+    }
+
+    let mut missing: Vec<(CheckEntry, String)> =
+        missing_files.map_or_else(Vec::new, |missing| missing.into_inner().unwrap());
+    missing.sort_by(|a, b| a.0.name.cmp(&b.0.name));
+    let missing_entries: Vec<CheckEntry> = missing.iter().map(|(entry, _)| entry.clone()).collect();
+    let moved_to = find_moved(&missing_entries, &unlisted, config, &io_lock);
+    let mut moved = 0;
+    let mut targets = HashSet::new();
+    for ((entry, err), target) in missing.into_iter().zip(moved_to) {
+        match target {
+            Some(target) => {
+                let status = stdout_color("MOVED", WARNING);
+                outln!("{}: {} to {}", entry.name, status, target.display());
+                record(&entry.name, &target, FileStatus::Moved, None, None, None);
+                targets.insert(target);
+                moved += 1;
+            }
+            None => {
+                record(
+                    &entry.name,
+                    &entry.path,
+                    FileStatus::Missing,
+                    None,
+                    None,
+                    None,
+                );
+                let err = std::io::Error::new(std::io::ErrorKind::NotFound, err);
+                print_error(&entry.path, err.into());
+                let failed = stdout_color("FAILED open or read", FAILED);
+                outln!("{}: {}", entry.name, failed);
+                read_errors.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+    }
+    let mut extraneous = 0;
+    for path in unlisted.into_iter().filter(|path| !targets.contains(path)) {
+        outln!("{}: {}", path.display(), stdout_color("NOT LISTED", FAILED));
+        record(
+            &path.to_string_lossy(),
+            &path,
+            FileStatus::NotListed,
+            None,
+            None,
+            None,
+        );
+        extraneous += 1;
+    }
+
+    let plural = |count: usize, singular: &str, plural: &str| {
+        format!("{} {}", count, if count == 1 { singular } else { plural })
+    };
+    if failed.load(Ordering::Relaxed) {
+        print_warning("stopped at the first failure, so some files were not checked");
+    }
+    if bad_lines > 0 {
+        print_warning(&format!(
+            "{} improperly formatted",
+            plural(bad_lines, "line is", "lines are")
+        ));
+    }
+    let read_errors = read_errors.into_inner();
+    if read_errors > 0 {
+        print_warning(&format!(
+            "{} could not be read",
+            plural(read_errors, "listed file", "listed files")
+        ));
+    }
+    if conflicts > 0 {
+        print_warning(&format!(
+            "{} listed with different checksums",
+            plural(conflicts, "file is", "files are")
+        ));
+    }
+    let mismatches = mismatches.into_inner();
+    if mismatches > 0 {
+        print_warning(&format!(
+            "{} did NOT match",
+            plural(mismatches, "computed checksum", "computed checksums")
+        ));
+    }
+    let metadata_changes = metadata_changes.into_inner();
+    if metadata_changes > 0 {
+        print_warning(&format!(
+            "{} metadata changed",
+            plural(metadata_changes, "listed file's", "listed files'")
+        ));
+    }
+    if moved > 0 {
+        print_warning(&format!(
+            "{} moved",
+            plural(moved, "listed file was", "listed files were")
+        ));
+    }
+    if extraneous > 0 {
+        print_warning(&format!(
+            "{} not listed in any checkfile",
+            plural(extraneous, "file is", "files are")
+        ));
+    }
+    if verify.spot_check.is_some() && !QUIET.load(Ordering::Relaxed) {
+        let coverage = spot_check_coverage(
+            checked.into_inner(),
+            listed_count,
+            failures.into_inner(),
+            seed,
+        );
+        eprintln!("{}: {}", binary_name(), coverage);
+    }
+    let passed = mismatches == 0
+        && metadata_changes == 0
+        && read_errors == 0
+        && conflicts == 0
+        && extraneous == 0;
+    if let Some(report) = report {
+        let bytes_read = bytes_read.load(Ordering::Relaxed);
+        let report = report.finish(&checkfiles, bad_lines, bytes_read, start.elapsed(), passed);
+        if let Some(target) = &verify.report {
+            if let Err(err) = report.write(target) {
+                print_error(&target.path, err);
+            }
+        }
+        if !passed {
+            let payload = report.to_failure_json();
+            if let Some(url) = &verify.notify_url {
+                if let Err(err) = post_json(url, &payload) {
+                    print_error(Path::new(url), err);
+                }
+            }
+            if let Some(command) = &verify.on_fail {
+                if let Err(err) = run_hook(command, &payload) {
+                    print_error(Path::new(command), err);
+                }
+            }
+        }
+    }
+    passed
+}
+
+/// Verify the files listed in checkfiles against the copies on another machine, for
+/// `remote-verify`. Each entry is sent through `requests` to a [`serve_remote`] on the other end,
+/// such as over SSH, which reads its copy and answers through `responses` whether it matches, so
+/// only the checkfile lines and the answers cross the network. `OK` or `FAILED` is printed for
+/// each file, as by [`check`]. Returns true if every file matched.
+///
+/// The requests are written on another thread while the answers are read, so that neither side
+/// waits for the other.
+pub fn remote_verify<R, W>(
+    checkfiles: &[PathBuf],
+    format: Format,
+    requests: W,
+    responses: R,
+) -> bool
+where
+    R: BufRead,
+    W: Write + Send + 'static,
+{
+    let mut entries = Vec::new();
+    let mut bad_lines = 0;
+    let mut passed = true;
+    for checkfile in checkfiles {
+        match read_checkfile(
+            checkfile,
+            format,
+            &VerifyOptions::default(),
+            &Blake3Mode::Hash,
+        ) {
+            Ok((listed, bad)) => {
+                entries.extend(listed);
+                bad_lines += bad;
+            }
+            Err(err) => {
+                print_error(checkfile, err);
+                passed = false;
+            }
+        }
+    }
+    let lines: Vec<String> = entries.iter().map(remote_request).collect();
+    let sender = std::thread::Builder::new().spawn(move || -> std::io::Result<()> {
+        let mut requests = std::io::BufWriter::new(requests);
+        for line in lines {
+            writeln!(requests, "{}", line)?;
+        }
+        requests.flush()
+    });
+    // Without threads, as on WASI, the requests can't be written while the answers are read.
+    let sender = match sender {
+        Ok(sender) => sender,
+        Err(err) => {
+            print_error(Path::new("-"), err.into());
+            return false;
+        }
+    };
+
+    let plural = |count: usize, singular: &str, plural: &str| {
+        format!("{} {}", count, if count == 1 { singular } else { plural })
+    };
+    let mut mismatches = 0;
+    let mut read_errors = 0;
+    let mut responses = responses.lines();
+    for entry in &entries {
+        let response = match responses.next() {
+            Some(Ok(response)) => response,
+            Some(Err(err)) => {
+                print_error(Path::new(&entry.name), err.into());
+                return false;
+            }
+            None => {
+                let err = "the connection was closed before every file was verified";
+                print_error(Path::new(&entry.name), err.into());
+                return false;
+            }
+        };
+        let status = match &response[..] {
+            "match" => FileStatus::Ok,
+            "mismatch" => FileStatus::Failed,
+            _ => FileStatus::Unreadable,
+        };
+        record_status(status.name());
+        match status {
+            FileStatus::Ok if !QUIET.load(Ordering::Relaxed) => {
+                outln!("{}: {}", entry.name, stdout_color("OK", OK));
+            }
+            FileStatus::Ok => {}
+            FileStatus::Failed => {
+                outln!("{}: {}", entry.name, stdout_color("FAILED", FAILED));
+                mismatches += 1;
+            }
+            _ => {
+                let failed = stdout_color("FAILED open or read", FAILED);
+                outln!("{}: {}", entry.name, failed);
+                let message = response.strip_prefix("error ").unwrap_or(&response);
+                print_error(Path::new(&entry.name), message.to_owned().into());
+                read_errors += 1;
+            }
+        }
+    }
+    if let Ok(Err(err)) = sender.join() {
+        // The server stops reading once it has failed, and that error was already printed.
+        if err.kind() != std::io::ErrorKind::BrokenPipe {
+            print_error(Path::new("-"), err.into());
+            passed = false;
+        }
+    }
+
+    if bad_lines > 0 {
+        print_warning(&format!(
+            "{} improperly formatted",
+            plural(bad_lines, "line is", "lines are")
+        ));
+    }
+    if read_errors > 0 {
+        print_warning(&format!(
+            "{} could not be read",
+            plural(read_errors, "remote file", "remote files")
+        ));
+    }
+    if mismatches > 0 {
+        print_warning(&format!(
+            "{} did NOT match",
+            plural(mismatches, "remote file", "remote files")
+        ));
+    }
+    passed && mismatches == 0 && read_errors == 0
+}
+
+/// The request of [`remote_verify`] for an entry, which is its line in the BSD style so that the
+/// algorithm is named.
+fn remote_request(entry: &CheckEntry) -> String {
+    let entry = crate::checkfile::Entry {
+        name: entry.path.to_string_lossy().into_owned(),
+        checksum: entry.expected.clone(),
+        algorithm: entry.algorithm,
+        size: None,
+        timestamp: None,
+        metadata: None,
+        pieces: Vec::new(),
+    };
+    // Every algorithm has a name, so every entry has a line.
+    format_line(&entry, Format::Tag).unwrap()
+}
+
+/// Answer the requests of [`remote_verify`] until `requests` ends, for `remote-verify --serve`.
+/// Each request is a line of a checkfile, and each answer is `match`, `mismatch` or
+/// `error <message>` on a line of its own. The files are read relative to `root`, and only files
+/// under it may be requested: absolute paths, `..` and symlinks that lead out of it are errors,
+/// so that a client can't test guesses of the contents of other files.
+pub fn serve_remote<R, W>(requests: R, mut responses: W, root: &Path, config: &Config) -> Result<()>
+where
+    R: BufRead,
+    W: Write,
+{
+    let root = root.canonicalize()?;
+    for request in requests.lines() {
+        let request = request?;
+        let response = match Parser::new(Dialect::default()).parse_line(&request) {
+            Ok(Some(entry)) => remote_answer(&entry, &root, config),
+            Ok(None) => "error the request isn't a checkfile line".to_owned(),
+            Err(err) => format!("error {}", err.message),
+        };
+        writeln!(responses, "{}", response.replace('\n', " "))?;
+        responses.flush()?;
+    }
+    Ok(())
+}
+
+/// Whether the file of an entry matches it, as answered by [`serve_remote`]. `root` is
+/// canonical.
+fn remote_answer(entry: &crate::checkfile::Entry, root: &Path, config: &Config) -> String {
+    const OUTSIDE: &str = "error only the files under the served directory can be requested";
+    if entry.name == "-" {
+        return "error the request isn't the path of a file".to_owned();
+    }
+    let relative = Path::new(&entry.name);
+    if relative
+        .components()
+        .any(|component| !matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        return OUTSIDE.to_owned();
+    }
+    let path = match root.join(relative).canonicalize() {
+        Ok(path) if path.starts_with(root) => path,
+        Ok(_) => return OUTSIDE.to_owned(),
+        Err(err) => return format!("error {}", err),
+    };
+    if !entry.algorithm.is_available() {
+        return format!(
+            "error {} checksums can only be verified when b3sum-ng is built with the \
+            extra-algorithms feature",
+            entry.algorithm
+        );
+    }
+    let config = Config {
+        algorithm: entry.algorithm,
+        length: if entry.algorithm == Algorithm::Blake3 {
+            entry.checksum.0.len()
+        } else {
+            config.length
+        },
+        entropy: false,
+        piecewise: None,
+        ssdeep: false,
+        ..config.clone()
+    };
+    match hash_path(&path, &config) {
+        Ok(checksum) if checksum.constant_time_eq(&entry.checksum) => "match".to_owned(),
+        Ok(_) => "mismatch".to_owned(),
+        Err(err) => format!("error {}", err),
+    }
+}
+
+/// The ranges of bytes whose pieces are different between the expected and actual pieces of a
+/// file, such as `0-1048575, 5242880-6291455`. Adjacent pieces are joined, and only the first few
+/// ranges are listed.
+fn changed_ranges(expected: &[Chunk], actual: &[Chunk]) -> String {
+    const MAX_RANGES: usize = 8;
+    let end = |piece: &Chunk| piece.offset + piece.length as u64;
+    let mut ranges: Vec<(u64, u64)> = Vec::new();
+    for i in 0..expected.len().max(actual.len()) {
+        let range = match (expected.get(i), actual.get(i)) {
+            (Some(expected), Some(actual)) if expected == actual => continue,
+            (Some(expected), Some(actual)) => (expected.offset, end(expected).max(end(actual))),
+            (Some(piece), None) | (None, Some(piece)) => (piece.offset, end(piece)),
+            (None, None) => unreachable!(),
+        };
+        match ranges.last_mut() {
+            Some(last) if last.1 >= range.0 => last.1 = last.1.max(range.1),
+            _ => ranges.push(range),
+        }
+    }
+    let mut listed: Vec<String> = ranges
+        .iter()
+        .take(MAX_RANGES)
+        .map(|(start, end)| format!("{}-{}", start, end.saturating_sub(1)))
+        .collect();
+    if ranges.len() > MAX_RANGES {
+        listed.push(format!("and {} more", ranges.len() - MAX_RANGES));
+    }
+    if listed.is_empty() {
+        // The pieces match, but the whole file doesn't, so the pieces were for other contents.
+        "unknown".to_owned()
+    } else {
+        listed.join(", ")
+    }
+}
+
+/// Whether an error is because a file doesn't exist.
+fn is_not_found(err: &(dyn Error + 'static)) -> bool {
+    error_status_of(err) == EXIT_MISSING
+}
+
+/// Find where missing files were moved: for each entry, one of `candidates` that has its expected
+/// checksum, or `None`. Each candidate is used for one entry at most, and the first in order is
+/// used if several have the same checksum. Candidates that can't be read are skipped.
+fn find_moved(
+    entries: &[CheckEntry],
+    candidates: &[PathBuf],
+    config: &Config,
+    io_lock: &Arc<Semaphore>,
+) -> Vec<Option<PathBuf>> {
+    if entries.is_empty() || candidates.is_empty() {
+        return vec![None; entries.len()];
+    }
+    let mut algorithms = Vec::new();
+    for entry in entries {
+        if !algorithms.contains(&entry.algorithm) {
+            algorithms.push(entry.algorithm);
+        }
+    }
+    add_progress_total((candidates.len() * algorithms.len()) as u64, 0);
+    // The index of each candidate with each checksum, keeping the first.
+    let found: Mutex<HashMap<(Algorithm, Checksum), usize>> = Mutex::new(HashMap::new());
+    rayon::scope(|s| {
+        for (index, candidate) in candidates.iter().enumerate() {
+            for &algorithm in &algorithms {
+                let found = &found;
+                let config = Config {
+                    algorithm,
+                    ..config.clone()
+                };
+                let result = checksum_with(
+                    candidate.clone(),
+                    &config,
+                    Arc::clone(io_lock),
+                    s,
+                    move |_, result| {
+                        if let Ok(hashed) = result {
+                            let mut found = found.lock().unwrap();
+                            let first = found.entry((algorithm, hashed.checksum)).or_insert(index);
+                            *first = index.min(*first);
+                        }
+                    },
+                );
+                if result.is_err() {
+                    record_progress(0);
+                }
+            }
+        }
+    });
+    let mut found = found.into_inner().unwrap();
+    entries
+        .iter()
+        .map(|entry| {
+            let index = found.remove(&(entry.algorithm, entry.expected.clone()))?;
+            Some(candidates[index].clone())
+        })
+        .collect()
+}
+
+/// Expand `*` and `?` in any part of a path, like a shell does, for platforms whose shells don't.
+/// Names starting with `.` only match patterns that start with `.`. The matches are sorted. A
+/// path without wildcards, or a pattern that matches nothing, is returned as it is.
+pub fn expand_glob(pattern: &Path) -> Vec<PathBuf> {
+    let has_wildcard = |part: &str| part.contains(|c| c == '*' || c == '?');
+    if !pattern.to_str().map_or(false, has_wildcard) {
+        return vec![pattern.to_owned()];
+    }
+    let mut matches = vec![PathBuf::new()];
+    for component in pattern.components() {
+        let part = match component.as_os_str().to_str() {
+            Some(part) if has_wildcard(part) => part,
+            _ => {
+                for path in &mut matches {
+                    path.push(component);
+                }
+                continue;
+            }
+        };
+        let mut next = Vec::new();
+        for dir in &matches {
+            let read_from = if dir.as_os_str().is_empty() {
+                Path::new(".")
+            } else {
+                dir
+            };
+            if let Ok(read_dir) = std::fs::read_dir(read_from) {
+                for name in read_dir.flatten().map(|entry| entry.file_name()) {
+                    if let Some(name) = name.to_str() {
+                        if (part.starts_with('.') || !name.starts_with('.'))
+                            && wildcard_match(part, name)
+                        {
+                            next.push(dir.join(name));
+                        }
+                    }
+                }
+            }
+        }
+        matches = next;
+    }
+    // Parts without wildcards were added without checking that they exist.
+    matches.retain(|path| path.exists());
+    if matches.is_empty() {
+        return vec![pattern.to_owned()];
+    }
+    matches.sort();
+    matches
+}
+
+/// Whether a path matches a pattern of `/`-separated parts, where `**` matches any number of
+/// directories and other parts are matched with [`wildcard_match`]. Leading `./` is ignored in both.
+fn path_pattern_match(pattern: &str, path: &Path) -> bool {
+    let pattern: Vec<&str> = pattern
+        .split('/')
+        .filter(|part| !part.is_empty() && *part != ".")
+        .collect();
+    let names: Vec<String> = path
+        .components()
+        .filter_map(|component| match component {
+            std::path::Component::Normal(name) => Some(name.to_string_lossy().into_owned()),
+            _ => None,
+        })
+        .collect();
+    fn matches(pattern: &[&str], names: &[String]) -> bool {
+        match pattern.split_first() {
+            None => names.is_empty(),
+            Some((&"**", rest)) => (0..=names.len()).any(|skip| matches(rest, &names[skip..])),
+            Some((part, rest)) => match names.split_first() {
+                Some((name, names)) => wildcard_match(part, name) && matches(rest, names),
+                None => false,
+            },
+        }
+    }
+    matches(&pattern, &names)
+}
+
+/// Whether `name` matches `pattern`, where `*` matches any characters and `?` matches one.
+fn wildcard_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+    // Where the last `*` was, and how much of the name it matched, to backtrack to.
+    let (mut p, mut n, mut star) = (0, 0, None);
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+/// The metadata of a file to compare with what was recorded for `entry`, with the digest of its
+/// extended attributes from `xattrs` if they were recorded. An attribute that can't be read is
+/// reported as changed.
+fn actual_metadata(
+    entry: &CheckEntry,
+    metadata: Option<Metadata>,
+    xattrs: impl FnOnce() -> Option<Checksum>,
+) -> Option<FileMetadata> {
+    let recorded = entry.metadata.as_ref()?;
+    let mut actual = FileMetadata::of(&metadata?);
+    if recorded.xattrs.is_some() {
+        actual.xattrs = xattrs();
+    }
+    Some(actual)
+}
+
+/// One expected checksum listed in a checkfile.
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) struct CheckEntry {
+    /// The filename as it was written in the checkfile.
+    pub name: String,
+    /// Where the file is read from.
+    pub path: PathBuf,
+    pub expected: Checksum,
+    pub algorithm: Algorithm,
+    /// The size of the file, if the checkfile lists it.
+    pub size: Option<u64>,
+    /// The metadata of the file, if the checkfile lists it.
+    pub metadata: Option<FileMetadata>,
+    /// The checksums of the pieces of the file, if the checkfile lists them.
+    pub pieces: Vec<Chunk>,
+}
+
+/// The size and modification time of a file, to tell whether it has changed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct FileStamp {
+    pub size: u64,
+    /// Nanoseconds since the Unix epoch.
+    pub modified: u128,
+}
+
+impl FileStamp {
+    /// The stamp of a file, if its modification time is known.
+    pub fn of(metadata: &Metadata) -> Option<Self> {
+        let modified = metadata.modified().ok()?;
+        Some(FileStamp {
+            size: metadata.len(),
+            modified: modified
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .ok()?
+                .as_nanos(),
+        })
+    }
+}
+
+/// The files that matched their checksums in earlier checks, and their stamps when they were
+/// read, as kept in the file given to `--verified-cache`.
+///
+/// Each line is `<size> <modified> <checksum> <path>`. Paths that aren't Unicode or that contain a
+/// newline aren't recorded.
+#[derive(Debug, Default)]
+pub(crate) struct VerifiedCache {
+    files: HashMap<PathBuf, (FileStamp, Checksum)>,
+}
+
+impl VerifiedCache {
+    /// Read a cache file. A file that doesn't exist yet is an empty cache, and lines that can't be
+    /// parsed are skipped.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(err.into()),
+        };
+        let parse = |line: &str| {
+            let mut fields = line.splitn(4, ' ');
+            let size = fields.next()?.parse().ok()?;
+            let modified = fields.next()?.parse().ok()?;
+            let checksum = fields.next()?.parse().ok()?;
+            let path = PathBuf::from(fields.next()?);
+            Some((path, (FileStamp { size, modified }, checksum)))
+        };
+        Ok(VerifiedCache {
+            files: contents.lines().filter_map(parse).collect(),
+        })
+    }
+
+    /// Write the cache file, with its lines sorted by path, replacing it at once.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut files: Vec<_> = self.files.iter().collect();
+        files.sort_by(|a, b| a.0.cmp(b.0));
+        let mut contents = String::new();
+        for (path, (stamp, checksum)) in files {
+            if let Some(path) = path.to_str() {
+                contents += &format!("{} {} {} {}\n", stamp.size, stamp.modified, checksum, path);
+            }
+        }
+        Ok(write_atomically(path, contents.as_bytes())?)
+    }
+
+    /// Whether the file at `path` matched `expected` when it last had this stamp.
+    pub fn is_unchanged(&self, path: &Path, stamp: FileStamp, expected: &Checksum) -> bool {
+        self.files.get(path) == Some(&(stamp, expected.clone()))
+    }
+
+    /// Record that the file at `path` matched `checksum` when it had this stamp.
+    pub fn record(&mut self, path: &Path, stamp: FileStamp, checksum: Checksum) {
+        if path.to_str().map_or(false, |path| !path.contains('\n')) {
+            self.files.insert(path.to_owned(), (stamp, checksum));
+        }
+    }
+
+    /// Forget a file that no longer matches.
+    pub fn forget(&mut self, path: &Path) {
+        self.files.remove(path);
+    }
+}
+
+/// When each listed file last matched its checksum, as kept in the file given to
+/// `--last-verified`, so that `--budget` can check the files that were verified longest ago
+/// first.
+///
+/// Each line is `<verified> <path>`, where `verified` is in nanoseconds since the Unix epoch.
+/// Paths that aren't Unicode or that contain a newline aren't recorded.
+#[derive(Debug, Default)]
+pub(crate) struct LastVerified {
+    files: HashMap<PathBuf, u128>,
+}
+
+impl LastVerified {
+    /// Read a file of verification times. A file that doesn't exist yet has none, and lines that
+    /// can't be parsed are skipped.
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = match std::fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(err.into()),
+        };
+        let parse = |line: &str| {
+            let (verified, path) = line.split_once(' ')?;
+            Some((PathBuf::from(path), verified.parse().ok()?))
+        };
+        Ok(LastVerified {
+            files: contents.lines().filter_map(parse).collect(),
+        })
+    }
+
+    /// Write the file, with its lines sorted by path, replacing it at once, so that a crash
+    /// leaves the old schedule rather than part of the new one.
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let mut files: Vec<_> = self.files.iter().collect();
+        files.sort_by(|a, b| a.0.cmp(b.0));
+        let mut contents = String::new();
+        for (path, verified) in files {
+            if let Some(path) = path.to_str() {
+                contents += &format!("{} {}\n", verified, path);
+            }
+        }
+        Ok(write_atomically(path, contents.as_bytes())?)
+    }
+
+    /// Record that the file at `path` matched just now.
+    pub fn record(&mut self, path: &Path) {
+        if path.to_str().map_or(false, |path| !path.contains('\n')) {
+            let now = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map_or(0, |now| now.as_nanos());
+            self.files.insert(path.to_owned(), now);
+        }
+    }
+
+    /// Choose the entries that are checked within a budget of `budget` bytes: the files that
+    /// were never verified, and then the ones that were verified longest ago, until the next one
+    /// doesn't fit. The first one is always checked, so that a file larger than the budget
+    /// isn't skipped forever.
+    pub fn schedule(&self, mut entries: Vec<CheckEntry>, budget: u64) -> Vec<CheckEntry> {
+        entries.sort_by_key(|entry| self.files.get(&entry.path).copied().unwrap_or(0));
+        let mut total = 0;
+        let mut scheduled = Vec::new();
+        for entry in entries {
+            let size = entry
+                .size
+                .or_else(|| metadata(&entry.path).ok().map(|metadata| metadata.len()))
+                .unwrap_or(0);
+            if !scheduled.is_empty() && total + size > budget {
+                break;
+            }
+            total += size;
+            scheduled.push(entry);
+        }
+        scheduled
+    }
+}
+
+/// How many of the listed files `--spot-check` checks, which are chosen at random: a share of
+/// them, like `10%`, or as many as can be checked in some time, like `15min`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SpotCheck {
+    /// This percentage of the files, rounded up.
+    Percent(f64),
+    /// As many files as can be checked in this time, in a random order. The files that are being
+    /// read when it's up are finished.
+    Time(Duration),
+}
+
+impl FromStr for SpotCheck {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_suffix('%') {
+            Some(percent) => match percent.parse() {
+                Ok(percent) if percent > 0.0 && percent <= 100.0 => Ok(SpotCheck::Percent(percent)),
+                _ => Err(format!("the percentage isn't between 0 and 100: {}", s)),
+            },
+            None => {
+                let TimeLimit(time) = s.parse()?;
+                Ok(SpotCheck::Time(time))
+            }
+        }
+    }
+}
+
+impl SpotCheck {
+    /// Shuffle the entries in an order given by `seed`, and keep the ones that are checked.
+    fn choose(self, mut entries: Vec<CheckEntry>, seed: u64) -> Vec<CheckEntry> {
+        shuffle(&mut entries, seed);
+        if let SpotCheck::Percent(percent) = self {
+            let count = (entries.len() as f64 * percent / 100.0).ceil() as usize;
+            entries.truncate(count);
+        }
+        entries
+    }
+}
+
+/// Sets a flag once a time has passed, for the time limit of `--spot-check`. If it's dropped
+/// first, it stops without setting the flag, and its thread is joined. Without threads, as on
+/// WASI, the flag is never set.
+struct Timer {
+    stop: Option<mpsc::Sender<()>>,
+    thread: Option<std::thread::JoinHandle<()>>,
+}
+
+impl Timer {
+    fn start(time: Duration, flag: Arc<AtomicBool>) -> Timer {
+        let (stop, stopped) = mpsc::channel::<()>();
+        let thread = std::thread::Builder::new().spawn(move || {
+            // The sender is only dropped, so anything else is a timeout.
+            if let Err(mpsc::RecvTimeoutError::Timeout) = stopped.recv_timeout(time) {
+                flag.store(true, Ordering::Relaxed);
+            }
+        });
+        Timer {
+            stop: Some(stop),
+            thread: thread.ok(),
+        }
+    }
+}
+
+impl Drop for Timer {
+    fn drop(&mut self) {
+        self.stop.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// A seed for `--spot-check`, when none is given, from the time and the process ID.
+fn random_seed() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map_or(0, |now| now.as_nanos() as u64);
+    now ^ u64::from(std::process::id()).rotate_left(32)
+}
+
+/// Shuffle `items` in an order that only depends on `seed`, with the Fisher-Yates shuffle and
+/// splitmix64, so that a spot check can be repeated. The order doesn't need to be unpredictable.
+fn shuffle<T>(items: &mut [T], seed: u64) {
+    let mut state = seed;
+    let mut next = || {
+        state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        z ^ (z >> 31)
+    };
+    for i in (1..items.len()).rev() {
+        let j = (next() % (i as u64 + 1)) as usize;
+        items.swap(i, j);
+    }
+}
+
+/// Describe what a spot check covered. If none of the files failed, the share of bad files that
+/// could still be missed is bounded with 95% confidence, as for a random sample with replacement,
+/// which overestimates it slightly.
+fn spot_check_coverage(checked: usize, listed: usize, failures: usize, seed: u64) -> String {
+    let share = if listed > 0 {
+        checked as f64 * 100.0 / listed as f64
+    } else {
+        0.0
+    };
+    let result = if checked == 0 {
+        "nothing was checked".to_owned()
+    } else if failures == 0 {
+        let bound = 1.0 - 0.05f64.powf(1.0 / checked as f64);
+        format!(
+            "none failed, so with 95% confidence fewer than {:.2}% of the listed files are bad",
+            bound * 100.0
+        )
+    } else {
+        format!(
+            "{} failed, {:.2}% of the files that were checked",
+            failures,
+            failures as f64 * 100.0 / checked as f64
+        )
+    };
+    format!(
+        "spot check of {} of {} listed files ({:.1}%) with seed {}: {}",
+        checked, listed, share, seed, result
+    )
+}
+
+/// What happened to a listed file when it was checked, or to a file found with `--root`. With
+/// serde, it's written as its [name](FileStatus::name).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum FileStatus {
+    Ok,
+    /// It matched before, and `--verified-cache` found that it hasn't changed.
+    Unchanged,
+    Failed,
+    SizeMismatch,
+    /// Its contents matched, but its metadata was different from what `--metadata` recorded.
+    MetadataChanged,
+    Unreadable,
+    Missing,
+    /// The checkfiles list different checksums for it.
+    Conflict,
+    /// It was found with `--root`, but no checkfile lists it.
+    NotListed,
+    /// It's missing, but a file that no checkfile lists was found with `--root` that has its
+    /// checksum. The path that is reported is where it was found.
+    Moved,
+}
+
+impl FileStatus {
+    const ALL: [FileStatus; 10] = [
+        FileStatus::Ok,
+        FileStatus::Unchanged,
+        FileStatus::Failed,
+        FileStatus::SizeMismatch,
+        FileStatus::MetadataChanged,
+        FileStatus::Unreadable,
+        FileStatus::Missing,
+        FileStatus::Conflict,
+        FileStatus::NotListed,
+        FileStatus::Moved,
+    ];
+
+    /// The name of the status in a report.
+    pub fn name(self) -> &'static str {
+        match self {
+            FileStatus::Ok => "ok",
+            FileStatus::Unchanged => "unchanged",
+            FileStatus::Failed => "failed",
+            FileStatus::SizeMismatch => "size_mismatch",
+            FileStatus::MetadataChanged => "metadata_changed",
+            FileStatus::Unreadable => "unreadable",
+            FileStatus::Missing => "missing",
+            FileStatus::Conflict => "conflict",
+            FileStatus::NotListed => "not_listed",
+            FileStatus::Moved => "moved",
+        }
+    }
+
+    /// Whether the status fails the check.
+    pub fn is_failure(self) -> bool {
+        !matches!(
+            self,
+            FileStatus::Ok | FileStatus::Unchanged | FileStatus::Moved
+        )
+    }
+
+    /// The status of a file that couldn't be read because of `err`.
+    fn of_error(err: &(dyn Error + 'static)) -> Self {
+        if error_status_of(err) == EXIT_MISSING {
+            FileStatus::Missing
+        } else {
+            FileStatus::Unreadable
+        }
+    }
+}
+
+/// One file in a [`Report`].
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ReportedFile {
+    /// The name of the file in the checkfile. Files that no checkfile lists are named by their
+    /// path.
+    pub name: String,
+    /// Where the file was read, or for moved files, where it was found.
+    pub path: PathBuf,
+    pub status: FileStatus,
+    /// How long the file took to read, if it was read.
+    pub read_time: Option<Duration>,
+    /// The entropy of the file in bits per byte, with `--entropy`, if it was read.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub entropy: Option<f64>,
+    /// The ssdeep hash of the file, with `--ssdeep`, if it was read.
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "Option::is_none")
+    )]
+    pub ssdeep: Option<String>,
+}
+
+/// The results of a check, as written by `--report`.
+#[derive(Clone, Debug)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Report {
+    pub checkfiles: Vec<PathBuf>,
+    /// The files, sorted by name.
+    pub files: Vec<ReportedFile>,
+    /// The number of lines in the checkfiles that couldn't be parsed.
+    pub bad_lines: usize,
+    /// The number of bytes that were read. Files that `--verified-cache` skipped aren't counted.
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub bytes_read: u64,
+    /// How long the check took.
+    pub elapsed: Duration,
+    /// Whether the check passed, as given by the exit status.
+    pub passed: bool,
+}
+
+/// The results of a check, collected for `--report`. Files may be recorded from any thread.
+#[derive(Debug, Default)]
+pub struct VerifyReport {
+    files: Mutex<Vec<ReportedFile>>,
+}
+
+impl VerifyReport {
+    /// Record what happened to the file listed as `name`, and how long it took to read and its
+    /// entropy and ssdeep hash if it was read.
+    pub fn record(
+        &self,
+        name: &str,
+        path: &Path,
+        status: FileStatus,
+        read_time: Option<Duration>,
+        entropy: Option<f64>,
+        ssdeep: Option<&str>,
+    ) {
+        self.files.lock().unwrap().push(ReportedFile {
+            name: name.to_owned(),
+            path: path.to_owned(),
+            status,
+            read_time,
+            entropy,
+            ssdeep: ssdeep.map(str::to_owned),
+        });
+    }
+
+    /// The report of the files that were recorded, once the check is done.
+    pub fn finish(
+        &self,
+        checkfiles: &[PathBuf],
+        bad_lines: usize,
+        bytes_read: u64,
+        elapsed: Duration,
+        passed: bool,
+    ) -> Report {
+        let mut files = self.files.lock().unwrap().clone();
+        files.sort_by(|a, b| a.name.cmp(&b.name));
+        Report {
+            checkfiles: checkfiles.to_vec(),
+            files,
+            bad_lines,
+            bytes_read,
+            elapsed,
+            passed,
+        }
+    }
+}
+
+impl Report {
+    /// Write the report to its target, in its format. The file is replaced at once, so that
+    /// programs that read it, such as the textfile collector of the Prometheus node exporter,
+    /// never see it half written.
+    pub fn write(&self, target: &ReportTarget) -> Result<()> {
+        let contents = match target.format {
+            ReportFormat::Json => self.to_json(),
+            ReportFormat::Prometheus => {
+                // The last success is kept from the report before, if this check failed.
+                let previous = std::fs::read_to_string(&target.path).ok();
+                let last_success = previous.as_deref().and_then(|previous| {
+                    previous.lines().find_map(|line| {
+                        line.strip_prefix("b3sum_ng_check_last_success_timestamp_seconds ")?
+                            .parse()
+                            .ok()
+                    })
+                });
+                self.to_prometheus(SystemTime::now(), last_success)
+            }
+        };
+        Ok(write_atomically(&target.path, contents.as_bytes())?)
+    }
+
+    /// Format the report as metrics in the text format of Prometheus, for the textfile collector of
+    /// the node exporter, like `b3sum_ng_check_files{status="failed"} 0`. There are the numbers of
+    /// files of each status, the number of bad lines and of bytes read, how long the check took
+    /// and its speed in bytes per second, whether it passed, and the Unix times of this check and
+    /// of the last one that passed, which is `last_success` if this one didn't, or 0 if none has.
+    /// The files themselves aren't listed.
+    pub fn to_prometheus(&self, now: SystemTime, last_success: Option<f64>) -> String {
+        let mut text = String::new();
+        let mut metric = |name: &str, help: &str, values: &[(String, String)]| {
+            text.push_str(&format!("# HELP b3sum_ng_check_{} {}\n", name, help));
+            text.push_str(&format!("# TYPE b3sum_ng_check_{} gauge\n", name));
+            for (labels, value) in values {
+                text.push_str(&format!("b3sum_ng_check_{}{} {}\n", name, labels, value));
+            }
+        };
+        let counts: Vec<(String, String)> = FileStatus::ALL
+            .iter()
+            .map(|&status| {
+                let count = self
+                    .files
+                    .iter()
+                    .filter(|file| file.status == status)
+                    .count();
+                (
+                    format!("{{status=\"{}\"}}", status.name()),
+                    count.to_string(),
+                )
+            })
+            .collect();
+        metric(
+            "files",
+            "The number of listed files of each status in the last check.",
+            &counts,
+        );
+        let value = |value: String| [(String::new(), value)];
+        metric(
+            "bad_lines",
+            "The number of checkfile lines that couldn't be read in the last check.",
+            &value(self.bad_lines.to_string()),
+        );
+        metric(
+            "bytes_read",
+            "The number of bytes read by the last check.",
+            &value(self.bytes_read.to_string()),
+        );
+        let seconds = self.elapsed.as_secs_f64();
+        metric(
+            "duration_seconds",
+            "How long the last check took.",
+            &value(format!("{:.6}", seconds)),
+        );
+        let speed = if seconds > 0.0 {
+            self.bytes_read as f64 / seconds
+        } else {
+            0.0
+        };
+        metric(
+            "bytes_per_second",
+            "How fast the last check read files.",
+            &value(format!("{:.0}", speed)),
+        );
+        metric(
+            "passed",
+            "Whether the last check passed.",
+            &value((self.passed as u8).to_string()),
+        );
+        let now = now
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0.0, |time| time.as_secs_f64());
+        metric(
+            "last_run_timestamp_seconds",
+            "When the last check finished, in seconds since the Unix epoch.",
+            &value(format!("{:.3}", now)),
+        );
+        let last_success = if self.passed {
+            now
+        } else {
+            last_success.unwrap_or(0.0)
+        };
+        metric(
+            "last_success_timestamp_seconds",
+            "When the last check that passed finished, in seconds since the Unix epoch.",
+            &value(format!("{:.3}", last_success)),
+        );
+        text
+    }
+
+    /// Format the report as JSON, like `{"checkfiles":["B3SUMS"],"files":[{"name":"a",
+    /// "path":"a","status":"ok","seconds":0.001}],"totals":{"files":1,"ok":1,...,
+    /// "bad_lines":0,"bytes_read":11,"seconds":0.002},"passed":true}`. `seconds` is `null` for files that weren't
+    /// read. This doesn't need serde.
+    pub fn to_json(&self) -> String {
+        self.json_with(self.files.iter().collect())
+    }
+
+    /// Format the report as JSON like [`to_json`](Report::to_json), but only list the files that
+    /// failed, for `--notify-url` and `--on-fail`. The totals still count every file.
+    pub fn to_failure_json(&self) -> String {
+        let failures = self.files.iter().filter(|file| file.status.is_failure());
+        self.json_with(failures.collect())
+    }
+
+    /// Format the report as JSON, listing `listed` of its files.
+    fn json_with(&self, listed: Vec<&ReportedFile>) -> String {
+        let files = &self.files;
+        let checkfiles: Vec<String> = self
+            .checkfiles
+            .iter()
+            .map(|path| json_string(&path.to_string_lossy()))
+            .collect();
+        let file_lines: Vec<String> = listed
+            .iter()
+            .map(|file| {
+                // The entropy and ssdeep hash are left out unless they were computed, like with
+                // serde.
+                let entropy = file.entropy.map_or_else(String::new, |entropy| {
+                    format!(",\"entropy\":{:.6}", entropy)
+                });
+                let ssdeep = file.ssdeep.as_ref().map_or_else(String::new, |ssdeep| {
+                    format!(",\"ssdeep\":{}", json_string(ssdeep))
+                });
+                format!(
+                    "{{\"name\":{},\"path\":{},\"status\":\"{}\",\"seconds\":{}{}{}}}",
+                    json_string(&file.name),
+                    json_string(&file.path.to_string_lossy()),
+                    file.status.name(),
+                    file.read_time.map_or("null".to_owned(), |time| format!(
+                        "{:.6}",
+                        time.as_secs_f64()
+                    )),
+                    entropy,
+                    ssdeep
+                )
+            })
+            .collect();
+        let counts: Vec<String> = FileStatus::ALL
+            .iter()
+            .map(|&status| {
+                let count = files.iter().filter(|file| file.status == status).count();
+                format!("\"{}\":{}", status.name(), count)
+            })
+            .collect();
+        format!(
+            "{{\"checkfiles\":[{}],\"files\":[{}],\"totals\":{{\"files\":{},{},\"bad_lines\":{},\
+            \"bytes_read\":{},\"seconds\":{:.6}}},\"passed\":{}}}\n",
+            checkfiles.join(","),
+            file_lines.join(","),
+            files.len(),
+            counts.join(","),
+            self.bad_lines,
+            self.bytes_read,
+            self.elapsed.as_secs_f64(),
+            self.passed
+        )
+    }
+}
+
+/// How long a webhook of `--notify-url` may take to connect, and then to answer.
+const NOTIFY_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Post a JSON payload to a webhook, and check that it answers with a 2xx status. Only `http://`
+/// URLs are supported, since there's no TLS library, so HTTPS services need `--on-fail` with a
+/// tool like curl.
+fn post_json(url: &str, payload: &str) -> Result<()> {
+    use std::net::{TcpStream, ToSocketAddrs};
+
+    let rest = match url.strip_prefix("http://") {
+        Some(rest) => rest,
+        None if url.starts_with("https://") => {
+            return Err(
+                "only http:// URLs can be notified; use --on-fail with curl for HTTPS".into(),
+            )
+        }
+        None => return Err("the notification URL doesn't start with http://".into()),
+    };
+    let (authority, path) = match rest.find('/') {
+        Some(slash) => rest.split_at(slash),
+        None => (rest, "/"),
+    };
+    let mut addresses = socket_address(authority).to_socket_addrs()?;
+    let address = addresses
+        .next()
+        .ok_or("the host of the notification URL wasn't found")?;
+    let mut stream = TcpStream::connect_timeout(&address, NOTIFY_TIMEOUT)?;
+    stream.set_read_timeout(Some(NOTIFY_TIMEOUT))?;
+    stream.set_write_timeout(Some(NOTIFY_TIMEOUT))?;
+    write!(
+        stream,
+        "POST {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: b3sum-ng/{}\r\n\
+        Content-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        path,
+        authority,
+        env!("CARGO_PKG_VERSION"),
+        payload.len(),
+        payload
+    )?;
+    stream.flush()?;
+    // Only the status line of the response is needed.
+    let mut response = Vec::new();
+    stream.take(1024).read_to_end(&mut response)?;
+    let response = String::from_utf8_lossy(&response);
+    let status = response.lines().next().unwrap_or_default();
+    match status.split(' ').nth(1) {
+        Some(code) if code.starts_with('2') => Ok(()),
+        _ => Err(format!("the webhook answered {:?}", status).into()),
+    }
+}
+
+/// The host and port of the authority of an `http://` URL, like `example.com:80`, with port 80 if
+/// it has none. An IPv6 address is in brackets, like `[::1]:8080`, so only a colon after the
+/// closing bracket starts the port.
+fn socket_address(authority: &str) -> String {
+    let host_end = authority.rfind(']').map_or(0, |bracket| bracket + 1);
+    if authority[host_end..].contains(':') {
+        authority.to_owned()
+    } else {
+        format!("{}:80", authority)
+    }
+}
+
+/// Run a shell command, with a JSON payload on its standard input, and check that it succeeds.
+fn run_hook(command: &str, payload: &str) -> Result<()> {
+    use std::process::{Command, Stdio};
+
+    let mut child = if cfg!(windows) {
+        Command::new("cmd")
+            .arg("/C")
+            .arg(command)
+            .stdin(Stdio::piped())
+            .spawn()?
+    } else {
+        Command::new("sh")
+            .arg("-c")
+            .arg(command)
+            .stdin(Stdio::piped())
+            .spawn()?
+    };
+    // The command may not read its input, so a broken pipe isn't an error.
+    let _ = child.stdin.take().unwrap().write_all(payload.as_bytes());
+    let status = child.wait()?;
+    if status.success() {
+        Ok(())
+    } else {
+        Err(format!("the command failed with {}", status).into())
+    }
+}
+
+/// Read all entries of a checkfile, or standard input if the path is `-`. Also returns the number
+/// of lines that could not be parsed.
+///
+/// Files ending in `.sfv` are read as SFV files no matter what `format` is. Filenames in an SFV
+/// file are relative to the directory containing it, as other SFV tools expect, while filenames
+/// in a BLAKE3 checkfile are relative to the current directory.
+///
+/// Files written by md5sum, sha1sum, sha256sum and hashdeep are also accepted. Their algorithm is
+/// detected from the digest length, but SHA-256 digests are as long as BLAKE3 digests, so they are
+/// only recognized if the checkfile is named like `SHA256SUMS` or `files.sha256`. The lines are
+/// read by [`checkfile::parse`](crate::checkfile::parse).
+///
+/// If the checkfile ends with a seal, it must match the rest of the checkfile. With `require_seal`,
+/// it must have one, and when `mode` is keyed, it must be a keyed seal. Likewise, if it's chained,
+/// its chain must be unbroken, and with `chain_head`, it must end there.
+pub(crate) fn read_checkfile(
+    path: &Path,
+    format: Format,
+    verify: &VerifyOptions,
+    mode: &Blake3Mode,
+) -> Result<(Vec<CheckEntry>, usize)> {
+    let dialect = Dialect::of_path(path, format);
+    let contents = read_contents(path)?;
+    match check_seal(&contents, mode)? {
+        None if verify.require_seal => return Err("the checkfile has no seal".into()),
+        Some(seal) if verify.require_seal && !seal.keyed && *mode != Blake3Mode::Hash => {
+            return Err("the checkfile's seal isn't keyed".into())
+        }
+        _ => {}
+    }
+    match (check_chain(&contents)?, &verify.chain_head) {
+        (Some(head), Some(expected)) if head != *expected => {
+            return Err(
+                "the chain of the checkfile doesn't end at the head that was given, so \
+                records were removed from its end or appended to it"
+                    .into(),
+            )
+        }
+        (None, Some(_)) => return Err("the checkfile isn't chained".into()),
+        _ => {}
+    }
+    let base_dir = base_dir(path, dialect);
+
+    let (parsed, bad_lines) = parse(&mut &contents[..], dialect)?;
+    let mut entries = Vec::with_capacity(parsed.len());
+    for entry in parsed {
+        if !entry.algorithm.is_available() {
+            return Err(format!(
+                "{} checksums can only be verified when b3sum-ng is built with the \
+                extra-algorithms feature",
+                entry.algorithm
+            )
+            .into());
+        }
+        entries.push(CheckEntry {
+            path: base_dir.join(&entry.name),
+            name: entry.name,
+            expected: entry.checksum,
+            algorithm: entry.algorithm,
+            size: entry.size,
+            metadata: entry.metadata,
+            pieces: entry.pieces,
+        });
+    }
+    Ok((entries, bad_lines.len()))
+}
+
+/// The paths of the files listed in a checkfile, in the order they're listed, and the number of
+/// lines that couldn't be read, for `--from-manifest`. The checksums are ignored, so the
+/// checkfile's seal and chain aren't checked, and its algorithm doesn't need to be available.
+/// Paths are found as in [`check`].
+pub fn listed_paths(path: &Path, format: Format) -> Result<(Vec<PathBuf>, usize)> {
+    let dialect = Dialect::of_path(path, format);
+    let contents = read_contents(path)?;
+    let base_dir = base_dir(path, dialect);
+    let (parsed, bad_lines) = parse(&mut &contents[..], dialect)?;
+    let paths = parsed
+        .into_iter()
+        .map(|entry| base_dir.join(entry.name))
+        .collect();
+    Ok((paths, bad_lines.len()))
+}
+
+/// The contents of a checkfile, or of standard input for `-`.
+fn read_contents(path: &Path) -> Result<Vec<u8>> {
+    let mut contents = Vec::new();
+    if path.to_str() == Some("-") {
+        std::io::stdin().read_to_end(&mut contents)?;
+    } else {
+        File::open(path)?.read_to_end(&mut contents)?;
+    }
+    Ok(contents)
+}
+
+/// The directory that the paths in a checkfile are relative to.
+fn base_dir(path: &Path, dialect: Dialect) -> PathBuf {
+    match path.parent() {
+        Some(parent) if dialect.sfv && path.to_str() != Some("-") => parent.to_owned(),
+        _ => PathBuf::new(),
+    }
+}
+
+/// Options for finding the files listed in checkfiles somewhere else, such as a backup that is
+/// mounted at a different path than the files were checksummed at, or on another platform, and
+/// for checking only some of them.
+#[derive(Clone, Default)]
+pub struct VerifyOptions {
+    /// The number of leading directories to remove from each path in the checkfiles.
+    pub strip_prefix: usize,
+    /// Read files from another directory. The first one whose `old` directory starts the path
+    /// is used, after `strip_prefix`.
+    pub path_map: Vec<PathMap>,
+    /// Find files whose names differ in case, and read backslashes as directory separators.
+    pub ignore_case: bool,
+    /// If any are given, only the files whose names match one of these patterns are checked.
+    pub only: Vec<String>,
+    /// Stop at the first file that fails or can't be read.
+    pub fail_fast: bool,
+    /// Also report the files in this directory that no checkfile lists, and find missing files
+    /// among them.
+    pub root: Option<PathBuf>,
+    /// Skip files that haven't changed since they last matched, as recorded in this file.
+    pub verified_cache: Option<PathBuf>,
+    /// Write a report of every file to this file.
+    pub report: Option<ReportTarget>,
+    /// If the check fails, post the files that failed to this `http://` URL as JSON.
+    pub notify_url: Option<String>,
+    /// If the check fails, run this shell command with the files that failed as JSON on its
+    /// standard input.
+    pub on_fail: Option<String>,
+    /// Fail checkfiles that don't end with a [`Seal`](crate::checkfile::Seal). Seals are checked
+    /// either way.
+    pub require_seal: bool,
+    /// Move, rename or delete the files whose contents don't match.
+    pub on_mismatch: Option<MismatchAction>,
+    /// Fail checkfiles that aren't chained with `--chain`, or whose chain doesn't end at this
+    /// head. Chains are checked either way.
+    pub chain_head: Option<Checksum>,
+    /// Record when each file last matched in this file.
+    pub last_verified: Option<PathBuf>,
+    /// Only check this many bytes of files, the ones that were verified longest ago according to
+    /// `last_verified` first. The rest are left for later checks.
+    pub budget: Option<u64>,
+    /// Only check a random part of the files.
+    pub spot_check: Option<SpotCheck>,
+    /// The seed of the random order of `spot_check`. Without one, the order is different each
+    /// time, and the seed is printed.
+    pub seed: Option<u64>,
+}
+
+impl VerifyOptions {
+    /// Rewrite a path from a checkfile as given by `--strip-prefix`, `--path-map` and
+    /// `--ignore-case`.
+    pub fn apply(&self, path: &Path) -> PathBuf {
+        let folded;
+        let path = match path.to_str() {
+            Some(str) if self.ignore_case && cfg!(not(windows)) && str.contains('\\') => {
+                folded = PathBuf::from(str.replace('\\', "/"));
+                &folded
+            }
+            _ => path,
+        };
+        let path: PathBuf = if self.strip_prefix > 0 {
+            path.components()
+                .filter(|component| matches!(component, std::path::Component::Normal(_)))
+                .skip(self.strip_prefix)
+                .collect()
+        } else {
+            path.to_owned()
+        };
+        let path = match self
+            .path_map
+            .iter()
+            .find_map(|map| Some(map.new.join(path.strip_prefix(&map.old).ok()?)))
+        {
+            Some(mapped) => mapped,
+            None => path,
+        };
+        if self.ignore_case {
+            find_ignoring_case(&path)
+        } else {
+            path
+        }
+    }
+
+    /// Whether a file with `name` in a checkfile is checked, as given by `--only`.
+    pub fn selects(&self, name: &str) -> bool {
+        self.only.is_empty()
+            || self
+                .only
+                .iter()
+                .any(|pattern| path_pattern_match(pattern, Path::new(name)))
+    }
+}
+
+/// Find the file that `path` names, if its name, or any directory in it, differs in case from the
+/// names on disk. If the path exists as it is, or it can't be found, it is returned unchanged.
+fn find_ignoring_case(path: &Path) -> PathBuf {
+    if std::fs::symlink_metadata(path).is_ok() {
+        return path.to_owned();
+    }
+    let mut found = PathBuf::new();
+    for component in path.components() {
+        let name = match component {
+            std::path::Component::Normal(name) => name,
+            _ => {
+                found.push(component);
+                continue;
+            }
+        };
+        let exact = found.join(name);
+        if std::fs::symlink_metadata(&exact).is_ok() {
+            found = exact;
+            continue;
+        }
+        let dir = if found.as_os_str().is_empty() {
+            Path::new(".")
+        } else {
+            &found
+        };
+        let wanted = name.to_string_lossy().to_lowercase();
+        let matching = std::fs::read_dir(dir).ok().and_then(|entries| {
+            entries
+                .flatten()
+                .map(|entry| entry.file_name())
+                .find(|entry| entry.to_string_lossy().to_lowercase() == wanted)
+        });
+        match matching {
+            Some(matching) => found.push(matching),
+            None => return path.to_owned(),
+        }
+    }
+    found
+}
+
+/// Where `--report` writes a report. The format is given before the path, as in `json=report.json`
+/// or `prometheus=/var/lib/node_exporter/b3sum.prom`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReportTarget {
+    pub format: ReportFormat,
+    pub path: PathBuf,
+}
+
+/// The formats of `--report`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReportFormat {
+    /// See [`Report::to_json`].
+    Json,
+    /// See [`Report::to_prometheus`].
+    Prometheus,
+}
+
+impl FromStr for ReportTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (format, path) = match s.split_once('=') {
+            Some((format, path)) if !path.is_empty() => (format, path),
+            _ => return Err(format!("expected json=file or prometheus=file, not {}", s)),
+        };
+        let format = match format {
+            "json" => ReportFormat::Json,
+            "prometheus" => ReportFormat::Prometheus,
+            _ => return Err(format!("unknown report format: {}", format)),
+        };
+        Ok(ReportTarget {
+            format,
+            path: path.into(),
+        })
+    }
+}
+
+/// What `--on-mismatch` does with a file whose contents don't match its checksum, so that it can
+/// be inspected or restored from a backup.
+#[derive(Clone, Debug, PartialEq)]
+pub enum MismatchAction {
+    /// Move the file into this directory, under the path it was read from, so that files with the
+    /// same name in different directories don't collide.
+    Move(PathBuf),
+    /// Delete the file.
+    Delete,
+    /// Rename the file to its name with this suffix, `.mismatch` unless another is given.
+    RenameSuffix(OsString),
+}
+
+impl MismatchAction {
+    /// Move, rename or delete a file that failed. Returns the path it was moved to, or `None` if it
+    /// was deleted. A file is never moved over another one, even one that appears while it's
+    /// moved: it's moved by making a hard link to it, which fails if the target exists, and then
+    /// deleting it. Where a link can't be made, such as on a different filesystem than the
+    /// directory, it's copied into a file that's created new, and then deleted.
+    pub fn apply(&self, path: &Path) -> Result<Option<PathBuf>> {
+        let target = match self {
+            MismatchAction::Delete => {
+                std::fs::remove_file(path)?;
+                return Ok(None);
+            }
+            MismatchAction::Move(dir) => dir.join(
+                path.components()
+                    .filter(|component| matches!(component, std::path::Component::Normal(_)))
+                    .collect::<PathBuf>(),
+            ),
+            MismatchAction::RenameSuffix(suffix) => {
+                let mut name = path.as_os_str().to_owned();
+                name.push(suffix);
+                PathBuf::from(name)
+            }
+        };
+        if let Some(parent) = target.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        let exists = |err: std::io::Error| -> Box<dyn Error> {
+            if err.kind() == std::io::ErrorKind::AlreadyExists {
+                format!("can't move it to {}, which exists", target.display()).into()
+            } else {
+                err.into()
+            }
+        };
+        match std::fs::hard_link(path, &target) {
+            Ok(()) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::AlreadyExists => return Err(exists(err)),
+            Err(_) => {
+                let mut copy = std::fs::OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(&target)
+                    .map_err(exists)?;
+                let copied = File::open(path)
+                    .and_then(|mut file| std::io::copy(&mut file, &mut copy))
+                    .and_then(|_| std::fs::set_permissions(&target, metadata(path)?.permissions()));
+                if let Err(err) = copied {
+                    let _ = std::fs::remove_file(&target);
+                    return Err(err.into());
+                }
+            }
+        }
+        std::fs::remove_file(path)?;
+        Ok(Some(target))
+    }
+}
+
+impl FromStr for MismatchAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once(':') {
+            None if s == "delete" => Ok(MismatchAction::Delete),
+            None if s == "rename-suffix" => Ok(MismatchAction::RenameSuffix(".mismatch".into())),
+            Some(("move", dir)) if !dir.is_empty() => Ok(MismatchAction::Move(dir.into())),
+            Some(("rename-suffix", suffix)) if !suffix.is_empty() => {
+                Ok(MismatchAction::RenameSuffix(suffix.into()))
+            }
+            _ => Err(format!(
+                "expected move:dir, delete or rename-suffix[:suffix], not {}",
+                s
+            )),
+        }
+    }
+}
+
+/// One `--path-map`: paths under `old` are read from under `new`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PathMap {
+    pub old: PathBuf,
+    pub new: PathBuf,
+}
+
+impl FromStr for PathMap {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split_once('=') {
+            Some((old, new)) if !old.is_empty() => Ok(PathMap {
+                old: old.into(),
+                new: new.into(),
+            }),
+            _ => Err(format!("expected old=new, not {}", s)),
+        }
+    }
+}
+
+#[test]
+fn b3_test_read_sfv() -> Result<()> {
+    let (_file, path, _guard) =
+        make_temp_file(b"; comment\r\nhello.txt 0D4A1185\r\n\r\nbad line\r\n");
+    let (entries, bad_lines) = read_checkfile(
+        &path,
+        Format::Sfv,
+        &VerifyOptions::default(),
+        &Blake3Mode::Hash,
+    )?;
+    assert_eq!(1, bad_lines);
+    assert_eq!(1, entries.len());
+    assert_eq!("hello.txt", entries[0].name);
+    assert_eq!(path.parent().unwrap().join("hello.txt"), entries[0].path);
+    Ok(())
+}
+
+#[test]
+fn b3_test_listed_paths() -> Result<()> {
+    let manifest = format!(
+        "{}  zebra.txt\nbad line\n{}  dir/apple.txt\n",
+        "0".repeat(64),
+        "d41d8cd98f00b204e9800998ecf8427e",
+    );
+    let (_file, path, _guard) = make_temp_file(manifest.as_bytes());
+    let (paths, bad_lines) = listed_paths(&path, Format::B3sum)?;
+    assert_eq!(1, bad_lines);
+    let expected: Vec<PathBuf> = vec!["zebra.txt".into(), "dir/apple.txt".into()];
+    assert_eq!(expected, paths);
+    Ok(())
+}
+
+#[test]
+fn b3_test_check() {
+    let (_file, path, _guard) = make_temp_file(b"hello world");
+    let (_file, bad_path, _bad_guard) = make_temp_file(b"hello, world");
+    let checkfile = format!(
+        "d74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24  {}\n",
+        path.display()
+    );
+    let (_file, checkfile, _checkfile_guard) = make_temp_file(checkfile.as_bytes());
+    let sfv = format!("{} 0D4A1185\n", path.file_name().unwrap().to_string_lossy());
+    let (_file, sfv, _sfv_guard) = make_temp_file(sfv.as_bytes());
+    let semaphore = Arc::new(Semaphore::new(16));
+    assert!(check(
+        &[checkfile],
+        &Config::default(),
+        Arc::clone(&semaphore),
+        Format::B3sum,
+        &VerifyOptions::default()
+    ));
+    assert!(check(
+        &[sfv],
+        &Config::default(),
+        Arc::clone(&semaphore),
+        Format::Sfv,
+        &VerifyOptions::default()
+    ));
+
+    let sfv = format!(
+        "{} 0D4A1185\n",
+        bad_path.file_name().unwrap().to_string_lossy()
+    );
+    let (_file, sfv, _sfv_guard) = make_temp_file(sfv.as_bytes());
+    assert!(!check(
+        &[sfv],
+        &Config::default(),
+        semaphore,
+        Format::Sfv,
+        &VerifyOptions::default()
+    ));
+}
+
+#[test]
+#[cfg(feature = "extra-algorithms")]
+fn b3_test_check_hashdeep() {
+    let (_file, path, _guard) = make_temp_file(b"hello world");
+    let checkfile = format!(
+        "%%%% HASHDEEP-1.0\n\
+        %%%% size,md5,filename\n\
+        ## Invoked from: /tmp\n\
+        ##\n\
+        11,5eb63bbbe01eeed093cb22bb8f5acdc3,{}\n",
+        path.display()
+    );
+    let (_file, checkfile, _checkfile_guard) = make_temp_file(checkfile.as_bytes());
+    assert!(check(
+        std::slice::from_ref(&checkfile),
+        &Config::default(),
+        Arc::new(Semaphore::new(16)),
+        Format::B3sum,
+        &VerifyOptions::default()
+    ));
+
+    // A file of the wrong size fails without being read, and so it's left alone by
+    // `--on-mismatch`.
+    let (entries, _) = read_checkfile(
+        &checkfile,
+        Format::B3sum,
+        &VerifyOptions::default(),
+        &Blake3Mode::Hash,
+    )
+    .unwrap();
+    assert_eq!(Some(11), entries[0].size);
+    std::fs::write(&path, b"hello").unwrap();
+    let options = VerifyOptions {
+        on_mismatch: Some(MismatchAction::Delete),
+        ..VerifyOptions::default()
+    };
+    assert!(!check(
+        &[checkfile],
+        &Config::default(),
+        Arc::new(Semaphore::new(16)),
+        Format::B3sum,
+        &options
+    ));
+    assert!(path.exists());
+}
+
+#[test]
+fn b3_test_check_seal() -> Result<()> {
+    let (_file, path, _guard) = make_temp_file(b"hello world");
+    let body = format!(
+        "d74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24  {}\n",
+        path.display()
+    );
+    let sealed = format!(
+        "{}{}\n",
+        body,
+        Seal::of(body.as_bytes(), &Blake3Mode::Hash).to_line(Format::B3sum)
+    );
+    let semaphore = Arc::new(Semaphore::new(16));
+    let require_seal = VerifyOptions {
+        require_seal: true,
+        ..VerifyOptions::default()
+    };
+    let check_keyed = |contents: &str, options: &VerifyOptions, mode: &Blake3Mode| {
+        let (_file, checkfile, _guard) = make_temp_file(contents.as_bytes());
+        let config = Config {
+            blake3_mode: mode.clone(),
+            ..Config::default()
+        };
+        check(
+            &[checkfile],
+            &config,
+            Arc::clone(&semaphore),
+            Format::B3sum,
+            options,
+        )
+    };
+    let check_contents =
+        |contents: &str, options: &VerifyOptions| check_keyed(contents, options, &Blake3Mode::Hash);
+    assert!(check_contents(&sealed, &require_seal));
+    assert!(check_contents(&body, &VerifyOptions::default()));
+    assert!(!check_contents(&body, &require_seal));
+    let tampered = sealed.replacen("d7", "00", 1);
+    assert!(!check_contents(&tampered, &VerifyOptions::default()));
+
+    // A keyed seal needs the key, and with a key, a plain seal doesn't satisfy require_seal.
+    let key = Blake3Mode::Keyed([7; blake3::KEY_LEN]);
+    let keyed_body = format!(
+        "{}  {}\n",
+        blake3::keyed_hash(&[7; blake3::KEY_LEN], b"hello world").to_hex(),
+        path.display()
+    );
+    let keyed = format!(
+        "{}{}\n",
+        keyed_body,
+        Seal::of(keyed_body.as_bytes(), &key).to_line(Format::B3sum)
+    );
+    assert!(check_keyed(&keyed, &require_seal, &key));
+    assert!(!check_keyed(
+        &keyed,
+        &VerifyOptions::default(),
+        &Blake3Mode::Hash
+    ));
+    let other_key = Blake3Mode::Keyed([8; blake3::KEY_LEN]);
+    assert!(!check_keyed(&keyed, &VerifyOptions::default(), &other_key));
+    let plain = format!(
+        "{}{}\n",
+        keyed_body,
+        Seal::of(keyed_body.as_bytes(), &Blake3Mode::Hash).to_line(Format::B3sum)
+    );
+    assert!(check_keyed(&plain, &VerifyOptions::default(), &key));
+    assert!(!check_keyed(&plain, &require_seal, &key));
+    Ok(())
+}
+
+#[test]
+fn b3_test_check_chain() -> Result<()> {
+    let (_file, path, _guard) = make_temp_file(b"hello world");
+    let record = format!(
+        "# chain {}\nd74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24  {}\n",
+        chain_start(),
+        path.display()
+    );
+    let head = chain_link(record.as_bytes());
+    let semaphore = Arc::new(Semaphore::new(16));
+    let check_contents = |contents: &str, chain_head: Option<&Checksum>| {
+        let (_file, checkfile, _guard) = make_temp_file(contents.as_bytes());
+        let options = VerifyOptions {
+            chain_head: chain_head.cloned(),
+            ..VerifyOptions::default()
+        };
+        check(
+            &[checkfile],
+            &Config::default(),
+            Arc::clone(&semaphore),
+            Format::B3sum,
+            &options,
+        )
+    };
+    assert!(check_contents(&record, None));
+    assert!(check_contents(&record, Some(&head)));
+    assert!(!check_contents(&record, Some(&chain_start())));
+    assert!(!check_contents(&format!("{}{}", record, record), None));
+    let unchained = record.lines().nth(1).unwrap().to_owned();
+    assert!(check_contents(&unchained, None));
+    assert!(!check_contents(&unchained, Some(&head)));
+    Ok(())
+}
+
+#[test]
+fn b3_test_check_metadata() -> Result<()> {
+    let (_file, path, _guard) = make_temp_file(b"hello world");
+    let recorded = FileMetadata::of(&metadata(&path)?);
+    let semaphore = Arc::new(Semaphore::new(16));
+    let check_metadata = |metadata: &FileMetadata| {
+        let contents = format!(
+            "{}\nd74981efa70a0c880b8d8c1985d075dbcbf679b99a5f9914e5aaf96b831a9e24  {}\n",
+            metadata.to_line(Format::B3sum),
+            path.display()
+        );
+        let (_file, checkfile, _guard) = make_temp_file(contents.as_bytes());
+        check(
+            &[checkfile],
+            &Config::default(),
+            Arc::clone(&semaphore),
+            Format::B3sum,
+            &VerifyOptions::default(),
+        )
+    };
+    assert!(check_metadata(&recorded));
+    let touched = FileMetadata {
+        mtime: recorded.mtime.map(|mtime| mtime + Duration::from_secs(1)),
+        ..recorded.clone()
+    };
+    assert!(!check_metadata(&touched));
+    assert!(check_metadata(&FileMetadata::default()));
+    Ok(())
+}
+
+#[test]
+fn b3_test_check_pieces() -> Result<()> {
+    let mut data: Vec<u8> = (0..10_000u32).map(|i| (i % 251) as u8).collect();
+    let (_file, path, _guard) = make_temp_file(&data);
+    let config = Config {
+        piecewise: Some(1000),
+        ..Config::default()
+    };
+    let hashed = hash_small(&mut &data[..], &config)?;
+    let entry = crate::checkfile::Entry {
+        name: path.to_string_lossy().into_owned(),
+        checksum: hashed.checksum,
+        algorithm: Algorithm::Blake3,
+        size: None,
+        timestamp: None,
+        metadata: None,
+        pieces: hashed.pieces,
+    };
+    assert_eq!(10, entry.pieces.len());
+    let contents = crate::checkfile::format_line(&entry, Format::B3sum).unwrap() + "\n";
+    let (_file, checkfile, _checkfile_guard) = make_temp_file(contents.as_bytes());
+    let check_file = || {
+        check(
+            std::slice::from_ref(&checkfile),
+            &Config::default(),
+            Arc::new(Semaphore::new(16)),
+            Format::B3sum,
+            &VerifyOptions::default(),
+        )
+    };
+    assert!(check_file());
+    data[4321] ^= 1;
+    std::fs::write(&path, &data)?;
+    assert!(!check_file());
+
+    let mut changed = entry.pieces.clone();
+    changed[4].checksum = Checksum(vec![0; 32]);
+    changed[5].checksum = Checksum(vec![0; 32]);
+    changed[8].checksum = Checksum(vec![0; 32]);
+    assert_eq!(
+        "4000-5999, 8000-8999",
+        changed_ranges(&entry.pieces, &changed)
+    );
+    assert_eq!(
+        "9000-9999",
+        changed_ranges(&entry.pieces, &entry.pieces[..9])
+    );
+    assert_eq!("unknown", changed_ranges(&entry.pieces, &entry.pieces));
+    Ok(())
+}
+
+#[test]
+fn b3_test_verified_cache() -> Result<()> {
+    let (_file, path, _guard) = make_temp_file(b"contents");
+    let checksum = b3sum_small(&mut &b"contents"[..], &Config::default())?;
+    let checkfile = format!("{}  {}\n", checksum, path.display());
+    let (_file, checkfile, _checkfile_guard) = make_temp_file(checkfile.as_bytes());
+    let (_file, cache, _cache_guard) = make_temp_file(b"");
+    let options = VerifyOptions {
+        verified_cache: Some(cache.clone()),
+        ..VerifyOptions::default()
+    };
+    let check = |options: &VerifyOptions| {
+        check(
+            std::slice::from_ref(&checkfile),
+            &Config::default(),
+            Arc::new(Semaphore::new(16)),
+            Format::B3sum,
+            options,
+        )
+    };
+    assert!(check(&options));
+    let stamp = FileStamp::of(&metadata(&path)?).unwrap();
+    assert!(VerifiedCache::load(&cache)?.is_unchanged(&path, stamp, &checksum));
+
+    // Record the stamp of a changed file as if it had matched, so it isn't read again.
+    std::fs::write(&path, b"changed!")?;
+    let stamp = FileStamp::of(&metadata(&path)?).unwrap();
+    let mut verified = VerifiedCache::load(&cache)?;
+    verified.record(&path, stamp, checksum.clone());
+    verified.save(&cache)?;
+    assert!(check(&options));
+    assert!(!check(&VerifyOptions::default()));
+
+    // A file that fails is forgotten.
+    std::fs::write(&path, b"changed again")?;
+    assert!(!check(&options));
+    assert!(!VerifiedCache::load(&cache)?.files.contains_key(&path));
+    Ok(())
+}
+
+#[test]
+fn b3_test_timer() {
+    // Dropping it stops its thread at once, and the thread's copy of the flag is dropped.
+    let flag = Arc::new(AtomicBool::new(false));
+    let start = Instant::now();
+    drop(Timer::start(Duration::from_secs(60), Arc::clone(&flag)));
+    assert!(start.elapsed() < Duration::from_secs(30));
+    assert!(!flag.load(Ordering::Relaxed));
+    assert_eq!(1, Arc::strong_count(&flag));
+
+    let timer = Timer::start(Duration::from_millis(1), Arc::clone(&flag));
+    std::thread::sleep(Duration::from_millis(100));
+    assert!(flag.load(Ordering::Relaxed));
+    drop(timer);
+}
+
+#[test]
+fn b3_test_spot_check() -> Result<()> {
+    assert_eq!(Ok(SpotCheck::Percent(10.0)), "10%".parse());
+    assert_eq!(
+        Ok(SpotCheck::Time(Duration::from_secs(15 * 60))),
+        "15min".parse()
+    );
+    assert!("0%".parse::<SpotCheck>().is_err());
+    assert!("150%".parse::<SpotCheck>().is_err());
+
+    let mut items: Vec<u32> = (0..100).collect();
+    let mut again = items.clone();
+    shuffle(&mut items, 7);
+    shuffle(&mut again, 7);
+    assert_eq!(items, again);
+    assert_ne!((0..100).collect::<Vec<u32>>(), items);
+    items.sort_unstable();
+    assert_eq!((0..100).collect::<Vec<u32>>(), items);
+
+    let mut files = Vec::new();
+    let mut checkfile = String::new();
+    for i in 0..10 {
+        let contents = format!("file {}", i);
+        let (_file, path, guard) = make_temp_file(contents.as_bytes());
+        let checksum = b3sum_small(&mut contents.as_bytes(), &Config::default())?;
+        checkfile.push_str(&format!("{}  {}\n", checksum, path.display()));
+        files.push(guard);
+    }
+    let (_file, checkfile, _guard) = make_temp_file(checkfile.as_bytes());
+    let (_file, report, _report_guard) = make_temp_file(b"");
+    let options = VerifyOptions {
+        spot_check: Some(SpotCheck::Percent(25.0)),
+        seed: Some(1),
+        report: Some(format!("json={}", report.display()).parse()?),
+        ..VerifyOptions::default()
+    };
+    let io_lock = Arc::new(Semaphore::new(4));
+    assert!(check(
+        &[checkfile],
+        &Config::default(),
+        io_lock,
+        Format::B3sum,
+        &options
+    ));
+    // A quarter of the files, rounded up.
+    let contents = std::fs::read_to_string(&report)?;
+    assert!(
+        contents.contains("\"totals\":{\"files\":3,\"ok\":3,"),
+        "{}",
+        contents
+    );
+
+    assert_eq!(
+        "spot check of 300 of 48000 listed files (0.6%) with seed 1: none failed, so with 95% \
+        confidence fewer than 0.99% of the listed files are bad",
+        spot_check_coverage(300, 48000, 0, 1)
+    );
+    assert_eq!(
+        "spot check of 300 of 48000 listed files (0.6%) with seed 1: 3 failed, 1.00% of the files \
+        that were checked",
+        spot_check_coverage(300, 48000, 3, 1)
+    );
+    Ok(())
+}
+
+#[test]
+fn b3_test_check_budget() -> Result<()> {
+    let (_file, first, _first_guard) = make_temp_file(b"first");
+    let (_file, second, _second_guard) = make_temp_file(b"second");
+    let (_file, third, _third_guard) = make_temp_file(b"third");
+    let mut checkfile = String::new();
+    for (path, contents) in [(&first, "first"), (&second, "second"), (&third, "third")].iter() {
+        let checksum = b3sum_small(&mut contents.as_bytes(), &Config::default())?;
+        checkfile += &format!("{}  {}\n", checksum, path.display());
+    }
+    let (_file, checkfile, _checkfile_guard) = make_temp_file(checkfile.as_bytes());
+    let (_file, log, _log_guard) = make_temp_file(b"");
+    let options = VerifyOptions {
+        last_verified: Some(log.clone()),
+        budget: Some(6),
+        ..VerifyOptions::default()
+    };
+    let check = || {
+        check(
+            std::slice::from_ref(&checkfile),
+            &Config::default(),
+            Arc::new(Semaphore::new(16)),
+            Format::B3sum,
+            &options,
+        )
+    };
+    let verified = |path: &PathBuf| LastVerified::load(&log).unwrap().files.get(path).copied();
+
+    // Only one file fits in the budget each time, and the files that were never verified are
+    // checked first.
+    assert!(check());
+    assert!(verified(&first).is_some());
+    assert_eq!((None, None), (verified(&second), verified(&third)));
+    assert!(check());
+    assert!(verified(&second) > verified(&first));
+    assert_eq!(None, verified(&third));
+    assert!(check());
+    assert!(verified(&third) > verified(&second));
+
+    // Then the file that was verified longest ago.
+    assert!(check());
+    assert!(verified(&first) > verified(&third));
+
+    // A file that fails isn't recorded, so it's checked first next time.
+    let before = verified(&second);
+    std::fs::write(&second, b"changed")?;
+    assert!(!check());
+    assert!(!check());
+    assert_eq!(before, verified(&second));
+    Ok(())
+}
+
+#[test]
+fn b3_test_remote_verify() -> Result<()> {
+    let (_file, path, _guard) = make_temp_file(b"hello");
+    let checksum = b3sum_small(&mut &b"hello"[..], &Config::default())?;
+    let lines = format!(
+        "{c}  {p}\n{}  {p}\n{c}  {p}.missing\n",
+        "0".repeat(64),
+        c = checksum,
+        p = path.file_name().unwrap().to_string_lossy()
+    );
+    let (_file, checkfile, _checkfile_guard) = make_temp_file(lines.as_bytes());
+    let (entries, _) = read_checkfile(
+        &checkfile,
+        Format::B3sum,
+        &VerifyOptions::default(),
+        &Blake3Mode::Hash,
+    )?;
+    let mut requests: String = entries
+        .iter()
+        .map(|entry| remote_request(entry) + "\n")
+        .collect();
+    requests.push_str("not a checkfile line\n# a comment\nBLAKE3 (-) = 00\n");
+    // Only the files under the served directory can be asked about.
+    let outside = [
+        path.clone(),
+        Path::new("..").join(path.file_name().unwrap()),
+    ];
+    for outside in &outside {
+        requests.push_str(&format!("BLAKE3 ({}) = {}\n", outside.display(), checksum));
+    }
+    let mut responses = Vec::new();
+    let root = path.parent().unwrap();
+    serve_remote(
+        requests.as_bytes(),
+        &mut responses,
+        root,
+        &Config::default(),
+    )?;
+    let responses = String::from_utf8(responses)?;
+    let answers: Vec<&str> = responses
+        .lines()
+        .map(|line| line.split(' ').next().unwrap())
+        .collect();
+    assert_eq!(
+        vec!["match", "mismatch", "error", "error", "error", "error", "error", "error"],
+        answers
+    );
+
+    let checkfiles = [checkfile];
+    let verify = |responses: &str| {
+        remote_verify(
+            &checkfiles,
+            Format::B3sum,
+            std::io::sink(),
+            responses.as_bytes(),
+        )
+    };
+    assert!(!verify(&responses));
+    assert!(verify("match\nmatch\nmatch\n"));
+    assert!(!verify("match\n"));
+    Ok(())
+}
+
+#[test]
+fn b3_test_compare_paths() -> Result<()> {
+    let root = std::env::temp_dir().join(format!("b3sum-cmp-{}", std::process::id()));
+    let _guard = TempDirGuard { dir: root.clone() };
+    for (path, contents) in &[
+        ("a/same.txt", "hello world"),
+        ("a/sub/changed.txt", "hello world"),
+        ("a/only-a.txt", ""),
+        ("b/same.txt", "hello world"),
+        ("b/sub/changed.txt", "hello World"),
+        ("b/only-b.txt", ""),
+    ] {
+        let path = root.join(path);
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        std::fs::write(path, contents)?;
+    }
+
+    let compare = |first: &str, second: &str| {
+        let mut differences = Vec::new();
+        compare_paths(
+            &root.join(first),
+            &root.join(second),
+            &Config::default(),
+            &mut |difference| differences.push(format!("{:?}", difference)),
+        );
+        differences
+    };
+    assert!(compare("a/same.txt", "b/same.txt").is_empty());
+    assert!(compare("a/same.txt", "a/sub/changed.txt").is_empty());
+    assert_eq!(
+        vec![
+            format!("OnlyIn({:?}, \"only-a.txt\")", root.join("a")),
+            format!("OnlyIn({:?}, \"only-b.txt\")", root.join("b")),
+            format!(
+                "Content({:?}, {:?})",
+                root.join("a/sub/changed.txt"),
+                root.join("b/sub/changed.txt")
+            ),
+        ],
+        compare("a", "b")
+    );
+    assert_eq!(1, compare("a/missing.txt", "b/same.txt").len());
+
+    // Symlinks inside the directories are compared by their targets, so a loop isn't followed.
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::symlink;
+
+        symlink(".", root.join("a/self"))?;
+        symlink(".", root.join("b/self"))?;
+        symlink("same.txt", root.join("a/link"))?;
+        symlink("only-b.txt", root.join("b/link"))?;
+        assert_eq!(
+            format!(
+                "Content({:?}, {:?})",
+                root.join("a/link"),
+                root.join("b/link")
+            ),
+            compare("a", "b")[0]
+        );
+        assert_eq!(4, compare("a", "b").len());
+    }
+    Ok(())
+}
+
+#[test]
+fn b3_test_wildcard_match() {
+    assert!(wildcard_match("*.b3", "files.b3"));
+    assert!(wildcard_match("B3SUM?", "B3SUMS"));
+    assert!(wildcard_match("*a*b", "xaxxab"));
+    assert!(wildcard_match("*", ""));
+    assert!(!wildcard_match("*.b3", "files.b3.bak"));
+    assert!(!wildcard_match("?", ""));
+
+    let matches = |pattern, path| path_pattern_match(pattern, Path::new(path));
+    assert!(matches("photos/**", "photos/2021/a.jpg"));
+    assert!(matches("photos/**", "./photos/a.jpg"));
+    assert!(matches("**/*.jpg", "photos/2021/a.jpg"));
+    assert!(matches("**/*.jpg", "a.jpg"));
+    assert!(matches("photos/*/a.jpg", "photos/2021/a.jpg"));
+    assert!(!matches("photos/*", "photos/2021/a.jpg"));
+    assert!(!matches("photos/**", "videos/a.mp4"));
+}
+
+#[test]
+fn b3_test_check_conflicts() -> Result<()> {
+    let root = std::env::temp_dir().join(format!("b3sum-conflicts-{}", std::process::id()));
+    let _guard = TempDirGuard { dir: root.clone() };
+    std::fs::create_dir_all(root.join("a"))?;
+    std::fs::create_dir(root.join("b"))?;
+    let file = root.join("file");
+    std::fs::write(&file, b"contents")?;
+    let checksum = b3sum_small(&mut &b"contents"[..], &Config::default())?;
+    let line = |checksum: &dyn fmt::Display| format!("{}  {}\n", checksum, file.display());
+    std::fs::write(root.join("a/B3SUMS"), line(&checksum))?;
+    std::fs::write(root.join("b/B3SUMS"), line(&checksum))?;
+    let checkfiles = expand_glob(&root.join("*/B3SUMS"));
+    assert_eq!(2, checkfiles.len());
+    let io_lock = Arc::new(Semaphore::new(1));
+    assert!(check(
+        &checkfiles,
+        &Config::default(),
+        Arc::clone(&io_lock),
+        Format::B3sum,
+        &VerifyOptions::default()
+    ));
+
+    let fail_fast = VerifyOptions {
+        fail_fast: true,
+        ..VerifyOptions::default()
+    };
+    assert!(check(
+        &checkfiles,
+        &Config::default(),
+        Arc::clone(&io_lock),
+        Format::B3sum,
+        &fail_fast
+    ));
+
+    std::fs::write(root.join("b/B3SUMS"), line(&"0".repeat(64)))?;
+    assert!(!check(
+        &checkfiles,
+        &Config::default(),
+        Arc::clone(&io_lock),
+        Format::B3sum,
+        &fail_fast
+    ));
+    assert!(!check(
+        &checkfiles,
+        &Config::default(),
+        io_lock,
+        Format::B3sum,
+        &VerifyOptions::default()
+    ));
+    Ok(())
+}
+
+#[test]
+fn b3_test_check_root() -> Result<()> {
+    let root = std::env::temp_dir().join(format!("b3sum-check-root-{}", std::process::id()));
+    let _guard = TempDirGuard { dir: root.clone() };
+    std::fs::create_dir_all(root.join("dir"))?;
+    std::fs::write(root.join("a"), b"a")?;
+    std::fs::write(root.join("dir/b"), b"b")?;
+    let line = |name: &str, contents: &[u8]| -> Result<String> {
+        let checksum = b3sum_small(&mut &contents[..], &Config::default())?;
+        Ok(format!("{}  {}\n", checksum, root.join(name).display()))
+    };
+    let checkfile = root.join("B3SUMS");
+    std::fs::write(&checkfile, line("a", b"a")?)?;
+    let options = VerifyOptions {
+        root: Some(root.clone()),
+        ..VerifyOptions::default()
+    };
+    let checkfiles = [checkfile.clone()];
+    let check = |options: &VerifyOptions| {
+        let io_lock = Arc::new(Semaphore::new(1));
+        check(
+            &checkfiles,
+            &Config::default(),
+            io_lock,
+            Format::B3sum,
+            options,
+        )
+    };
+    assert!(check(&VerifyOptions::default()));
+    assert!(!check(&options));
+
+    // Files that --only skips still count as listed.
+    std::fs::write(&checkfile, line("a", b"a")? + &line("./dir/b", b"b")?)?;
+    assert!(check(&options));
+    let only = VerifyOptions {
+        only: vec!["**/a".to_owned()],
+        ..options
+    };
+    assert!(check(&only));
+
+    // A listed file that was moved is found by its checksum.
+    std::fs::rename(root.join("a"), root.join("dir/moved"))?;
+    assert!(!check(&VerifyOptions::default()));
+    assert!(check(&only));
+    std::fs::write(root.join("dir/moved"), b"changed")?;
+    assert!(!check(&only));
+
+    let moved = vec![CheckEntry {
+        name: "a".to_owned(),
+        path: root.join("a"),
+        expected: b3sum_small(&mut &b"a"[..], &Config::default())?,
+        algorithm: Algorithm::Blake3,
+        size: None,
+        metadata: None,
+        pieces: Vec::new(),
+    }];
+    std::fs::write(root.join("copy1"), b"a")?;
+    std::fs::write(root.join("copy2"), b"a")?;
+    let candidates = [root.join("dir/b"), root.join("copy2"), root.join("copy1")];
+    let io_lock = Arc::new(Semaphore::new(1));
+    assert_eq!(
+        vec![Some(root.join("copy2"))],
+        find_moved(&moved, &candidates, &Config::default(), &io_lock)
+    );
+    Ok(())
+}
+
+#[test]
+fn b3_test_check_report() -> Result<()> {
+    let (_file, path, _guard) = make_temp_file(b"contents");
+    let checksum = b3sum_small(&mut &b"contents"[..], &Config::default())?;
+    let missing = path.with_extension("missing");
+    let checkfile = format!(
+        "{}  {}\n{}  {}\n",
+        checksum,
+        path.display(),
+        checksum,
+        missing.display()
+    );
+    let (_file, checkfile, _checkfile_guard) = make_temp_file(checkfile.as_bytes());
+    let (_file, report, _report_guard) = make_temp_file(b"");
+    let options = VerifyOptions {
+        report: Some(format!("json={}", report.display()).parse()?),
+        ..VerifyOptions::default()
+    };
+    assert!(!check(
+        std::slice::from_ref(&checkfile),
+        &Config::default(),
+        Arc::new(Semaphore::new(16)),
+        Format::B3sum,
+        &options
+    ));
+    let contents = std::fs::read_to_string(&report)?;
+    let name = json_string(&path.to_string_lossy());
+    let ok = format!("{{\"name\":{},\"path\":{},\"status\":\"ok\",", name, name);
+    assert!(contents.contains(&ok));
+    assert!(contents.contains("\"status\":\"missing\""));
+    assert!(contents.contains("\"totals\":{\"files\":2,\"ok\":1,\"unchanged\":0,\"failed\":0,"));
+    assert!(contents.ends_with("\"passed\":false}\n"));
+    assert!(!contents.contains("entropy"));
+
+    // "contents" has 4 bytes that appear once, and 2 that appear twice.
+    let config = Config {
+        entropy: true,
+        ..Config::default()
+    };
+    check(
+        std::slice::from_ref(&checkfile),
+        &config,
+        Arc::new(Semaphore::new(16)),
+        Format::B3sum,
+        &options,
+    );
+    let contents = std::fs::read_to_string(&report)?;
+    assert!(contents.contains("\"status\":\"ok\",\"seconds\":"));
+    assert!(contents.contains(",\"entropy\":2.500000}"));
+    assert_eq!(1, contents.matches("entropy").count());
+
+    assert!(!contents.contains("ssdeep"));
+
+    #[cfg(feature = "fuzzy")]
+    {
+        let config = Config {
+            ssdeep: true,
+            ..Config::default()
+        };
+        check(
+            std::slice::from_ref(&checkfile),
+            &config,
+            Arc::new(Semaphore::new(16)),
+            Format::B3sum,
+            &options,
+        );
+        let contents = std::fs::read_to_string(&report)?;
+        assert!(contents.contains(",\"ssdeep\":\"3:"));
+    }
+
+    assert!(ReportTarget::from_str("xml=report.xml").is_err());
+    assert!(ReportTarget::from_str("json=").is_err());
+    assert!(ReportTarget::from_str("report.json").is_err());
+    Ok(())
+}
+
+#[test]
+fn b3_test_check_quarantine() -> Result<()> {
+    let root = std::env::temp_dir().join(format!("b3sum-quarantine-{}", std::process::id()));
+    let _guard = TempDirGuard { dir: root.clone() };
+    std::fs::create_dir_all(root.join("dir"))?;
+    std::fs::write(root.join("dir/good"), b"good")?;
+    let checksum = b3sum_small(&mut &b"good"[..], &Config::default())?;
+    let checkfile = root.join("B3SUMS");
+    let contents: String = ["dir/good", "dir/bad"]
+        .iter()
+        .map(|name| format!("{}  {}\n", checksum, root.join(name).display()))
+        .collect();
+    std::fs::write(&checkfile, contents)?;
+    let check = |action: &str| {
+        std::fs::write(root.join("dir/bad"), b"bad").unwrap();
+        let options = VerifyOptions {
+            on_mismatch: Some(MismatchAction::from_str(action).unwrap()),
+            ..VerifyOptions::default()
+        };
+        let io_lock = Arc::new(Semaphore::new(1));
+        let checkfiles = [checkfile.clone()];
+        assert!(!check(
+            &checkfiles,
+            &Config::default(),
+            io_lock,
+            Format::B3sum,
+            &options
+        ));
+        assert!(!root.join("dir/bad").exists());
+        assert!(root.join("dir/good").exists());
+    };
+
+    check("rename-suffix");
+    assert_eq!(
+        b"bad".to_vec(),
+        std::fs::read(root.join("dir/bad.mismatch"))?
+    );
+    check("rename-suffix:.corrupt");
+    assert!(root.join("dir/bad.corrupt").exists());
+    let quarantine = root.join("quarantine");
+    check(&format!("move:{}", quarantine.display()));
+    let moved: PathBuf = root
+        .join("dir/bad")
+        .components()
+        .filter(|component| matches!(component, std::path::Component::Normal(_)))
+        .collect();
+    assert!(quarantine.join(moved).exists());
+    check("delete");
+
+    // A file isn't moved over another one.
+    std::fs::write(root.join("dir/bad"), b"bad")?;
+    let rename = MismatchAction::from_str("rename-suffix")?;
+    assert!(rename.apply(&root.join("dir/bad")).is_err());
+    assert!(root.join("dir/bad").exists());
+    assert!(MismatchAction::from_str("move:").is_err());
+    assert!(MismatchAction::from_str("shred").is_err());
+    Ok(())
+}
+
+#[test]
+fn b3_test_socket_address() {
+    assert_eq!("example.com:80", socket_address("example.com"));
+    assert_eq!("example.com:8080", socket_address("example.com:8080"));
+    assert_eq!("[::1]:80", socket_address("[::1]"));
+    assert_eq!("[::1]:8080", socket_address("[::1]:8080"));
+    assert_eq!("[2001:db8::1]:80", socket_address("[2001:db8::1]"));
+}
+
+#[test]
+fn b3_test_notify_failure() -> Result<()> {
+    use std::net::TcpListener;
+
+    let (_file, path, _guard) = make_temp_file(b"contents");
+    let (_file, other, _other_guard) = make_temp_file(b"other");
+    let checksum = b3sum_small(&mut &b"contents"[..], &Config::default())?;
+    let contents = format!(
+        "{}  {}\n{}  {}\n",
+        checksum,
+        path.display(),
+        Checksum(vec![0; 32]),
+        other.display()
+    );
+    let (_file, checkfile, _checkfile_guard) = make_temp_file(contents.as_bytes());
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let url = format!("http://{}/hooks/b3sum", listener.local_addr()?);
+    let server = std::thread::spawn(move || {
+        let (mut stream, _) = listener.accept().unwrap();
+        let mut request = Vec::new();
+        let mut buf = [0; 4096];
+        // Read until the body, which ends the request, is complete.
+        while !String::from_utf8_lossy(&request).ends_with("}\n") {
+            let length = stream.read(&mut buf).unwrap();
+            assert!(length > 0, "the request ended early");
+            request.extend_from_slice(&buf[..length]);
+        }
+        stream
+            .write_all(b"HTTP/1.1 204 No Content\r\n\r\n")
+            .unwrap();
+        String::from_utf8(request).unwrap()
+    });
+    let (_file, hooked, _hooked_guard) = make_temp_file(b"");
+    let options = VerifyOptions {
+        notify_url: Some(url),
+        on_fail: Some(format!("cat > '{}'", hooked.display())),
+        ..VerifyOptions::default()
+    };
+    let semaphore = Arc::new(Semaphore::new(16));
+    assert!(!check(
+        &[checkfile],
+        &Config::default(),
+        semaphore,
+        Format::B3sum,
+        &options
+    ));
+    let request = server.join().unwrap();
+    assert!(request.starts_with("POST /hooks/b3sum HTTP/1.1\r\n"));
+    assert!(request.contains("\r\nContent-Type: application/json\r\n"));
+    let payload = request.split("\r\n\r\n").nth(1).unwrap();
+    // Only the file that failed is listed, and the totals count both.
+    let listed = |name: &Path| payload.contains(&json_string(&name.to_string_lossy()));
+    assert!(listed(&other) && !listed(&path));
+    assert!(payload.contains("\"totals\":{\"files\":2,\"ok\":1,"));
+    assert!(payload.ends_with("\"passed\":false}\n"));
+    if cfg!(unix) {
+        assert_eq!(payload, std::fs::read_to_string(&hooked)?);
+    }
+
+    assert!(post_json("https://example.com/", "{}").is_err());
+    assert!(run_hook("exit 3", "{}").is_err());
+    Ok(())
+}
+
+#[test]
+fn b3_test_prometheus_report() -> Result<()> {
+    let (_file, path, _guard) = make_temp_file(b"contents");
+    let checksum = b3sum_small(&mut &b"contents"[..], &Config::default())?;
+    let (_file, report, _report_guard) = make_temp_file(b"");
+    let options = VerifyOptions {
+        report: Some(format!("prometheus={}", report.display()).parse()?),
+        ..VerifyOptions::default()
+    };
+    let check_with = |checksum: &Checksum| -> Result<String> {
+        let contents = format!("{}  {}\n", checksum, path.display());
+        let (_file, checkfile, _guard) = make_temp_file(contents.as_bytes());
+        let semaphore = Arc::new(Semaphore::new(16));
+        check(
+            &[checkfile],
+            &Config::default(),
+            semaphore,
+            Format::B3sum,
+            &options,
+        );
+        Ok(std::fs::read_to_string(&report)?)
+    };
+    let value = |contents: &str, name: &str| -> f64 {
+        let prefix = format!("b3sum_ng_check_{} ", name);
+        let line = contents.lines().find(|line| line.starts_with(&prefix));
+        line.unwrap()[prefix.len()..].parse().unwrap()
+    };
+
+    let failed = check_with(&Checksum(vec![0; 32]))?;
+    assert!(failed.contains("# TYPE b3sum_ng_check_files gauge\n"));
+    assert!(failed.contains("\nb3sum_ng_check_files{status=\"failed\"} 1\n"));
+    assert!(failed.contains("\nb3sum_ng_check_files{status=\"ok\"} 0\n"));
+    assert_eq!(0.0, value(&failed, "passed"));
+    // Only the file that was checked is counted, though other tests read files at the same time.
+    assert_eq!(8.0, value(&failed, "bytes_read"));
+    assert_eq!(0.0, value(&failed, "last_success_timestamp_seconds"));
+    assert!(value(&failed, "last_run_timestamp_seconds") > 0.0);
+
+    let passed = check_with(&checksum)?;
+    assert_eq!(1.0, value(&passed, "passed"));
+    let last_success = value(&passed, "last_success_timestamp_seconds");
+    assert_eq!(value(&passed, "last_run_timestamp_seconds"), last_success);
+    // A failure keeps the time of the last success.
+    let failed = check_with(&Checksum(vec![0; 32]))?;
+    assert_eq!(
+        last_success,
+        value(&failed, "last_success_timestamp_seconds")
+    );
+    Ok(())
+}
+
+#[test]
+fn b3_test_path_map() {
+    let map = |path: &str| PathMap::from_str(path).unwrap();
+    let options = VerifyOptions {
+        strip_prefix: 0,
+        path_map: vec![map("/data/archive=/mnt/restore"), map("/data=/mnt/data")],
+        ..VerifyOptions::default()
+    };
+    let apply = |options: &VerifyOptions, path: &str| options.apply(Path::new(path));
+    assert_eq!(
+        PathBuf::from("/mnt/restore/a/b.txt"),
+        apply(&options, "/data/archive/a/b.txt")
+    );
+    assert_eq!(
+        PathBuf::from("/mnt/data/other"),
+        apply(&options, "/data/other")
+    );
+    // Only whole directories match.
+    assert_eq!(PathBuf::from("/data2/file"), apply(&options, "/data2/file"));
+
+    let options = VerifyOptions {
+        strip_prefix: 2,
+        path_map: vec![map("a=restored")],
+        ..VerifyOptions::default()
+    };
+    assert_eq!(
+        PathBuf::from("restored/b.txt"),
+        apply(&options, "/data/archive/a/b.txt")
+    );
+    assert_eq!(PathBuf::from("c"), apply(&options, "./a/b/c"));
+    assert!(PathMap::from_str("no-equals").is_err());
+    assert!(PathMap::from_str("=new").is_err());
+}
+
+#[test]
+fn b3_test_ignore_case() -> Result<()> {
+    let root = std::env::temp_dir().join(format!("b3sum-case-{}", std::process::id()));
+    let _guard = TempDirGuard { dir: root.clone() };
+    std::fs::create_dir_all(root.join("Photos"))?;
+    std::fs::write(root.join("Photos/IMG_0001.JPG"), b"photo")?;
+    let options = VerifyOptions {
+        ignore_case: true,
+        ..VerifyOptions::default()
+    };
+    let listed = root.join("photos").join("img_0001.jpg");
+    assert_eq!(root.join("Photos/IMG_0001.JPG"), options.apply(&listed));
+    let missing = root.join("photos/missing.jpg");
+    assert_eq!(missing, options.apply(&missing));
+    #[cfg(unix)]
+    assert_eq!(
+        root.join("Photos/IMG_0001.JPG"),
+        options.apply(Path::new(&format!(
+            "{}\\photos\\img_0001.jpg",
+            root.display()
+        )))
+    );
+    Ok(())
+}